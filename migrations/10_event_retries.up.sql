@@ -0,0 +1,6 @@
+ALTER TABLE events ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE events ADD COLUMN next_attempt TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE events ADD COLUMN last_error TEXT;
+
+DROP INDEX events_undelivered;
+CREATE INDEX events_undelivered ON events (next_attempt) WHERE delivered IS NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE replies ADD COLUMN in_reply_to INTEGER;
+
+CREATE INDEX replies_in_reply_to ON replies (in_reply_to) WHERE in_reply_to IS NOT NULL;
@@ -0,0 +1,96 @@
+-- Denormalized rows used to render the thread list, kept up to date by the
+-- triggers below so that the index is a single SELECT.
+CREATE TABLE thread_summaries (
+  thread_id INTEGER PRIMARY KEY,
+  title TEXT NOT NULL,
+  tags INTEGER[] NOT NULL,
+  tag_names TEXT[] NOT NULL,
+  last_post INTEGER NOT NULL,
+  last_poster_id INTEGER,
+  last_poster_name TEXT,
+  last_activity TIMESTAMP,
+  num_replies INTEGER NOT NULL,
+  pinned BOOLEAN NOT NULL,
+  locked BOOLEAN NOT NULL,
+  hidden BOOLEAN NOT NULL
+);
+
+CREATE INDEX thread_summaries_tags ON thread_summaries USING GIN (tags);
+CREATE INDEX thread_summaries_order ON thread_summaries (pinned DESC, last_post DESC);
+
+CREATE FUNCTION refresh_thread_summary(summary_thread_id INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM thread_summaries WHERE thread_id = summary_thread_id;
+  INSERT INTO thread_summaries
+  SELECT
+    threads.id,
+    threads.title,
+    threads.tags,
+    ARRAY(
+      SELECT tags.name
+      FROM unnest(threads.tags) WITH ORDINALITY AS tag(id, position)
+      JOIN tags ON tags.id = tag.id
+      ORDER BY tag.position
+    ),
+    threads.last_post,
+    users.id,
+    users.name,
+    replies.post_date,
+    threads.num_replies,
+    threads.pinned,
+    threads.locked,
+    threads.hidden
+  FROM threads
+  LEFT JOIN replies ON replies.id = threads.last_post
+  LEFT JOIN users ON users.id = replies.author_id
+  WHERE threads.id = summary_thread_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION thread_summaries_threads_changed() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    DELETE FROM thread_summaries WHERE thread_id = OLD.id;
+  ELSE
+    PERFORM refresh_thread_summary(NEW.id);
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER thread_summaries_threads
+AFTER INSERT OR UPDATE OR DELETE ON threads
+FOR EACH ROW EXECUTE FUNCTION thread_summaries_threads_changed();
+
+CREATE FUNCTION thread_summaries_tags_changed() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE thread_summaries SET tag_names = ARRAY(
+    SELECT tags.name
+    FROM unnest(thread_summaries.tags) WITH ORDINALITY AS tag(id, position)
+    JOIN tags ON tags.id = tag.id
+    ORDER BY tag.position
+  )
+  WHERE thread_summaries.tags @> ARRAY[NEW.id];
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER thread_summaries_tags
+AFTER UPDATE OF name ON tags
+FOR EACH ROW EXECUTE FUNCTION thread_summaries_tags_changed();
+
+CREATE FUNCTION thread_summaries_users_changed() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE thread_summaries SET last_poster_name = NEW.name
+  WHERE last_poster_id = NEW.id;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER thread_summaries_users
+AFTER UPDATE OF name ON users
+FOR EACH ROW EXECUTE FUNCTION thread_summaries_users_changed();
+
+CREATE INDEX thread_summaries_last_poster ON thread_summaries (last_poster_id);
+
+SELECT refresh_thread_summary(id) FROM threads;
@@ -0,0 +1,46 @@
+ALTER TABLE threads ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE thread_summaries ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE OR REPLACE FUNCTION refresh_thread_summary(summary_thread_id INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM thread_summaries WHERE thread_id = summary_thread_id;
+  INSERT INTO thread_summaries (
+    thread_id,
+    title,
+    tags,
+    tag_names,
+    last_post,
+    last_poster_id,
+    last_poster_name,
+    last_activity,
+    num_replies,
+    pinned,
+    locked,
+    hidden,
+    archived
+  )
+  SELECT
+    threads.id,
+    threads.title,
+    threads.tags,
+    ARRAY(
+      SELECT tags.name
+      FROM unnest(threads.tags) WITH ORDINALITY AS tag(id, position)
+      JOIN tags ON tags.id = tag.id
+      ORDER BY tag.position
+    ),
+    threads.last_post,
+    users.id,
+    users.name,
+    replies.post_date,
+    threads.num_replies,
+    threads.pinned,
+    threads.locked,
+    threads.hidden,
+    threads.archived
+  FROM threads
+  LEFT JOIN replies ON replies.id = threads.last_post
+  LEFT JOIN users ON users.id = replies.author_id
+  WHERE threads.id = summary_thread_id;
+END;
+$$ LANGUAGE plpgsql;
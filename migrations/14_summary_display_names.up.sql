@@ -0,0 +1,59 @@
+-- The thread list shows display names, not the names used to log in.
+CREATE OR REPLACE FUNCTION refresh_thread_summary(summary_thread_id INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM thread_summaries WHERE thread_id = summary_thread_id;
+  INSERT INTO thread_summaries (
+    thread_id,
+    title,
+    tags,
+    tag_names,
+    last_post,
+    last_poster_id,
+    last_poster_name,
+    last_activity,
+    num_replies,
+    pinned,
+    locked,
+    hidden,
+    archived
+  )
+  SELECT
+    threads.id,
+    threads.title,
+    threads.tags,
+    ARRAY(
+      SELECT tags.name
+      FROM unnest(threads.tags) WITH ORDINALITY AS tag(id, position)
+      JOIN tags ON tags.id = tag.id
+      ORDER BY tag.position
+    ),
+    threads.last_post,
+    users.id,
+    users.display_name,
+    replies.post_date,
+    threads.num_replies,
+    threads.pinned,
+    threads.locked,
+    threads.hidden,
+    threads.archived
+  FROM threads
+  LEFT JOIN replies ON replies.id = threads.last_post
+  LEFT JOIN users ON users.id = replies.author_id
+  WHERE threads.id = summary_thread_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION thread_summaries_users_changed() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE thread_summaries SET last_poster_name = NEW.display_name
+  WHERE last_poster_id = NEW.id;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER thread_summaries_users ON users;
+CREATE TRIGGER thread_summaries_users
+AFTER UPDATE OF display_name ON users
+FOR EACH ROW EXECUTE FUNCTION thread_summaries_users_changed();
+
+SELECT refresh_thread_summary(id) FROM threads;
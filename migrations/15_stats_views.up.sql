@@ -0,0 +1,27 @@
+-- Aggregates that are too expensive to compute on every request. These are
+-- refreshed periodically by the server, see `stats::refresh_views`.
+CREATE MATERIALIZED VIEW leaderboard AS
+SELECT
+  id AS user_id,
+  ROW_NUMBER() OVER (ORDER BY experience DESC, id ASC) AS rank
+FROM users
+ORDER BY experience DESC, id ASC
+LIMIT 100;
+
+CREATE UNIQUE INDEX leaderboard_user_id ON leaderboard (user_id);
+
+CREATE MATERIALIZED VIEW forum_stats AS
+SELECT
+  TRUE AS singleton,
+  (SELECT COUNT(*) FROM users) AS users,
+  (SELECT COUNT(*) FROM threads) AS threads,
+  (SELECT COUNT(*) FROM replies) AS replies,
+  (SELECT COUNT(*) FROM drops) AS drops,
+  (SELECT COUNT(*) FROM trade_requests) AS open_trades;
+
+CREATE UNIQUE INDEX forum_stats_singleton ON forum_stats (singleton);
+
+CREATE TABLE view_refreshes (
+  name TEXT PRIMARY KEY,
+  refreshed TIMESTAMP NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN home_tags TEXT NOT NULL DEFAULT 'en';
@@ -0,0 +1,4 @@
+CREATE TABLE limits (
+  name TEXT PRIMARY KEY,
+  value BIGINT NOT NULL
+);
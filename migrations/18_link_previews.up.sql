@@ -0,0 +1,12 @@
+-- Previews of links posted in replies. Rows are inserted when a link is first
+-- seen and filled in once the page has been fetched.
+CREATE TABLE link_previews (
+  url TEXT PRIMARY KEY,
+  title TEXT,
+  description TEXT,
+  image TEXT,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  fetched TIMESTAMP
+);
+
+CREATE INDEX link_previews_pending ON link_previews (url) WHERE fetched IS NULL;
@@ -0,0 +1,10 @@
+-- Threads that have been deleted or merged into another thread, so that their
+-- old URLs can explain what happened.
+CREATE TABLE thread_tombstones (
+  thread_id INTEGER PRIMARY KEY,
+  title TEXT NOT NULL,
+  hidden BOOLEAN NOT NULL,
+  merged_into INTEGER,
+  moderator_id INTEGER NOT NULL,
+  removed TIMESTAMP NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Form of each user name shared by all names that look alike, used to reject
+-- names that impersonate existing users. Filled in for existing users on
+-- startup, since it cannot be computed in SQL.
+ALTER TABLE users ADD COLUMN name_skeleton TEXT;
+
+CREATE INDEX users_name_skeleton ON users (name_skeleton);
@@ -0,0 +1,11 @@
+CREATE TYPE username_rule_kind AS ENUM ('reserved', 'banned');
+
+-- Patterns of names that users may not register, managed by administrators.
+CREATE TABLE username_rules (
+  id SERIAL PRIMARY KEY,
+  pattern TEXT NOT NULL,
+  is_regex BOOLEAN NOT NULL,
+  kind username_rule_kind NOT NULL,
+  created_by INTEGER NOT NULL,
+  created TIMESTAMP NOT NULL
+);
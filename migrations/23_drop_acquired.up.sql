@@ -0,0 +1,11 @@
+-- When the current owner of a drop received it. Drops that were created
+-- after events were introduced and have not changed hands since can be
+-- dated from their DropCreated event; older ones are left unknown.
+ALTER TABLE drops ADD COLUMN acquired TIMESTAMP;
+
+UPDATE drops SET acquired = events.created
+FROM events
+WHERE
+  events.event->>'type' = 'DropCreated'
+  AND (events.event->>'drop_id')::INTEGER = drops.id
+  AND (events.event->>'owner_id')::INTEGER = drops.owner_id;
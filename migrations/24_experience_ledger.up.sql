@@ -0,0 +1,19 @@
+-- Every change to a user's experience and what caused it. `delta` is the
+-- change that was actually applied, which can be smaller than the value of
+-- the cause since experience never drops below zero.
+CREATE TYPE experience_source AS ENUM (
+  'reaction'
+);
+
+CREATE TABLE experience_ledger (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  delta BIGINT NOT NULL,
+  source experience_source NOT NULL,
+  reply_id INT,
+  drop_id INT,
+  source_user_id INT,
+  created TIMESTAMP NOT NULL
+);
+
+CREATE INDEX experience_ledger_user_id ON experience_ledger (user_id, created);
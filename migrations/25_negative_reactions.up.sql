@@ -0,0 +1,3 @@
+-- Whether other users may react to the user's replies with reactions that
+-- take experience away.
+ALTER TABLE users ADD COLUMN negative_reactions BOOLEAN NOT NULL DEFAULT TRUE;
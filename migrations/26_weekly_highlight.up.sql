@@ -0,0 +1,21 @@
+-- The reply that received the most reactions in the past week, shown on the
+-- index. Refreshed with the other views, see `stats::refresh_views`.
+CREATE MATERIALIZED VIEW weekly_highlight AS
+SELECT
+  replies.id AS reply_id,
+  threads.id AS thread_id,
+  threads.title,
+  COUNT(*) AS reactions
+FROM experience_ledger
+JOIN replies ON replies.id = experience_ledger.reply_id
+JOIN threads ON threads.id = replies.thread_id
+WHERE
+  experience_ledger.source = 'reaction'
+  AND experience_ledger.created >= (NOW() AT TIME ZONE 'UTC') - INTERVAL '7 days'
+  AND NOT replies.hidden
+  AND NOT threads.hidden
+GROUP BY replies.id, threads.id
+ORDER BY reactions DESC, replies.id DESC
+LIMIT 1;
+
+CREATE UNIQUE INDEX weekly_highlight_reply_id ON weekly_highlight (reply_id);
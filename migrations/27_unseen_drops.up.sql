@@ -0,0 +1,6 @@
+-- Whether the owner has been shown the drop. Existing drops count as seen,
+-- new ones are unseen until the owner's browser reveals them.
+ALTER TABLE drops ADD COLUMN seen BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE drops ALTER COLUMN seen SET DEFAULT FALSE;
+
+CREATE INDEX drops_unseen ON drops (owner_id) WHERE NOT seen;
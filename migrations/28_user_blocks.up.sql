@@ -0,0 +1,6 @@
+CREATE TABLE user_blocks (
+  blocker_id INT NOT NULL,
+  blocked_id INT NOT NULL,
+  created TIMESTAMP NOT NULL,
+  PRIMARY KEY (blocker_id, blocked_id)
+);
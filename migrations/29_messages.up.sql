@@ -0,0 +1,25 @@
+-- Private messages. Every pair of users has a single conversation, stored
+-- with the smaller user id first.
+CREATE TABLE conversations (
+  id SERIAL PRIMARY KEY,
+  first_user_id INT NOT NULL,
+  second_user_id INT NOT NULL,
+  last_message TIMESTAMP NOT NULL,
+  UNIQUE (first_user_id, second_user_id),
+  CHECK (first_user_id < second_user_id)
+);
+
+CREATE INDEX conversations_second_user_id ON conversations (second_user_id);
+
+CREATE TABLE messages (
+  id SERIAL PRIMARY KEY,
+  conversation_id INT NOT NULL,
+  sender_id INT NOT NULL,
+  recipient_id INT NOT NULL,
+  body TEXT NOT NULL,
+  sent TIMESTAMP NOT NULL,
+  read TIMESTAMP
+);
+
+CREATE INDEX messages_conversation_id ON messages (conversation_id, id);
+CREATE INDEX messages_unread ON messages (recipient_id) WHERE read IS NULL;
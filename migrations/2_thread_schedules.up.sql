@@ -0,0 +1,2 @@
+ALTER TABLE threads ADD COLUMN locked_at TIMESTAMP;
+ALTER TABLE threads ADD COLUMN pinned_until TIMESTAMP;
@@ -0,0 +1,14 @@
+-- Every change to a user's name, kept so that moderators can follow users
+-- across renames.
+CREATE TABLE name_history (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  old_display_name TEXT NOT NULL,
+  old_name TEXT NOT NULL,
+  new_display_name TEXT NOT NULL,
+  new_name TEXT NOT NULL,
+  changed_by INT NOT NULL,
+  changed TIMESTAMP NOT NULL
+);
+
+CREATE INDEX name_history_user_id ON name_history (user_id, changed);
@@ -0,0 +1,15 @@
+CREATE TYPE achievement AS ENUM (
+  'first_post',
+  'hundred_replies',
+  'week_streak',
+  'first_legendary',
+  'first_trade'
+);
+
+-- Achievements are awarded at most once per user.
+CREATE TABLE achievements (
+  user_id INT NOT NULL,
+  achievement achievement NOT NULL,
+  awarded TIMESTAMP NOT NULL,
+  PRIMARY KEY (user_id, achievement)
+);
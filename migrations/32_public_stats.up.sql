@@ -0,0 +1,24 @@
+-- Aggregates that anyone may see, served to community sites that embed live
+-- stats. Refreshed with the other views, see `stats::refresh_views`.
+CREATE MATERIALIZED VIEW public_stats AS
+SELECT
+  TRUE AS singleton,
+  (SELECT COUNT(*) FROM users WHERE deleted IS NULL) AS members,
+  (
+    SELECT COUNT(*) FROM replies
+    WHERE post_date >= (NOW() AT TIME ZONE 'UTC')::DATE AND NOT hidden
+  ) AS posts_today,
+  (
+    SELECT COUNT(DISTINCT replies.thread_id) FROM replies
+    JOIN threads ON threads.id = replies.thread_id
+    WHERE
+      replies.post_date >= (NOW() AT TIME ZONE 'UTC') - INTERVAL '1 day'
+      AND NOT replies.hidden
+      AND NOT threads.hidden
+  ) AS active_threads,
+  (
+    SELECT COUNT(*) FROM drops
+    WHERE acquired >= (NOW() AT TIME ZONE 'UTC') - INTERVAL '7 days'
+  ) AS drops_this_week;
+
+CREATE UNIQUE INDEX public_stats_singleton ON public_stats (singleton);
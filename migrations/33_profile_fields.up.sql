@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN pronouns TEXT NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN location TEXT NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN website TEXT NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN signature TEXT NOT NULL DEFAULT '';
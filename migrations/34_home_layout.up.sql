@@ -0,0 +1,6 @@
+-- Sections shown on the page logged in users land on, in order. There is at
+-- most one layout; without one, users are sent to their home tags.
+CREATE TABLE home_layout (
+  singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+  sections JSONB NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE user_follows (
+  follower_id INT NOT NULL,
+  followee_id INT NOT NULL,
+  created TIMESTAMP NOT NULL,
+  PRIMARY KEY (follower_id, followee_id)
+);
+
+-- The following feed looks up the latest replies of each followed user.
+CREATE INDEX replies_author_id ON replies (author_id, id);
@@ -0,0 +1,3 @@
+-- Which notifications each user wants, see `NotificationSettings`. Missing
+-- keys mean the notification is wanted.
+ALTER TABLE users ADD COLUMN notification_settings JSONB NOT NULL DEFAULT '{}';
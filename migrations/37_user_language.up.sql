@@ -0,0 +1,4 @@
+-- The language tag a user reads, detected from their browser on their first
+-- visit. Users that have not picked home tags land on it.
+ALTER TABLE users ADD COLUMN language TEXT;
+ALTER TABLE users ALTER COLUMN home_tags SET DEFAULT '';
@@ -0,0 +1,12 @@
+-- Rarities rolled for drops, kept so that the odds can be checked against
+-- what users actually receive.
+CREATE TABLE drop_rolls (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  rarity rarity NOT NULL,
+  -- Item chosen for the rolled rarity, if any was available.
+  item_id INT,
+  rolled TIMESTAMP NOT NULL
+);
+
+CREATE INDEX drop_rolls_user_id ON drop_rolls (user_id, rolled);
@@ -0,0 +1,12 @@
+ALTER TYPE user_role ADD VALUE 'trusted';
+
+-- Accounts created before this migration are taken to have joined when they
+-- first posted or logged in.
+ALTER TABLE users ADD COLUMN joined TIMESTAMP;
+UPDATE users SET joined = COALESCE(
+  (SELECT MIN(post_date) FROM replies WHERE replies.author_id = users.id),
+  (SELECT MIN(session_start) FROM login_sessions WHERE login_sessions.user_id = users.id),
+  NOW() AT TIME ZONE 'utc'
+);
+ALTER TABLE users ALTER COLUMN joined SET DEFAULT (NOW() AT TIME ZONE 'utc');
+ALTER TABLE users ALTER COLUMN joined SET NOT NULL;
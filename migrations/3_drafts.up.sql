@@ -0,0 +1,8 @@
+CREATE TABLE drafts (
+  id SERIAL PRIMARY KEY,
+  author_id INT NOT NULL,
+  thread_id INT NOT NULL,
+  body TEXT NOT NULL,
+  updated TIMESTAMP NOT NULL,
+  UNIQUE (author_id, thread_id)
+);
@@ -0,0 +1,9 @@
+-- Accounts that were merged into another by an admin, usually because the
+-- owner lost access and registered again.
+CREATE TABLE user_merges (
+  id SERIAL PRIMARY KEY,
+  from_id INT NOT NULL,
+  to_id INT NOT NULL,
+  merged_by INT NOT NULL,
+  merged TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Picture uploaded by the user, shown when no avatar item is equipped.
+ALTER TABLE users ADD COLUMN avatar TEXT;
@@ -0,0 +1,17 @@
+-- Every successful login, so that users can see where their account has been
+-- used from. `ip_range` is the /24 (IPv4) or /48 (IPv6) network of the
+-- address, and `new_range` whether the user had never logged in from it
+-- before.
+CREATE TABLE login_audit (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  ip_addr CIDR NOT NULL,
+  ip_range CIDR NOT NULL,
+  user_agent TEXT NOT NULL,
+  country TEXT,
+  new_range BOOLEAN NOT NULL,
+  logged_in TIMESTAMP NOT NULL
+);
+
+CREATE INDEX login_audit_user_id ON login_audit (user_id, logged_in);
+CREATE INDEX login_audit_ip_range ON login_audit (user_id, ip_range);
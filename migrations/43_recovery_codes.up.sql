@@ -0,0 +1,11 @@
+-- Single use codes that can be used in place of the reset code to recover an
+-- account. Only hashes of the codes are stored.
+CREATE TABLE recovery_codes (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  code_hash TEXT NOT NULL,
+  created TIMESTAMP NOT NULL,
+  used TIMESTAMP
+);
+
+CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
@@ -0,0 +1,90 @@
+-- Hidden replies are left out of the reply counts and last posts shown in the
+-- thread list. Threads keep both the total, used to paginate for moderators,
+-- and the visible count, kept up to date by the trigger below.
+ALTER TABLE threads ADD COLUMN visible_replies INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE threads ADD COLUMN last_visible_post INTEGER NOT NULL DEFAULT 0;
+
+CREATE FUNCTION refresh_visible_replies(visible_thread_id INTEGER) RETURNS VOID AS $$
+DECLARE
+  first_post INTEGER;
+BEGIN
+  SELECT MIN(id) INTO first_post FROM replies WHERE thread_id = visible_thread_id;
+  UPDATE threads SET
+    visible_replies = (
+      SELECT COUNT(*) FROM replies
+      WHERE thread_id = visible_thread_id AND id > first_post AND NOT hidden
+    ),
+    last_visible_post = COALESCE(
+      (SELECT MAX(id) FROM replies WHERE thread_id = visible_thread_id AND NOT hidden),
+      first_post,
+      0
+    )
+  WHERE id = visible_thread_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION visible_replies_replies_changed() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    PERFORM refresh_visible_replies(NEW.thread_id);
+  ELSIF TG_OP = 'DELETE' THEN
+    PERFORM refresh_visible_replies(OLD.thread_id);
+  ELSE
+    PERFORM refresh_visible_replies(NEW.thread_id);
+    IF OLD.thread_id <> NEW.thread_id THEN
+      PERFORM refresh_visible_replies(OLD.thread_id);
+    END IF;
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER visible_replies_replies
+AFTER INSERT OR DELETE OR UPDATE OF hidden, thread_id ON replies
+FOR EACH ROW EXECUTE FUNCTION visible_replies_replies_changed();
+
+CREATE OR REPLACE FUNCTION refresh_thread_summary(summary_thread_id INTEGER) RETURNS VOID AS $$
+BEGIN
+  DELETE FROM thread_summaries WHERE thread_id = summary_thread_id;
+  INSERT INTO thread_summaries (
+    thread_id,
+    title,
+    tags,
+    tag_names,
+    last_post,
+    last_poster_id,
+    last_poster_name,
+    last_activity,
+    num_replies,
+    pinned,
+    locked,
+    hidden,
+    archived
+  )
+  SELECT
+    threads.id,
+    threads.title,
+    threads.tags,
+    ARRAY(
+      SELECT tags.name
+      FROM unnest(threads.tags) WITH ORDINALITY AS tag(id, position)
+      JOIN tags ON tags.id = tag.id
+      ORDER BY tag.position
+    ),
+    threads.last_visible_post,
+    users.id,
+    users.display_name,
+    replies.post_date,
+    threads.visible_replies,
+    threads.pinned,
+    threads.locked,
+    threads.hidden,
+    threads.archived
+  FROM threads
+  LEFT JOIN replies ON replies.id = threads.last_visible_post
+  LEFT JOIN users ON users.id = replies.author_id
+  WHERE threads.id = summary_thread_id;
+END;
+$$ LANGUAGE plpgsql;
+
+SELECT refresh_visible_replies(id) FROM threads;
@@ -0,0 +1,54 @@
+-- Leaderboards of the experience gained over the past week and month, from
+-- the experience ledger, alongside the all time leaderboard. Each keeps
+-- enough ranks to be paged through.
+DROP MATERIALIZED VIEW leaderboard;
+
+CREATE MATERIALIZED VIEW leaderboard AS
+SELECT
+  id AS user_id,
+  ROW_NUMBER() OVER (ORDER BY experience DESC, id ASC) AS rank,
+  experience
+FROM users
+WHERE deleted IS NULL
+ORDER BY experience DESC, id ASC
+LIMIT 1000;
+
+CREATE UNIQUE INDEX leaderboard_user_id ON leaderboard (user_id);
+
+CREATE MATERIALIZED VIEW weekly_leaderboard AS
+SELECT
+  experience_ledger.user_id,
+  ROW_NUMBER() OVER (
+    ORDER BY SUM(experience_ledger.delta) DESC, experience_ledger.user_id ASC
+  ) AS rank,
+  SUM(experience_ledger.delta)::BIGINT AS experience
+FROM experience_ledger
+JOIN users ON users.id = experience_ledger.user_id
+WHERE
+  experience_ledger.created >= (NOW() AT TIME ZONE 'UTC') - INTERVAL '7 days'
+  AND users.deleted IS NULL
+GROUP BY experience_ledger.user_id
+HAVING SUM(experience_ledger.delta) > 0
+ORDER BY rank
+LIMIT 1000;
+
+CREATE UNIQUE INDEX weekly_leaderboard_user_id ON weekly_leaderboard (user_id);
+
+CREATE MATERIALIZED VIEW monthly_leaderboard AS
+SELECT
+  experience_ledger.user_id,
+  ROW_NUMBER() OVER (
+    ORDER BY SUM(experience_ledger.delta) DESC, experience_ledger.user_id ASC
+  ) AS rank,
+  SUM(experience_ledger.delta)::BIGINT AS experience
+FROM experience_ledger
+JOIN users ON users.id = experience_ledger.user_id
+WHERE
+  experience_ledger.created >= (NOW() AT TIME ZONE 'UTC') - INTERVAL '30 days'
+  AND users.deleted IS NULL
+GROUP BY experience_ledger.user_id
+HAVING SUM(experience_ledger.delta) > 0
+ORDER BY rank
+LIMIT 1000;
+
+CREATE UNIQUE INDEX monthly_leaderboard_user_id ON monthly_leaderboard (user_id);
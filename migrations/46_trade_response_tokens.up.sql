@@ -0,0 +1,3 @@
+-- Hash of a token included in the links to accept or decline an offer from
+-- its notification. Offers made before this have no links.
+ALTER TABLE trade_requests ADD COLUMN response_token TEXT;
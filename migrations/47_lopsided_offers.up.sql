@@ -0,0 +1,3 @@
+-- Offers that ask a new or low level account for far more than they give, so
+-- that the receiver is asked to confirm before accepting.
+ALTER TABLE trade_requests ADD COLUMN lopsided BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+-- Mythic items sit between legendary and unique ones. Unique was never added
+-- to the type, so it is added here as well.
+ALTER TYPE rarity ADD VALUE IF NOT EXISTS 'mythic' AFTER 'legendary';
+ALTER TYPE rarity ADD VALUE IF NOT EXISTS 'unique' AFTER 'mythic';
@@ -0,0 +1,24 @@
+-- Tokens that let bots act as a user with an Authorization header instead of
+-- a session cookie. Only hashes of the tokens are stored.
+CREATE TABLE api_tokens (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE,
+  created TIMESTAMP NOT NULL,
+  last_used TIMESTAMP,
+  revoked TIMESTAMP
+);
+
+CREATE INDEX api_tokens_user_id ON api_tokens (user_id);
+
+-- Requests made with each API token, counted per day, so that owners can spot
+-- a leaked token and admins an abusive bot.
+CREATE TABLE api_token_usage (
+  token_id INT NOT NULL,
+  day DATE NOT NULL,
+  requests BIGINT NOT NULL DEFAULT 0,
+  -- Requests that were answered with an error status.
+  errors BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (token_id, day)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE cluster_counters (
+  key TEXT PRIMARY KEY,
+  count BIGINT NOT NULL,
+  expires TIMESTAMP NOT NULL
+);
+
+CREATE TABLE feature_flags (
+  name TEXT PRIMARY KEY,
+  enabled BOOLEAN NOT NULL
+);
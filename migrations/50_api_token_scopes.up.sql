@@ -0,0 +1,2 @@
+-- Kinds of requests each API token may make: read, post and trade.
+ALTER TABLE api_tokens ADD COLUMN scopes TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,20 @@
+-- Why a user was banned and by whom, shown to them while they are banned.
+ALTER TABLE users ADD COLUMN ban_reason TEXT;
+ALTER TABLE users ADD COLUMN banned_by INT;
+
+-- Appeals against bans, reviewed by moderators. A user may appeal each ban
+-- once, bans being told apart by when they end.
+CREATE TABLE ban_appeals (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  banned_until TIMESTAMP NOT NULL,
+  body TEXT NOT NULL,
+  submitted TIMESTAMP NOT NULL,
+  resolved TIMESTAMP,
+  resolved_by INT,
+  -- Whether the ban was lifted when the appeal was resolved.
+  lifted BOOLEAN NOT NULL DEFAULT FALSE,
+  UNIQUE (user_id, banned_until)
+);
+
+CREATE INDEX ban_appeals_unresolved ON ban_appeals (submitted) WHERE resolved IS NULL;
@@ -0,0 +1,4 @@
+-- License and attribution chosen by the poster for an attached image, shown
+-- beneath it.
+ALTER TABLE replies ADD COLUMN image_license TEXT;
+ALTER TABLE replies ADD COLUMN image_attribution TEXT;
@@ -0,0 +1,12 @@
+-- Networks that may neither register nor log in. A ban without an expiry
+-- lasts until a moderator removes it.
+CREATE TABLE ip_bans (
+  id SERIAL PRIMARY KEY,
+  ip_range CIDR NOT NULL,
+  reason TEXT,
+  banned_by INT NOT NULL,
+  created TIMESTAMP NOT NULL,
+  expires TIMESTAMP
+);
+
+CREATE INDEX ip_bans_ip_range ON ip_bans USING gist (ip_range inet_ops);
@@ -0,0 +1,13 @@
+-- Replies queued by moderators, published once `publish_at` has passed even
+-- if the thread is locked.
+CREATE TABLE scheduled_replies (
+  id SERIAL PRIMARY KEY,
+  thread_id INT NOT NULL,
+  author_id INT NOT NULL,
+  body TEXT NOT NULL,
+  publish_at TIMESTAMP NOT NULL,
+  created TIMESTAMP NOT NULL
+);
+
+CREATE INDEX scheduled_replies_publish_at ON scheduled_replies (publish_at);
+CREATE INDEX scheduled_replies_thread_id ON scheduled_replies (thread_id);
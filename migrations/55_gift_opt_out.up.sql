@@ -0,0 +1,2 @@
+-- Whether other users may give the user items.
+ALTER TABLE users ADD COLUMN accepts_gifts BOOLEAN NOT NULL DEFAULT TRUE;
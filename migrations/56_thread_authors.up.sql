@@ -0,0 +1,10 @@
+-- Users the creator of a thread has made co-authors of it. Co-authors may
+-- edit the first post and pin replies, as the creator can.
+CREATE TABLE thread_authors (
+  thread_id INT NOT NULL,
+  user_id INT NOT NULL,
+  added TIMESTAMP NOT NULL,
+  PRIMARY KEY (thread_id, user_id)
+);
+
+CREATE INDEX thread_authors_user_id ON thread_authors (user_id);
@@ -0,0 +1,15 @@
+-- Drops put up for sale on the market. A listing asks for a set of items in
+-- return, by item id; a buyer pays with drops of those items. Listings whose
+-- drop has since changed hands are left out of the market.
+CREATE TABLE listings (
+  id SERIAL PRIMARY KEY,
+  seller_id INT NOT NULL,
+  drop_id INT NOT NULL,
+  asking_items INT[] NOT NULL,
+  listed TIMESTAMP NOT NULL,
+  sold TIMESTAMP,
+  buyer_id INT
+);
+
+CREATE UNIQUE INDEX listings_drop_id ON listings (drop_id) WHERE sold IS NULL;
+CREATE INDEX listings_listed ON listings (listed) WHERE sold IS NULL;
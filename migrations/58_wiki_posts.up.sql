@@ -0,0 +1,14 @@
+-- Wiki posts may be edited by any user of a high enough level.
+ALTER TABLE replies ADD COLUMN wiki BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Every version of an edited reply. The original body is recorded along with
+-- the first edit, so a reply that was never edited has no revisions.
+CREATE TABLE reply_revisions (
+  id SERIAL PRIMARY KEY,
+  reply_id INT NOT NULL,
+  editor_id INT NOT NULL,
+  body TEXT NOT NULL,
+  edited TIMESTAMP NOT NULL
+);
+
+CREATE INDEX reply_revisions_reply_id ON reply_revisions (reply_id);
@@ -0,0 +1,22 @@
+-- External RSS feeds whose items are posted as threads in a tag, authored by
+-- a bot account.
+CREATE TABLE ingested_feeds (
+  id SERIAL PRIMARY KEY,
+  url TEXT NOT NULL UNIQUE,
+  tag TEXT NOT NULL,
+  bot_id INT NOT NULL,
+  added TIMESTAMP NOT NULL,
+  last_fetched TIMESTAMP,
+  -- Consecutive fetches that failed.
+  failures INT NOT NULL DEFAULT 0
+);
+
+-- Items of a feed that have been posted, by their GUID, so that no item is
+-- posted twice.
+CREATE TABLE ingested_items (
+  feed_id INT NOT NULL,
+  guid TEXT NOT NULL,
+  thread_id INT,
+  ingested TIMESTAMP NOT NULL,
+  PRIMARY KEY (feed_id, guid)
+);
@@ -0,0 +1 @@
+ALTER TABLE tags ADD COLUMN alias_of INTEGER;
@@ -0,0 +1,28 @@
+-- Soft currency. Users earn it by posting and by reaching new levels, and
+-- by selling drops they no longer want, and can spend it on the market.
+ALTER TABLE users ADD COLUMN balance BIGINT NOT NULL DEFAULT 0;
+
+CREATE TYPE wallet_reason AS ENUM (
+  'post',
+  'level_up',
+  'sale',
+  'purchase',
+  'market_sale'
+);
+
+-- Every change to a user's balance. `reference_id` is the reply, level, drop
+-- or listing the change is for, and no change is recorded twice for the same
+-- one, so that rewards are never granted twice.
+CREATE TABLE wallet_ledger (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  delta BIGINT NOT NULL,
+  reason wallet_reason NOT NULL,
+  reference_id INT NOT NULL,
+  created TIMESTAMP NOT NULL
+);
+
+CREATE UNIQUE INDEX wallet_ledger_reference ON wallet_ledger (user_id, reason, reference_id);
+CREATE INDEX wallet_ledger_user_id ON wallet_ledger (user_id, created);
+
+ALTER TABLE listings ADD COLUMN price BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+-- Recipes for crafting: a number of drops of one item are consumed to make a
+-- drop of an item of higher rarity.
+CREATE TABLE craft_recipes (
+  id SERIAL PRIMARY KEY,
+  input_item_id INT NOT NULL,
+  input_count INT NOT NULL,
+  output_item_id INT NOT NULL,
+  created_by INT NOT NULL,
+  created TIMESTAMP NOT NULL
+);
+
+CREATE INDEX craft_recipes_input_item_id ON craft_recipes (input_item_id);
@@ -0,0 +1,12 @@
+-- Weekly email digests of unread activity, which users opt in to. The token
+-- lets a user unsubscribe from a link in a digest without logging in.
+ALTER TABLE users ADD COLUMN email_digest BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN digest_token TEXT;
+ALTER TABLE users ADD COLUMN last_digest TIMESTAMP;
+
+CREATE INDEX users_last_digest ON users (last_digest) WHERE email_digest;
+
+-- When each item was added to the catalog, so that digests can list new
+-- items. Items added before this are left without a date.
+ALTER TABLE items ADD COLUMN added TIMESTAMP;
+ALTER TABLE items ALTER COLUMN added SET DEFAULT (NOW() AT TIME ZONE 'UTC');
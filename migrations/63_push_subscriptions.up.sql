@@ -0,0 +1,13 @@
+-- Web Push subscriptions, each made from a login session. Notifications are
+-- only pushed to subscriptions whose session is still logged in.
+CREATE TABLE push_subscriptions (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  session_id INT NOT NULL,
+  endpoint TEXT NOT NULL UNIQUE,
+  p256dh TEXT NOT NULL,
+  auth TEXT NOT NULL,
+  created TIMESTAMP NOT NULL
+);
+
+CREATE INDEX push_subscriptions_user_id ON push_subscriptions (user_id);
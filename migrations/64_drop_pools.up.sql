@@ -0,0 +1,21 @@
+-- Limited-time drop pools. While a pool is active, drops are chosen from the
+-- items in active pools. Items in no pool make up the evergreen pool, which
+-- drops are chosen from otherwise.
+CREATE TABLE drop_pools (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  starts TIMESTAMP NOT NULL,
+  ends TIMESTAMP NOT NULL,
+  created_by INT NOT NULL,
+  created TIMESTAMP NOT NULL
+);
+
+CREATE INDEX drop_pools_ends ON drop_pools (ends);
+
+CREATE TABLE drop_pool_items (
+  pool_id INT NOT NULL,
+  item_id INT NOT NULL,
+  PRIMARY KEY (pool_id, item_id)
+);
+
+CREATE INDEX drop_pool_items_item_id ON drop_pool_items (item_id);
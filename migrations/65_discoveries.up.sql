@@ -0,0 +1,13 @@
+-- Items each user has ever owned, for the catalog. Existing discoveries are
+-- taken from the drops users hold now, since earlier owners are not recorded,
+-- and are undated where the drop's acquisition is.
+CREATE TABLE discoveries (
+  user_id INT NOT NULL,
+  item_id INT NOT NULL,
+  discovered TIMESTAMP,
+  PRIMARY KEY (user_id, item_id)
+);
+
+INSERT INTO discoveries (user_id, item_id, discovered)
+  SELECT owner_id, item_id, MIN(acquired) FROM drops
+  GROUP BY owner_id, item_id;
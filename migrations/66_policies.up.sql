@@ -0,0 +1,19 @@
+-- Versioned terms of service and privacy policy. Policies are only ever
+-- added, so the policies a user has accepted are every policy up to the id
+-- stored on the user.
+CREATE TYPE policy_kind AS ENUM (
+  'terms',
+  'privacy'
+);
+
+CREATE TABLE policies (
+  id SERIAL PRIMARY KEY,
+  kind policy_kind NOT NULL,
+  version INT NOT NULL,
+  body TEXT NOT NULL,
+  published_by INT NOT NULL,
+  published TIMESTAMP NOT NULL,
+  UNIQUE (kind, version)
+);
+
+ALTER TABLE users ADD COLUMN policies_accepted INT NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- Content ratings of tags. Threads with a mature tag are left out of listings,
+-- search and feeds for users who have not confirmed they want to see them.
+CREATE TYPE content_rating AS ENUM (
+  'general',
+  'mature'
+);
+
+ALTER TABLE tags ADD COLUMN rating content_rating NOT NULL DEFAULT 'general';
+
+ALTER TABLE users ADD COLUMN show_mature BOOLEAN NOT NULL DEFAULT FALSE;
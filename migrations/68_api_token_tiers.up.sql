@@ -0,0 +1,9 @@
+-- Rate limit tiers of API tokens, set by admins for bots that need more than
+-- the default number of requests.
+CREATE TYPE rate_limit_tier AS ENUM (
+  'default',
+  'elevated',
+  'bot'
+);
+
+ALTER TABLE api_tokens ADD COLUMN tier rate_limit_tier NOT NULL DEFAULT 'default';
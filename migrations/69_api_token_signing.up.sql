@@ -0,0 +1,3 @@
+-- Keys that requests made with an API token must be signed with, so that a
+-- token leaked in a log cannot be replayed. Tokens without one are not signed.
+ALTER TABLE api_tokens ADD COLUMN signing_key TEXT;
@@ -0,0 +1,6 @@
+-- Whether the user has seen the alert for a login from a new network, so that
+-- alerts sent while they were away are shown when they come back. Earlier
+-- logins count as seen.
+ALTER TABLE login_audit ADD COLUMN alert_seen BOOLEAN NOT NULL DEFAULT TRUE;
+
+CREATE INDEX login_audit_unseen_alerts ON login_audit (user_id) WHERE NOT alert_seen;
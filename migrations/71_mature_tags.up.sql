@@ -0,0 +1,5 @@
+-- Whether any of a thread's tags is rated mature. Shared by every query that
+-- leaves mature threads out for users who have not opted in to them.
+CREATE FUNCTION has_mature_tag(tag_ids INTEGER[]) RETURNS BOOLEAN AS $$
+  SELECT EXISTS (SELECT 1 FROM tags WHERE id = ANY(tag_ids) AND rating = 'mature');
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,4 @@
+-- Time until which an instance has claimed a link to fetch its preview. Links
+-- are claimed in a short transaction and fetched outside of it, and a claim
+-- that runs out is taken over by the next pass.
+ALTER TABLE link_previews ADD COLUMN claimed_until TIMESTAMP;
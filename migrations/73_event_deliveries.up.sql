@@ -0,0 +1,8 @@
+-- Subscribers that each event has been delivered to, so that a failed
+-- delivery is only retried for the subscribers that failed.
+CREATE TABLE event_deliveries (
+  event_id BIGINT NOT NULL,
+  subscriber TEXT NOT NULL,
+  delivered TIMESTAMP NOT NULL,
+  PRIMARY KEY (event_id, subscriber)
+);
@@ -0,0 +1,2 @@
+-- Expired counters are swept out periodically.
+CREATE INDEX cluster_counters_expires ON cluster_counters (expires);
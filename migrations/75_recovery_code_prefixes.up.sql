@@ -0,0 +1,4 @@
+-- Start of each recovery code, which is not secret and lets a code be found
+-- without checking its hash against every code the user has left. Codes made
+-- before there were prefixes have none.
+ALTER TABLE recovery_codes ADD COLUMN prefix TEXT;
@@ -0,0 +1 @@
+ALTER TABLE replies ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,8 @@
+CREATE TABLE thread_templates (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  author_id INT NOT NULL,
+  title TEXT NOT NULL,
+  tags TEXT NOT NULL,
+  body TEXT NOT NULL
+);
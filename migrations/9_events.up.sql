@@ -0,0 +1,8 @@
+CREATE TABLE events (
+  id BIGSERIAL PRIMARY KEY,
+  event JSONB NOT NULL,
+  created TIMESTAMP NOT NULL,
+  delivered TIMESTAMP
+);
+
+CREATE INDEX events_undelivered ON events (id) WHERE delivered IS NULL;
@@ -0,0 +1,226 @@
+//! Achievements awarded for taking part in the forum, such as posting every
+//! day for a week. They are evaluated as events are delivered, and awarding
+//! one that a user already has does nothing, so an event that is delivered
+//! twice never awards anything twice.
+use axum::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool, Type};
+
+use crate::{
+    events::{Event, Subscriber},
+    items::Rarity,
+    notifications::{NotificationKind, Notifications},
+};
+
+/// Number of replies needed for `Achievement::HundredReplies`.
+const MANY_REPLIES: i64 = 100;
+
+/// Number of consecutive days with a post needed for
+/// `Achievement::WeekStreak`.
+const STREAK_DAYS: i64 = 7;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "achievement")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Achievement {
+    FirstPost,
+    HundredReplies,
+    WeekStreak,
+    FirstLegendary,
+    FirstTrade,
+}
+
+impl Achievement {
+    pub const ALL: &'static [Achievement] = &[
+        Achievement::FirstPost,
+        Achievement::HundredReplies,
+        Achievement::WeekStreak,
+        Achievement::FirstLegendary,
+        Achievement::FirstTrade,
+    ];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::FirstPost => "First Words",
+            Self::HundredReplies => "Regular",
+            Self::WeekStreak => "On a Roll",
+            Self::FirstLegendary => "Legend",
+            Self::FirstTrade => "Trader",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::FirstPost => "Post for the first time",
+            Self::HundredReplies => "Post 100 replies",
+            Self::WeekStreak => "Post every day for a week",
+            Self::FirstLegendary => "Find a Legendary item",
+            Self::FirstTrade => "Complete a trade",
+        }
+    }
+
+    /// Symbol shown as the achievement's badge.
+    pub fn badge(self) -> &'static str {
+        match self {
+            Self::FirstPost => "✏️",
+            Self::HundredReplies => "💬",
+            Self::WeekStreak => "🔥",
+            Self::FirstLegendary => "👑",
+            Self::FirstTrade => "🤝",
+        }
+    }
+
+    /// Award the achievement to a user. Returns whether they did not have it
+    /// yet.
+    pub async fn award(self, conn: impl PgExecutor<'_>, user_id: i32) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query(
+            r#"
+                INSERT INTO achievements (user_id, achievement, awarded)
+                VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id)
+        .bind(self)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+}
+
+/// An achievement a user has earned.
+#[derive(Debug, FromRow, Serialize)]
+pub struct AwardedAchievement {
+    pub user_id:     i32,
+    pub achievement: Achievement,
+    pub awarded:     NaiveDateTime,
+}
+
+impl AwardedAchievement {
+    /// Every achievement a user has earned, in the order they earned them.
+    pub async fn fetch_for(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM achievements WHERE user_id = $1 ORDER BY awarded ASC")
+            .bind(user_id)
+            .fetch_all(conn)
+            .await
+    }
+}
+
+/// Awards achievements as events happen and notifies users that earn one.
+pub struct Achievements {
+    notifications: Notifications,
+}
+
+impl Achievements {
+    pub fn new(notifications: Notifications) -> Self {
+        Self { notifications }
+    }
+
+    async fn award(
+        &self,
+        conn: &PgPool,
+        user_id: i32,
+        achievement: Achievement,
+    ) -> Result<(), sqlx::Error> {
+        if achievement.award(conn, user_id).await? {
+            self.notifications
+                .notify(
+                    user_id,
+                    NotificationKind::Achievement {
+                        title:       achievement.title().to_string(),
+                        description: achievement.description().to_string(),
+                        badge:       achievement.badge().to_string(),
+                    },
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn reply_created(&self, conn: &PgPool, author_id: i32) -> Result<(), sqlx::Error> {
+        let replies: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM replies WHERE author_id = $1")
+            .bind(author_id)
+            .fetch_one(conn)
+            .await?;
+        if replies >= 1 {
+            self.award(conn, author_id, Achievement::FirstPost).await?;
+        }
+        if replies >= MANY_REPLIES {
+            self.award(conn, author_id, Achievement::HundredReplies)
+                .await?;
+        }
+
+        // Days are counted in UTC, like every other timestamp.
+        let days: i64 = sqlx::query_scalar(
+            r#"
+                SELECT COUNT(DISTINCT post_date::DATE) FROM replies
+                WHERE author_id = $1 AND post_date >= $2::DATE - $3::INT + 1
+            "#,
+        )
+        .bind(author_id)
+        .bind(Utc::now().naive_utc())
+        .bind(STREAK_DAYS as i32)
+        .fetch_one(conn)
+        .await?;
+        if days >= STREAK_DAYS {
+            self.award(conn, author_id, Achievement::WeekStreak).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn drop_created(
+        &self,
+        conn: &PgPool,
+        owner_id: i32,
+        item_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        let rarity: Rarity = sqlx::query_scalar("SELECT rarity FROM items WHERE id = $1")
+            .bind(item_id)
+            .fetch_one(conn)
+            .await?;
+        if rarity == Rarity::Legendary {
+            self.award(conn, owner_id, Achievement::FirstLegendary)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Subscriber for Achievements {
+    fn name(&self) -> &'static str {
+        "achievements"
+    }
+
+    async fn handle(&self, conn: &PgPool, event: &Event) -> anyhow::Result<()> {
+        match *event {
+            Event::ReplyCreated { author_id, .. } => {
+                self.reply_created(conn, author_id).await?;
+            }
+            Event::DropCreated {
+                owner_id, item_id, ..
+            } => {
+                self.drop_created(conn, owner_id, item_id).await?;
+            }
+            Event::TradeAccepted {
+                sender_id,
+                receiver_id,
+                ..
+            } => {
+                self.award(conn, sender_id, Achievement::FirstTrade).await?;
+                self.award(conn, receiver_id, Achievement::FirstTrade)
+                    .await?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,637 @@
+//! API tokens, which let bots act as a user by sending
+//! `Authorization: Bearer <token>` instead of a session cookie. Every token is
+//! limited to the scopes it was minted with, never acts with more than a
+//! user's role, and can never manage the account it belongs to. Tokens are
+//! random enough that a plain SHA-256 hash is safe to store, and cheap enough
+//! to check on every request.
+//!
+//! Tokens may also be minted with a signing key. Requests made with them must
+//! be signed as described in `signing`, so a token leaked in a log is useless
+//! without the key.
+//!
+//! Requests made with each token are counted per day by `record_usage`, and
+//! limited per minute by `rate_limit` according to the token's tier. The
+//! token resolved by `rate_limit` is left in the request's extensions for the
+//! `User` extractor.
+use std::{collections::HashMap, sync::Mutex};
+
+use axum::{
+    extract::{Extension, Form, Path},
+    http::{header, HeaderMap, Method, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{
+    cluster::Cluster,
+    get, post,
+    rate_limits::{RateLimit, RateLimitTier},
+    signing::{self, SignatureError},
+    users::{Role, User},
+};
+
+/// Prefix of every token, so that leaked tokens are easy to recognize.
+const TOKEN_PREFIX: &str = "marche_";
+
+/// Maximum length of a token's name.
+const MAX_TOKEN_NAME_LENGTH: usize = 64;
+
+/// Maximum number of tokens a user may have that are not revoked.
+const MAX_TOKENS_PER_USER: i64 = 10;
+
+/// Number of days of usage shown for a token.
+const USAGE_DAYS: i64 = 30;
+
+/// How often the usage counted in memory is written.
+const USAGE_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Usage of a token over a day that has yet to be written.
+struct PendingUsage {
+    requests:  i64,
+    errors:    i64,
+    last_used: NaiveDateTime,
+}
+
+lazy_static! {
+    /// Usage per token hash and day that has yet to be written.
+    static ref PENDING_USAGE: Mutex<HashMap<(String, NaiveDate), PendingUsage>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Paths that can only be reached with a session, as they manage the account
+/// or how it is accessed.
+const SESSION_ONLY_PATHS: &[&str] = &[
+    "/account",
+    "/admin",
+    "/api_tokens",
+    "/bio",
+    "/display_name",
+    "/logout",
+    "/logout_all",
+    "/policies",
+    "/profile/avatar",
+    "/profile/security",
+    "/profile/settings",
+    "/push",
+    "/reset_password",
+    "/security",
+    "/sessions",
+    "/settings",
+    "/tokens",
+    "/view_as",
+];
+
+/// Paths of requests that trade, gift or equip items.
+const TRADE_PATHS: &[&str] = &[
+    "/accept",
+    "/craft",
+    "/decline",
+    "/equip",
+    "/gift",
+    "/give",
+    "/market/buy",
+    "/market/cancel",
+    "/market/list",
+    "/offer",
+    "/offers",
+    "/sell",
+    "/unequip",
+];
+
+/// Kinds of requests a token may make.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// Any `GET` request.
+    Read,
+    /// Requests that change anything other than items, such as posting.
+    Post,
+    /// Requests that trade, gift or equip items.
+    Trade,
+}
+
+impl TokenScope {
+    pub const ALL: &'static [Self] = &[Self::Read, Self::Post, Self::Trade];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Post => "post",
+            Self::Trade => "trade",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|scope| scope.name() == name)
+    }
+
+    /// Scope needed to make a request, or None if tokens may not make it at
+    /// all.
+    pub fn required_for(method: &Method, path: &str) -> Option<Self> {
+        let matches = |prefix: &&str| {
+            path.strip_prefix(prefix)
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        if SESSION_ONLY_PATHS.iter().any(matches) {
+            None
+        } else if TRADE_PATHS.iter().any(matches) {
+            Some(Self::Trade)
+        } else if method == Method::GET {
+            Some(Self::Read)
+        } else {
+            Some(Self::Post)
+        }
+    }
+}
+
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct ApiToken {
+    pub id:          i32,
+    pub user_id:     i32,
+    pub name:        String,
+    #[serde(skip)]
+    pub token_hash:  String,
+    pub scopes:      Vec<String>,
+    pub tier:        RateLimitTier,
+    #[serde(skip)]
+    pub signing_key: Option<String>,
+    pub created:     NaiveDateTime,
+    pub last_used:   Option<NaiveDateTime>,
+    pub revoked:     Option<NaiveDateTime>,
+}
+
+impl ApiToken {
+    /// The token that has not been revoked matching a secret, if any.
+    pub async fn fetch_by_secret(
+        conn: impl PgExecutor<'_>,
+        secret: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM api_tokens WHERE token_hash = $1 AND revoked IS NULL")
+            .bind(hash(secret))
+            .fetch_optional(conn)
+            .await
+    }
+
+    /// Every token of a user that has not been revoked, newest first.
+    pub async fn fetch_for(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT * FROM api_tokens WHERE user_id = $1 AND revoked IS NULL ORDER BY id DESC",
+        )
+        .bind(user_id)
+        .fetch_all(conn)
+        .await
+    }
+
+    pub fn allows(&self, scope: TokenScope) -> bool {
+        self.scopes.iter().any(|name| name == scope.name())
+    }
+
+    /// Whether requests made with the token must be signed.
+    pub fn is_signed(&self) -> bool {
+        self.signing_key.is_some()
+    }
+
+    /// Check the signature of a request made with the token, if it has a
+    /// signing key.
+    pub async fn verify_signature(
+        &self,
+        cluster: &Cluster,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+    ) -> Result<(), SignatureError> {
+        let Some(ref signing_key) = self.signing_key else {
+            return Ok(());
+        };
+        let signer = format!("api_token:{}", self.id);
+        signing::verify(cluster, &signer, signing_key, method, uri, headers).await
+    }
+}
+
+/// The secret of an `Authorization: Bearer` header, if there is one.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct MintTokenForm {
+    name:   String,
+    /// Scopes separated by commas or spaces, e.g. `read,post`.
+    scopes: String,
+    /// Whether requests made with the token must be signed.
+    #[serde(default)]
+    signed: bool,
+}
+
+/// A newly minted token. This is the only time the secret and signing key
+/// are seen.
+#[derive(Serialize)]
+pub struct MintedToken {
+    #[serde(flatten)]
+    token:       ApiToken,
+    secret:      String,
+    signing_key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum MintTokenError {
+    #[error("Token name cannot be empty")]
+    EmptyName,
+    #[error(
+        "Token name is too long (maximum {} characters allowed)",
+        MAX_TOKEN_NAME_LENGTH
+    )]
+    NameTooLong,
+    #[error("Unknown scope `{0}`")]
+    UnknownScope(String),
+    #[error("A token needs at least one scope")]
+    NoScopes,
+    #[error("You cannot have more than {} tokens", MAX_TOKENS_PER_USER)]
+    TooManyTokens,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/tokens",
+    #[json]
+    async fn mint_token(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(MintTokenForm {
+            name,
+            scopes,
+            signed,
+        }): Form<MintTokenForm>,
+    ) -> Result<MintedToken, MintTokenError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(MintTokenError::EmptyName);
+        }
+        if name.chars().count() > MAX_TOKEN_NAME_LENGTH {
+            return Err(MintTokenError::NameTooLong);
+        }
+
+        let mut parsed = Vec::new();
+        for scope in scopes
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|scope| !scope.is_empty())
+        {
+            let scope = TokenScope::from_name(scope)
+                .ok_or_else(|| MintTokenError::UnknownScope(scope.to_string()))?;
+            if !parsed.contains(&scope) {
+                parsed.push(scope);
+            }
+        }
+        if parsed.is_empty() {
+            return Err(MintTokenError::NoScopes);
+        }
+
+        let mut transaction = conn.begin().await?;
+        let existing: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM api_tokens WHERE user_id = $1 AND revoked IS NULL",
+        )
+        .bind(user.id)
+        .fetch_one(&mut transaction)
+        .await?;
+        if existing >= MAX_TOKENS_PER_USER {
+            return Err(MintTokenError::TooManyTokens);
+        }
+
+        let secret = format!(
+            "{TOKEN_PREFIX}{}",
+            base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+        );
+        let signing_key = signed
+            .then(|| base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD));
+        let token = sqlx::query_as(
+            r#"
+                INSERT INTO api_tokens (user_id, name, token_hash, scopes, signing_key, created)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                RETURNING *
+            "#,
+        )
+        .bind(user.id)
+        .bind(name)
+        .bind(hash(&secret))
+        .bind(parsed.iter().map(|scope| scope.name()).collect::<Vec<_>>())
+        .bind(&signing_key)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(MintedToken {
+            token,
+            secret,
+            signing_key,
+        })
+    }
+);
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum RevokeTokenError {
+    #[error("No such token")]
+    NoSuchToken,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/tokens/:token_id/revoke",
+    #[json]
+    async fn revoke_token(
+        conn: Extension<PgPool>,
+        cluster: Extension<Cluster>,
+        user: User,
+        Path(token_id): Path<i32>,
+    ) -> Result<(), RevokeTokenError> {
+        let revoked = sqlx::query(
+            "UPDATE api_tokens SET revoked = $1 WHERE id = $2 AND user_id = $3 AND revoked IS NULL",
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(token_id)
+        .bind(user.id)
+        .execute(&*conn)
+        .await?
+        .rows_affected();
+        if revoked == 0 {
+            return Err(RevokeTokenError::NoSuchToken);
+        }
+        RateLimit::forget(&cluster, &format!("api_token:{token_id}")).await?;
+        Ok(())
+    }
+);
+
+/// Middleware that limits how many requests each API token may make per
+/// minute according to its tier. Every response to a token tells the bot where
+/// it stands with `X-RateLimit-*` headers. Requests with tokens that do not
+/// exist are left to be rejected by the `User` extractor.
+pub async fn rate_limit<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let secret = bearer_token(req.headers()).map(str::to_string);
+    let conn = req.extensions().get::<PgPool>().cloned();
+    let cluster = req.extensions().get::<Cluster>().cloned();
+    let (Some(secret), Some(conn), Some(cluster)) = (secret, conn, cluster) else {
+        return next.run(req).await;
+    };
+
+    let limit = async {
+        let Some(token) = ApiToken::fetch_by_secret(&conn, &secret).await? else {
+            return Ok(None);
+        };
+        let client = format!("api_token:{}", token.id);
+        let limit = RateLimit::count(&cluster, &client, token.tier).await?;
+        Ok::<_, sqlx::Error>(Some((token, limit)))
+    }
+    .await;
+    let limit = match limit {
+        Ok(Some((token, limit))) => {
+            req.extensions_mut().insert(token);
+            limit
+        }
+        Ok(None) => return next.run(req).await,
+        Err(err) => {
+            tracing::error!("Failed to count API token requests: {err}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut response = if limit.exceeded() {
+        limit.rejection()
+    } else {
+        next.run(req).await
+    };
+    limit.add_headers(response.headers_mut());
+    response
+}
+
+/// Middleware that counts the requests made with each API token, and how many
+/// of them failed. Counts, along with when each token was last used, are kept
+/// in memory and written by `flush_usage`, so that busy bots don't cost a write
+/// per request. Requests with tokens that do not exist are not counted.
+pub async fn record_usage<B>(req: Request<B>, next: Next<B>) -> Response {
+    let token_hash = bearer_token(req.headers()).map(hash);
+
+    let response = next.run(req).await;
+    let Some(token_hash) = token_hash else {
+        return response;
+    };
+    let failed = response.status().is_client_error() || response.status().is_server_error();
+    let now = Utc::now().naive_utc();
+    let mut pending = PENDING_USAGE.lock().unwrap();
+    let usage = pending
+        .entry((token_hash, now.date()))
+        .or_insert(PendingUsage {
+            requests:  0,
+            errors:    0,
+            last_used: now,
+        });
+    usage.requests += 1;
+    usage.errors += failed as i64;
+    usage.last_used = now;
+    drop(pending);
+    response
+}
+
+/// Background task that periodically writes the usage counted by
+/// `record_usage`.
+pub async fn flush_usage(conn: PgPool) {
+    let mut interval = tokio::time::interval(USAGE_FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pending = std::mem::take(&mut *PENDING_USAGE.lock().unwrap());
+        if pending.is_empty() {
+            continue;
+        }
+        let mut token_hashes = Vec::with_capacity(pending.len());
+        let mut days = Vec::with_capacity(pending.len());
+        let mut requests = Vec::with_capacity(pending.len());
+        let mut errors = Vec::with_capacity(pending.len());
+        let mut last_used = Vec::with_capacity(pending.len());
+        for ((token_hash, day), usage) in pending {
+            token_hashes.push(token_hash);
+            days.push(day);
+            requests.push(usage.requests);
+            errors.push(usage.errors);
+            last_used.push(usage.last_used);
+        }
+        let result = sqlx::query(
+            r#"
+                WITH usage AS (
+                    SELECT * FROM UNNEST(
+                        $1::TEXT[], $2::DATE[], $3::BIGINT[], $4::BIGINT[], $5::TIMESTAMP[]
+                    ) AS usage (token_hash, day, requests, errors, last_used)
+                ), touched AS (
+                    UPDATE api_tokens SET last_used = GREATEST(api_tokens.last_used, latest.last_used)
+                    FROM (
+                        SELECT token_hash, MAX(last_used) AS last_used FROM usage
+                        GROUP BY token_hash
+                    ) AS latest
+                    WHERE api_tokens.token_hash = latest.token_hash
+                )
+                INSERT INTO api_token_usage (token_id, day, requests, errors)
+                SELECT api_tokens.id, usage.day, usage.requests, usage.errors
+                FROM usage
+                JOIN api_tokens ON api_tokens.token_hash = usage.token_hash
+                ON CONFLICT (token_id, day) DO UPDATE SET
+                    requests = api_token_usage.requests + EXCLUDED.requests,
+                    errors = api_token_usage.errors + EXCLUDED.errors
+            "#,
+        )
+        .bind(token_hashes)
+        .bind(days)
+        .bind(requests)
+        .bind(errors)
+        .bind(last_used)
+        .execute(&conn)
+        .await;
+        if let Err(err) = result {
+            tracing::error!("Failed to record API token usage: {err}");
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct DailyUsage {
+    pub day:      NaiveDate,
+    pub requests: i64,
+    pub errors:   i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenUsage {
+    pub token:      ApiToken,
+    /// Requests made with the token over the days shown.
+    pub requests:   i64,
+    pub errors:     i64,
+    /// Fraction of requests that failed, between 0 and 1.
+    pub error_rate: f64,
+    /// Usage on each day the token was used, most recent first.
+    pub days:       Vec<DailyUsage>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum TokenUsageError {
+    #[error("No such token")]
+    NoSuchToken,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/api_tokens/:token_id/usage",
+    #[json]
+    async fn token_usage(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(token_id): Path<i32>,
+    ) -> Result<TokenUsage, TokenUsageError> {
+        // Admins may look at any token, to find bots that misbehave.
+        let token: ApiToken = sqlx::query_as("SELECT * FROM api_tokens WHERE id = $1")
+            .bind(token_id)
+            .fetch_optional(&*conn)
+            .await?
+            .filter(|token: &ApiToken| token.user_id == user.id || user.role >= Role::Admin)
+            .ok_or(TokenUsageError::NoSuchToken)?;
+
+        let days: Vec<DailyUsage> = sqlx::query_as(
+            r#"
+                SELECT day, requests, errors FROM api_token_usage
+                WHERE token_id = $1 AND day > $2
+                ORDER BY day DESC
+            "#,
+        )
+        .bind(token.id)
+        .bind((Utc::now() - Duration::days(USAGE_DAYS)).naive_utc().date())
+        .fetch_all(&*conn)
+        .await?;
+        let requests = days.iter().map(|day| day.requests).sum::<i64>();
+        let errors = days.iter().map(|day| day.errors).sum::<i64>();
+
+        Ok(TokenUsage {
+            token,
+            requests,
+            errors,
+            error_rate: if requests > 0 {
+                errors as f64 / requests as f64
+            } else {
+                0.0
+            },
+            days,
+        })
+    }
+);
+
+#[derive(Deserialize)]
+pub struct SetTierForm {
+    tier: RateLimitTier,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum SetTierError {
+    #[error("You are not authorized to do that")]
+    Unauthorized,
+    #[error("No such token")]
+    NoSuchToken,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/admin/api_tokens/:token_id/tier",
+    #[json]
+    async fn set_token_tier(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(token_id): Path<i32>,
+        Form(SetTierForm { tier }): Form<SetTierForm>,
+    ) -> Result<ApiToken, SetTierError> {
+        if user.role < Role::Admin {
+            return Err(SetTierError::Unauthorized);
+        }
+        sqlx::query_as("UPDATE api_tokens SET tier = $1 WHERE id = $2 RETURNING *")
+            .bind(tier)
+            .bind(token_id)
+            .fetch_optional(&*conn)
+            .await?
+            .ok_or(SetTierError::NoSuchToken)
+    }
+);
@@ -0,0 +1,167 @@
+//! Fingerprinted static assets. Every file under `static/` is read at startup
+//! and served under a name containing a hash of its contents, so it can be
+//! cached forever: a new version of a file gets a new URL.
+//!
+//! Templates link to assets with `{{ crate::assets::asset("styles.css") }}`.
+//! `rarities.css` is not a file: it is generated from the rarity registry, and
+//! `branding.css` from the branding of the deployment.
+use std::{collections::HashMap, fs, path::Path as FsPath};
+
+use axum::{
+    extract::Path,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use crate::{branding, get, items};
+
+/// Directory assets are read from.
+const STATIC_DIR: &str = "static";
+
+/// Fingerprinted assets never change, so they may be cached for a year.
+const ASSET_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Number of bytes of the content hash included in fingerprinted names.
+const FINGERPRINT_LENGTH: usize = 8;
+
+lazy_static! {
+    static ref ASSETS: Assets = Assets::load(FsPath::new(STATIC_DIR));
+}
+
+struct Asset {
+    content_type: &'static str,
+    contents:     Vec<u8>,
+}
+
+#[derive(Default)]
+struct Assets {
+    /// Fingerprinted URL of each asset, keyed by its path under `static/`.
+    urls:  HashMap<String, String>,
+    /// Assets keyed by their fingerprinted name.
+    files: HashMap<String, Asset>,
+}
+
+impl Assets {
+    fn load(dir: &FsPath) -> Self {
+        let mut assets = Self::default();
+        assets.load_dir(dir, "");
+        assets.insert(
+            "rarities.css".to_string(),
+            items::rarity_stylesheet().into_bytes(),
+        );
+        assets.insert(
+            "branding.css".to_string(),
+            branding::branding_stylesheet().into_bytes(),
+        );
+        assets
+    }
+
+    fn load_dir(&mut self, dir: &FsPath, prefix: &str) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::error!("Failed to read assets from {}: {err}", dir.display());
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let name = format!("{prefix}{file_name}");
+            if path.is_dir() {
+                self.load_dir(&path, &format!("{name}/"));
+                continue;
+            }
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    tracing::error!("Failed to read asset {}: {err}", path.display());
+                    continue;
+                }
+            };
+            self.insert(name, contents);
+        }
+    }
+
+    fn insert(&mut self, name: String, contents: Vec<u8>) {
+        let fingerprinted = fingerprint(&name, &contents);
+        self.urls
+            .insert(name.clone(), format!("/assets/{fingerprinted}"));
+        self.files.insert(
+            fingerprinted,
+            Asset {
+                content_type: content_type(&name),
+                contents,
+            },
+        );
+    }
+}
+
+/// Insert a hash of the contents before the extension, e.g.
+/// `styles.css` becomes `styles.0a1b2c3d4e5f6a7b.css`. Slashes are replaced
+/// so that every asset is served from a single path segment.
+fn fingerprint(name: &str, contents: &[u8]) -> String {
+    let hash = Sha256::digest(contents)
+        .iter()
+        .take(FINGERPRINT_LENGTH)
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    let name = name.replace('/', "~");
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => format!("{stem}.{hash}.{extension}"),
+        None => format!("{name}.{hash}"),
+    }
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("ico") => "image/x-icon",
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Read and fingerprint every asset, returning how many were found. Assets
+/// are otherwise loaded when a page first links to one.
+pub fn load() -> usize {
+    ASSETS.files.len()
+}
+
+/// URL of an asset, given its path under `static/`. Assets that could not be
+/// read are linked to without a fingerprint.
+pub fn asset(name: &str) -> String {
+    ASSETS
+        .urls
+        .get(name)
+        .cloned()
+        .unwrap_or_else(|| format!("/static/{name}"))
+}
+
+get!(
+    "/assets/:name",
+    async fn serve_asset(Path(name): Path<String>) -> Response {
+        let Some(asset) = ASSETS.files.get(&name) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(asset.content_type),
+                ),
+                (
+                    header::CACHE_CONTROL,
+                    HeaderValue::from_static(ASSET_CACHE_CONTROL),
+                ),
+            ],
+            asset.contents.clone(),
+        )
+            .into_response()
+    }
+);
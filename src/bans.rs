@@ -0,0 +1,429 @@
+//! Details of bans and appeals against them. Banned users are told why they
+//! were banned and by whom, and may appeal each ban once. Appeals wait in a
+//! queue until a moderator either lifts the ban or lets it stand.
+//!
+//! Moderators may also ban whole networks, which stops anyone connecting from
+//! them from registering or logging in, save for moderators and admins.
+use std::net::IpAddr;
+
+use axum::extract::{Extension, Form, Path};
+use chrono::{Duration, NaiveDateTime, Utc};
+use ipnetwork::IpNetwork;
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{
+    get,
+    invalidation::InvalidationBus,
+    post,
+    users::{LoginSession, Role, User},
+};
+
+/// Maximum length of an appeal.
+const MAX_APPEAL_LENGTH: usize = 2000;
+
+/// Shortest prefixes an IP ban may have, so that a typo cannot ban a large
+/// part of the internet.
+const MIN_IPV4_BAN_PREFIX: u8 = 16;
+const MIN_IPV6_BAN_PREFIX: u8 = 32;
+
+/// What a banned user is shown about their ban.
+#[derive(Debug)]
+pub struct BanDetails {
+    pub until:     NaiveDateTime,
+    pub reason:    Option<String>,
+    /// Name of the moderator who issued the ban.
+    pub banned_by: Option<String>,
+    /// The user's appeal against the ban, if they have made one.
+    pub appeal:    Option<BanAppeal>,
+}
+
+impl BanDetails {
+    /// Details of a user's current ban. Returns None if they are not banned.
+    pub async fn fetch(conn: &PgPool, user: &User) -> Result<Option<Self>, sqlx::Error> {
+        let Some(until) = user.banned_until.filter(|_| user.is_banned()) else {
+            return Ok(None);
+        };
+        let banned_by = match user.banned_by {
+            Some(moderator_id) => User::fetch_optional(conn, moderator_id)
+                .await?
+                .map(|moderator| moderator.display_name),
+            None => None,
+        };
+        Ok(Some(Self {
+            until,
+            reason: user.ban_reason.clone(),
+            banned_by,
+            appeal: BanAppeal::fetch_for_ban(conn, user.id, until).await?,
+        }))
+    }
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct BanAppeal {
+    pub id:           i32,
+    pub user_id:      i32,
+    /// End of the ban that is being appealed.
+    pub banned_until: NaiveDateTime,
+    pub body:         String,
+    pub submitted:    NaiveDateTime,
+    pub resolved:     Option<NaiveDateTime>,
+    pub resolved_by:  Option<i32>,
+    pub lifted:       bool,
+}
+
+impl BanAppeal {
+    pub async fn fetch_for_ban(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+        banned_until: NaiveDateTime,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM ban_appeals WHERE user_id = $1 AND banned_until = $2")
+            .bind(user_id)
+            .bind(banned_until)
+            .fetch_optional(conn)
+            .await
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BanAppealForm {
+    body: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum BanAppealError {
+    #[error("You are not banned")]
+    NotBanned,
+    #[error("You have already appealed this ban")]
+    AlreadyAppealed,
+    #[error("Appeal cannot be empty")]
+    EmptyAppeal,
+    #[error(
+        "Appeal is too long (maximum {} characters allowed)",
+        MAX_APPEAL_LENGTH
+    )]
+    AppealTooLong,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/ban_appeal",
+    #[json]
+    async fn submit_ban_appeal(
+        conn: Extension<PgPool>,
+        // Banned users are turned away by the `User` extractor, so only their
+        // session is checked.
+        session: LoginSession,
+        Form(BanAppealForm { body }): Form<BanAppealForm>,
+    ) -> Result<BanAppeal, BanAppealError> {
+        let user = User::fetch(&*conn, session.user_id).await?;
+        let Some(until) = user.banned_until.filter(|_| user.is_banned()) else {
+            return Err(BanAppealError::NotBanned);
+        };
+
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(BanAppealError::EmptyAppeal);
+        }
+        if body.chars().count() > MAX_APPEAL_LENGTH {
+            return Err(BanAppealError::AppealTooLong);
+        }
+
+        sqlx::query_as(
+            r#"
+                INSERT INTO ban_appeals (user_id, banned_until, body, submitted)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (user_id, banned_until) DO NOTHING
+                RETURNING *
+            "#,
+        )
+        .bind(user.id)
+        .bind(until)
+        .bind(body)
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&*conn)
+        .await?
+        .ok_or(BanAppealError::AlreadyAppealed)
+    }
+);
+
+/// An appeal as listed in the moderator queue.
+#[derive(Debug, FromRow, Serialize)]
+pub struct PendingAppeal {
+    pub id:           i32,
+    pub user_id:      i32,
+    pub user_name:    String,
+    pub banned_until: NaiveDateTime,
+    pub ban_reason:   Option<String>,
+    pub banned_by:    Option<i32>,
+    pub body:         String,
+    pub submitted:    NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum BanAppealQueueError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such appeal")]
+    NoSuchAppeal,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/ban_appeals",
+    #[json]
+    async fn ban_appeal_queue(
+        conn: Extension<PgPool>,
+        moderator: User,
+    ) -> Result<Vec<PendingAppeal>, BanAppealQueueError> {
+        if moderator.role < Role::Moderator {
+            return Err(BanAppealQueueError::Unauthorized);
+        }
+        // Appeals against bans that have since ended need no answer.
+        Ok(sqlx::query_as(
+            r#"
+                SELECT
+                    ban_appeals.id,
+                    ban_appeals.user_id,
+                    users.name AS user_name,
+                    ban_appeals.banned_until,
+                    users.ban_reason,
+                    users.banned_by,
+                    ban_appeals.body,
+                    ban_appeals.submitted
+                FROM ban_appeals
+                JOIN users ON users.id = ban_appeals.user_id
+                WHERE
+                    ban_appeals.resolved IS NULL
+                    AND users.banned_until = ban_appeals.banned_until
+                    AND ban_appeals.banned_until > $1
+                ORDER BY ban_appeals.submitted ASC
+            "#,
+        )
+        .bind(Utc::now().naive_utc())
+        .fetch_all(&*conn)
+        .await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct ResolveAppealForm {
+    /// Lift the ban, rather than letting it stand.
+    #[serde(default)]
+    lift: bool,
+}
+
+post!(
+    "/ban_appeals/:appeal_id/resolve",
+    #[json]
+    async fn resolve_ban_appeal(
+        conn: Extension<PgPool>,
+        moderator: User,
+        Path(appeal_id): Path<i32>,
+        Form(ResolveAppealForm { lift }): Form<ResolveAppealForm>,
+    ) -> Result<(), BanAppealQueueError> {
+        if moderator.role < Role::Moderator {
+            return Err(BanAppealQueueError::Unauthorized);
+        }
+
+        let mut transaction = conn.begin().await?;
+        let appeal: BanAppeal = sqlx::query_as(
+            r#"
+                UPDATE ban_appeals SET resolved = $1, resolved_by = $2, lifted = $3
+                WHERE id = $4 AND resolved IS NULL
+                RETURNING *
+            "#,
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(moderator.id)
+        .bind(lift)
+        .bind(appeal_id)
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(BanAppealQueueError::NoSuchAppeal)?;
+        if appeal.user_id == moderator.id {
+            return Err(BanAppealQueueError::Unauthorized);
+        }
+
+        if lift {
+            sqlx::query(
+                r#"
+                    UPDATE users SET banned_until = NULL, ban_reason = NULL, banned_by = NULL
+                    WHERE id = $1 AND banned_until = $2
+                "#,
+            )
+            .bind(appeal.user_id)
+            .bind(appeal.banned_until)
+            .execute(&mut transaction)
+            .await?;
+            InvalidationBus::user_updated(&mut transaction, appeal.user_id).await?;
+        }
+        transaction.commit().await?;
+
+        Ok(())
+    }
+);
+
+/// A banned network.
+#[derive(Debug, FromRow, Serialize)]
+pub struct IpBan {
+    pub id:        i32,
+    pub ip_range:  IpNetwork,
+    pub reason:    Option<String>,
+    pub banned_by: i32,
+    pub created:   NaiveDateTime,
+    /// When the ban ends. Bans without an expiry last until they are removed.
+    pub expires:   Option<NaiveDateTime>,
+}
+
+impl IpBan {
+    /// The unexpired ban covering an address, if there is one.
+    pub async fn covering(
+        conn: impl PgExecutor<'_>,
+        ip: IpAddr,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT * FROM ip_bans
+                WHERE ip_range >>= $1 AND (expires IS NULL OR expires > $2)
+                ORDER BY expires DESC NULLS FIRST
+                LIMIT 1
+            "#,
+        )
+        .bind(IpNetwork::from(ip))
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(conn)
+        .await
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum IpBanError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Invalid address range")]
+    InvalidRange,
+    #[error("Address range is too wide, its prefix must be at least /{min_prefix}")]
+    RangeTooWide { min_prefix: u8 },
+    #[error("No such ban")]
+    NoSuchBan,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/ip_bans",
+    #[json]
+    async fn ip_bans(conn: Extension<PgPool>, moderator: User) -> Result<Vec<IpBan>, IpBanError> {
+        if moderator.role < Role::Moderator {
+            return Err(IpBanError::Unauthorized);
+        }
+        Ok(sqlx::query_as(
+            r#"
+                SELECT * FROM ip_bans
+                WHERE expires IS NULL OR expires > $1
+                ORDER BY created DESC
+            "#,
+        )
+        .bind(Utc::now().naive_utc())
+        .fetch_all(&*conn)
+        .await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct IpBanForm {
+    /// Address or CIDR range, e.g. `203.0.113.0/24`
+    range:   String,
+    /// Length of the ban in days. Bans without a length are permanent.
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    ban_len: Option<u32>,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    reason:  Option<String>,
+}
+
+post!(
+    "/ip_bans",
+    #[json]
+    async fn add_ip_ban(
+        conn: Extension<PgPool>,
+        moderator: User,
+        Form(IpBanForm {
+            range,
+            ban_len,
+            reason,
+        }): Form<IpBanForm>,
+    ) -> Result<IpBan, IpBanError> {
+        if moderator.role < Role::Moderator {
+            return Err(IpBanError::Unauthorized);
+        }
+        let range: IpNetwork = range.trim().parse().map_err(|_| IpBanError::InvalidRange)?;
+        // Store the network itself, so `10.1.2.3/8` bans `10.0.0.0/8`.
+        let range = IpNetwork::new(range.network(), range.prefix())
+            .map_err(|_| IpBanError::InvalidRange)?;
+        let min_prefix = match range {
+            IpNetwork::V4(_) => MIN_IPV4_BAN_PREFIX,
+            IpNetwork::V6(_) => MIN_IPV6_BAN_PREFIX,
+        };
+        if range.prefix() < min_prefix {
+            return Err(IpBanError::RangeTooWide { min_prefix });
+        }
+        let now = Utc::now();
+        let expires = ban_len.map(|days| (now + Duration::days(days as i64)).naive_utc());
+
+        Ok(sqlx::query_as(
+            r#"
+                INSERT INTO ip_bans (ip_range, reason, banned_by, created, expires)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+            "#,
+        )
+        .bind(range)
+        .bind(reason.map(|reason| reason.trim().to_string()))
+        .bind(moderator.id)
+        .bind(now.naive_utc())
+        .bind(expires)
+        .fetch_one(&*conn)
+        .await?)
+    }
+);
+
+post!(
+    "/ip_bans/:ban_id/remove",
+    #[json]
+    async fn remove_ip_ban(
+        conn: Extension<PgPool>,
+        moderator: User,
+        Path(ban_id): Path<i32>,
+    ) -> Result<(), IpBanError> {
+        if moderator.role < Role::Moderator {
+            return Err(IpBanError::Unauthorized);
+        }
+        let removed = sqlx::query("DELETE FROM ip_bans WHERE id = $1")
+            .bind(ban_id)
+            .execute(&*conn)
+            .await?
+            .rows_affected();
+        if removed == 0 {
+            return Err(IpBanError::NoSuchBan);
+        }
+        Ok(())
+    }
+);
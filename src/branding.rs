@@ -0,0 +1,144 @@
+//! Branding of a deployment: the site name, logo, accent colors and footer
+//! links. Read at startup from the JSON file named by `BRANDING_FILE`, so that
+//! another community can run the forum under its own name without editing
+//! source. Anything the file leaves out keeps its default.
+//!
+//! Templates read the branding with `{{ crate::branding::branding().site_name
+//! }}`. Colors are applied through `branding.css`, which is generated like
+//! `rarities.css`.
+use std::fs;
+
+use axum::{http::header, response::IntoResponse};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::get;
+
+lazy_static! {
+    static ref BRANDING: Branding = Branding::from_env();
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Branding {
+    /// Name shown in the header, in emails and in authenticator apps
+    pub site_name:    String,
+    /// Name used where space is short, such as under an installed app's icon
+    pub short_name:   String,
+    /// Text shown above the site name, usually an emoji
+    pub logo:         String,
+    /// Image shown above the site name instead of `logo`
+    pub logo_url:     Option<String>,
+    /// Icon of the installed app
+    pub icon_url:     String,
+    /// Color of links
+    pub accent_color: String,
+    /// Background of the header and of every panel
+    pub panel_color:  String,
+    /// Color of the browser's interface around the site
+    pub theme_color:  String,
+    pub footer_links: Vec<FooterLink>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FooterLink {
+    pub label: String,
+    pub url:   String,
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Self {
+            site_name:    "C'est le Marché".to_string(),
+            short_name:   "Marché".to_string(),
+            logo:         "⚖️".to_string(),
+            logo_url:     None,
+            icon_url:     "/static/icon.svg".to_string(),
+            accent_color: "#2e2e2e".to_string(),
+            panel_color:  "#ededed".to_string(),
+            theme_color:  "#006daa".to_string(),
+            footer_links: Vec::new(),
+        }
+    }
+}
+
+impl Branding {
+    /// Read the branding file, falling back to the defaults when none is
+    /// given or it cannot be read.
+    fn from_env() -> Self {
+        let Ok(path) = std::env::var("BRANDING_FILE") else {
+            return Self::default();
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                tracing::error!("Failed to read branding from {path}: {err}");
+                return Self::default();
+            }
+        };
+        match serde_json::from_str(&contents) {
+            Ok(branding) => branding,
+            Err(err) => {
+                tracing::error!("Invalid branding in {path}: {err}");
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Branding of this deployment.
+pub fn branding() -> &'static Branding {
+    &BRANDING
+}
+
+/// Read the branding, returning the site name. The branding is otherwise
+/// read when a page first uses it.
+pub fn load() -> &'static str {
+    &BRANDING.site_name
+}
+
+/// Stylesheet applying the accent colors.
+pub fn branding_stylesheet() -> String {
+    let Branding {
+        accent_color,
+        panel_color,
+        ..
+    } = branding();
+    format!(
+        r#"
+a {{
+    color: {accent_color};
+}}
+
+li.menu-item, div.reply-box, div.header {{
+    background: {panel_color};
+}}
+"#
+    )
+}
+
+get!(
+    "/manifest.webmanifest",
+    async fn manifest() -> impl IntoResponse {
+        let branding = branding();
+        (
+            [(header::CONTENT_TYPE, "application/manifest+json")],
+            json!({
+                "name": branding.site_name,
+                "short_name": branding.short_name,
+                "start_url": "/",
+                "scope": "/",
+                "display": "standalone",
+                "background_color": "#ffffff",
+                "theme_color": branding.theme_color,
+                "icons": [{
+                    "src": branding.icon_url,
+                    "sizes": "any",
+                    "purpose": "any",
+                }],
+            })
+            .to_string(),
+        )
+    }
+);
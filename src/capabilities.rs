@@ -0,0 +1,45 @@
+//! Optional parts of the server and whether this binary was built with them.
+//! Minimal deployments can leave out subsystems they don't need, along with
+//! their dependencies, by disabling the corresponding Cargo features.
+use marche_proc_macros::{json, ErrorCode};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    get,
+    users::{Role, User},
+};
+
+#[derive(Serialize)]
+pub struct Capabilities {
+    /// Images can be uploaded to object storage (`s3-images`).
+    pub s3_images:  bool,
+    /// Threads can be watched over websockets (`websockets`).
+    pub websockets: bool,
+}
+
+impl Capabilities {
+    pub const fn current() -> Self {
+        Self {
+            s3_images:  cfg!(feature = "s3-images"),
+            websockets: cfg!(feature = "websockets"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum CapabilitiesError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+}
+
+get!(
+    "/admin/capabilities",
+    #[json]
+    async fn capabilities(user: User) -> Result<Capabilities, CapabilitiesError> {
+        if user.role < Role::Admin {
+            return Err(CapabilitiesError::Unauthorized);
+        }
+        Ok(Capabilities::current())
+    }
+);
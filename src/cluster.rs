@@ -0,0 +1,331 @@
+//! State shared between every instance of the server.
+//!
+//! A single Marche process can keep everything in memory, but once several
+//! replicas run behind a load balancer anything that has to be seen by all of
+//! them (rate limit counters, presence, notification fan-out and feature flags)
+//! must go through a shared store instead. The backend is chosen at startup
+//! with the `CLUSTER_BACKEND` environment variable:
+//!
+//!  * `memory` (the default): single node, nothing leaves the process.
+//!  * `postgres`: counters and flags are kept in tables and messages are sent
+//!    with `NOTIFY`, so any number of instances can share one database.
+//!
+//! Per-connection state such as the watch socket limits in `threads` remains
+//! local to each instance.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::broadcast;
+
+/// Number of messages that can be buffered for a slow subscriber.
+const TOPIC_CHANNEL_CAPACITY: usize = 256;
+
+/// How long to wait before listening again after losing the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often expired counters are swept out.
+const COUNTER_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Channels that messages can be published on. Each topic maps to a Postgres
+/// channel of the same name when running with the Postgres backend.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    /// Revoked sessions and users, see `users::Revocations`.
+    Revocations,
+    /// Badge notifications, see `notifications::Notifications`.
+    Notifications,
+    /// Changed limits, see `limits::Limits`.
+    Limits,
+}
+
+impl Topic {
+    pub const ALL: &'static [Topic] = &[Topic::Revocations, Topic::Notifications, Topic::Limits];
+
+    pub fn channel(self) -> &'static str {
+        match self {
+            Self::Revocations => "cluster_revocations",
+            Self::Notifications => "cluster_notifications",
+            Self::Limits => "cluster_limits",
+        }
+    }
+
+    fn from_channel(channel: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|topic| topic.channel() == channel)
+    }
+}
+
+/// Where shared state is kept.
+#[derive(Clone)]
+pub enum ClusterBackend {
+    /// Single node: state lives in the memory of this process.
+    Memory,
+    /// Multiple nodes sharing state through a Postgres database.
+    Postgres(PgPool),
+}
+
+impl ClusterBackend {
+    /// Select the backend from the `CLUSTER_BACKEND` environment variable.
+    pub fn from_env(conn: &PgPool) -> Self {
+        match std::env::var("CLUSTER_BACKEND").as_deref() {
+            Ok("postgres") => Self::Postgres(conn.clone()),
+            Ok("memory") | Err(_) => Self::Memory,
+            Ok(other) => {
+                tracing::warn!("Unknown CLUSTER_BACKEND `{other}`, running as a single node");
+                Self::Memory
+            }
+        }
+    }
+}
+
+/// Handle to the shared state of the cluster.
+#[derive(Clone)]
+pub struct Cluster {
+    backend:  ClusterBackend,
+    topics:   Arc<HashMap<Topic, broadcast::Sender<String>>>,
+    counters: Arc<Mutex<HashMap<String, (i64, Instant)>>>,
+    flags:    Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl Cluster {
+    pub fn new(backend: ClusterBackend) -> Self {
+        let topics = Topic::ALL
+            .iter()
+            .map(|&topic| (topic, broadcast::channel(TOPIC_CHANNEL_CAPACITY).0))
+            .collect();
+        Self {
+            backend,
+            topics: Arc::new(topics),
+            counters: Default::default(),
+            flags: Default::default(),
+        }
+    }
+
+    pub fn is_distributed(&self) -> bool {
+        matches!(self.backend, ClusterBackend::Postgres(_))
+    }
+
+    /// Send a message to the subscribers of a topic on every instance.
+    pub async fn publish(&self, topic: Topic, payload: &str) -> Result<(), sqlx::Error> {
+        match self.backend {
+            ClusterBackend::Memory => self.deliver(topic, payload),
+            // Delivered back to this instance by `listen`.
+            ClusterBackend::Postgres(ref conn) => {
+                sqlx::query("SELECT pg_notify($1, $2)")
+                    .bind(topic.channel())
+                    .bind(payload)
+                    .execute(conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn subscribe(&self, topic: Topic) -> broadcast::Receiver<String> {
+        self.topics[&topic].subscribe()
+    }
+
+    fn deliver(&self, topic: Topic, payload: &str) {
+        // An error only means that nobody is currently listening.
+        let _ = self.topics[&topic].send(payload.to_string());
+    }
+
+    /// Background task that forwards messages published by any instance to
+    /// the subscribers on this one. Returns immediately for a single node.
+    pub async fn listen(self) {
+        let ClusterBackend::Postgres(ref conn) = self.backend else {
+            return;
+        };
+        loop {
+            if let Err(err) = self.forward(conn).await {
+                tracing::error!("Cluster listener failed: {err}");
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn forward(&self, conn: &PgPool) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(conn).await?;
+        listener
+            .listen_all(Topic::ALL.iter().map(|topic| topic.channel()))
+            .await?;
+        loop {
+            let notification = listener.recv().await?;
+            if let Some(topic) = Topic::from_channel(notification.channel()) {
+                self.deliver(topic, notification.payload());
+            }
+        }
+    }
+
+    /// Increment a counter that resets once `window` has passed since it was
+    /// first incremented, returning the new count. This is the building block
+    /// for rate limits and presence. The window is also how long the counter is
+    /// kept: once it has passed, the counter is removed by `sweep`.
+    pub async fn increment(&self, key: &str, window: Duration) -> Result<i64, sqlx::Error> {
+        match self.backend {
+            ClusterBackend::Memory => {
+                let mut counters = self.counters.lock().unwrap();
+                let counter = counters
+                    .entry(key.to_string())
+                    .or_insert((0, Instant::now() + window));
+                if counter.1 <= Instant::now() {
+                    *counter = (0, Instant::now() + window);
+                }
+                counter.0 += 1;
+                Ok(counter.0)
+            }
+            ClusterBackend::Postgres(ref conn) => {
+                let now = Utc::now().naive_utc();
+                let expires = now + chrono::Duration::from_std(window).unwrap();
+                sqlx::query_scalar(
+                    r#"
+                        INSERT INTO cluster_counters (key, count, expires)
+                        VALUES ($1, 1, $3)
+                        ON CONFLICT (key) DO UPDATE SET
+                            count = CASE WHEN cluster_counters.expires <= $2
+                                THEN 1 ELSE cluster_counters.count + 1 END,
+                            expires = CASE WHEN cluster_counters.expires <= $2
+                                THEN $3 ELSE cluster_counters.expires END
+                        RETURNING count
+                    "#,
+                )
+                .bind(key)
+                .bind(now)
+                .bind(expires)
+                .fetch_one(conn)
+                .await
+            }
+        }
+    }
+
+    /// Current value of a counter and how long until it resets, without
+    /// incrementing it. Counters that were never incremented or have expired
+    /// are `None`.
+    pub async fn counter(&self, key: &str) -> Result<Option<(i64, Duration)>, sqlx::Error> {
+        match self.backend {
+            ClusterBackend::Memory => {
+                let now = Instant::now();
+                Ok(self
+                    .counters
+                    .lock()
+                    .unwrap()
+                    .get(key)
+                    .filter(|(_, expires)| *expires > now)
+                    .map(|&(count, expires)| (count, expires - now)))
+            }
+            ClusterBackend::Postgres(ref conn) => {
+                let now = Utc::now().naive_utc();
+                let counter: Option<(i64, chrono::NaiveDateTime)> = sqlx::query_as(
+                    "SELECT count, expires FROM cluster_counters WHERE key = $1 AND expires > $2",
+                )
+                .bind(key)
+                .bind(now)
+                .fetch_optional(conn)
+                .await?;
+                Ok(counter.map(|(count, expires)| {
+                    (count, (expires - now).to_std().unwrap_or(Duration::ZERO))
+                }))
+            }
+        }
+    }
+
+    /// Reset a counter to zero.
+    pub async fn reset(&self, key: &str) -> Result<(), sqlx::Error> {
+        match self.backend {
+            ClusterBackend::Memory => {
+                self.counters.lock().unwrap().remove(key);
+            }
+            ClusterBackend::Postgres(ref conn) => {
+                sqlx::query("DELETE FROM cluster_counters WHERE key = $1")
+                    .bind(key)
+                    .execute(conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Background task that removes expired counters, so that keys which are
+    /// never incremented again don't pile up.
+    pub async fn sweep(self) {
+        let mut interval = tokio::time::interval(COUNTER_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.sweep_counters().await {
+                tracing::error!("Failed to sweep expired cluster counters: {err}");
+            }
+        }
+    }
+
+    async fn sweep_counters(&self) -> Result<(), sqlx::Error> {
+        match self.backend {
+            ClusterBackend::Memory => {
+                let now = Instant::now();
+                self.counters
+                    .lock()
+                    .unwrap()
+                    .retain(|_, (_, expires)| *expires > now);
+            }
+            ClusterBackend::Postgres(ref conn) => {
+                sqlx::query("DELETE FROM cluster_counters WHERE expires <= $1")
+                    .bind(Utc::now().naive_utc())
+                    .execute(conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a feature flag is enabled. Flags that were never set are off.
+    /// Flags are switched by admins while the server runs, such as
+    /// `items::MYTHIC_RARITY_FLAG`, and take effect on every instance at once.
+    /// With the memory backend flags are not persisted, so every flag is off
+    /// again after a restart.
+    pub async fn flag(&self, name: &str) -> Result<bool, sqlx::Error> {
+        match self.backend {
+            ClusterBackend::Memory => Ok(self
+                .flags
+                .lock()
+                .unwrap()
+                .get(name)
+                .copied()
+                .unwrap_or(false)),
+            ClusterBackend::Postgres(ref conn) => Ok(sqlx::query_scalar(
+                "SELECT enabled FROM feature_flags WHERE name = $1",
+            )
+            .bind(name)
+            .fetch_optional(conn)
+            .await?
+            .unwrap_or(false)),
+        }
+    }
+
+    /// Switch a feature flag on or off for every instance.
+    pub async fn set_flag(&self, name: &str, enabled: bool) -> Result<(), sqlx::Error> {
+        match self.backend {
+            ClusterBackend::Memory => {
+                self.flags.lock().unwrap().insert(name.to_string(), enabled);
+            }
+            ClusterBackend::Postgres(ref conn) => {
+                sqlx::query(
+                    r#"
+                        INSERT INTO feature_flags (name, enabled) VALUES ($1, $2)
+                        ON CONFLICT (name) DO UPDATE SET enabled = EXCLUDED.enabled
+                    "#,
+                )
+                .bind(name)
+                .bind(enabled)
+                .execute(conn)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
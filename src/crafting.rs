@@ -0,0 +1,331 @@
+//! Crafting. Admins define recipes that turn a number of drops of one item
+//! into a drop of an item of higher rarity, and users holding enough
+//! duplicates can craft them. The inputs are consumed and the output created
+//! in a single transaction.
+use axum::extract::{Extension, Form, Path};
+use chrono::{NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{
+    get,
+    items::{Item, ItemDrop, Rarity},
+    post,
+    users::{Role, User},
+};
+
+/// Fewest drops a recipe may consume.
+pub const MIN_RECIPE_INPUTS: i32 = 2;
+
+/// Most drops a recipe may consume.
+pub const MAX_RECIPE_INPUTS: i32 = 100;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct CraftRecipe {
+    pub id:             i32,
+    pub input_item_id:  i32,
+    /// Number of drops of the input item consumed
+    pub input_count:    i32,
+    pub output_item_id: i32,
+    pub created_by:     i32,
+    pub created:        NaiveDateTime,
+}
+
+impl CraftRecipe {
+    pub async fn fetch_optional(
+        conn: impl PgExecutor<'_>,
+        recipe_id: i32,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM craft_recipes WHERE id = $1")
+            .bind(recipe_id)
+            .fetch_optional(conn)
+            .await
+    }
+}
+
+/// A recipe as listed to users, with the names of its items.
+#[derive(Debug, Serialize)]
+pub struct RecipeView {
+    pub id:            i32,
+    pub input_id:      i32,
+    pub input_name:    String,
+    pub input_rarity:  String,
+    pub input_count:   i32,
+    pub output_id:     i32,
+    pub output_name:   String,
+    pub output_rarity: String,
+}
+
+#[derive(FromRow)]
+struct RecipeRow {
+    id:            i32,
+    input_id:      i32,
+    input_name:    String,
+    input_rarity:  Rarity,
+    input_count:   i32,
+    output_id:     i32,
+    output_name:   String,
+    output_rarity: Rarity,
+}
+
+impl RecipeView {
+    pub async fn fetch_all(conn: impl PgExecutor<'_>) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<RecipeRow> = sqlx::query_as(
+            r#"
+                SELECT
+                    craft_recipes.id,
+                    input.id AS input_id,
+                    input.name AS input_name,
+                    input.rarity AS input_rarity,
+                    craft_recipes.input_count,
+                    output.id AS output_id,
+                    output.name AS output_name,
+                    output.rarity AS output_rarity
+                FROM craft_recipes
+                JOIN items AS input ON input.id = craft_recipes.input_item_id
+                JOIN items AS output ON output.id = craft_recipes.output_item_id
+                ORDER BY input.name ASC, craft_recipes.id ASC
+            "#,
+        )
+        .fetch_all(conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| RecipeView {
+                id:            row.id,
+                input_id:      row.input_id,
+                input_name:    row.input_name,
+                input_rarity:  row.input_rarity.to_string(),
+                input_count:   row.input_count,
+                output_id:     row.output_id,
+                output_name:   row.output_name,
+                output_rarity: row.output_rarity.to_string(),
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum RecipesError {
+    #[error("You are not authorized to do that")]
+    Unauthorized,
+    #[error("No such recipe")]
+    NoSuchRecipe,
+    #[error("No such item")]
+    NoSuchItem,
+    #[error("The crafted item must be rarer than the items it is made from")]
+    NotRarer,
+    #[error(
+        "A recipe must consume between {} and {} items",
+        MIN_RECIPE_INPUTS,
+        MAX_RECIPE_INPUTS
+    )]
+    InvalidInputCount,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/craft",
+    #[json]
+    async fn recipes(
+        conn: Extension<PgPool>,
+        _user: User,
+    ) -> Result<Vec<RecipeView>, RecipesError> {
+        Ok(RecipeView::fetch_all(&*conn).await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct CraftForm {
+    recipe_id: i32,
+    /// Comma separated ids of the drops to consume
+    drops:     String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum CraftError {
+    #[error("No such recipe")]
+    NoSuchRecipe,
+    #[error("This recipe needs {needed} drops of the same item")]
+    WrongInputCount { needed: i32 },
+    #[error("Drops must be unconsumed drops of the item the recipe asks for")]
+    WrongInput,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/craft",
+    #[json]
+    async fn craft(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(CraftForm { recipe_id, drops }): Form<CraftForm>,
+    ) -> Result<ItemDrop, CraftError> {
+        let recipe = CraftRecipe::fetch_optional(&*conn, recipe_id)
+            .await?
+            .ok_or(CraftError::NoSuchRecipe)?;
+        let mut drop_ids = crate::parse_ids(&drops).ok_or(CraftError::WrongInput)?;
+        drop_ids.sort_unstable();
+        drop_ids.dedup();
+        if drop_ids.len() != recipe.input_count as usize {
+            return Err(CraftError::WrongInputCount {
+                needed: recipe.input_count,
+            });
+        }
+
+        let mut transaction = conn.begin().await?;
+
+        // The drops are locked so that they cannot be traded or crafted with
+        // twice at once.
+        let inputs: Vec<ItemDrop> = sqlx::query_as(
+            r#"
+                SELECT * FROM drops
+                WHERE id = ANY($1) AND owner_id = $2 AND item_id = $3 AND consumed = FALSE
+                FOR UPDATE
+            "#,
+        )
+        .bind(&drop_ids)
+        .bind(user.id)
+        .bind(recipe.input_item_id)
+        .fetch_all(&mut transaction)
+        .await?;
+        if inputs.len() != drop_ids.len() {
+            return Err(CraftError::WrongInput);
+        }
+
+        for input in &inputs {
+            input.unequip(&mut transaction).await?;
+        }
+        sqlx::query("UPDATE drops SET consumed = TRUE WHERE id = ANY($1)")
+            .bind(&drop_ids)
+            .execute(&mut transaction)
+            .await?;
+
+        let item_drop: ItemDrop = sqlx::query_as(
+            r#"
+            INSERT INTO drops (owner_id, item_id, pattern, consumed, acquired)
+            VALUES ($1, $2, $3, FALSE, $4)
+            RETURNING *
+            "#,
+        )
+        .bind(user.id)
+        .bind(recipe.output_item_id)
+        .bind(rand::random::<i32>())
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&mut transaction)
+        .await?;
+
+        item_drop.created_event().publish(&mut *transaction).await?;
+
+        transaction.commit().await?;
+
+        Ok(item_drop)
+    }
+);
+
+get!(
+    "/admin/recipes",
+    #[json]
+    async fn admin_recipes(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<Vec<CraftRecipe>, RecipesError> {
+        if user.role < Role::Admin {
+            return Err(RecipesError::Unauthorized);
+        }
+        Ok(
+            sqlx::query_as("SELECT * FROM craft_recipes ORDER BY id ASC")
+                .fetch_all(&*conn)
+                .await?,
+        )
+    }
+);
+
+#[derive(Deserialize)]
+pub struct AddRecipeForm {
+    input_item_id:  i32,
+    input_count:    i32,
+    output_item_id: i32,
+}
+
+post!(
+    "/admin/recipes",
+    #[json]
+    async fn add_recipe(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(AddRecipeForm {
+            input_item_id,
+            input_count,
+            output_item_id,
+        }): Form<AddRecipeForm>,
+    ) -> Result<CraftRecipe, RecipesError> {
+        if user.role < Role::Admin {
+            return Err(RecipesError::Unauthorized);
+        }
+        if !(MIN_RECIPE_INPUTS..=MAX_RECIPE_INPUTS).contains(&input_count) {
+            return Err(RecipesError::InvalidInputCount);
+        }
+        let input = Item::fetch_optional(&*conn, input_item_id)
+            .await?
+            .ok_or(RecipesError::NoSuchItem)?;
+        let output = Item::fetch_optional(&*conn, output_item_id)
+            .await?
+            .ok_or(RecipesError::NoSuchItem)?;
+        if output.rarity <= input.rarity {
+            return Err(RecipesError::NotRarer);
+        }
+
+        Ok(sqlx::query_as(
+            r#"
+                INSERT INTO craft_recipes
+                    (input_item_id, input_count, output_item_id, created_by, created)
+                VALUES
+                    ($1, $2, $3, $4, $5)
+                RETURNING *
+            "#,
+        )
+        .bind(input.id)
+        .bind(input_count)
+        .bind(output.id)
+        .bind(user.id)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&*conn)
+        .await?)
+    }
+);
+
+post!(
+    "/admin/recipes/:recipe_id/remove",
+    #[json]
+    async fn remove_recipe(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(recipe_id): Path<i32>,
+    ) -> Result<(), RecipesError> {
+        if user.role < Role::Admin {
+            return Err(RecipesError::Unauthorized);
+        }
+        let removed = sqlx::query("DELETE FROM craft_recipes WHERE id = $1")
+            .bind(recipe_id)
+            .execute(&*conn)
+            .await?
+            .rows_affected();
+        if removed == 0 {
+            return Err(RecipesError::NoSuchRecipe);
+        }
+        Ok(())
+    }
+);
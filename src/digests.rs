@@ -0,0 +1,416 @@
+//! Weekly email digests. Users who opt in are sent a summary of what they
+//! missed: unread replies in threads they have read, replies to their posts,
+//! pending trade offers and items new to the catalog. Every digest carries a
+//! link that unsubscribes the user in one click.
+//!
+//! Digests are only sent when `SMTP_URL`, `DIGEST_FROM` and `SITE_URL` are
+//! set. A digest is claimed before it is sent, so a digest that fails to send
+//! is skipped rather than sent twice.
+use std::time::Duration;
+
+use askama::Template;
+use axum::extract::{Extension, Path, Query};
+use chrono::{NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use lettre::{
+    message::{
+        header::{ContentType, HeaderName, HeaderValue},
+        Mailbox,
+    },
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+use sqlx::{FromRow, PgPool};
+use thiserror::Error;
+
+use crate::{get, invalidation::InvalidationBus, items::Rarity, pages::ServerError, post};
+
+/// How often the background task checks for digests that are due.
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Largest number of digests sent per pass of the background task.
+const DIGEST_BATCH_SIZE: i64 = 50;
+
+/// Largest number of entries in each section of a digest.
+const DIGEST_SECTION_LENGTH: i64 = 10;
+
+lazy_static! {
+    /// Time between digests sent to the same user.
+    static ref DIGEST_INTERVAL: chrono::Duration = chrono::Duration::days(7);
+}
+
+#[derive(Debug, Error)]
+pub enum DigestError {
+    #[error("{0} is not set")]
+    Missing(&'static str),
+    #[error("Invalid email address: {0}")]
+    InvalidAddress(#[from] lettre::address::AddressError),
+    #[error("Could not build email: {0}")]
+    Email(#[from] lettre::error::Error),
+    #[error("Could not send email: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Could not render email: {0}")]
+    Render(#[from] askama::Error),
+    #[error("Internal database error: {0}")]
+    InternalDbError(#[from] sqlx::Error),
+}
+
+/// Sends digests over SMTP.
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from:      Mailbox,
+    /// Root of the links in digests, e.g. `https://marche.example`
+    site_url:  String,
+}
+
+impl Mailer {
+    /// Configure the mailer from the environment. Returns `None` when
+    /// `SMTP_URL` is not set.
+    pub fn from_env() -> Result<Option<Self>, DigestError> {
+        let Ok(smtp_url) = std::env::var("SMTP_URL") else {
+            return Ok(None);
+        };
+        let from = std::env::var("DIGEST_FROM")
+            .map_err(|_| DigestError::Missing("DIGEST_FROM"))?
+            .parse()?;
+        let site_url = std::env::var("SITE_URL").map_err(|_| DigestError::Missing("SITE_URL"))?;
+        Ok(Some(Self {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(&smtp_url)?.build(),
+            from,
+            site_url: site_url.trim_end_matches('/').to_string(),
+        }))
+    }
+
+    async fn send(&self, recipient: &Recipient, digest: &DigestEmail) -> Result<(), DigestError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(Mailbox::new(
+                Some(recipient.display_name.clone()),
+                recipient.email.parse()?,
+            ))
+            .subject("What you missed this week")
+            .header(ContentType::TEXT_HTML)
+            .raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe"),
+                format!("<{}>", digest.unsubscribe_link),
+            ))
+            .raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                "List-Unsubscribe=One-Click".to_string(),
+            ))
+            .body(digest.render()?)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// A user who is due a digest.
+#[derive(FromRow)]
+struct Recipient {
+    id:           i32,
+    email:        String,
+    display_name: String,
+    digest_token: Option<String>,
+    /// When the previous digest was sent, if one was
+    last_digest:  Option<NaiveDateTime>,
+}
+
+#[derive(FromRow)]
+pub struct UnreadThread {
+    pub id:     i32,
+    pub title:  String,
+    pub unread: i64,
+    /// First reply the user has not read
+    pub first:  i32,
+}
+
+#[derive(FromRow)]
+pub struct ReplyToUser {
+    pub id:          i32,
+    pub title:       String,
+    pub author_name: String,
+}
+
+pub struct NewItem {
+    pub name:   String,
+    pub rarity: String,
+}
+
+#[derive(FromRow)]
+struct NewItemRow {
+    name:   String,
+    rarity: Rarity,
+}
+
+#[derive(Template)]
+#[template(path = "digest.html")]
+pub struct DigestEmail {
+    site_url:         String,
+    name:             String,
+    threads:          Vec<UnreadThread>,
+    replies:          Vec<ReplyToUser>,
+    offers:           i64,
+    new_items:        Vec<NewItem>,
+    unsubscribe_link: String,
+}
+
+impl DigestEmail {
+    async fn build(
+        conn: &PgPool,
+        site_url: &str,
+        recipient: &Recipient,
+        token: &str,
+        since: NaiveDateTime,
+    ) -> Result<Self, sqlx::Error> {
+        let threads = sqlx::query_as(
+            r#"
+                SELECT
+                    threads.id,
+                    threads.title,
+                    COUNT(replies.id) AS unread,
+                    MIN(replies.id) AS first
+                FROM reading_history
+                JOIN threads ON threads.id = reading_history.thread_id
+                JOIN replies ON replies.thread_id = threads.id
+                WHERE
+                    reading_history.reader_id = $1
+                    AND replies.id > reading_history.last_read
+                    AND replies.author_id <> $1
+                    AND NOT replies.hidden
+                    AND NOT threads.hidden
+                GROUP BY threads.id
+                ORDER BY MAX(replies.id) DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(recipient.id)
+        .bind(DIGEST_SECTION_LENGTH)
+        .fetch_all(conn)
+        .await?;
+
+        let replies = sqlx::query_as(
+            r#"
+                SELECT replies.id, threads.title, users.display_name AS author_name
+                FROM replies
+                JOIN replies AS parent ON parent.id = replies.in_reply_to
+                JOIN threads ON threads.id = replies.thread_id
+                JOIN users ON users.id = replies.author_id
+                WHERE
+                    parent.author_id = $1
+                    AND replies.author_id <> $1
+                    AND replies.post_date >= $2
+                    AND NOT replies.hidden
+                    AND NOT threads.hidden
+                ORDER BY replies.id DESC
+                LIMIT $3
+            "#,
+        )
+        .bind(recipient.id)
+        .bind(since)
+        .bind(DIGEST_SECTION_LENGTH)
+        .fetch_all(conn)
+        .await?;
+
+        let offers =
+            sqlx::query_scalar("SELECT COUNT(*) FROM trade_requests WHERE receiver_id = $1")
+                .bind(recipient.id)
+                .fetch_one(conn)
+                .await?;
+
+        let new_items: Vec<NewItemRow> = sqlx::query_as(
+            r#"
+                SELECT name, rarity FROM items
+                WHERE available AND added >= $1
+                ORDER BY id DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(since)
+        .bind(DIGEST_SECTION_LENGTH)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(Self {
+            site_url: site_url.to_string(),
+            name: recipient.display_name.clone(),
+            threads,
+            replies,
+            offers,
+            new_items: new_items
+                .into_iter()
+                .map(|item| NewItem {
+                    name:   item.name,
+                    rarity: item.rarity.to_string(),
+                })
+                .collect(),
+            unsubscribe_link: format!(
+                "{site_url}/digest/unsubscribe/{}?token={token}",
+                recipient.id
+            ),
+        })
+    }
+
+    /// Whether there is nothing to tell the user about.
+    fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+            && self.replies.is_empty()
+            && self.offers == 0
+            && self.new_items.is_empty()
+    }
+}
+
+/// Background task that sends digests to users who are due one.
+pub async fn send_digests(conn: PgPool) {
+    let mailer = match Mailer::from_env() {
+        Ok(Some(mailer)) => mailer,
+        Ok(None) => {
+            tracing::info!("SMTP_URL is not set, email digests are disabled");
+            return;
+        }
+        Err(err) => {
+            tracing::error!("{err}, email digests are disabled");
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(DIGEST_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = send_batch(&conn, &mailer).await {
+            tracing::error!("Failed to send email digests: {err}");
+        }
+    }
+}
+
+async fn send_batch(conn: &PgPool, mailer: &Mailer) -> Result<(), sqlx::Error> {
+    let now = Utc::now().naive_utc();
+
+    // Digests are claimed by moving the time of the last digest forward, so
+    // that another instance does not send them too.
+    let recipients: Vec<Recipient> = sqlx::query_as(
+        r#"
+            UPDATE users SET last_digest = $1
+            FROM users AS previous
+            WHERE
+                users.id = previous.id
+                AND users.id IN (
+                    SELECT id FROM users
+                    WHERE
+                        email_digest
+                        AND deleted IS NULL
+                        AND email <> ''
+                        AND (last_digest IS NULL OR last_digest <= $2)
+                    ORDER BY last_digest ASC NULLS FIRST
+                    LIMIT $3
+                    FOR UPDATE SKIP LOCKED
+                )
+            RETURNING
+                users.id,
+                users.email,
+                users.display_name,
+                users.digest_token,
+                previous.last_digest
+        "#,
+    )
+    .bind(now)
+    .bind(now - *DIGEST_INTERVAL)
+    .bind(DIGEST_BATCH_SIZE)
+    .fetch_all(conn)
+    .await?;
+
+    // A digest that fails is logged and does not hold back the others.
+    for recipient in recipients {
+        if let Err(err) = send_digest(conn, mailer, &recipient, now).await {
+            tracing::error!("Failed to send digest to user {}: {err}", recipient.id);
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_digest(
+    conn: &PgPool,
+    mailer: &Mailer,
+    recipient: &Recipient,
+    now: NaiveDateTime,
+) -> Result<(), DigestError> {
+    let Some(ref token) = recipient.digest_token else {
+        return Ok(());
+    };
+    let since = recipient
+        .last_digest
+        .unwrap_or_else(|| now - *DIGEST_INTERVAL);
+    let digest = DigestEmail::build(conn, &mailer.site_url, recipient, token, since).await?;
+    if digest.is_empty() {
+        return Ok(());
+    }
+    mailer.send(recipient, &digest).await
+}
+
+/// Unsubscribe a user from digests with the token from a digest. Returns
+/// whether the token was valid.
+pub async fn unsubscribe(conn: &PgPool, user_id: i32, token: &str) -> Result<bool, sqlx::Error> {
+    let unsubscribed =
+        sqlx::query("UPDATE users SET email_digest = FALSE WHERE id = $1 AND digest_token = $2")
+            .bind(user_id)
+            .bind(token)
+            .execute(conn)
+            .await?
+            .rows_affected();
+    if unsubscribed == 0 {
+        return Ok(false);
+    }
+    InvalidationBus::user_updated(conn, user_id).await?;
+    Ok(true)
+}
+
+#[derive(Template)]
+#[template(path = "unsubscribe.html")]
+pub struct UnsubscribePage {
+    offers:       i64,
+    user_id:      i32,
+    token:        String,
+    unsubscribed: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeParams {
+    token: String,
+}
+
+// Following the link asks for confirmation, since mail scanners follow links
+// too. Mail clients that support one-click unsubscribing post to the same
+// link instead.
+get!(
+    "/digest/unsubscribe/:user_id",
+    async fn unsubscribe_page(
+        Path(user_id): Path<i32>,
+        Query(UnsubscribeParams { token }): Query<UnsubscribeParams>,
+    ) -> UnsubscribePage {
+        UnsubscribePage {
+            offers: 0,
+            user_id,
+            token: urlencoding::encode(&token).into_owned(),
+            unsubscribed: false,
+        }
+    }
+);
+
+post!(
+    "/digest/unsubscribe/:user_id",
+    async fn unsubscribe_from_digest(
+        conn: Extension<PgPool>,
+        Path(user_id): Path<i32>,
+        Query(UnsubscribeParams { token }): Query<UnsubscribeParams>,
+    ) -> Result<UnsubscribePage, ServerError> {
+        if !unsubscribe(&conn, user_id, &token).await? {
+            return Err(ServerError::NotFound);
+        }
+        Ok(UnsubscribePage {
+            offers: 0,
+            user_id,
+            token,
+            unsubscribed: true,
+        })
+    }
+);
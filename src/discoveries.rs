@@ -0,0 +1,166 @@
+//! Discoveries: every item a user has ever owned, whether it dropped for them,
+//! was crafted, or was received in a trade. The catalog lists every item with
+//! the ones the user has not discovered yet hidden, so that collecting them
+//! all is a visible goal.
+use std::collections::HashMap;
+
+use askama::Template;
+use axum::{async_trait, extract::Extension};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{PgExecutor, PgPool};
+
+use crate::{
+    events::{Event, Subscriber},
+    get,
+    items::Item,
+    pages::ServerError,
+    users::User,
+};
+
+pub struct Discovery;
+
+impl Discovery {
+    /// Record that the user has discovered an item.
+    pub async fn record(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+        item_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+                INSERT INTO discoveries (user_id, item_id, discovered)
+                VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id)
+        .bind(item_id)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Record every item the users currently own as discovered. Used after
+    /// drops change hands.
+    pub async fn record_owned(
+        conn: impl PgExecutor<'_>,
+        user_ids: &[i32],
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+                INSERT INTO discoveries (user_id, item_id, discovered)
+                SELECT owner_id, item_id, $2 FROM drops
+                WHERE owner_id = ANY($1)
+                GROUP BY owner_id, item_id
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_ids)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Records discoveries as drops are created, given and traded.
+pub struct Discoveries;
+
+#[async_trait]
+impl Subscriber for Discoveries {
+    fn name(&self) -> &'static str {
+        "discoveries"
+    }
+
+    async fn handle(&self, conn: &PgPool, event: &Event) -> anyhow::Result<()> {
+        match *event {
+            Event::DropCreated {
+                owner_id, item_id, ..
+            } => {
+                Discovery::record(conn, owner_id, item_id).await?;
+            }
+            Event::DropGiven {
+                receiver_id,
+                item_id,
+                ..
+            } => {
+                Discovery::record(conn, receiver_id, item_id).await?;
+            }
+            Event::TradeAccepted {
+                sender_id,
+                receiver_id,
+                ..
+            } => {
+                Discovery::record_owned(conn, &[sender_id, receiver_id]).await?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "catalog.html")]
+pub struct CatalogPage {
+    offers:     i64,
+    entries:    Vec<CatalogEntry>,
+    discovered: usize,
+    language:   String,
+}
+
+pub struct CatalogEntry {
+    rarity:    String,
+    /// Details of the item, if the user has discovered it
+    discovery: Option<DiscoveredItem>,
+}
+
+pub struct DiscoveredItem {
+    name:        String,
+    description: String,
+    thumbnail:   String,
+    discovered:  Option<String>,
+}
+
+get!(
+    "/catalog",
+    async fn catalog(conn: Extension<PgPool>, user: User) -> Result<CatalogPage, ServerError> {
+        let discoveries: HashMap<i32, Option<NaiveDateTime>> =
+            sqlx::query_as("SELECT item_id, discovered FROM discoveries WHERE user_id = $1")
+                .bind(user.id)
+                .fetch_all(&*conn)
+                .await?
+                .into_iter()
+                .collect();
+        // Items that can no longer be found are only listed to those who
+        // found them.
+        let items: Vec<Item> = sqlx::query_as("SELECT * FROM items ORDER BY rarity ASC, id ASC")
+            .fetch_all(&*conn)
+            .await?;
+
+        let entries: Vec<CatalogEntry> = items
+            .into_iter()
+            .filter(|item| item.available || discoveries.contains_key(&item.id))
+            .map(|item| CatalogEntry {
+                rarity:    item.rarity.to_string(),
+                discovery: discoveries.get(&item.id).map(|discovered| DiscoveredItem {
+                    thumbnail:   item.get_thumbnail_html(0),
+                    name:        item.name,
+                    description: item.description,
+                    discovered:  discovered
+                        .map(|discovered| discovered.format(crate::DATE_FMT).to_string()),
+                }),
+            })
+            .collect();
+
+        Ok(CatalogPage {
+            offers: user.incoming_offers(&*conn).await?,
+            discovered: entries
+                .iter()
+                .filter(|entry| entry.discovery.is_some())
+                .count(),
+            entries,
+            language: user.language().to_string(),
+        })
+    }
+);
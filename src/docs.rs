@@ -0,0 +1,174 @@
+//! Documentation of the API, generated at runtime from the registered
+//! endpoints. Served both as an OpenAPI document and as a browsable page.
+use std::collections::BTreeMap;
+
+use askama::Template;
+use axum::Json;
+use serde_json::{json, Value};
+
+use crate::{branding::branding, get, Endpoint, RouteType};
+
+/// Modules whose endpoints render HTML pages rather than JSON.
+const PAGE_MODULES: &[&str] = &[
+    "pages",
+    "docs",
+    "assets",
+    "branding",
+    "digests",
+    "discoveries",
+];
+
+/// An endpoint as listed in the docs.
+#[derive(Debug)]
+pub struct EndpointDoc {
+    method:     String,
+    /// Path in OpenAPI form, e.g. `/thread/{thread_id}`
+    path:       String,
+    parameters: Vec<String>,
+}
+
+impl EndpointDoc {
+    fn new(endpoint: &Endpoint) -> Self {
+        let mut parameters = Vec::new();
+        let path = endpoint
+            .path()
+            .split('/')
+            .map(|segment| match segment.strip_prefix(&[':', '*'][..]) {
+                Some(parameter) => {
+                    parameters.push(parameter.to_string());
+                    format!("{{{parameter}}}")
+                }
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        Self {
+            method: endpoint.route_type().to_string(),
+            path,
+            parameters,
+        }
+    }
+}
+
+/// JSON endpoints grouped by the module they are defined in, sorted by path.
+fn api_endpoints() -> BTreeMap<&'static str, Vec<EndpointDoc>> {
+    let mut modules = BTreeMap::<_, Vec<_>>::new();
+    for endpoint in inventory::iter::<Endpoint>() {
+        if PAGE_MODULES.contains(&endpoint.module()) {
+            continue;
+        }
+        modules
+            .entry(endpoint.module())
+            .or_default()
+            .push(EndpointDoc::new(endpoint));
+    }
+    for endpoints in modules.values_mut() {
+        endpoints.sort_by(|a, b| (&a.path, &a.method).cmp(&(&b.path, &b.method)));
+    }
+    modules
+}
+
+/// Build the OpenAPI document describing every JSON endpoint. Every response
+/// uses the same envelope, so only that is described.
+pub fn openapi() -> Value {
+    let mut paths = serde_json::Map::new();
+    for (module, endpoints) in api_endpoints() {
+        for endpoint in endpoints {
+            let parameters = endpoint
+                .parameters
+                .iter()
+                .map(|name| {
+                    json!({
+                        "name": name,
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut operation = json!({
+                "tags": [module],
+                "parameters": parameters,
+                "responses": {
+                    "200": { "$ref": "#/components/responses/Ok" },
+                    "default": { "$ref": "#/components/responses/Error" },
+                },
+            });
+            if endpoint.method != RouteType::Get.to_string() {
+                operation["requestBody"] = json!({
+                    "content": {
+                        "application/x-www-form-urlencoded": {
+                            "schema": { "type": "object" },
+                        },
+                    },
+                });
+            }
+            let path = paths.entry(endpoint.path).or_insert_with(|| json!({}));
+            path[endpoint.method.to_lowercase()] = operation;
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": branding().site_name,
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "responses": {
+                "Ok": {
+                    "description": "The request succeeded",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": { "ok": {} },
+                                "required": ["ok"],
+                            },
+                        },
+                    },
+                },
+                "Error": {
+                    "description": "The request failed",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "error": { "type": "string" },
+                                    "error_type": {},
+                                },
+                                "required": ["error", "error_type"],
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+get!(
+    "/api/openapi.json",
+    async fn openapi_document() -> Json<Value> {
+        Json(openapi())
+    }
+);
+
+#[derive(Template)]
+#[template(path = "api_docs.html")]
+pub struct ApiDocsPage {
+    offers:  usize,
+    modules: Vec<(&'static str, Vec<EndpointDoc>)>,
+}
+
+get!(
+    "/api/docs",
+    async fn api_docs() -> ApiDocsPage {
+        ApiDocsPage {
+            offers:  0,
+            modules: api_endpoints().into_iter().collect(),
+        }
+    }
+);
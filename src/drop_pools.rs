@@ -0,0 +1,221 @@
+//! Limited-time drop pools. Admins group items into pools that are only
+//! active between two times, such as a holiday event. While any pool with
+//! items of the rolled rarity is active, drops are chosen from those items;
+//! otherwise they are chosen from the evergreen pool, the items that belong
+//! to no pool at all.
+use axum::extract::{Extension, Form, Path};
+use chrono::{NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{
+    get,
+    items::{Item, Rarity},
+    post,
+    users::{Role, User},
+};
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct DropPool {
+    pub id:         i32,
+    pub name:       String,
+    pub starts:     NaiveDateTime,
+    pub ends:       NaiveDateTime,
+    pub created_by: i32,
+    pub created:    NaiveDateTime,
+}
+
+impl DropPool {
+    /// Available items of the given rarity that may currently drop.
+    pub async fn droppable_items(
+        conn: impl PgExecutor<'_>,
+        rarity: Rarity,
+    ) -> Result<Vec<Item>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                WITH candidates AS (
+                    SELECT
+                        items.*,
+                        EXISTS (
+                            SELECT 1 FROM drop_pool_items
+                            JOIN drop_pools ON drop_pools.id = drop_pool_items.pool_id
+                            WHERE
+                                drop_pool_items.item_id = items.id
+                                AND drop_pools.starts <= $2
+                                AND drop_pools.ends > $2
+                        ) AS seasonal,
+                        EXISTS (
+                            SELECT 1 FROM drop_pool_items WHERE item_id = items.id
+                        ) AS pooled
+                    FROM items
+                    WHERE items.rarity = $1 AND items.available = TRUE
+                )
+                SELECT * FROM candidates
+                WHERE
+                    seasonal
+                    OR (NOT pooled AND NOT EXISTS (SELECT 1 FROM candidates WHERE seasonal))
+            "#,
+        )
+        .bind(rarity)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(conn)
+        .await
+    }
+}
+
+/// A pool as listed to admins, with the ids of its items.
+#[derive(Debug, FromRow, Serialize)]
+pub struct DropPoolView {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub pool:  DropPool,
+    pub items: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum DropPoolsError {
+    #[error("You are not authorized to do that")]
+    Unauthorized,
+    #[error("No such drop pool")]
+    NoSuchPool,
+    #[error("No such item")]
+    NoSuchItem,
+    #[error("A drop pool must end after it starts")]
+    EndsBeforeStart,
+    #[error("A drop pool must have at least one item")]
+    NoItems,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/admin/drop_pools",
+    #[json]
+    async fn drop_pools(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<Vec<DropPoolView>, DropPoolsError> {
+        if user.role < Role::Admin {
+            return Err(DropPoolsError::Unauthorized);
+        }
+        Ok(sqlx::query_as(
+            r#"
+                SELECT
+                    drop_pools.*,
+                    ARRAY(
+                        SELECT item_id FROM drop_pool_items
+                        WHERE pool_id = drop_pools.id
+                        ORDER BY item_id ASC
+                    ) AS items
+                FROM drop_pools
+                ORDER BY starts DESC, id DESC
+            "#,
+        )
+        .fetch_all(&*conn)
+        .await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct AddDropPoolForm {
+    name:   String,
+    starts: NaiveDateTime,
+    ends:   NaiveDateTime,
+    /// Comma separated ids of the items in the pool
+    items:  String,
+}
+
+post!(
+    "/admin/drop_pools",
+    #[json]
+    async fn add_drop_pool(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(AddDropPoolForm {
+            name,
+            starts,
+            ends,
+            items,
+        }): Form<AddDropPoolForm>,
+    ) -> Result<DropPoolView, DropPoolsError> {
+        if user.role < Role::Admin {
+            return Err(DropPoolsError::Unauthorized);
+        }
+        if ends <= starts {
+            return Err(DropPoolsError::EndsBeforeStart);
+        }
+        let mut items = crate::parse_ids(&items).ok_or(DropPoolsError::NoSuchItem)?;
+        items.sort_unstable();
+        items.dedup();
+        if items.is_empty() {
+            return Err(DropPoolsError::NoItems);
+        }
+
+        let mut transaction = conn.begin().await?;
+        let found: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM items WHERE id = ANY($1)")
+            .bind(&items)
+            .fetch_one(&mut transaction)
+            .await?;
+        if found != items.len() as i64 {
+            return Err(DropPoolsError::NoSuchItem);
+        }
+
+        let pool: DropPool = sqlx::query_as(
+            r#"
+                INSERT INTO drop_pools (name, starts, ends, created_by, created)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+            "#,
+        )
+        .bind(name)
+        .bind(starts)
+        .bind(ends)
+        .bind(user.id)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&mut transaction)
+        .await?;
+        sqlx::query("INSERT INTO drop_pool_items (pool_id, item_id) SELECT $1, UNNEST($2::INT[])")
+            .bind(pool.id)
+            .bind(&items)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(DropPoolView { pool, items })
+    }
+);
+
+post!(
+    "/admin/drop_pools/:pool_id/remove",
+    #[json]
+    async fn remove_drop_pool(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(pool_id): Path<i32>,
+    ) -> Result<(), DropPoolsError> {
+        if user.role < Role::Admin {
+            return Err(DropPoolsError::Unauthorized);
+        }
+        let mut transaction = conn.begin().await?;
+        let removed = sqlx::query("DELETE FROM drop_pools WHERE id = $1")
+            .bind(pool_id)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        if removed == 0 {
+            return Err(DropPoolsError::NoSuchPool);
+        }
+        sqlx::query("DELETE FROM drop_pool_items WHERE pool_id = $1")
+            .bind(pool_id)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+);
@@ -0,0 +1,183 @@
+//! Audit log of drop rolls. Every time a user earns a drop, the rarity rolled
+//! and the item chosen for it are recorded, so that complaints about the odds
+//! can be answered with data and mistakes in the roll can be caught.
+use axum::extract::{Extension, Query};
+use chrono::{NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{
+    cluster::Cluster,
+    get,
+    items::{Rarity, MYTHIC_RARITY_FLAG},
+    users::{Role, User},
+};
+
+lazy_static! {
+    /// Fraction of rolls that are recorded, between 0 and 1. Set with
+    /// `DROP_ROLL_SAMPLE_RATE`. Every roll is recorded by default, which is
+    /// needed to answer questions about a single user.
+    static ref SAMPLE_RATE: f64 = std::env::var("DROP_ROLL_SAMPLE_RATE")
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| (0.0..=1.0).contains(value))
+        .unwrap_or(1.0);
+}
+
+/// Number of recent rolls included in a report.
+const RECENT_ROLLS: i64 = 100;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct DropRoll {
+    pub id:      i32,
+    pub user_id: i32,
+    pub rarity:  Rarity,
+    /// Item chosen for the rolled rarity. None if no item of that rarity was
+    /// available.
+    pub item_id: Option<i32>,
+    pub rolled:  NaiveDateTime,
+}
+
+impl DropRoll {
+    /// Record a roll, subject to the sample rate.
+    pub async fn record(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+        rarity: Rarity,
+        item_id: Option<i32>,
+    ) -> Result<(), sqlx::Error> {
+        if rand::random::<f64>() >= *SAMPLE_RATE {
+            return Ok(());
+        }
+        sqlx::query(
+            "INSERT INTO drop_rolls (user_id, rarity, item_id, rolled) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(user_id)
+        .bind(rarity)
+        .bind(item_id)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+/// How often a rarity was rolled, compared to how often it should be.
+#[derive(Debug, Serialize)]
+pub struct RarityRolls {
+    pub rarity:   Rarity,
+    pub rolls:    i64,
+    /// Rolls that resulted in an item.
+    pub awarded:  i64,
+    /// Fraction of rolls that landed on this rarity.
+    pub observed: f64,
+    /// Fraction of rolls that should land on this rarity.
+    pub expected: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DropRollReport {
+    pub rolls:       i64,
+    pub rarities:    Vec<RarityRolls>,
+    /// Most recent rolls, newest first.
+    pub recent:      Vec<DropRoll>,
+    /// Fraction of rolls that are recorded.
+    pub sample_rate: f64,
+}
+
+impl DropRollReport {
+    /// Report on every recorded roll, or only those of one user, expecting
+    /// the odds of whether mythic items are currently rolled.
+    pub async fn fetch(
+        conn: &PgPool,
+        user_id: Option<i32>,
+        mythic: bool,
+    ) -> Result<Self, sqlx::Error> {
+        let counts: Vec<(Rarity, i64, i64)> = sqlx::query_as(
+            r#"
+                SELECT rarity, COUNT(*), COUNT(item_id) FROM drop_rolls
+                WHERE $1::INT IS NULL OR user_id = $1
+                GROUP BY rarity
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(conn)
+        .await?;
+        let rolls = counts.iter().map(|(_, rolls, _)| rolls).sum::<i64>();
+        let rarities = Rarity::rolled(mythic)
+            .iter()
+            .map(|&rarity| {
+                let (rolls_of, awarded) = counts
+                    .iter()
+                    .find(|(counted, _, _)| *counted == rarity)
+                    .map_or((0, 0), |&(_, rolls, awarded)| (rolls, awarded));
+                RarityRolls {
+                    rarity,
+                    rolls: rolls_of,
+                    awarded,
+                    observed: if rolls > 0 {
+                        rolls_of as f64 / rolls as f64
+                    } else {
+                        0.0
+                    },
+                    expected: rarity.chance(mythic),
+                }
+            })
+            .collect();
+        let recent = sqlx::query_as(
+            r#"
+                SELECT * FROM drop_rolls
+                WHERE $1::INT IS NULL OR user_id = $1
+                ORDER BY id DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(RECENT_ROLLS)
+        .fetch_all(conn)
+        .await?;
+        Ok(Self {
+            rolls,
+            rarities,
+            recent,
+            sample_rate: *SAMPLE_RATE,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DropRollParams {
+    user_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum DropRollReportError {
+    #[error("You are not authorized to view drop rolls")]
+    Unauthorized,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/drop_rolls",
+    #[json]
+    async fn drop_roll_report(
+        conn: Extension<PgPool>,
+        cluster: Extension<Cluster>,
+        user: User,
+        Query(DropRollParams { user_id }): Query<DropRollParams>,
+    ) -> Result<DropRollReport, DropRollReportError> {
+        if user.role < Role::Admin {
+            return Err(DropRollReportError::Unauthorized);
+        }
+        let mythic = cluster.flag(MYTHIC_RARITY_FLAG).await?;
+        Ok(DropRollReport::fetch(&conn, user_id, mythic).await?)
+    }
+);
@@ -0,0 +1,244 @@
+//! Domain events. Mutations describe what happened with an `Event`, which is
+//! written to the `events` outbox table in the same transaction as the change
+//! itself. A background dispatcher then hands each event to every registered
+//! `Subscriber`, so side effects happen if and only if the change committed.
+//!
+//! Delivery is at least once: an event whose delivery failed is retried with
+//! exponential backoff and given again to the subscribers that failed, so
+//! subscribers must tolerate seeing the same event more than once.
+use std::{sync::Arc, time::Duration};
+
+use axum::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgExecutor, PgPool};
+
+/// Largest number of events delivered per pass of the dispatcher.
+const DISPATCH_BATCH_SIZE: i64 = 100;
+
+/// How long the dispatcher waits before checking for new events.
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an instance has to deliver the events it claimed before they can
+/// be claimed by another.
+const CLAIM_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Number of times delivery of an event is attempted before giving up on it.
+const MAX_DELIVERY_ATTEMPTS: i32 = 10;
+
+/// Delay before the first retry of a failed delivery. Doubles with every
+/// further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// Longest delay between two attempts at delivering an event.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    /// A reply was posted, including the first post of a new thread.
+    ReplyCreated {
+        reply_id:  i32,
+        thread_id: i32,
+        author_id: i32,
+    },
+    /// A trade offer was accepted and the items changed hands.
+    TradeAccepted {
+        trade_id:    i32,
+        sender_id:   i32,
+        receiver_id: i32,
+    },
+    /// A user was banned by a moderator.
+    UserBanned {
+        user_id:      i32,
+        moderator_id: i32,
+        until:        NaiveDateTime,
+    },
+    /// A user reacted to a reply, changing the experience of its author.
+    ReactionAdded {
+        reply_id:   i32,
+        author_id:  i32,
+        reactor_id: i32,
+        drop_id:    i32,
+        /// Change in the author's experience that was actually applied
+        xp:         i64,
+    },
+    /// An item was dropped or gifted to a user.
+    DropCreated {
+        drop_id:  i32,
+        owner_id: i32,
+        item_id:  i32,
+    },
+    /// A user gave one of their drops to another user. Unlike `DropCreated`,
+    /// no new drop came into being.
+    DropGiven {
+        drop_id:     i32,
+        sender_id:   i32,
+        receiver_id: i32,
+        item_id:     i32,
+    },
+}
+
+impl Event {
+    /// Record the event in the outbox to be delivered to subscribers. Should be
+    /// called with the transaction that makes the change the event describes.
+    pub async fn publish(self, conn: impl PgExecutor<'_>) -> Result<(), sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        sqlx::query("INSERT INTO events (event, created, next_attempt) VALUES ($1, $2, $2)")
+            .bind(Json(self))
+            .bind(now)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(FromRow)]
+struct OutboxEntry {
+    id:       i64,
+    event:    Json<Event>,
+    attempts: i32,
+}
+
+impl OutboxEntry {
+    /// Time to wait before the next attempt after this one fails.
+    fn retry_delay(&self) -> Duration {
+        RETRY_BASE_DELAY
+            .checked_mul(1 << self.attempts.clamp(0, 16))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+    }
+}
+
+/// Something that reacts to events, such as notifications.
+#[async_trait]
+pub trait Subscriber: Send + Sync {
+    /// Name of the subscriber, used when logging failures and to remember which
+    /// subscribers an event was delivered to. Must not change once deployed.
+    fn name(&self) -> &'static str;
+
+    async fn handle(&self, conn: &PgPool, event: &Event) -> anyhow::Result<()>;
+}
+
+/// The set of subscribers that every event is delivered to.
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Vec<Arc<dyn Subscriber>>,
+}
+
+impl Events {
+    pub fn register(&mut self, subscriber: impl Subscriber + 'static) {
+        self.subscribers.push(Arc::new(subscriber));
+    }
+
+    /// Background task that delivers events from the outbox. Events are
+    /// claimed before they are delivered, so any number of instances can run a
+    /// dispatcher against the same database.
+    pub async fn dispatch(self, conn: PgPool) {
+        loop {
+            match self.dispatch_batch(&conn).await {
+                Ok(delivered) if delivered > 0 => continue,
+                Ok(_) => (),
+                Err(err) => tracing::error!("Event dispatcher failed: {err}"),
+            }
+            tokio::time::sleep(DISPATCH_INTERVAL).await;
+        }
+    }
+
+    async fn dispatch_batch(&self, conn: &PgPool) -> Result<usize, sqlx::Error> {
+        // Claiming is a single statement, so no transaction is held open while
+        // the events are delivered. A claim that runs out is taken over by the
+        // next pass.
+        let now = Utc::now().naive_utc();
+        let entries: Vec<OutboxEntry> = sqlx::query_as(
+            r#"
+                UPDATE events SET next_attempt = $2
+                WHERE id IN (
+                    SELECT id FROM events
+                    WHERE delivered IS NULL AND next_attempt <= $1 AND attempts < $3
+                    ORDER BY id ASC
+                    LIMIT $4
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING id, event, attempts
+            "#,
+        )
+        .bind(now)
+        .bind(now + chrono::Duration::from_std(CLAIM_DURATION).unwrap())
+        .bind(MAX_DELIVERY_ATTEMPTS)
+        .bind(DISPATCH_BATCH_SIZE)
+        .fetch_all(conn)
+        .await?;
+
+        for entry in &entries {
+            self.deliver(conn, entry).await?;
+        }
+
+        Ok(entries.len())
+    }
+
+    /// Hand an event to every subscriber that has not received it yet.
+    async fn deliver(&self, conn: &PgPool, entry: &OutboxEntry) -> Result<(), sqlx::Error> {
+        let delivered: Vec<String> =
+            sqlx::query_scalar("SELECT subscriber FROM event_deliveries WHERE event_id = $1")
+                .bind(entry.id)
+                .fetch_all(conn)
+                .await?;
+
+        let mut errors = Vec::new();
+        for subscriber in &self.subscribers {
+            if delivered.iter().any(|name| name == subscriber.name()) {
+                continue;
+            }
+            match subscriber.handle(conn, &entry.event).await {
+                Ok(()) => {
+                    sqlx::query(
+                        r#"
+                            INSERT INTO event_deliveries (event_id, subscriber, delivered)
+                            VALUES ($1, $2, $3)
+                            ON CONFLICT DO NOTHING
+                        "#,
+                    )
+                    .bind(entry.id)
+                    .bind(subscriber.name())
+                    .bind(Utc::now().naive_utc())
+                    .execute(conn)
+                    .await?;
+                }
+                Err(err) => errors.push(format!("{}: {err}", subscriber.name())),
+            }
+        }
+
+        let now = Utc::now().naive_utc();
+        if errors.is_empty() {
+            sqlx::query("UPDATE events SET delivered = $1 WHERE id = $2")
+                .bind(now)
+                .bind(entry.id)
+                .execute(conn)
+                .await?;
+            return Ok(());
+        }
+
+        let last_error = errors.join("; ");
+        if entry.attempts + 1 >= MAX_DELIVERY_ATTEMPTS {
+            tracing::error!("Giving up on delivering event {}: {last_error}", entry.id);
+        } else {
+            tracing::warn!("Failed to deliver event {}: {last_error}", entry.id);
+        }
+        sqlx::query(
+            r#"
+                UPDATE events SET
+                    attempts = attempts + 1,
+                    next_attempt = $1,
+                    last_error = $2
+                WHERE id = $3
+            "#,
+        )
+        .bind(now + chrono::Duration::from_std(entry.retry_delay()).unwrap())
+        .bind(last_error)
+        .bind(entry.id)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+}
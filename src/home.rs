@@ -0,0 +1,296 @@
+//! The page logged in users land on. Administrators choose which sections it
+//! shows and in what order. Until they do, users are sent straight to their
+//! home tags.
+use axum::extract::{Extension, Form};
+use chrono::{Duration, NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{
+    get, post,
+    users::{Role, User},
+};
+
+/// Largest number of sections on the home page.
+const MAX_SECTIONS: usize = 10;
+
+/// Largest number of entries a single section may list.
+const MAX_SECTION_ENTRIES: i64 = 25;
+
+/// How far back replies count towards a thread trending.
+const TRENDING_WINDOW_HOURS: i64 = 24;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HomeSection {
+    /// Links to tags, separated by slashes
+    PinnedTags { tags: String },
+    /// Threads with the most replies in the past day
+    Trending { count: i64 },
+    /// Recent trades and rare finds
+    LatestEvents { count: i64 },
+    /// The top of the leaderboard
+    Leaderboard { count: i64 },
+}
+
+impl HomeSection {
+    fn count(&self) -> Option<i64> {
+        match *self {
+            Self::PinnedTags { .. } => None,
+            Self::Trending { count }
+            | Self::LatestEvents { count }
+            | Self::Leaderboard { count } => Some(count),
+        }
+    }
+
+    /// Fetch what the section shows.
+    pub async fn render(&self, conn: &PgPool) -> Result<HomeSectionView, sqlx::Error> {
+        Ok(match *self {
+            Self::PinnedTags { ref tags } => HomeSectionView::PinnedTags(
+                tags.split('/')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            Self::Trending { count } => {
+                HomeSectionView::Trending(TrendingThread::fetch(conn, count).await?)
+            }
+            Self::LatestEvents { count } => {
+                HomeSectionView::LatestEvents(RecentEvent::fetch(conn, count).await?)
+            }
+            Self::Leaderboard { count } => {
+                HomeSectionView::Leaderboard(LeaderboardEntry::fetch(conn, count).await?)
+            }
+        })
+    }
+}
+
+/// The sections of the home page, in order. Empty if no layout is configured.
+pub async fn fetch_layout(conn: impl PgExecutor<'_>) -> Result<Vec<HomeSection>, sqlx::Error> {
+    let layout: Option<Json<Vec<HomeSection>>> =
+        sqlx::query_scalar("SELECT sections FROM home_layout")
+            .fetch_optional(conn)
+            .await?;
+    Ok(layout.map_or_else(Vec::new, |Json(sections)| sections))
+}
+
+/// A section of the home page along with what it shows.
+pub enum HomeSectionView {
+    PinnedTags(Vec<String>),
+    Trending(Vec<TrendingThread>),
+    LatestEvents(Vec<RecentEvent>),
+    Leaderboard(Vec<LeaderboardEntry>),
+}
+
+#[derive(Debug, FromRow)]
+pub struct TrendingThread {
+    pub id:      i32,
+    pub title:   String,
+    /// Replies posted in the trending window
+    pub replies: i64,
+}
+
+impl TrendingThread {
+    /// Trending threads are shown to everyone, so threads with mature tags
+    /// are never among them.
+    async fn fetch(conn: &PgPool, count: i64) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT threads.id, threads.title, COUNT(*) AS replies
+                FROM replies
+                JOIN threads ON threads.id = replies.thread_id
+                WHERE
+                    replies.post_date >= $1
+                    AND NOT replies.hidden
+                    AND NOT threads.hidden
+                    AND NOT has_mature_tag(threads.tags)
+                GROUP BY threads.id
+                ORDER BY replies DESC, threads.id DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(Utc::now().naive_utc() - Duration::hours(TRENDING_WINDOW_HOURS))
+        .bind(count)
+        .fetch_all(conn)
+        .await
+    }
+}
+
+/// A completed trade or a rare find, taken from the events outbox.
+#[derive(Debug)]
+pub struct RecentEvent {
+    pub description: String,
+    pub date:        String,
+}
+
+#[derive(FromRow)]
+struct RecentEventRow {
+    kind:        String,
+    created:     NaiveDateTime,
+    first_user:  Option<String>,
+    second_user: Option<String>,
+    item_name:   Option<String>,
+}
+
+impl RecentEvent {
+    async fn fetch(conn: &PgPool, count: i64) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<RecentEventRow> = sqlx::query_as(
+            r#"
+                SELECT
+                    events.event->>'type' AS kind,
+                    events.created,
+                    first_user.display_name AS first_user,
+                    second_user.display_name AS second_user,
+                    items.name AS item_name
+                FROM events
+                LEFT JOIN items ON
+                    events.event->>'type' = 'DropCreated'
+                    AND items.id = (events.event->>'item_id')::INT
+                LEFT JOIN users AS first_user ON first_user.id = COALESCE(
+                    events.event->>'owner_id',
+                    events.event->>'sender_id'
+                )::INT
+                LEFT JOIN users AS second_user ON
+                    second_user.id = (events.event->>'receiver_id')::INT
+                WHERE
+                    events.event->>'type' = 'TradeAccepted'
+                    OR (events.event->>'type' = 'DropCreated' AND items.rarity >= 'rare')
+                ORDER BY events.id DESC
+                LIMIT $1
+            "#,
+        )
+        .bind(count)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                let first_user = row.first_user?;
+                let description = match &row.kind[..] {
+                    "TradeAccepted" => format!("{first_user} traded with {}", row.second_user?),
+                    _ => format!("{first_user} found {}", row.item_name?),
+                };
+                Some(Self {
+                    description,
+                    date: row.created.format(crate::DATE_FMT).to_string(),
+                })
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, FromRow)]
+pub struct LeaderboardEntry {
+    pub rank:    i64,
+    pub user_id: i32,
+    pub name:    String,
+}
+
+impl LeaderboardEntry {
+    async fn fetch(conn: &PgPool, count: i64) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT leaderboard.rank, users.id AS user_id, users.display_name AS name
+                FROM leaderboard
+                JOIN users ON users.id = leaderboard.user_id
+                ORDER BY leaderboard.rank
+                LIMIT $1
+            "#,
+        )
+        .bind(count)
+        .fetch_all(conn)
+        .await
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum HomeLayoutError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Invalid layout: {0}")]
+    InvalidLayout(String),
+    #[error("Too many sections (maximum {max} allowed)")]
+    TooManySections { max: usize },
+    #[error("Sections must list between 1 and {max} entries")]
+    InvalidCount { max: i64 },
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/home_layout",
+    #[json]
+    async fn show_home_layout(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<Vec<HomeSection>, HomeLayoutError> {
+        if user.role < Role::Admin {
+            return Err(HomeLayoutError::Unauthorized);
+        }
+
+        Ok(fetch_layout(&*conn).await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct HomeLayoutForm {
+    /// JSON list of sections. An empty list removes the layout.
+    sections: String,
+}
+
+post!(
+    "/home_layout",
+    #[json]
+    async fn set_home_layout(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(HomeLayoutForm { sections }): Form<HomeLayoutForm>,
+    ) -> Result<(), HomeLayoutError> {
+        if user.role < Role::Admin {
+            return Err(HomeLayoutError::Unauthorized);
+        }
+
+        let sections: Vec<HomeSection> = serde_json::from_str(&sections)
+            .map_err(|err| HomeLayoutError::InvalidLayout(err.to_string()))?;
+        if sections.len() > MAX_SECTIONS {
+            return Err(HomeLayoutError::TooManySections { max: MAX_SECTIONS });
+        }
+        if sections
+            .iter()
+            .filter_map(HomeSection::count)
+            .any(|count| !(1..=MAX_SECTION_ENTRIES).contains(&count))
+        {
+            return Err(HomeLayoutError::InvalidCount {
+                max: MAX_SECTION_ENTRIES,
+            });
+        }
+
+        if sections.is_empty() {
+            sqlx::query("DELETE FROM home_layout")
+                .execute(&*conn)
+                .await?;
+        } else {
+            sqlx::query(
+                r#"
+                    INSERT INTO home_layout (sections) VALUES ($1)
+                    ON CONFLICT (singleton) DO UPDATE SET sections = EXCLUDED.sections
+                "#,
+            )
+            .bind(Json(&sections))
+            .execute(&*conn)
+            .await?;
+        }
+
+        tracing::info!("User `{}` has changed the home page layout", user.name);
+
+        Ok(())
+    }
+);
@@ -1,17 +1,29 @@
+//! Images attached to posts and uploaded as profile pictures. Images are
+//! stored in S3-compatible object storage, which requires the `s3-images`
+//! feature; without it, uploads are refused.
+//!
+//! Images attached to posts may carry a license and an attribution chosen by
+//! the poster, for communities that share art.
+#[cfg(feature = "s3-images")]
 use std::io::Cursor;
+use std::str::FromStr;
 
+#[cfg(feature = "s3-images")]
 use aws_sdk_s3::{
-    error::PutObjectError,
+    error::{HeadBucketError, PutObjectError},
     model::ObjectCannedAcl,
     output::PutObjectOutput,
     types::{ByteStream, SdkError},
     Client, Endpoint,
 };
 use axum::body::Bytes;
+#[cfg(feature = "s3-images")]
 use image::ImageFormat;
 use serde::Serialize;
+#[cfg(feature = "s3-images")]
 use sha2::{Digest, Sha256};
 use thiserror::Error;
+#[cfg(feature = "s3-images")]
 use tokio::task;
 
 pub struct Image {
@@ -21,6 +33,8 @@ pub struct Image {
 
 #[derive(Debug, Serialize, Error)]
 pub enum UploadImageError {
+    #[error("image uploads are not enabled on this server")]
+    Disabled,
     #[error("invalid file type")]
     InvalidExtension,
     #[error("error decoding image: {0}")]
@@ -35,6 +49,7 @@ pub enum UploadImageError {
         #[serde(skip)]
         tokio::task::JoinError,
     ),
+    #[cfg(feature = "s3-images")]
     #[error("internal block storage error: {0}")]
     InternalBlockStorageError(
         #[from]
@@ -43,8 +58,124 @@ pub enum UploadImageError {
     ),
 }
 
+/// Maximum length of the attribution of an image.
+pub const MAX_ATTRIBUTION_LENGTH: usize = 200;
+
+/// License an attached image is shared under.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageLicense {
+    AllRightsReserved,
+    Cc0,
+    CcBy,
+    CcBySa,
+    CcByNc,
+}
+
+impl ImageLicense {
+    pub const ALL: &'static [Self] = &[
+        Self::AllRightsReserved,
+        Self::Cc0,
+        Self::CcBy,
+        Self::CcBySa,
+        Self::CcByNc,
+    ];
+
+    /// Name the license is stored and submitted as.
+    pub fn slug(self) -> &'static str {
+        match self {
+            Self::AllRightsReserved => "all-rights-reserved",
+            Self::Cc0 => "cc0",
+            Self::CcBy => "cc-by",
+            Self::CcBySa => "cc-by-sa",
+            Self::CcByNc => "cc-by-nc",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::AllRightsReserved => "All rights reserved",
+            Self::Cc0 => "CC0 1.0 (public domain)",
+            Self::CcBy => "CC BY 4.0",
+            Self::CcBySa => "CC BY-SA 4.0",
+            Self::CcByNc => "CC BY-NC 4.0",
+        }
+    }
+
+    /// Where the terms of the license can be read.
+    pub fn url(self) -> Option<&'static str> {
+        match self {
+            Self::AllRightsReserved => None,
+            Self::Cc0 => Some("https://creativecommons.org/publicdomain/zero/1.0/"),
+            Self::CcBy => Some("https://creativecommons.org/licenses/by/4.0/"),
+            Self::CcBySa => Some("https://creativecommons.org/licenses/by-sa/4.0/"),
+            Self::CcByNc => Some("https://creativecommons.org/licenses/by-nc/4.0/"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Error)]
+#[error("unknown license")]
+pub struct UnknownLicense;
+
+impl FromStr for ImageLicense {
+    type Err = UnknownLicense;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|license| license.slug() == s)
+            .ok_or(UnknownLicense)
+    }
+}
+
+/// License and attribution of an attached image, as submitted with a post.
+#[derive(Debug, Default)]
+pub struct ImageCredits {
+    pub license:     Option<ImageLicense>,
+    pub attribution: Option<String>,
+}
+
+#[derive(Debug, Serialize, Error)]
+pub enum ImageCreditsError {
+    #[error("Unknown image license")]
+    UnknownLicense,
+    #[error(
+        "Image attribution is too long (maximum {} characters allowed)",
+        MAX_ATTRIBUTION_LENGTH
+    )]
+    AttributionTooLong,
+}
+
+impl ImageCredits {
+    /// Parse the license and attribution fields of a post form. Both may be
+    /// left empty.
+    pub fn parse(license: &str, attribution: &str) -> Result<Self, ImageCreditsError> {
+        let license = match license.trim() {
+            "" => None,
+            license => Some(
+                license
+                    .parse()
+                    .map_err(|_| ImageCreditsError::UnknownLicense)?,
+            ),
+        };
+        let attribution = match attribution.trim() {
+            "" => None,
+            attribution if attribution.chars().count() > MAX_ATTRIBUTION_LENGTH => {
+                return Err(ImageCreditsError::AttributionTooLong)
+            }
+            attribution => Some(attribution.to_string()),
+        };
+        Ok(Self {
+            license,
+            attribution,
+        })
+    }
+}
+
 impl Image {
     /// Upload image to object storage
+    #[cfg(feature = "s3-images")]
     pub async fn upload_image(bytes: Bytes) -> Result<Self, UploadImageError> {
         /// Maximum width/height of an image.
         const MAX_WH: u32 = 400;
@@ -69,13 +200,7 @@ impl Image {
         .await?;
 
         // Check if file already exists:
-        let config = aws_config::from_env()
-            .endpoint_resolver(Endpoint::immutable(
-                IMAGE_STORE_ENDPOINT.parse().expect("valid URI"),
-            ))
-            .load()
-            .await;
-        let client = Client::new(&config);
+        let client = client().await;
         let filename = format!("{hash}.{ext}");
 
         if image_exists(&client, &filename).await {
@@ -114,17 +239,50 @@ impl Image {
             thumbnail: thumbnail.as_deref().map(get_url),
         })
     }
+
+    /// Images cannot be stored without the `s3-images` feature.
+    #[cfg(not(feature = "s3-images"))]
+    pub async fn upload_image(_bytes: Bytes) -> Result<Self, UploadImageError> {
+        Err(UploadImageError::Disabled)
+    }
 }
 
+#[cfg(feature = "s3-images")]
 pub const IMAGE_STORE_ENDPOINT: &'static str = "https://marche-storage.nyc3.digitaloceanspaces.com";
+#[cfg(feature = "s3-images")]
 pub const IMAGE_STORE_BUCKET: &'static str = "images";
 
+#[cfg(feature = "s3-images")]
 pub fn get_url(filename: &str) -> String {
     format!("{IMAGE_STORE_ENDPOINT}/{IMAGE_STORE_BUCKET}/{filename}")
 }
 
+#[cfg(feature = "s3-images")]
+async fn client() -> Client {
+    let config = aws_config::from_env()
+        .endpoint_resolver(Endpoint::immutable(
+            IMAGE_STORE_ENDPOINT.parse().expect("valid URI"),
+        ))
+        .load()
+        .await;
+    Client::new(&config)
+}
+
+/// Check that the image store is reachable with the configured credentials.
+#[cfg(feature = "s3-images")]
+pub async fn check_image_store() -> Result<(), SdkError<HeadBucketError>> {
+    client()
+        .await
+        .head_bucket()
+        .bucket(IMAGE_STORE_BUCKET)
+        .send()
+        .await?;
+    Ok(())
+}
+
 pub const MAXIMUM_FILE_SIZE: u64 = 12 * 1024 * 1024; /* 12mb */
 
+#[cfg(feature = "s3-images")]
 async fn image_exists(client: &Client, filename: &str) -> bool {
     client
         .head_object()
@@ -135,6 +293,7 @@ async fn image_exists(client: &Client, filename: &str) -> bool {
         .is_ok()
 }
 
+#[cfg(feature = "s3-images")]
 async fn put_image(
     client: &Client,
     filename: &str,
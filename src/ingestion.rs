@@ -0,0 +1,444 @@
+//! Threads posted automatically from external RSS feeds. Administrators pick
+//! a feed, the tag its items are posted in and the bot account they are
+//! posted by. A background task polls every feed and posts a thread for each
+//! item it has not seen before, told apart by GUID.
+use std::time::Duration;
+
+use axum::extract::{Extension, Form, Path};
+use chrono::{NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use marche_proc_macros::{json, ErrorCode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use thiserror::Error;
+
+use crate::{
+    events::Event,
+    get, post,
+    threads::{Tag, Thread},
+    users::{Role, User},
+};
+
+/// How often the background task checks for feeds that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest time spent fetching a feed.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest number of bytes read from a feed.
+const MAX_FEED_SIZE: usize = 1024 * 1024;
+
+/// Largest number of items posted from a single fetch. Only the newest items
+/// are posted, so adding a feed with a long history does not flood its tag.
+const MAX_ITEMS_PER_FETCH: usize = 5;
+
+/// Largest number of feeds fetched per pass of the background task.
+const FETCH_BATCH_SIZE: i64 = 8;
+
+/// Longest title of an ingested thread.
+const MAX_TITLE_LENGTH: usize = 200;
+
+/// Longest body of an ingested thread, not counting the link to the item.
+const MAX_BODY_LENGTH: usize = 2000;
+
+lazy_static! {
+    /// Time between fetches of the same feed.
+    static ref REFRESH_INTERVAL: chrono::Duration = chrono::Duration::minutes(15);
+    static ref ITEM: Regex = Regex::new(r#"(?is)<item[\s>].*?</item>"#).unwrap();
+    static ref TITLE: Regex = Regex::new(r#"(?is)<title[^>]*>(.*?)</title>"#).unwrap();
+    static ref LINK: Regex = Regex::new(r#"(?is)<link[^>]*>(.*?)</link>"#).unwrap();
+    static ref GUID: Regex = Regex::new(r#"(?is)<guid[^>]*>(.*?)</guid>"#).unwrap();
+    static ref DESCRIPTION: Regex =
+        Regex::new(r#"(?is)<description[^>]*>(.*?)</description>"#).unwrap();
+    static ref CDATA: Regex = Regex::new(r#"(?s)<!\[CDATA\[(.*?)\]\]>"#).unwrap();
+    static ref HTML_TAG: Regex = Regex::new(r#"(?s)<[^>]*>"#).unwrap();
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct IngestedFeed {
+    pub id:           i32,
+    pub url:          String,
+    /// Tag that threads are posted in
+    pub tag:          String,
+    /// User that threads are posted by
+    pub bot_id:       i32,
+    pub added:        NaiveDateTime,
+    pub last_fetched: Option<NaiveDateTime>,
+    /// Consecutive fetches that failed
+    pub failures:     i32,
+}
+
+/// An item of a feed.
+#[derive(Debug)]
+struct FeedItem {
+    guid:        String,
+    title:       String,
+    link:        Option<String>,
+    description: String,
+}
+
+/// Text of an element, with CDATA sections unwrapped, markup removed and
+/// entities decoded.
+fn text(element: &Regex, xml: &str) -> Option<String> {
+    let inner = element.captures(xml)?.get(1)?.as_str();
+    let inner = CDATA.replace_all(inner, "$1");
+    // Entities are decoded twice, as the markup of descriptions is usually
+    // escaped rather than wrapped in CDATA.
+    let inner = html_escape::decode_html_entities(&inner).to_string();
+    let inner = HTML_TAG.replace_all(&inner, " ");
+    let inner = html_escape::decode_html_entities(&inner);
+    let inner = inner.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!inner.is_empty()).then_some(inner)
+}
+
+/// Items of an RSS feed, newest first as feeds list them. Items with neither
+/// a GUID, a link nor a title cannot be told apart and are skipped.
+fn parse_items(feed: &str) -> Vec<FeedItem> {
+    ITEM.find_iter(feed)
+        .filter_map(|item| {
+            let item = item.as_str();
+            let title = text(&TITLE, item);
+            let link = text(&LINK, item).filter(|link| link.starts_with("https://"));
+            let guid = text(&GUID, item)
+                .or_else(|| link.clone())
+                .or_else(|| title.clone())?;
+            Some(FeedItem {
+                guid,
+                title: title.unwrap_or_else(|| "Untitled".to_string()),
+                link,
+                description: text(&DESCRIPTION, item).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+async fn fetch_feed(client: &reqwest::Client, url: &str) -> anyhow::Result<String> {
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    let mut feed = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        feed.extend_from_slice(&chunk);
+        if feed.len() >= MAX_FEED_SIZE {
+            anyhow::bail!("feed is larger than {MAX_FEED_SIZE} bytes");
+        }
+    }
+    Ok(String::from_utf8_lossy(&feed).into_owned())
+}
+
+/// Post an item as a thread, unless it has been posted before.
+async fn ingest_item(
+    transaction: &mut Transaction<'_, Postgres>,
+    feed: &IngestedFeed,
+    item: &FeedItem,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let claimed = sqlx::query(
+        r#"
+            INSERT INTO ingested_items (feed_id, guid, ingested)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(feed.id)
+    .bind(&item.guid)
+    .bind(now)
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    if claimed == 0 {
+        return Ok(());
+    }
+
+    let title = item
+        .title
+        .chars()
+        .take(MAX_TITLE_LENGTH)
+        .collect::<String>();
+    let mut body = item
+        .description
+        .chars()
+        .take(MAX_BODY_LENGTH)
+        .collect::<String>();
+    if let Some(ref link) = item.link {
+        if !body.is_empty() {
+            body.push_str("\n\n");
+        }
+        body.push_str(link);
+    }
+
+    let tag_ids = Tag::fetch_from_str_and_inc(&mut *transaction, &feed.tag)
+        .await?
+        .map(|tag| tag.id())
+        .into_iter()
+        .collect::<Vec<_>>();
+    let thread: Thread = sqlx::query_as(
+        r#"
+            INSERT INTO threads
+                (title, tags, last_post, num_replies, pinned, locked, hidden)
+            VALUES
+                ($1, $2, 0, 0, FALSE, FALSE, FALSE)
+            RETURNING *
+        "#,
+    )
+    .bind(title)
+    .bind(tag_ids)
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    let reply_id: i32 = sqlx::query_scalar(
+        r#"
+            INSERT INTO replies
+                (author_id, thread_id, post_date, body, image, thumbnail, filename, reactions)
+            VALUES
+                ($1, $2, $3, $4, NULL, NULL, '', '{}')
+            RETURNING id
+        "#,
+    )
+    .bind(feed.bot_id)
+    .bind(thread.id)
+    .bind(now)
+    .bind(body)
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    sqlx::query("UPDATE threads SET last_post = $1 WHERE id = $2")
+        .bind(reply_id)
+        .bind(thread.id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("UPDATE ingested_items SET thread_id = $1 WHERE feed_id = $2 AND guid = $3")
+        .bind(thread.id)
+        .bind(feed.id)
+        .bind(&item.guid)
+        .execute(&mut *transaction)
+        .await?;
+
+    Event::ReplyCreated {
+        reply_id,
+        thread_id: thread.id,
+        author_id: feed.bot_id,
+    }
+    .publish(&mut *transaction)
+    .await
+}
+
+/// Background task that polls the feeds that are due. Feeds are claimed by
+/// marking them as fetched before they are fetched, so any number of instances
+/// can run this against the same database.
+pub async fn poll_feeds(conn: PgPool) {
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .expect("Failed to build feed client");
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = poll_batch(&conn, &client).await {
+            tracing::error!("Failed to poll feeds: {err}");
+        }
+    }
+}
+
+async fn poll_batch(conn: &PgPool, client: &reqwest::Client) -> Result<(), sqlx::Error> {
+    // Claiming is a single statement, so no transaction is held open while
+    // the feeds are fetched. A claimed feed is not due again until the
+    // refresh interval has passed, whether or not fetching it succeeds.
+    let now = Utc::now().naive_utc();
+    let due: Vec<IngestedFeed> = sqlx::query_as(
+        r#"
+            UPDATE ingested_feeds SET last_fetched = $1
+            WHERE id IN (
+                SELECT id FROM ingested_feeds
+                WHERE last_fetched IS NULL OR last_fetched <= $2
+                ORDER BY last_fetched ASC NULLS FIRST
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+        "#,
+    )
+    .bind(now)
+    .bind(now - *REFRESH_INTERVAL)
+    .bind(FETCH_BATCH_SIZE)
+    .fetch_all(conn)
+    .await?;
+
+    // A feed that fails is logged and does not hold back the others.
+    for feed in &due {
+        if let Err(err) = poll_feed(conn, client, feed).await {
+            tracing::error!("Failed to poll feed {}: {err}", feed.url);
+        }
+    }
+
+    Ok(())
+}
+
+async fn poll_feed(
+    conn: &PgPool,
+    client: &reqwest::Client,
+    feed: &IngestedFeed,
+) -> Result<(), sqlx::Error> {
+    let failed = match fetch_feed(client, &feed.url).await {
+        Ok(xml) => {
+            let mut items = parse_items(&xml);
+            items.truncate(MAX_ITEMS_PER_FETCH);
+            // Oldest first, so that threads are posted in the order the items
+            // were published. Each item is committed on its own, so that one
+            // that fails does not hold back the others.
+            let mut failed = false;
+            for item in items.iter().rev() {
+                if let Err(err) = ingest(conn, feed, item).await {
+                    tracing::warn!(
+                        "Failed to ingest item {} of feed {}: {err}",
+                        item.guid,
+                        feed.url
+                    );
+                    failed = true;
+                }
+            }
+            failed
+        }
+        Err(err) => {
+            tracing::warn!("Failed to fetch feed {}: {err}", feed.url);
+            true
+        }
+    };
+    sqlx::query(
+        r#"
+            UPDATE ingested_feeds SET
+                failures = CASE WHEN $1 THEN failures + 1 ELSE 0 END
+            WHERE id = $2
+        "#,
+    )
+    .bind(failed)
+    .bind(feed.id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Post an item in a transaction of its own.
+async fn ingest(conn: &PgPool, feed: &IngestedFeed, item: &FeedItem) -> Result<(), sqlx::Error> {
+    let mut transaction = conn.begin().await?;
+    ingest_item(&mut transaction, feed, item).await?;
+    transaction.commit().await
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum FeedsError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Feeds must be fetched over https")]
+    InvalidUrl,
+    #[error("Tag cannot be empty")]
+    EmptyTag,
+    #[error("No such user")]
+    NoSuchBot,
+    #[error("This feed has already been added")]
+    AlreadyAdded,
+    #[error("No such feed")]
+    NoSuchFeed,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/admin/feeds",
+    #[json]
+    async fn feeds(conn: Extension<PgPool>, user: User) -> Result<Vec<IngestedFeed>, FeedsError> {
+        if user.role < Role::Admin {
+            return Err(FeedsError::Unauthorized);
+        }
+        Ok(
+            sqlx::query_as("SELECT * FROM ingested_feeds ORDER BY id ASC")
+                .fetch_all(&*conn)
+                .await?,
+        )
+    }
+);
+
+#[derive(Deserialize)]
+pub struct AddFeedForm {
+    url:    String,
+    tag:    String,
+    bot_id: i32,
+}
+
+post!(
+    "/admin/feeds",
+    #[json]
+    async fn add_feed(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(AddFeedForm { url, tag, bot_id }): Form<AddFeedForm>,
+    ) -> Result<IngestedFeed, FeedsError> {
+        if user.role < Role::Admin {
+            return Err(FeedsError::Unauthorized);
+        }
+        let url = url.trim();
+        match url.parse::<http::Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("https") && uri.host().is_some() => (),
+            _ => return Err(FeedsError::InvalidUrl),
+        }
+        let tag = tag.trim();
+        if tag.is_empty() {
+            return Err(FeedsError::EmptyTag);
+        }
+        User::fetch_optional(&*conn, bot_id)
+            .await?
+            .filter(|bot| bot.deleted.is_none())
+            .ok_or(FeedsError::NoSuchBot)?;
+
+        sqlx::query_as(
+            r#"
+                INSERT INTO ingested_feeds (url, tag, bot_id, added)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (url) DO NOTHING
+                RETURNING *
+            "#,
+        )
+        .bind(url)
+        .bind(tag)
+        .bind(bot_id)
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&*conn)
+        .await?
+        .ok_or(FeedsError::AlreadyAdded)
+    }
+);
+
+post!(
+    "/admin/feeds/:feed_id/remove",
+    #[json]
+    async fn remove_feed(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(feed_id): Path<i32>,
+    ) -> Result<(), FeedsError> {
+        if user.role < Role::Admin {
+            return Err(FeedsError::Unauthorized);
+        }
+        let mut transaction = conn.begin().await?;
+        let removed = sqlx::query("DELETE FROM ingested_feeds WHERE id = $1")
+            .bind(feed_id)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        if removed == 0 {
+            return Err(FeedsError::NoSuchFeed);
+        }
+        sqlx::query("DELETE FROM ingested_items WHERE feed_id = $1")
+            .bind(feed_id)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+);
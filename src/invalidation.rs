@@ -0,0 +1,73 @@
+//! Cache invalidation bus. Changes to a user are announced with Postgres
+//! `NOTIFY` so that every running instance hears about them, and are then
+//! rebroadcast in-process to the caches that need to drop stale data.
+use std::time::Duration;
+
+use sqlx::{postgres::PgListener, PgExecutor, PgPool};
+use tokio::sync::broadcast;
+
+/// Postgres channel that user update notifications are sent over.
+pub const USER_UPDATED_CHANNEL: &str = "user_updated";
+
+/// Number of invalidations that can be buffered for a slow subscriber.
+const INVALIDATION_CHANNEL_CAPACITY: usize = 256;
+
+/// How long to wait before listening again after losing the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct InvalidationBus {
+    sender: broadcast::Sender<i32>,
+}
+
+impl Default for InvalidationBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(INVALIDATION_CHANNEL_CAPACITY);
+        Self { sender }
+    }
+}
+
+impl InvalidationBus {
+    /// Announce that a user has changed. When called inside of a transaction
+    /// the notification is only delivered once the transaction commits.
+    pub async fn user_updated(conn: impl PgExecutor<'_>, user_id: i32) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(USER_UPDATED_CHANNEL)
+            .bind(user_id.to_string())
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Subscribe to the ids of users that have changed.
+    pub fn subscribe(&self) -> broadcast::Receiver<i32> {
+        self.sender.subscribe()
+    }
+
+    /// Background task that forwards user update notifications from Postgres
+    /// to in-process subscribers.
+    pub async fn listen(self, conn: PgPool) {
+        loop {
+            if let Err(err) = self.forward(&conn).await {
+                tracing::error!("Invalidation listener failed: {err}");
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn forward(&self, conn: &PgPool) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(conn).await?;
+        listener.listen(USER_UPDATED_CHANNEL).await?;
+        loop {
+            let notification = listener.recv().await?;
+            match notification.payload().parse::<i32>() {
+                // An error only means that nobody is currently listening.
+                Ok(user_id) => drop(self.sender.send(user_id)),
+                Err(_) => tracing::warn!(
+                    "Invalid user update notification: `{}`",
+                    notification.payload()
+                ),
+            }
+        }
+    }
+}
@@ -1,8 +1,15 @@
-use std::{cmp::PartialEq, collections::HashMap, num::ParseIntError, str::FromStr, sync::Arc};
+use std::{
+    cmp::PartialEq,
+    collections::HashMap,
+    num::ParseIntError,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use axum::extract::{Extension, Form, Path, Query};
-use chrono::{Duration, Utc};
-use futures::{future, StreamExt};
+use chrono::{Duration, NaiveDateTime, Utc};
+use derive_more::Display;
+use futures::{future, stream, StreamExt};
 use lazy_static::lazy_static;
 use maplit::hashmap;
 use marche_proc_macros::{json, ErrorCode};
@@ -15,9 +22,19 @@ use sqlx::{
 use thiserror::Error;
 
 use crate::{
+    cluster::Cluster,
+    drop_pools::DropPool,
+    drop_rolls::DropRoll,
+    events::Event,
+    get,
     images::{Image, UploadImageError, MAXIMUM_FILE_SIZE},
-    post,
+    invalidation::InvalidationBus,
+    languages,
+    limits::{Limit, Limits},
+    notifications::Notifications,
+    passwords, post,
     users::{ProfileStub, Role, User, UserCache},
+    wallets::{self, Wallet, WalletReason},
     MultipartForm, MultipartFormError,
 };
 
@@ -36,20 +53,16 @@ pub enum Rarity {
     UltraRare,
     /// Corresponds to a ~0.01% chance of being dropped:
     Legendary,
+    /// Corresponds to a ~0.001% chance of being dropped, but only dropped while
+    /// the `MYTHIC_RARITY_FLAG` is on. Otherwise must be minted
+    Mythic,
     /// Unique items have no chance of being dropped, and must be minted
     Unique,
 }
 
 impl ToString for Rarity {
     fn to_string(&self) -> String {
-        String::from(match self {
-            Self::Common => "common",
-            Self::Uncommon => "uncommon",
-            Self::Rare => "rare",
-            Self::UltraRare => "ultra-rare",
-            Self::Legendary => "legendary",
-            Self::Unique => "unique",
-        })
+        self.meta().slug.to_string()
     }
 }
 
@@ -61,28 +74,319 @@ impl FromStr for Rarity {
     type Err = InvalidRarity;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "common" => Ok(Self::Common),
-            "uncommon" => Ok(Self::Uncommon),
-            "rare" => Ok(Self::Rare),
-            "ultra-rare" => Ok(Self::UltraRare),
-            "legendary" => Ok(Self::Legendary),
-            "unique" => Ok(Self::Unique),
-            _ => Err(InvalidRarity),
+        RARITIES
+            .iter()
+            .find(|meta| meta.slug == s)
+            .map(|meta| meta.rarity)
+            .ok_or(InvalidRarity)
+    }
+}
+
+/// How a rarity is presented: its names, colors and place in listings.
+/// Templates, the item catalog and the API all read rarities from `RARITIES`,
+/// so a new tier only needs an entry there.
+#[derive(Debug)]
+pub struct RarityMeta {
+    pub rarity:     Rarity,
+    /// Name used in forms, CSS classes and the API, e.g. `ultra-rare`.
+    pub slug:       &'static str,
+    /// Name of the rarity in each language, by language tag. Languages that
+    /// are missing fall back to the default language.
+    pub names:      &'static [(&'static str, &'static str)],
+    pub color:      &'static str,
+    pub background: &'static str,
+    pub border:     &'static str,
+}
+
+/// Every rarity, from most to least common.
+pub const RARITIES: &[RarityMeta] = &[
+    RarityMeta {
+        rarity:     Rarity::Common,
+        slug:       "common",
+        names:      &[
+            ("en", "Common"),
+            ("fr", "Commun"),
+            ("es", "Común"),
+            ("de", "Gewöhnlich"),
+            ("it", "Comune"),
+            ("pt", "Comum"),
+            ("nl", "Gewoon"),
+            ("ja", "コモン"),
+        ],
+        color:      "#006daa",
+        background: "#b9d6f2",
+        border:     "#006daa",
+    },
+    RarityMeta {
+        rarity:     Rarity::Uncommon,
+        slug:       "uncommon",
+        names:      &[
+            ("en", "Uncommon"),
+            ("fr", "Peu commun"),
+            ("es", "Poco común"),
+            ("de", "Ungewöhnlich"),
+            ("it", "Non comune"),
+            ("pt", "Incomum"),
+            ("nl", "Ongewoon"),
+            ("ja", "アンコモン"),
+        ],
+        color:      "#5d6838",
+        background: "#b1c66d",
+        border:     "#5d6838",
+    },
+    RarityMeta {
+        rarity:     Rarity::Rare,
+        slug:       "rare",
+        names:      &[
+            ("en", "Rare"),
+            ("fr", "Rare"),
+            ("es", "Raro"),
+            ("de", "Selten"),
+            ("it", "Raro"),
+            ("pt", "Raro"),
+            ("nl", "Zeldzaam"),
+            ("ja", "レア"),
+        ],
+        color:      "#592a7a",
+        background: "#cb80ff",
+        border:     "#592a7a",
+    },
+    RarityMeta {
+        rarity:     Rarity::UltraRare,
+        slug:       "ultra-rare",
+        names:      &[
+            ("en", "Ultra-rare"),
+            ("fr", "Ultra-rare"),
+            ("es", "Ultrarraro"),
+            ("de", "Extrem selten"),
+            ("it", "Ultra raro"),
+            ("pt", "Ultrarraro"),
+            ("nl", "Uiterst zeldzaam"),
+            ("ja", "ウルトラレア"),
+        ],
+        color:      "#8a1824",
+        background: "#ff8570",
+        border:     "#8a1824",
+    },
+    RarityMeta {
+        rarity:     Rarity::Legendary,
+        slug:       "legendary",
+        names:      &[
+            ("en", "Legendary"),
+            ("fr", "Légendaire"),
+            ("es", "Legendario"),
+            ("de", "Legendär"),
+            ("it", "Leggendario"),
+            ("pt", "Lendário"),
+            ("nl", "Legendarisch"),
+            ("ja", "レジェンダリー"),
+        ],
+        color:      "#776a31",
+        background: "#f7ce5b",
+        border:     "#af9b46",
+    },
+    RarityMeta {
+        rarity:     Rarity::Mythic,
+        slug:       "mythic",
+        names:      &[
+            ("en", "Mythic"),
+            ("fr", "Mythique"),
+            ("es", "Mítico"),
+            ("de", "Mythisch"),
+            ("it", "Mitico"),
+            ("pt", "Mítico"),
+            ("nl", "Mythisch"),
+            ("ja", "ミシック"),
+        ],
+        color:      "#7a1456",
+        background: "#f59ad6",
+        border:     "#7a1456",
+    },
+    RarityMeta {
+        rarity:     Rarity::Unique,
+        slug:       "unique",
+        names:      &[
+            ("en", "Unique"),
+            ("fr", "Unique"),
+            ("es", "Único"),
+            ("de", "Einzigartig"),
+            ("it", "Unico"),
+            ("pt", "Único"),
+            ("nl", "Uniek"),
+            ("ja", "ユニーク"),
+        ],
+        color:      "#1d5c57",
+        background: "#7fd8cd",
+        border:     "#1d5c57",
+    },
+];
+
+impl RarityMeta {
+    /// Name of the rarity in a language, or in the default language if it
+    /// has not been translated.
+    pub fn name(&self, language: &str) -> &'static str {
+        let find = |language: &str| {
+            self.names
+                .iter()
+                .find(|(tag, _)| *tag == language)
+                .map(|(_, name)| *name)
+        };
+        find(language)
+            .or_else(|| find(languages::DEFAULT_LANGUAGE))
+            .unwrap_or(self.slug)
+    }
+
+    /// Position of the rarity in listings, from most to least common.
+    pub fn sort_order(&self) -> usize {
+        RARITIES
+            .iter()
+            .position(|meta| meta.rarity == self.rarity)
+            .unwrap_or(RARITIES.len())
+    }
+
+    /// How often the rarity drops, in words, depending on whether mythic items
+    /// are rolled.
+    pub fn drop_rate(&self, mythic: bool) -> String {
+        let chance = self.rarity.chance(mythic);
+        if chance <= 0.0 {
+            "Never dropped, only minted".to_string()
+        } else {
+            format!(
+                "About {:.2}% of drops (1 in {})",
+                chance * 100.0,
+                (1.0 / chance).round() as u64
+            )
         }
     }
 }
 
+/// Localized name of a rarity, given its slug. Templates only see slugs, as
+/// that is what item thumbnails carry.
+pub fn rarity_name(slug: &str, language: &str) -> &'static str {
+    match slug.parse::<Rarity>() {
+        Ok(rarity) => rarity.meta().name(language),
+        Err(_) => "",
+    }
+}
+
+/// Styles of the `item-*` and `rarity-*` classes of every rarity, served as
+/// the `rarities.css` asset.
+pub fn rarity_stylesheet() -> String {
+    let mut css = String::new();
+    for meta in RARITIES {
+        let RarityMeta {
+            slug,
+            color,
+            background,
+            border,
+            ..
+        } = meta;
+        css.push_str(&format!(
+            r#"
+.item-{slug} {{
+    display: inline-block;
+    text-align: center;
+    text-decoration: none;
+    vertical-align: middle;
+    margin: 5px;
+    padding: 10px;
+    font-size: 11pt;
+    color: {color};
+    background: {background};
+    border: 1px solid {border};
+    border-radius: 5px;
+    min-width: 60px;
+    min-height: 105px;
+}}
+
+.rarity-{slug} {{
+    display: inline-block;
+    padding: 10px;
+    color: {color};
+    background: {background};
+    border: 1px solid {border};
+    border-radius: 5px;
+}}
+"#
+        ));
+    }
+    css
+}
+
+/// Feature flag that makes mythic items roll, meant to be switched on by
+/// admins for events.
+pub const MYTHIC_RARITY_FLAG: &str = "mythic_rarity";
+
+/// Mythic items take the rarest tenth of the rolls that would otherwise be
+/// legendary.
+const MYTHIC: u32 = u32::MAX - 42949;
 const LEGENDARY: u32 = u32::MAX - 429497;
 const ULTRA_RARE: u32 = LEGENDARY - 4294970;
 const RARE: u32 = ULTRA_RARE - 42949672;
 const UNCOMMON: u32 = RARE - 644245090;
 
 impl Rarity {
-    /// Roll for a random rarity
-    pub fn roll() -> Self {
+    /// How the rarity is presented.
+    pub fn meta(self) -> &'static RarityMeta {
+        RARITIES
+            .iter()
+            .find(|meta| meta.rarity == self)
+            .expect("every rarity is registered")
+    }
+
+    /// Every rarity that can be rolled, mythic last.
+    const ROLLED: [Self; 6] = [
+        Self::Common,
+        Self::Uncommon,
+        Self::Rare,
+        Self::UltraRare,
+        Self::Legendary,
+        Self::Mythic,
+    ];
+
+    /// Every rarity that can be rolled, depending on whether mythic items are.
+    pub fn rolled(mythic: bool) -> &'static [Self] {
+        if mythic {
+            &Self::ROLLED
+        } else {
+            &Self::ROLLED[..Self::ROLLED.len() - 1]
+        }
+    }
+
+    /// Chance of rolling this rarity, between 0 and 1, depending on whether
+    /// mythic items are rolled.
+    pub fn chance(self, mythic: bool) -> f64 {
+        let range = match self {
+            Self::Mythic if mythic => u32::MAX as u64 + 1 - MYTHIC as u64,
+            Self::Mythic => 0,
+            Self::Legendary if mythic => (MYTHIC - LEGENDARY) as u64,
+            Self::Legendary => u32::MAX as u64 + 1 - LEGENDARY as u64,
+            Self::UltraRare => (LEGENDARY - ULTRA_RARE) as u64,
+            Self::Rare => (ULTRA_RARE - RARE) as u64,
+            Self::Uncommon => (RARE - UNCOMMON) as u64,
+            Self::Common => UNCOMMON as u64,
+            Self::Unique => 0,
+        };
+        range as f64 / (u32::MAX as u64 + 1) as f64
+    }
+
+    /// Value of an item of this rarity in common items, from how much less
+    /// likely it is to be rolled while mythic items are, so that values do not
+    /// shift when the flag is switched. Unique items are valued at ten
+    /// legendaries.
+    pub fn value(self) -> u64 {
+        match self {
+            Self::Unique => Self::Legendary.value() * 10,
+            rarity => (Self::Common.chance(true) / rarity.chance(true)).round() as u64,
+        }
+    }
+
+    /// Roll for a random rarity, depending on whether mythic items are rolled.
+    pub fn roll(mythic: bool) -> Self {
         let rng: u32 = rand::random();
-        if rng >= LEGENDARY {
+        if mythic && rng >= MYTHIC {
+            Self::Mythic
+        } else if rng >= LEGENDARY {
             Self::Legendary
         } else if rng >= ULTRA_RARE {
             Self::UltraRare
@@ -131,6 +435,16 @@ pub struct AttrInfo {
     seed:   usize,
 }
 
+/// Number of rendered thumbnails at which the cache is emptied.
+const THUMBNAIL_CACHE_CAPACITY: usize = 8192;
+
+lazy_static! {
+    /// Rendered thumbnails keyed by item and pattern. Items cannot be changed
+    /// once minted and the attribute registry is compiled in, so entries never
+    /// go stale while the server is running.
+    static ref THUMBNAIL_CACHE: Mutex<HashMap<(i32, i32), String>> = Mutex::new(HashMap::new());
+}
+
 /// An item that can be dropped
 #[derive(FromRow, Debug, Serialize, Clone)]
 pub struct Item {
@@ -206,9 +520,19 @@ impl Item {
         }
     }
 
-    pub fn as_badge(&self) -> Option<String> {
+    pub fn as_badge(&self, item_drop: &ItemDrop) -> Option<Badge> {
         match self.item_type {
-            Jsonb(ItemType::Badge { ref value }) => Some(format!("<div>{value}</div>")),
+            Jsonb(ItemType::Badge { ref value }) => Some(Badge {
+                item_id:     self.id,
+                drop_id:     item_drop.id,
+                name:        self.name.clone(),
+                description: self.description.clone(),
+                rarity:      self.rarity,
+                acquired:    item_drop
+                    .acquired
+                    .map(|acquired| acquired.format(crate::DATE_FMT).to_string()),
+                html:        format!("<div>{value}</div>"),
+            }),
             _ => None,
         }
     }
@@ -220,7 +544,23 @@ impl Item {
         }
     }
 
+    /// Renders the thumbnail of a drop of this item. Rendering is
+    /// deterministic in the item and the pattern, so the result is cached.
     pub fn get_thumbnail_html(&self, pattern: i32) -> String {
+        let key = (self.id, pattern);
+        if let Some(html) = THUMBNAIL_CACHE.lock().unwrap().get(&key) {
+            return html.clone();
+        }
+        let html = self.render_thumbnail_html(pattern);
+        let mut cache = THUMBNAIL_CACHE.lock().unwrap();
+        if cache.len() >= THUMBNAIL_CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(key, html.clone());
+        html
+    }
+
+    fn render_thumbnail_html(&self, pattern: i32) -> String {
         let Attributes {
             div_animation,
             transform,
@@ -305,8 +645,60 @@ post!(
     }
 );
 
+#[derive(Deserialize)]
+struct SetMythicRarity {
+    enabled: bool,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+enum SetMythicRarityError {
+    #[error("You are not authorized to change which rarities drop")]
+    Unauthorized,
+    #[error("Internal db error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/admin/mythic_rarity",
+    #[json]
+    async fn set_mythic_rarity(
+        cluster: Extension<Cluster>,
+        user: User,
+        Form(SetMythicRarity { enabled }): Form<SetMythicRarity>,
+    ) -> Result<(), SetMythicRarityError> {
+        if user.role < Role::Admin {
+            return Err(SetMythicRarityError::Unauthorized);
+        }
+        cluster.set_flag(MYTHIC_RARITY_FLAG, enabled).await?;
+        tracing::info!(
+            "User `{}` has turned mythic drops {}",
+            user.name,
+            if enabled { "on" } else { "off" }
+        );
+        Ok(())
+    }
+);
+
+/// An equipped badge, along with where it came from.
+#[derive(Clone, Debug, Serialize)]
+pub struct Badge {
+    pub item_id:     i32,
+    pub drop_id:     i32,
+    pub name:        String,
+    pub description: String,
+    pub rarity:      Rarity,
+    /// When the owner received the badge, if known
+    pub acquired:    Option<String>,
+    /// Rendered badge
+    pub html:        String,
+}
+
 /// A dropped item associated with a user
-#[derive(FromRow, Debug, Clone)]
+#[derive(FromRow, Debug, Clone, Serialize)]
 pub struct ItemDrop {
     /// Id of the dropped item
     pub id:       i32,
@@ -318,6 +710,10 @@ pub struct ItemDrop {
     pub pattern:  i32,
     /// Indicates if the drop has been consumed
     pub consumed: bool,
+    /// When the current owner received the drop, if known
+    pub acquired: Option<NaiveDateTime>,
+    /// Whether the owner has been shown the drop
+    pub seen:     bool,
 }
 
 impl PartialEq for ItemDrop {
@@ -337,6 +733,17 @@ lazy_static::lazy_static! {
 pub const DROP_CHANCE: u32 = 2;
 
 impl ItemDrop {
+    /// Every drop that a user owns, including consumed ones.
+    pub async fn fetch_owned_by(
+        conn: impl PgExecutor<'_>,
+        owner_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM drops WHERE owner_id = $1 ORDER BY id ASC")
+            .bind(owner_id)
+            .fetch_all(conn)
+            .await
+    }
+
     pub async fn fetch_optional(
         conn: impl PgExecutor<'_>,
         drop_id: i32,
@@ -358,10 +765,53 @@ impl ItemDrop {
         Item::fetch(conn, self.item_id).await
     }
 
+    /// Fetches a number of drops along with their items in two queries, keyed
+    /// by drop id. Drops that do not exist are left out.
+    pub async fn fetch_many_with_items(
+        conn: &PgPool,
+        drop_ids: &[i32],
+    ) -> Result<HashMap<i32, (Item, ItemDrop)>, sqlx::Error> {
+        if drop_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let drops: Vec<ItemDrop> = sqlx::query_as("SELECT * FROM drops WHERE id = ANY($1)")
+            .bind(drop_ids)
+            .fetch_all(conn)
+            .await?;
+        let items: HashMap<i32, Item> = sqlx::query_as("SELECT * FROM items WHERE id = ANY($1)")
+            .bind(
+                drops
+                    .iter()
+                    .map(|item_drop| item_drop.item_id)
+                    .collect::<Vec<_>>(),
+            )
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(|item: Item| (item.id, item))
+            .collect();
+        Ok(drops
+            .into_iter()
+            .filter_map(|item_drop| {
+                let item = items.get(&item_drop.item_id)?.clone();
+                Some((item_drop.id, (item, item_drop)))
+            })
+            .collect())
+    }
+
     pub fn to_id(self) -> i32 {
         self.id
     }
 
+    /// Event announcing that this item was given to its owner.
+    pub fn created_event(&self) -> Event {
+        Event::DropCreated {
+            drop_id:  self.id,
+            owner_id: self.owner_id,
+            item_id:  self.item_id,
+        }
+    }
+
     /// Equips an item. Up to the caller to ensure current user owns the item.
     pub async fn equip(&self, conn: &PgPool) -> Result<(), EquipError> {
         let mut transaction = conn.begin().await?;
@@ -401,6 +851,7 @@ impl ItemDrop {
             _ => return Err(EquipError::Unequipable),
         };
         if user.id == self.owner_id {
+            InvalidationBus::user_updated(&mut transaction, user.id).await?;
             transaction.commit().await?;
             Ok(())
         } else {
@@ -445,8 +896,9 @@ impl ItemDrop {
                         .execute(&mut *conn)
                         .await?;
                 }
-                _ => (),
+                _ => return Ok(()),
             }
+            InvalidationBus::user_updated(&mut *conn, user.id).await?;
             Ok(())
         }
     }
@@ -454,6 +906,7 @@ impl ItemDrop {
     /// Possibly selects an item, depending on the last drop.
     pub async fn drop(
         conn: &mut Transaction<'_, Postgres>,
+        cluster: &Cluster,
         user: &User,
     ) -> Result<Option<Self>, sqlx::Error> {
         // Determine if we have a drop
@@ -466,28 +919,30 @@ impl ItemDrop {
 
         let conn = conn.acquire().await?;
 
-        let chosen: Option<Item> =
-            sqlx::query_as("SELECT * FROM items WHERE rarity = $1 AND available = TRUE")
-                .bind(Rarity::roll())
-                .fetch_all(&mut *conn)
-                .await?
-                .into_iter()
-                .choose(&mut thread_rng());
-        let Some(chosen) = chosen else { return Ok(None); };
+        let rarity = Rarity::roll(cluster.flag(MYTHIC_RARITY_FLAG).await?);
+        let chosen = DropPool::droppable_items(&mut *conn, rarity)
+            .await?
+            .into_iter()
+            .choose(&mut thread_rng());
+        let Some(chosen) = chosen else {
+            DropRoll::record(&mut *conn, user.id, rarity, None).await?;
+            return Ok(None);
+        };
 
         let mut transaction = (&mut *conn).begin().await?;
 
         // Give the new item to the user
-        let item_drop = sqlx::query_as(
+        let item_drop: Self = sqlx::query_as(
             r#"
-            INSERT INTO drops (owner_id, item_id, pattern, consumed)
-            VALUES ($1, $2, $3, FALSE)
+            INSERT INTO drops (owner_id, item_id, pattern, consumed, acquired)
+            VALUES ($1, $2, $3, FALSE, $4)
             RETURNING *
             "#,
         )
         .bind(user.id)
         .bind(chosen.id)
         .bind(rand::random::<i32>())
+        .bind(Utc::now().naive_utc())
         .fetch_one(&mut transaction)
         .await?;
 
@@ -495,9 +950,13 @@ impl ItemDrop {
         // since the start of this function.
         if user.update_last_reward(&mut transaction).await? {
             // Row was update, commit the transaction
+            item_drop.created_event().publish(&mut *transaction).await?;
+            DropRoll::record(&mut *transaction, user.id, rarity, Some(chosen.id)).await?;
             transaction.commit().await?;
             Ok(Some(item_drop))
         } else {
+            // The user was given a drop elsewhere in the meantime, so this roll
+            // did not count and is not recorded.
             transaction.rollback().await?;
             Ok(None)
         }
@@ -509,6 +968,60 @@ impl ItemDrop {
     }
 }
 
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum UnseenDropsError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/drops/unseen",
+    #[json]
+    async fn unseen_drops(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<Vec<ItemThumbnail>, UnseenDropsError> {
+        let drops: Vec<ItemDrop> =
+            sqlx::query_as("SELECT * FROM drops WHERE owner_id = $1 AND NOT seen ORDER BY id ASC")
+                .bind(user.id)
+                .fetch_all(&*conn)
+                .await?;
+        let mut thumbnails = Vec::with_capacity(drops.len());
+        for item_drop in drops {
+            thumbnails.push(item_drop.get_thumbnail(&conn).await?);
+        }
+        Ok(thumbnails)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct SeenDropsForm {
+    /// Latest drop that was revealed. Drops received since are left unseen
+    /// so they can be revealed too.
+    up_to: i32,
+}
+
+post!(
+    "/drops/seen",
+    #[json]
+    async fn mark_drops_seen(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(SeenDropsForm { up_to }): Form<SeenDropsForm>,
+    ) -> Result<(), UnseenDropsError> {
+        sqlx::query("UPDATE drops SET seen = TRUE WHERE owner_id = $1 AND id <= $2 AND NOT seen")
+            .bind(user.id)
+            .bind(up_to)
+            .execute(&*conn)
+            .await?;
+        Ok(())
+    }
+);
+
 #[derive(Debug, Serialize, Error, ErrorCode)]
 pub enum EquipError {
     #[error("No such item exists")]
@@ -577,7 +1090,7 @@ post! {
 }
 
 // TODO: Take this struct and extract it somewhere
-#[derive(Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ItemThumbnail {
     pub id:          i32,
     pub name:        String,
@@ -598,6 +1111,205 @@ impl ItemThumbnail {
     }
 }
 
+/// Estimated value of a set of items, in common items. There are no prices to
+/// go by, so items are valued by rarity alone. That says nothing of how much
+/// an item is wanted, so the estimate is a band from half to twice the value.
+#[derive(Copy, Clone, Debug, Default, Serialize, Display)]
+#[display(fmt = "{low}–{high}")]
+pub struct ValueBand {
+    pub items: usize,
+    /// Value in common items, the middle of the band
+    pub value: u64,
+    pub low:   u64,
+    pub high:  u64,
+}
+
+impl ValueBand {
+    pub fn of_rarities(rarities: impl IntoIterator<Item = Rarity>) -> Self {
+        let mut items = 0;
+        let mut value = 0;
+        for rarity in rarities {
+            items += 1;
+            value += rarity.value();
+        }
+        Self {
+            items,
+            value,
+            low: (value + 1) / 2,
+            high: value * 2,
+        }
+    }
+
+    pub fn of_thumbnails(thumbnails: &[ItemThumbnail]) -> Self {
+        Self::of_rarities(
+            thumbnails
+                .iter()
+                .filter_map(|thumbnail| thumbnail.rarity.parse().ok()),
+        )
+    }
+
+    /// Value of the given drops.
+    pub async fn of_drops(
+        conn: impl PgExecutor<'_>,
+        drop_ids: &[i32],
+    ) -> Result<Self, sqlx::Error> {
+        let rarities: Vec<Rarity> = sqlx::query_scalar(
+            r#"
+                SELECT items.rarity FROM drops
+                JOIN items ON items.id = drops.item_id
+                WHERE drops.id = ANY($1)
+            "#,
+        )
+        .bind(drop_ids)
+        .fetch_all(conn)
+        .await?;
+        Ok(Self::of_rarities(rarities))
+    }
+
+    /// Value of the items a user owns and has not consumed.
+    pub async fn of_inventory(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Self, sqlx::Error> {
+        let rarities: Vec<Rarity> = sqlx::query_scalar(
+            r#"
+                SELECT items.rarity FROM drops
+                JOIN items ON items.id = drops.item_id
+                WHERE drops.owner_id = $1 AND NOT drops.consumed
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(conn)
+        .await?;
+        Ok(Self::of_rarities(rarities))
+    }
+}
+
+/// When an offer is flagged as lopsided, read from the environment. Lopsided
+/// offers ask new or low level accounts for far more than they give, which is
+/// how most trade scams look.
+pub struct FairTradePolicy {
+    /// Ratio of the value requested to the value offered above which an
+    /// offer is lopsided. Set with `FAIR_TRADE_MAX_VALUE_RATIO`.
+    pub max_value_ratio:    u64,
+    /// Receivers at or below this level are protected. Set with
+    /// `FAIR_TRADE_MAX_RECEIVER_LEVEL`.
+    pub max_receiver_level: u64,
+    /// Receivers whose accounts are younger than this are protected. Set with
+    /// `FAIR_TRADE_NEW_ACCOUNT_DAYS`.
+    pub new_account_days:   u64,
+}
+
+impl FairTradePolicy {
+    fn from_env() -> Self {
+        fn var(name: &str, default: u64) -> u64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            max_value_ratio:    var("FAIR_TRADE_MAX_VALUE_RATIO", 10),
+            max_receiver_level: var("FAIR_TRADE_MAX_RECEIVER_LEVEL", 5),
+            new_account_days:   var("FAIR_TRADE_NEW_ACCOUNT_DAYS", 30),
+        }
+    }
+
+    /// Whether an offer to the receiver should be flagged.
+    pub fn is_lopsided(&self, receiver: &User, offered: ValueBand, requested: ValueBand) -> bool {
+        let new_account =
+            receiver.joined > Utc::now().naive_utc() - Duration::days(self.new_account_days as i64);
+        let protected = new_account || receiver.level() as u64 <= self.max_receiver_level;
+        protected && requested.value > offered.value.saturating_mul(self.max_value_ratio)
+    }
+}
+
+lazy_static! {
+    pub static ref FAIR_TRADE_POLICY: FairTradePolicy = FairTradePolicy::from_env();
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum InventoryValueError {
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/profile/:user_id/inventory_value",
+    #[json]
+    async fn inventory_value(
+        conn: Extension<PgPool>,
+        _user: User,
+        Path(user_id): Path<i32>,
+    ) -> Result<ValueBand, InventoryValueError> {
+        if User::fetch_optional(&*conn, user_id).await?.is_none() {
+            return Err(InventoryValueError::NoSuchUser);
+        }
+        Ok(ValueBand::of_inventory(&*conn, user_id).await?)
+    }
+);
+
+/// A rarity as described by the API, in the language of the user.
+#[derive(Debug, Serialize)]
+pub struct RarityInfo {
+    pub slug:       &'static str,
+    pub name:       &'static str,
+    pub sort_order: usize,
+    pub color:      &'static str,
+    pub background: &'static str,
+    pub border:     &'static str,
+    pub drop_rate:  String,
+    /// Value of an item of this rarity, in common items.
+    pub value:      u64,
+    /// Number of items of this rarity that can currently drop.
+    pub available:  i64,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum RaritiesError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/items/rarities",
+    #[json]
+    async fn rarities(
+        conn: Extension<PgPool>,
+        cluster: Extension<Cluster>,
+        user: User,
+    ) -> Result<Vec<RarityInfo>, RaritiesError> {
+        let mythic = cluster.flag(MYTHIC_RARITY_FLAG).await?;
+        let mut rarities = Vec::with_capacity(RARITIES.len());
+        for meta in RARITIES.iter() {
+            let available = DropPool::droppable_items(&*conn, meta.rarity).await?.len();
+            rarities.push(RarityInfo {
+                slug:       meta.slug,
+                name:       meta.name(user.language()),
+                sort_order: meta.sort_order(),
+                color:      meta.color,
+                background: meta.background,
+                border:     meta.border,
+                drop_rate:  meta.drop_rate(mythic),
+                value:      meta.rarity.value(),
+                available:  available as i64,
+            });
+        }
+        Ok(rarities)
+    }
+);
+
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub enum AttributeType {
     Filter,
@@ -734,6 +1446,13 @@ pub struct TradeRequest {
     pub receiver_items: Vec<i32>,
     /// Any note attached to this request
     pub note:           Option<String>,
+    /// Hash of the token on the page that confirms a response to the offer
+    /// from its notification
+    #[serde(skip)]
+    pub response_token: Option<String>,
+    /// Whether the offer asks for far more than it gives, see
+    /// `FairTradePolicy`. The receiver must confirm before accepting.
+    pub lopsided:       bool,
 }
 
 #[derive(Debug, Serialize, Error, ErrorCode)]
@@ -746,6 +1465,10 @@ pub enum TradeResponseError {
     Unauthorized,
     #[error("A conflicting trade has already been executed")]
     ConflictingTradeExecuted,
+    #[error(
+        "This offer asks for much more than it gives in return. Make sure you want to accept it."
+    )]
+    ConfirmationRequired,
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
@@ -755,6 +1478,31 @@ pub enum TradeResponseError {
 }
 
 impl TradeRequest {
+    /// Every pending trade that a user has sent or received.
+    pub async fn fetch_involving(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT * FROM trade_requests WHERE sender_id = $1 OR receiver_id = $1 ORDER BY id ASC",
+        )
+        .bind(user_id)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Cancel every pending trade that a user has sent or received.
+    pub async fn cancel_involving(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM trade_requests WHERE sender_id = $1 OR receiver_id = $1")
+            .bind(user_id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
     pub async fn fetch(conn: &PgPool, id: i32) -> Result<Option<Self>, sqlx::Error> {
         sqlx::query_as("SELECT * FROM trade_requests WHERE id = $1")
             .bind(id)
@@ -764,6 +1512,7 @@ impl TradeRequest {
 
     pub async fn accept(&self, conn: &PgPool) -> Result<(), TradeResponseError> {
         let mut transaction = conn.begin().await?;
+        let now = Utc::now().naive_utc();
 
         for sender_item in &self.sender_items {
             ItemDrop::fetch_optional(&mut transaction, *sender_item)
@@ -772,12 +1521,18 @@ impl TradeRequest {
                 .unequip(&mut transaction)
                 .await?;
 
-            sqlx::query("UPDATE drops SET owner_id = $1 WHERE id = $2 AND owner_id = $3")
-                .bind(self.receiver_id)
-                .bind(*sender_item)
-                .bind(self.sender_id)
-                .execute(&mut transaction)
-                .await?;
+            sqlx::query(
+                r#"
+                    UPDATE drops SET owner_id = $1, acquired = $4, seen = TRUE
+                    WHERE id = $2 AND owner_id = $3
+                "#,
+            )
+            .bind(self.receiver_id)
+            .bind(*sender_item)
+            .bind(self.sender_id)
+            .bind(now)
+            .execute(&mut transaction)
+            .await?;
         }
 
         for receiver_item in &self.receiver_items {
@@ -787,12 +1542,18 @@ impl TradeRequest {
                 .unequip(&mut transaction)
                 .await?;
 
-            sqlx::query("UPDATE drops SET owner_id = $1 WHERE id = $2 AND owner_id = $3")
-                .bind(self.sender_id)
-                .bind(*receiver_item)
-                .bind(self.receiver_id)
-                .execute(&mut transaction)
-                .await?;
+            sqlx::query(
+                r#"
+                    UPDATE drops SET owner_id = $1, acquired = $4, seen = TRUE
+                    WHERE id = $2 AND owner_id = $3
+                "#,
+            )
+            .bind(self.sender_id)
+            .bind(*receiver_item)
+            .bind(self.receiver_id)
+            .bind(now)
+            .execute(&mut transaction)
+            .await?;
         }
 
         // Check if any item no longer belongs to their respective owner.
@@ -824,11 +1585,41 @@ impl TradeRequest {
 
         // Delete the transaction and commit
         self.decline(&mut *transaction).await?;
+        Event::TradeAccepted {
+            trade_id:    self.id,
+            sender_id:   self.sender_id,
+            receiver_id: self.receiver_id,
+        }
+        .publish(&mut *transaction)
+        .await?;
         transaction.commit().await?;
 
         Ok(())
     }
 
+    /// Issue a new token for the page that confirms a response to this offer
+    /// from its notification. Only the newest token is accepted.
+    pub async fn issue_response_token(
+        &self,
+        conn: impl PgExecutor<'_>,
+    ) -> Result<String, sqlx::Error> {
+        let token = base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+        sqlx::query("UPDATE trade_requests SET response_token = $1 WHERE id = $2")
+            .bind(passwords::hash(&token))
+            .bind(self.id)
+            .execute(conn)
+            .await?;
+        Ok(token)
+    }
+
+    /// Whether a token from the confirmation page allows responding to this
+    /// offer.
+    pub fn check_response_token(&self, token: &str) -> bool {
+        self.response_token
+            .as_deref()
+            .map_or(false, |hash| passwords::verify(hash, token))
+    }
+
     pub async fn decline(&self, conn: impl PgExecutor<'_>) -> Result<(), TradeResponseError> {
         sqlx::query("DELETE FROM trade_requests WHERE id = $1")
             .bind(self.id)
@@ -856,10 +1647,12 @@ pub enum SubmitOfferError {
     NoSuchUser,
     #[error("Invalid trade")]
     InvalidTrade,
-    #[error("Note is too long")]
-    NoteTooLong,
+    #[error("Note is too long (maximum {max} characters allowed)")]
+    NoteTooLong { max: usize },
     #[error("Trade is empty")]
     TradeIsEmpty,
+    #[error("This user is not accepting offers from you")]
+    OfferDeclined,
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
@@ -874,13 +1667,13 @@ pub enum SubmitOfferError {
     ),
 }
 
-const MAX_NOTE_LENGTH: usize = 150;
-
 post! {
     "/offer",
     #[json]
     pub async fn submit_offer(
         conn: Extension<PgPool>,
+        notifications: Extension<Notifications>,
+        limits: Extension<Limits>,
         sender: User,
         Form(TradeRequestForm { receiver_id, note, trade }): Form<TradeRequestForm>,
     ) -> Result<TradeRequest, SubmitOfferError> {
@@ -893,8 +1686,11 @@ post! {
             return Err(SubmitOfferError::CannotTradeWithSelf);
         }
 
-        if User::fetch_optional(&*conn, receiver_id).await?.is_none() {
-            return Err(SubmitOfferError::NoSuchUser);
+        let receiver = User::fetch_optional(&*conn, receiver_id)
+            .await?
+            .ok_or(SubmitOfferError::NoSuchUser)?;
+        if receiver.has_blocked(&*conn, sender.id).await? {
+            return Err(SubmitOfferError::OfferDeclined);
         }
 
         for (item, trader) in trade.into_iter() {
@@ -921,12 +1717,13 @@ post! {
             return Err(SubmitOfferError::TradeIsEmpty);
         }
 
+        let max_note_length = limits.get(Limit::MaxNoteLength);
         let note = note
             .and_then(|note| {
                 let trimmed = note.trim();
                 (!trimmed.is_empty()).then(|| {
-                    if trimmed.len() > MAX_NOTE_LENGTH {
-                        Err(SubmitOfferError::NoteTooLong)
+                    if trimmed.len() > max_note_length {
+                        Err(SubmitOfferError::NoteTooLong { max: max_note_length })
                     } else {
                         Ok(trimmed.to_string())
                     }
@@ -934,43 +1731,64 @@ post! {
             })
             .transpose()?;
 
-        Ok(
-            sqlx::query_as(
-                r#"
-                INSERT INTO trade_requests
-                    (sender_id, sender_items, receiver_id, receiver_items, note)
-                VALUES
-                    ($1, $2, $3, $4, $5)
-                RETURNING *
-                "#
-            )
-                .bind(sender.id)
-                .bind(sender_items)
-                .bind(receiver_id)
-                .bind(receiver_items)
-                .bind(note)
-                .fetch_one(&*conn)
-                .await?
+        let lopsided = FAIR_TRADE_POLICY.is_lopsided(
+            &receiver,
+            ValueBand::of_drops(&*conn, &sender_items).await?,
+            ValueBand::of_drops(&*conn, &receiver_items).await?,
+        );
+
+        let offer = sqlx::query_as(
+            r#"
+            INSERT INTO trade_requests
+                (sender_id, sender_items, receiver_id, receiver_items, note, lopsided)
+            VALUES
+                ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#
         )
+            .bind(sender.id)
+            .bind(sender_items)
+            .bind(receiver_id)
+            .bind(receiver_items)
+            .bind(note)
+            .bind(lopsided)
+            .fetch_one(&*conn)
+            .await?;
+
+        notifications.offers_changed(&*conn, receiver_id).await?;
+        notifications.offer_received(&*conn, &sender, &offer).await?;
+
+        Ok(offer)
     }
 }
 
+#[derive(Deserialize)]
+pub struct AcceptParams {
+    /// Set once the receiver has confirmed a lopsided offer.
+    #[serde(default)]
+    confirmed: bool,
+}
+
 post! {
     "/accept/:trade_id",
     #[json]
     async fn accept(
         conn: Extension<PgPool>,
         user: User,
-        Path(trade_id): Path<i32>
+        Path(trade_id): Path<i32>,
+        Query(AcceptParams { confirmed }): Query<AcceptParams>,
     ) -> Result<(), TradeResponseError> {
         let req = TradeRequest::fetch(&*conn, trade_id)
             .await?
             .ok_or(TradeResponseError::NoSuchTrade)?;
-        if req.receiver_id == user.id {
-            req.accept(&*conn).await
-        } else {
-            Err(TradeResponseError::Unauthorized)
+        if req.receiver_id != user.id {
+            return Err(TradeResponseError::Unauthorized);
         }
+        if req.lopsided && !confirmed {
+            return Err(TradeResponseError::ConfirmationRequired);
+        }
+        req.accept(&*conn).await?;
+        Ok(())
     }
 }
 
@@ -979,26 +1797,31 @@ post! {
     #[json]
     async fn decline_offer(
         conn: Extension<PgPool>,
+        notifications: Extension<Notifications>,
         user: User,
         Path(trade_id): Path<i32>,
     ) -> Result<(), TradeResponseError> {
         let req = TradeRequest::fetch(&*conn, trade_id)
             .await?
             .ok_or(TradeResponseError::NoSuchTrade)?;
-        if req.sender_id == user.id || req.receiver_id == user.id {
-            req.decline(&*conn).await
-        } else {
-            Err(TradeResponseError::Unauthorized)
+        if req.sender_id != user.id && req.receiver_id != user.id {
+            return Err(TradeResponseError::Unauthorized);
         }
+        req.decline(&*conn).await?;
+        notifications.offers_changed(&*conn, req.receiver_id).await?;
+        Ok(())
     }
 }
 
 #[derive(Serialize)]
 pub struct IncomingOffer {
     pub id:             i32,
+    pub lopsided:       bool,
     pub sender:         Arc<ProfileStub>,
     pub sender_items:   Vec<ItemThumbnail>,
+    pub sender_value:   ValueBand,
     pub receiver_items: Vec<ItemThumbnail>,
+    pub receiver_value: ValueBand,
     pub note:           Option<String>,
 }
 
@@ -1008,10 +1831,19 @@ impl IncomingOffer {
         user_cache: &UserCache<'_>,
         user: &User,
     ) -> Vec<IncomingOffer> {
-        sqlx::query_as("SELECT * FROM trade_requests WHERE receiver_id = $1")
-            .bind(user.id)
-            .fetch(conn)
-            .filter_map(|trade: Result<TradeRequest, _>| future::ready(trade.ok()))
+        let trades: Vec<TradeRequest> =
+            sqlx::query_as("SELECT * FROM trade_requests WHERE receiver_id = $1")
+                .bind(user.id)
+                .fetch_all(conn)
+                .await
+                .unwrap_or_default();
+        let user_ids = trades
+            .iter()
+            .map(|trade| trade.sender_id)
+            .collect::<Vec<_>>();
+        // Failing here only means each user is fetched on its own below.
+        let _ = user_cache.get_many(&user_ids).await;
+        stream::iter(trades)
             .then(|trade| async move {
                 let mut sender_items = Vec::new();
                 for sender_item in trade.sender_items.into_iter() {
@@ -1033,8 +1865,11 @@ impl IncomingOffer {
                 }
                 sqlx::Result::Ok(IncomingOffer {
                     id: trade.id,
+                    lopsided: trade.lopsided,
                     sender: user_cache.get(trade.sender_id).await?,
                     note: trade.note,
+                    sender_value: ValueBand::of_thumbnails(&sender_items),
+                    receiver_value: ValueBand::of_thumbnails(&receiver_items),
                     sender_items,
                     receiver_items,
                 })
@@ -1049,8 +1884,10 @@ impl IncomingOffer {
 pub struct OutgoingOffer {
     pub id:             i32,
     pub sender_items:   Vec<ItemThumbnail>,
+    pub sender_value:   ValueBand,
     pub receiver:       Arc<ProfileStub>,
     pub receiver_items: Vec<ItemThumbnail>,
+    pub receiver_value: ValueBand,
     pub note:           Option<String>,
 }
 
@@ -1060,10 +1897,19 @@ impl OutgoingOffer {
         user_cache: &UserCache<'_>,
         user: &User,
     ) -> Vec<OutgoingOffer> {
-        sqlx::query_as("SELECT * FROM trade_requests WHERE sender_id = $1")
-            .bind(user.id)
-            .fetch(conn)
-            .filter_map(|trade: Result<TradeRequest, _>| future::ready(trade.ok()))
+        let trades: Vec<TradeRequest> =
+            sqlx::query_as("SELECT * FROM trade_requests WHERE sender_id = $1")
+                .bind(user.id)
+                .fetch_all(conn)
+                .await
+                .unwrap_or_default();
+        let user_ids = trades
+            .iter()
+            .map(|trade| trade.receiver_id)
+            .collect::<Vec<_>>();
+        // Failing here only means each user is fetched on its own below.
+        let _ = user_cache.get_many(&user_ids).await;
+        stream::iter(trades)
             .then(|trade| async move {
                 let mut sender_items = Vec::new();
                 for sender_item in trade.sender_items.into_iter() {
@@ -1087,6 +1933,8 @@ impl OutgoingOffer {
                     id: trade.id,
                     receiver: user_cache.get(trade.receiver_id).await?,
                     note: trade.note,
+                    sender_value: ValueBand::of_thumbnails(&sender_items),
+                    receiver_value: ValueBand::of_thumbnails(&receiver_items),
                     sender_items,
                     receiver_items,
                 })
@@ -1292,18 +2140,172 @@ post!(
             return Err(GiftItemError::Unauthorized);
         }
 
-        sqlx::query(
+        let mut transaction = conn.begin().await?;
+
+        let item_drop: ItemDrop = sqlx::query_as(
             r#"
-            INSERT INTO drops (owner_id, item_id, pattern, consumed)
-            VALUES ($1, $2, $3, FALSE)
+            INSERT INTO drops (owner_id, item_id, pattern, consumed, acquired)
+            VALUES ($1, $2, $3, FALSE, $4)
+            RETURNING *
             "#,
         )
         .bind(receiver_id)
         .bind(item_id)
         .bind(pattern)
-        .execute(&*conn)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&mut transaction)
         .await?;
 
+        item_drop.created_event().publish(&mut *transaction).await?;
+
+        transaction.commit().await?;
+
         Ok(())
     }
 );
+
+#[derive(Debug, Error, Serialize, ErrorCode)]
+pub enum GiveItemError {
+    #[error("You cannot give items to yourself")]
+    CannotGiveToSelf,
+    #[error("You do not own this item")]
+    NotOwned,
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("This user is not accepting gifts from you")]
+    GiftDeclined,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/give/:drop_id/:user_id",
+    #[json]
+    async fn give(
+        conn: Extension<PgPool>,
+        sender: User,
+        Path((drop_id, receiver_id)): Path<(i32, i32)>,
+    ) -> Result<(), GiveItemError> {
+        if sender.id == receiver_id {
+            return Err(GiveItemError::CannotGiveToSelf);
+        }
+        let receiver = User::fetch_optional(&*conn, receiver_id)
+            .await?
+            .filter(|receiver| receiver.deleted.is_none())
+            .ok_or(GiveItemError::NoSuchUser)?;
+        if !receiver.accepts_gifts || receiver.has_blocked(&*conn, sender.id).await? {
+            return Err(GiveItemError::GiftDeclined);
+        }
+
+        let mut transaction = conn.begin().await?;
+
+        // The drop is locked so that it cannot be traded or given away twice
+        // at once.
+        let item_drop: ItemDrop = sqlx::query_as(
+            r#"
+                SELECT * FROM drops
+                WHERE id = $1 AND owner_id = $2 AND consumed = FALSE
+                FOR UPDATE
+            "#,
+        )
+        .bind(drop_id)
+        .bind(sender.id)
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(GiveItemError::NotOwned)?;
+
+        item_drop.unequip(&mut transaction).await?;
+
+        let item_drop: ItemDrop = sqlx::query_as(
+            r#"
+                UPDATE drops SET owner_id = $1, acquired = $2, seen = FALSE
+                WHERE id = $3
+                RETURNING *
+            "#,
+        )
+        .bind(receiver.id)
+        .bind(Utc::now().naive_utc())
+        .bind(item_drop.id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        Event::DropGiven {
+            drop_id:     item_drop.id,
+            sender_id:   sender.id,
+            receiver_id: receiver.id,
+            item_id:     item_drop.item_id,
+        }
+        .publish(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+);
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum SellItemError {
+    #[error("You do not own this item")]
+    NotOwned,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/sell/:drop_id",
+    #[json]
+    async fn sell(
+        conn: Extension<PgPool>,
+        seller: User,
+        Path(drop_id): Path<i32>,
+    ) -> Result<i64, SellItemError> {
+        let mut transaction = conn.begin().await?;
+
+        // The drop is locked so that it cannot be sold and traded at once.
+        let item_drop: ItemDrop = sqlx::query_as(
+            r#"
+                SELECT * FROM drops
+                WHERE id = $1 AND owner_id = $2 AND consumed = FALSE
+                FOR UPDATE
+            "#,
+        )
+        .bind(drop_id)
+        .bind(seller.id)
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(SellItemError::NotOwned)?;
+
+        item_drop.unequip(&mut transaction).await?;
+
+        // Sold drops are consumed rather than deleted, so that anything
+        // referring to them still has an item to show.
+        sqlx::query("UPDATE drops SET consumed = TRUE WHERE id = $1")
+            .bind(item_drop.id)
+            .execute(&mut transaction)
+            .await?;
+
+        let item = Item::fetch(&mut transaction, item_drop.item_id).await?;
+        let price = wallets::sale_price(item.rarity);
+        Wallet::credit(
+            &mut transaction,
+            seller.id,
+            price,
+            WalletReason::Sale,
+            item_drop.id,
+        )
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(price)
+    }
+);
@@ -0,0 +1,150 @@
+//! Languages of the forum. Every language has a tag of the same name, and
+//! users who have not chosen home tags land on the tag of their language,
+//! which is detected from their browser until they pick one themselves.
+use axum::{
+    extract::{Extension, Form},
+    http::{header, HeaderMap},
+};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{get, invalidation::InvalidationBus, post, users::User};
+
+/// Language used when nothing better is known.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Languages that can be chosen, by tag, along with their own names for
+/// themselves. A language is only offered once its tag exists.
+pub const LANGUAGES: &[(&str, &str)] = &[
+    ("en", "English"),
+    ("fr", "Français"),
+    ("es", "Español"),
+    ("de", "Deutsch"),
+    ("it", "Italiano"),
+    ("pt", "Português"),
+    ("nl", "Nederlands"),
+    ("ja", "日本語"),
+];
+
+#[derive(Debug, Serialize)]
+pub struct Language {
+    pub tag:  &'static str,
+    pub name: &'static str,
+}
+
+/// Languages whose tags exist.
+pub async fn available(conn: impl PgExecutor<'_>) -> Result<Vec<Language>, sqlx::Error> {
+    let existing: Vec<String> = sqlx::query_scalar("SELECT name FROM tags WHERE name = ANY($1)")
+        .bind(LANGUAGES.iter().map(|(tag, _)| *tag).collect::<Vec<_>>())
+        .fetch_all(conn)
+        .await?;
+    Ok(LANGUAGES
+        .iter()
+        .filter(|(tag, _)| *tag == DEFAULT_LANGUAGE || existing.iter().any(|name| name == tag))
+        .map(|&(tag, name)| Language { tag, name })
+        .collect())
+}
+
+/// Languages listed in an `Accept-Language` header, most preferred first.
+fn preferred(accept_language: &str) -> Vec<String> {
+    let mut languages = accept_language
+        .split(',')
+        .enumerate()
+        .filter_map(|(position, entry)| {
+            let mut parts = entry.split(';');
+            // Only the primary subtag is used, e.g. `fr` of `fr-CA`.
+            let language = parts.next()?.trim().split('-').next()?.to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.trim().parse::<f32>().ok())?;
+            (!language.is_empty() && language != "*" && quality > 0.0)
+                .then_some((language, quality, position))
+        })
+        .collect::<Vec<_>>();
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.2.cmp(&b.2)));
+    languages
+        .into_iter()
+        .map(|(language, ..)| language)
+        .collect()
+}
+
+/// The available language that the browser prefers most.
+pub async fn detect(
+    conn: impl PgExecutor<'_>,
+    headers: &HeaderMap,
+) -> Result<&'static str, sqlx::Error> {
+    let Some(accept_language) = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Ok(DEFAULT_LANGUAGE);
+    };
+    let available = available(conn).await?;
+    Ok(preferred(accept_language)
+        .iter()
+        .find_map(|preferred| {
+            available
+                .iter()
+                .find(|language| language.tag == preferred)
+                .map(|language| language.tag)
+        })
+        .unwrap_or(DEFAULT_LANGUAGE))
+}
+
+/// Store a user's language.
+pub async fn set(conn: &PgPool, user_id: i32, language: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET language = $1 WHERE id = $2")
+        .bind(language)
+        .bind(user_id)
+        .execute(conn)
+        .await?;
+    InvalidationBus::user_updated(conn, user_id).await
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum LanguageError {
+    #[error("Unsupported language")]
+    UnsupportedLanguage,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/languages",
+    #[json]
+    async fn list_languages(conn: Extension<PgPool>) -> Result<Vec<Language>, LanguageError> {
+        Ok(available(&*conn).await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct SetLanguageForm {
+    language: String,
+}
+
+post!(
+    "/settings/language",
+    #[json]
+    async fn set_language(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(SetLanguageForm { language }): Form<SetLanguageForm>,
+    ) -> Result<&'static str, LanguageError> {
+        let language = available(&*conn)
+            .await?
+            .into_iter()
+            .find(|available| available.tag == language)
+            .ok_or(LanguageError::UnsupportedLanguage)?
+            .tag;
+
+        set(&conn, user.id, language).await?;
+
+        Ok(language)
+    }
+);
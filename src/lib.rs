@@ -1,8 +1,47 @@
+pub mod achievements;
+pub mod api_tokens;
+pub mod assets;
+pub mod bans;
+pub mod branding;
+pub mod capabilities;
+pub mod cluster;
+pub mod crafting;
+pub mod digests;
+pub mod discoveries;
+pub mod docs;
+pub mod drop_pools;
+pub mod drop_rolls;
+pub mod events;
+pub mod home;
 pub mod images;
+pub mod ingestion;
+pub mod invalidation;
 pub mod items;
+pub mod languages;
+pub mod limits;
+pub mod link_previews;
+pub mod login_audit;
+pub mod market;
+pub mod messages;
+pub mod metrics;
+pub mod migrations;
+pub mod notifications;
 pub mod pages;
+pub mod passwords;
+pub mod policies;
+pub mod push;
+pub mod rate_limits;
+pub mod recovery_codes;
+pub mod revisions;
+pub mod self_check;
+pub mod signing;
+pub mod stats;
 pub mod threads;
+pub mod usernames;
 pub mod users;
+pub mod wallets;
+#[cfg(feature = "websockets")]
+pub mod watch;
 
 use std::{any::Any, collections::HashMap};
 
@@ -28,13 +67,22 @@ pub const DATE_FMT: &str = "%B %-d, %Y at %I:%M %P";
 pub struct Endpoint {
     route_type: RouteType,
     path:       &'static str,
+    /// Module that the endpoint is defined in, used to group the API docs
+    module:     &'static str,
+    /// Full path of the handler function
+    name:       fn() -> &'static str,
     handler:    &'static (dyn Any + Send + Sync + 'static),
     installer:
         fn(RouteType, &'static str, &'static (dyn Any + Send + Sync + 'static), Router) -> Router,
 }
 
 impl Endpoint {
-    pub const fn new<I, A>(route_type: RouteType, path: &'static str, handler: &'static I) -> Self
+    pub const fn new<I, A>(
+        route_type: RouteType,
+        path: &'static str,
+        module: &'static str,
+        handler: &'static I,
+    ) -> Self
     where
         I: Handler<A, (), Body> + Copy + Any + Send + Sync + 'static,
         A: 'static,
@@ -42,6 +90,8 @@ impl Endpoint {
         Self {
             path,
             route_type,
+            module,
+            name: std::any::type_name::<I>,
             handler: handler as &(dyn Any + Send + Sync + 'static),
             installer: install::<I, A>,
         }
@@ -50,16 +100,116 @@ impl Endpoint {
     pub fn install(&self, router: Router) -> Router {
         (self.installer)(self.route_type, self.path, self.handler, router)
     }
+
+    pub fn route_type(&self) -> RouteType {
+        self.route_type
+    }
+
+    pub fn path(&self) -> &'static str {
+        self.path
+    }
+
+    /// Name of the module the endpoint is defined in, without the crate.
+    pub fn module(&self) -> &'static str {
+        self.module
+            .split_once("::")
+            .map_or(self.module, |(_, module)| module)
+    }
+
+    /// Full path of the function handling the endpoint.
+    pub fn name(&self) -> &'static str {
+        (self.name)()
+    }
+
+    /// Why this endpoint cannot be routed alongside another, if it can't.
+    fn conflict(&self, other: &Endpoint) -> Option<ConflictKind> {
+        let mut ours = self.path.split('/');
+        let mut theirs = other.path.split('/');
+        loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) if self.route_type == other.route_type => {
+                    return Some(ConflictKind::Duplicate)
+                }
+                (None, None) | (None, Some(_)) | (Some(_), None) => return None,
+                (Some(a), Some(b)) => {
+                    match (a.chars().next(), b.chars().next()) {
+                        // The router requires parameters at the same position
+                        // to have the same name, whatever comes after them.
+                        (Some(':'), Some(':')) | (Some('*'), Some('*')) if a != b => {
+                            return Some(ConflictKind::ParameterNames)
+                        }
+                        (Some(':'), Some('*')) | (Some('*'), Some(':')) => {
+                            return Some(ConflictKind::Wildcard)
+                        }
+                        _ if a != b => return None,
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
 }
 
 inventory::collect!(Endpoint);
 
-#[derive(Copy, Clone, Display)]
+#[derive(Copy, Clone, Debug, Display)]
+pub enum ConflictKind {
+    #[display(fmt = "are registered twice")]
+    Duplicate,
+    #[display(fmt = "name the same parameter differently")]
+    ParameterNames,
+    #[display(fmt = "overlap with a wildcard")]
+    Wildcard,
+}
+
+/// Two endpoints that cannot both be added to the router.
+#[derive(Debug, Display)]
+#[display(
+    fmt = "{first_route} {first_path} ({first_name}) and {second_route} {second_path} \
+           ({second_name}) {kind}"
+)]
+pub struct EndpointConflict {
+    pub first_route:  RouteType,
+    pub first_path:   &'static str,
+    pub first_name:   &'static str,
+    pub second_route: RouteType,
+    pub second_path:  &'static str,
+    pub second_name:  &'static str,
+    pub kind:         ConflictKind,
+}
+
+/// Every pair of registered endpoints that conflict with each other. The
+/// router panics on the first conflict it finds, or worse, so these are
+/// checked before it is built in order to report all of them at once.
+pub fn endpoint_conflicts() -> Vec<EndpointConflict> {
+    let endpoints = inventory::iter::<Endpoint>().collect::<Vec<_>>();
+    let mut conflicts = Vec::new();
+    for (i, first) in endpoints.iter().enumerate() {
+        for second in &endpoints[i + 1..] {
+            if let Some(kind) = first.conflict(second) {
+                conflicts.push(EndpointConflict {
+                    first_route: first.route_type,
+                    first_path: first.path,
+                    first_name: first.name(),
+                    second_route: second.route_type,
+                    second_path: second.path,
+                    second_name: second.name(),
+                    kind,
+                });
+            }
+        }
+    }
+    conflicts
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display)]
 pub enum RouteType {
     #[display(fmt = "GET")]
     Get,
     #[display(fmt = "POST")]
     Post,
+    #[display(fmt = "PUT")]
+    Put,
 }
 
 pub fn install<I, A>(
@@ -78,6 +228,7 @@ where
         match route_type {
             RouteType::Get => axum::routing::get(*handler.downcast_ref::<I>().unwrap()),
             RouteType::Post => axum::routing::post(*handler.downcast_ref::<I>().unwrap()),
+            RouteType::Put => axum::routing::put(*handler.downcast_ref::<I>().unwrap()),
         },
     )
 }
@@ -87,7 +238,7 @@ macro_rules! get {
     ( $suffix:literal, $func:item ) => {
         inventory::submit! {
             crate::Endpoint::new::<_, _>(
-                crate::RouteType::Get, $suffix, &marche_proc_macros::get_fn_name!( $func )
+                crate::RouteType::Get, $suffix, module_path!(), &marche_proc_macros::get_fn_name!( $func )
             )
         }
         $func
@@ -99,7 +250,19 @@ macro_rules! post {
     ( $suffix:literal, $func:item ) => {
         inventory::submit! {
             crate::Endpoint::new::<_, _>(
-                crate::RouteType::Post, $suffix, &marche_proc_macros::get_fn_name!( $func )
+                crate::RouteType::Post, $suffix, module_path!(), &marche_proc_macros::get_fn_name!( $func )
+            )
+        }
+        $func
+    };
+}
+
+#[macro_export]
+macro_rules! put {
+    ( $suffix:literal, $func:item ) => {
+        inventory::submit! {
+            crate::Endpoint::new::<_, _>(
+                crate::RouteType::Put, $suffix, module_path!(), &marche_proc_macros::get_fn_name!( $func )
             )
         }
         $func
@@ -221,3 +384,12 @@ where
         Some(s) => FromStr::from_str(s).map_err(de::Error::custom).map(Some),
     }
 }
+
+/// Parse a comma separated list of ids.
+fn parse_ids(ids: &str) -> Option<Vec<i32>> {
+    ids.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().ok())
+        .collect()
+}
@@ -0,0 +1,266 @@
+//! Limits that administrators can tune at runtime, such as the maximum length
+//! of a bio. Values are stored in the `limits` table, cached by every instance
+//! and reloaded across the cluster whenever one of them changes.
+use std::{
+    collections::HashMap,
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
+};
+
+use axum::extract::{Extension, Form};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    cluster::{Cluster, Topic},
+    get, post,
+    users::{Role, User},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    /// Number of threads listed on the index
+    ThreadsPerPage,
+    /// Maximum length of a note attached to a trade offer
+    MaxNoteLength,
+    /// Maximum length of a user's bio
+    MaxBioLength,
+    /// Maximum number of tags on a thread
+    MaxNumTags,
+    /// Maximum length of a tag
+    MaxTagLength,
+    /// Maximum experience a user can lose to reactions in a day
+    MaxDailyNegativeXp,
+    /// Minimum level needed to use reactions that take experience away
+    MinLevelForNegativeReactions,
+    /// Maximum length of a private message
+    MaxMessageLength,
+    /// Minimum level needed to edit wiki posts
+    MinLevelForWikiEdits,
+}
+
+impl Limit {
+    pub const ALL: &'static [Limit] = &[
+        Limit::ThreadsPerPage,
+        Limit::MaxNoteLength,
+        Limit::MaxBioLength,
+        Limit::MaxNumTags,
+        Limit::MaxTagLength,
+        Limit::MaxDailyNegativeXp,
+        Limit::MinLevelForNegativeReactions,
+        Limit::MaxMessageLength,
+        Limit::MinLevelForWikiEdits,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ThreadsPerPage => "threads_per_page",
+            Self::MaxNoteLength => "max_note_length",
+            Self::MaxBioLength => "max_bio_length",
+            Self::MaxNumTags => "max_num_tags",
+            Self::MaxTagLength => "max_tag_length",
+            Self::MaxDailyNegativeXp => "max_daily_negative_xp",
+            Self::MinLevelForNegativeReactions => "min_level_for_negative_reactions",
+            Self::MaxMessageLength => "max_message_length",
+            Self::MinLevelForWikiEdits => "min_level_for_wiki_edits",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|limit| limit.name() == name)
+    }
+
+    /// Value used until an administrator changes it.
+    pub fn default_value(self) -> usize {
+        match self {
+            Self::ThreadsPerPage => 25,
+            Self::MaxNoteLength => 150,
+            Self::MaxBioLength => 300,
+            Self::MaxNumTags => 6,
+            Self::MaxTagLength => 16,
+            Self::MaxDailyNegativeXp => 50,
+            Self::MinLevelForNegativeReactions => 5,
+            Self::MaxMessageLength => 2000,
+            Self::MinLevelForWikiEdits => 5,
+        }
+    }
+
+    /// Values that an administrator may choose from.
+    pub fn allowed(self) -> RangeInclusive<usize> {
+        match self {
+            Self::ThreadsPerPage => 5..=200,
+            Self::MaxNoteLength => 0..=2000,
+            Self::MaxBioLength => 0..=10_000,
+            Self::MaxNumTags => 1..=20,
+            Self::MaxTagLength => 1..=64,
+            Self::MaxDailyNegativeXp => 0..=1_000_000,
+            Self::MinLevelForNegativeReactions => 1..=64,
+            Self::MaxMessageLength => 1..=20_000,
+            Self::MinLevelForWikiEdits => 1..=64,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Limits {
+    cluster: Cluster,
+    values:  Arc<RwLock<HashMap<Limit, usize>>>,
+}
+
+impl Limits {
+    /// Load the current limits from the database.
+    pub async fn load(conn: &PgPool, cluster: Cluster) -> Result<Self, sqlx::Error> {
+        let limits = Self {
+            cluster,
+            values: Default::default(),
+        };
+        limits.reload(conn).await?;
+        Ok(limits)
+    }
+
+    async fn reload(&self, conn: &PgPool) -> Result<(), sqlx::Error> {
+        let mut values = HashMap::new();
+        for row in sqlx::query("SELECT name, value FROM limits")
+            .fetch_all(conn)
+            .await?
+        {
+            let name: String = row.get("name");
+            let value: i64 = row.get("value");
+            match Limit::from_name(&name) {
+                Some(limit) => drop(values.insert(limit, value as usize)),
+                None => tracing::warn!("Unknown limit `{name}`"),
+            }
+        }
+        *self.values.write().unwrap() = values;
+        Ok(())
+    }
+
+    pub fn get(&self, limit: Limit) -> usize {
+        self.values
+            .read()
+            .unwrap()
+            .get(&limit)
+            .copied()
+            .unwrap_or_else(|| limit.default_value())
+    }
+
+    /// Change a limit on every instance.
+    pub async fn set(&self, conn: &PgPool, limit: Limit, value: usize) -> Result<(), LimitsError> {
+        let allowed = limit.allowed();
+        if !allowed.contains(&value) {
+            return Err(LimitsError::OutOfRange {
+                min: *allowed.start(),
+                max: *allowed.end(),
+            });
+        }
+
+        sqlx::query(
+            r#"
+                INSERT INTO limits (name, value) VALUES ($1, $2)
+                ON CONFLICT (name) DO UPDATE SET value = EXCLUDED.value
+            "#,
+        )
+        .bind(limit.name())
+        .bind(value as i64)
+        .execute(conn)
+        .await?;
+
+        self.cluster.publish(Topic::Limits, limit.name()).await?;
+
+        Ok(())
+    }
+
+    /// Background task that reloads the limits when any instance changes one.
+    pub async fn receive(self, conn: PgPool, mut messages: broadcast::Receiver<String>) {
+        loop {
+            match messages.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => (),
+                Err(RecvError::Closed) => return,
+            }
+            if let Err(err) = self.reload(&conn).await {
+                tracing::error!("Failed to reload limits: {err}");
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LimitInfo {
+    name:    &'static str,
+    value:   usize,
+    default: usize,
+    min:     usize,
+    max:     usize,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum LimitsError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such limit")]
+    NoSuchLimit,
+    #[error("Value must be between {min} and {max}")]
+    OutOfRange { min: usize, max: usize },
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/limits",
+    #[json]
+    async fn show_limits(
+        user: User,
+        limits: Extension<Limits>,
+    ) -> Result<Vec<LimitInfo>, LimitsError> {
+        if user.role < Role::Admin {
+            return Err(LimitsError::Unauthorized);
+        }
+
+        Ok(Limit::ALL
+            .iter()
+            .map(|&limit| LimitInfo {
+                name:    limit.name(),
+                value:   limits.get(limit),
+                default: limit.default_value(),
+                min:     *limit.allowed().start(),
+                max:     *limit.allowed().end(),
+            })
+            .collect())
+    }
+);
+
+#[derive(Deserialize)]
+pub struct SetLimitForm {
+    name:  String,
+    value: usize,
+}
+
+post!(
+    "/limits",
+    #[json]
+    async fn set_limit(
+        conn: Extension<PgPool>,
+        user: User,
+        limits: Extension<Limits>,
+        Form(SetLimitForm { name, value }): Form<SetLimitForm>,
+    ) -> Result<(), LimitsError> {
+        if user.role < Role::Admin {
+            return Err(LimitsError::Unauthorized);
+        }
+
+        let limit = Limit::from_name(&name).ok_or(LimitsError::NoSuchLimit)?;
+        limits.set(&conn, limit, value).await?;
+
+        tracing::info!("User `{}` has set {name} to {value}", user.name);
+
+        Ok(())
+    }
+);
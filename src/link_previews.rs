@@ -0,0 +1,353 @@
+//! Previews of links posted in replies, built from the OpenGraph metadata of
+//! the linked page. Links are queued when a reply is posted or edited and the
+//! pages are fetched by a background task, so posting never waits on another
+//! site.
+use std::{collections::HashMap, time::Duration};
+
+use axum::async_trait;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::redirect;
+use serde::Serialize;
+use sqlx::{FromRow, PgExecutor, PgPool};
+
+use crate::events::{Event, Subscriber};
+
+/// Hosts that previews are fetched from. Restricting previews to a few well
+/// known sites keeps the server from being used to probe internal addresses
+/// or to make requests to arbitrary sites on behalf of posters.
+const ALLOWED_HOSTS: &[&str] = &[
+    "github.com",
+    "en.wikipedia.org",
+    "www.youtube.com",
+    "youtube.com",
+    "youtu.be",
+    "www.reddit.com",
+];
+
+/// Hosts that preview images are shown from. Images are loaded by the
+/// browsers of readers, so only the image hosts of the sites above are
+/// allowed rather than whatever a page names.
+const ALLOWED_IMAGE_HOSTS: &[&str] = &[
+    "opengraph.githubassets.com",
+    "repository-images.githubusercontent.com",
+    "avatars.githubusercontent.com",
+    "upload.wikimedia.org",
+    "i.ytimg.com",
+    "i.redd.it",
+    "preview.redd.it",
+    "external-preview.redd.it",
+];
+
+/// Largest number of links previewed per reply.
+const MAX_PREVIEWS_PER_REPLY: usize = 4;
+
+/// Longest time spent fetching a single page.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest number of bytes read from a page. OpenGraph tags are in the head,
+/// so the rest of the page is not needed.
+const MAX_PAGE_SIZE: usize = 256 * 1024;
+
+/// Largest number of redirects followed, each of which must also be allowed.
+const MAX_REDIRECTS: usize = 3;
+
+/// Number of times fetching a page is attempted before giving up on it.
+const MAX_FETCH_ATTEMPTS: i32 = 3;
+
+/// Largest number of pages fetched per pass of the background task.
+const FETCH_BATCH_SIZE: i64 = 16;
+
+/// How long a batch of links stays claimed by the instance fetching it, which
+/// is longer than every page of the batch takes to time out.
+const CLAIM_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// How long the background task waits before checking for new links.
+const FETCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest description shown in a preview.
+const MAX_DESCRIPTION_LENGTH: usize = 300;
+
+lazy_static! {
+    static ref LINK: Regex = Regex::new(r#"https://[^\s<>"']+"#).unwrap();
+    static ref META_TAG: Regex = Regex::new(r#"(?is)<meta\s[^>]*>"#).unwrap();
+    static ref META_ATTRIBUTE: Regex =
+        Regex::new(r#"(?is)(property|name|content)\s*=\s*"([^"]*)""#).unwrap();
+    static ref TITLE_TAG: Regex = Regex::new(r#"(?is)<title[^>]*>([^<]*)</title>"#).unwrap();
+}
+
+#[derive(Clone, Debug, Serialize, FromRow)]
+pub struct LinkPreview {
+    pub url:         String,
+    pub title:       String,
+    pub description: Option<String>,
+    pub image:       Option<String>,
+}
+
+impl LinkPreview {
+    /// Previews that have been fetched for the links in the given bodies,
+    /// keyed by URL.
+    pub async fn fetch_for<'a>(
+        conn: impl PgExecutor<'_>,
+        bodies: impl Iterator<Item = &'a str>,
+    ) -> Result<HashMap<String, Self>, sqlx::Error> {
+        let urls = bodies.flat_map(links).collect::<Vec<_>>();
+        if urls.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let previews: Vec<Self> = sqlx::query_as(
+            r#"
+                SELECT url, title, description, image FROM link_previews
+                WHERE url = ANY($1) AND title IS NOT NULL
+            "#,
+        )
+        .bind(urls)
+        .fetch_all(conn)
+        .await?;
+        Ok(previews
+            .into_iter()
+            .map(|mut preview| {
+                // Images saved before they were restricted are left out.
+                preview.image = preview.image.filter(|image| is_allowed_image(image));
+                (preview.url.clone(), preview)
+            })
+            .collect())
+    }
+
+    /// Previews for a single body, in the order the links appear.
+    pub fn for_body(body: &str, previews: &HashMap<String, Self>) -> Vec<Self> {
+        links(body)
+            .into_iter()
+            .filter_map(|url| previews.get(&url).cloned())
+            .collect()
+    }
+}
+
+/// Links in a body that previews may be shown for.
+pub fn links(body: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    for link in LINK.find_iter(body) {
+        let link = link
+            .as_str()
+            .trim_end_matches(&['.', ',', ')', '!', '?'][..]);
+        if is_allowed(link) && !links.iter().any(|existing| existing == link) {
+            links.push(link.to_string());
+        }
+        if links.len() >= MAX_PREVIEWS_PER_REPLY {
+            break;
+        }
+    }
+    links
+}
+
+fn is_allowed(url: &str) -> bool {
+    is_allowed_on(url, ALLOWED_HOSTS)
+}
+
+fn is_allowed_image(url: &str) -> bool {
+    is_allowed_on(url, ALLOWED_IMAGE_HOSTS)
+}
+
+fn is_allowed_on(url: &str, hosts: &[&str]) -> bool {
+    let Ok(uri) = url.parse::<http::Uri>() else {
+        return false;
+    };
+    uri.scheme_str() == Some("https")
+        && uri.port().is_none()
+        && uri
+            .authority()
+            .map_or(false, |authority| !authority.as_str().contains('@'))
+        && uri.host().map_or(false, |host| hosts.contains(&host))
+}
+
+/// Queue the links in a body to have their previews fetched.
+pub async fn queue(conn: impl PgExecutor<'_>, body: &str) -> Result<(), sqlx::Error> {
+    let urls = links(body);
+    if urls.is_empty() {
+        return Ok(());
+    }
+    sqlx::query("INSERT INTO link_previews (url) SELECT unnest($1::TEXT[]) ON CONFLICT DO NOTHING")
+        .bind(urls)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Queues the links of new replies.
+pub struct LinkPreviews;
+
+#[async_trait]
+impl Subscriber for LinkPreviews {
+    fn name(&self) -> &'static str {
+        "link_previews"
+    }
+
+    async fn handle(&self, conn: &PgPool, event: &Event) -> anyhow::Result<()> {
+        if let Event::ReplyCreated { reply_id, .. } = *event {
+            let body: Option<String> = sqlx::query_scalar("SELECT body FROM replies WHERE id = $1")
+                .bind(reply_id)
+                .fetch_optional(conn)
+                .await?;
+            if let Some(body) = body {
+                queue(conn, &body).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Background task that fetches the pages of queued links. Links are claimed
+/// before they are fetched, so any number of instances can run this against
+/// the same database.
+pub async fn fetch_pending(conn: PgPool) {
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() > MAX_REDIRECTS || !is_allowed(attempt.url().as_str()) {
+                attempt.stop()
+            } else {
+                attempt.follow()
+            }
+        }))
+        .build()
+        .expect("Failed to build link preview client");
+
+    loop {
+        // Wait between every batch, so that failed fetches are not retried
+        // straight away.
+        if let Err(err) = fetch_batch(&conn, &client).await {
+            tracing::error!("Failed to fetch link previews: {err}");
+        }
+        tokio::time::sleep(FETCH_INTERVAL).await;
+    }
+}
+
+async fn fetch_batch(conn: &PgPool, client: &reqwest::Client) -> Result<(), sqlx::Error> {
+    // Claiming is a single statement, so no transaction is held open while
+    // the pages are fetched.
+    let now = Utc::now().naive_utc();
+    let pending: Vec<(String, i32)> = sqlx::query_as(
+        r#"
+            UPDATE link_previews SET claimed_until = $2
+            WHERE url IN (
+                SELECT url FROM link_previews
+                WHERE fetched IS NULL AND (claimed_until IS NULL OR claimed_until < $1)
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING url, attempts
+        "#,
+    )
+    .bind(now)
+    .bind(now + chrono::Duration::from_std(CLAIM_DURATION).unwrap())
+    .bind(FETCH_BATCH_SIZE)
+    .fetch_all(conn)
+    .await?;
+
+    for (url, attempts) in &pending {
+        let now = Utc::now().naive_utc();
+        match fetch_metadata(client, url).await {
+            Ok(metadata) => {
+                sqlx::query(
+                    r#"
+                        UPDATE link_previews SET
+                            title = $1,
+                            description = $2,
+                            image = $3,
+                            attempts = attempts + 1,
+                            fetched = $4,
+                            claimed_until = NULL
+                        WHERE url = $5
+                    "#,
+                )
+                .bind(metadata.title)
+                .bind(metadata.description)
+                .bind(metadata.image)
+                .bind(now)
+                .bind(url)
+                .execute(conn)
+                .await?;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to fetch preview for {url}: {err}");
+                // Links that keep failing are marked as fetched without a
+                // title, so that they are never shown or retried.
+                let give_up = attempts + 1 >= MAX_FETCH_ATTEMPTS;
+                sqlx::query(
+                    r#"
+                        UPDATE link_previews SET
+                            attempts = attempts + 1,
+                            fetched = CASE WHEN $1 THEN $2 ELSE NULL END,
+                            claimed_until = NULL
+                        WHERE url = $3
+                    "#,
+                )
+                .bind(give_up)
+                .bind(now)
+                .bind(url)
+                .execute(conn)
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Metadata {
+    title:       Option<String>,
+    description: Option<String>,
+    image:       Option<String>,
+}
+
+async fn fetch_metadata(client: &reqwest::Client, url: &str) -> anyhow::Result<Metadata> {
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    let mut page = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        page.extend_from_slice(&chunk);
+        if page.len() >= MAX_PAGE_SIZE {
+            break;
+        }
+    }
+    let page = String::from_utf8_lossy(&page);
+
+    let mut metadata = Metadata::default();
+    for tag in META_TAG.find_iter(&page) {
+        let mut property = None;
+        let mut content = None;
+        for attribute in META_ATTRIBUTE.captures_iter(tag.as_str()) {
+            let value = html_escape::decode_html_entities(&attribute[2])
+                .trim()
+                .to_string();
+            match &attribute[1].to_ascii_lowercase()[..] {
+                "content" => content = Some(value),
+                _ => property = Some(value),
+            }
+        }
+        let (Some(property), Some(content)) = (property, content) else {
+            continue;
+        };
+        if content.is_empty() {
+            continue;
+        }
+        match &property[..] {
+            "og:title" => metadata.title = Some(content),
+            "og:description" => {
+                metadata.description = Some(content.chars().take(MAX_DESCRIPTION_LENGTH).collect())
+            }
+            "og:image" if is_allowed_image(&content) => metadata.image = Some(content),
+            _ => (),
+        }
+    }
+
+    if metadata.title.is_none() {
+        metadata.title = TITLE_TAG
+            .captures(&page)
+            .map(|title| html_escape::decode_html_entities(title[1].trim()).to_string())
+            .filter(|title| !title.is_empty());
+    }
+
+    Ok(metadata)
+}
@@ -0,0 +1,162 @@
+//! History of successful logins. Each login records the address, browser and
+//! country it came from, and logins from a network the user has never logged
+//! in from before are flagged so that the user can be alerted. Alerts are kept
+//! until the user has seen their security page, so that users who were away
+//! when they were sent still see them.
+use std::net::IpAddr;
+
+use axum::http::{header, HeaderMap};
+use chrono::{NaiveDateTime, Utc};
+use ipnetwork::IpNetwork;
+use lazy_static::lazy_static;
+use sqlx::{FromRow, PgExecutor, PgPool};
+
+/// Size of the IPv4 network that counts as the same place.
+const IPV4_RANGE_PREFIX: u8 = 24;
+/// Size of the IPv6 network that counts as the same place.
+const IPV6_RANGE_PREFIX: u8 = 48;
+
+/// Longest user agent stored.
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// Number of logins shown on the security page.
+pub const RECENT_LOGINS: i64 = 50;
+
+lazy_static! {
+    /// Header that the proxy in front of the server puts the country of the
+    /// client in, such as Cloudflare's `cf-ipcountry`. Set with
+    /// `GEO_COUNTRY_HEADER`. Clients can send any header themselves, so none
+    /// is read unless one is configured.
+    static ref GEO_COUNTRY_HEADER: Option<String> = std::env::var("GEO_COUNTRY_HEADER")
+        .ok()
+        .filter(|header| !header.is_empty());
+}
+
+#[derive(Debug, FromRow)]
+pub struct LoginAudit {
+    pub id:         i32,
+    pub user_id:    i32,
+    pub ip_addr:    IpNetwork,
+    pub ip_range:   IpNetwork,
+    pub user_agent: String,
+    /// Country code given by the proxy, if any
+    pub country:    Option<String>,
+    /// Whether this was the first login from `ip_range`
+    pub new_range:  bool,
+    pub logged_in:  NaiveDateTime,
+    /// Whether the user has seen the alert for this login. Logins from known
+    /// networks send no alert and are always seen.
+    pub alert_seen: bool,
+}
+
+impl LoginAudit {
+    /// Record a successful login.
+    pub async fn record(
+        conn: &PgPool,
+        user_id: i32,
+        ip: IpAddr,
+        headers: &HeaderMap,
+    ) -> Result<Self, sqlx::Error> {
+        let range = ip_range(ip);
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .chars()
+            .take(MAX_USER_AGENT_LENGTH)
+            .collect::<String>();
+        let country = GEO_COUNTRY_HEADER
+            .as_deref()
+            .and_then(|name| headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .map(|country| country.trim().to_uppercase())
+            .filter(|country| !country.is_empty() && country != "XX");
+
+        // The first login recorded for a user is not flagged, since there is
+        // nothing to compare it to.
+        sqlx::query_as(
+            r#"
+                WITH login AS (
+                    SELECT
+                        EXISTS (SELECT 1 FROM login_audit WHERE user_id = $1)
+                            AND NOT EXISTS (
+                                SELECT 1 FROM login_audit WHERE user_id = $1 AND ip_range = $3
+                            ) AS new_range
+                )
+                INSERT INTO login_audit (
+                    user_id, ip_addr, ip_range, user_agent, country, new_range, logged_in,
+                    alert_seen
+                )
+                SELECT $1, $2, $3, $4, $5, new_range, $6, NOT new_range FROM login
+                RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(IpNetwork::from(ip))
+        .bind(range)
+        .bind(user_agent)
+        .bind(country)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(conn)
+        .await
+    }
+
+    /// The user's most recent logins, newest first.
+    pub async fn fetch_recent(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT * FROM login_audit WHERE user_id = $1 ORDER BY logged_in DESC LIMIT $2",
+        )
+        .bind(user_id)
+        .bind(RECENT_LOGINS)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Logins from new networks whose alert the user has yet to see, oldest
+    /// first.
+    pub async fn fetch_unseen_alerts(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT * FROM login_audit
+                WHERE user_id = $1 AND NOT alert_seen
+                ORDER BY logged_in ASC
+                LIMIT $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(RECENT_LOGINS)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Mark every alert of the user as seen.
+    pub async fn mark_alerts_seen(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE login_audit SET alert_seen = TRUE WHERE user_id = $1 AND NOT alert_seen",
+        )
+        .bind(user_id)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+/// The network an address belongs to, for the purpose of telling whether a
+/// login comes from somewhere new.
+pub fn ip_range(ip: IpAddr) -> IpNetwork {
+    let prefix = match ip {
+        IpAddr::V4(_) => IPV4_RANGE_PREFIX,
+        IpAddr::V6(_) => IPV6_RANGE_PREFIX,
+    };
+    let network = IpNetwork::new(ip, prefix).expect("prefix is valid for the address");
+    IpNetwork::new(network.network(), prefix).expect("prefix is valid for the address")
+}
@@ -3,11 +3,32 @@ use std::net::SocketAddr;
 use axum::{
     extract::Extension,
     http::StatusCode,
+    middleware,
     response::Redirect,
     routing::{get, get_service},
     Router,
 };
-use marche_server::{pages::ServerError, Endpoint};
+use marche_server::{
+    achievements::Achievements,
+    api_tokens, assets, branding,
+    cluster::{Cluster, ClusterBackend, Topic},
+    digests,
+    discoveries::Discoveries,
+    events::Events,
+    ingestion,
+    invalidation::InvalidationBus,
+    limits::Limits,
+    link_previews::{self, LinkPreviews},
+    migrations,
+    notifications::Notifications,
+    pages::{self, ServerError},
+    self_check, stats,
+    threads::{self, Watchers},
+    usernames,
+    users::{self, CookieKeys, ProfileStubs, Revocations},
+    wallets::Wallets,
+    Endpoint,
+};
 use sqlx::postgres::PgPoolOptions;
 use tower_cookies::CookieManagerLayer;
 use tower_http::{services::ServeDir, trace::TraceLayer};
@@ -28,13 +49,115 @@ async fn main() {
         return;
     };
 
+    if !self_check::report(&self_check::run().await) {
+        tracing::error!("Self-check failed, aborting.");
+        return;
+    }
+
+    let cookie_keys = match CookieKeys::from_env() {
+        Ok(cookie_keys) => cookie_keys,
+        Err(err) => {
+            tracing::error!("{err}, aborting.");
+            return;
+        }
+    };
+
+    let conflicts = marche_server::endpoint_conflicts();
+    if !conflicts.is_empty() {
+        for conflict in &conflicts {
+            tracing::error!("Endpoints {conflict}");
+        }
+        tracing::error!("Found {} conflicting endpoints, aborting.", conflicts.len());
+        return;
+    }
+
     let pool = PgPoolOptions::new()
         .max_connections(5)
         .connect(&db_url)
         .await
         .expect("Failed to create database pool");
 
-    sqlx::migrate!().run(&pool).await.expect("Migration failed");
+    if std::env::args()
+        .skip(1)
+        .any(|arg| arg == "--skip-migrations")
+    {
+        tracing::info!("Skipping migrations");
+    } else {
+        migrations::run(&pool).await.expect("Migration failed");
+    }
+
+    if let Err(err) = migrations::check_schema_version(&pool).await {
+        tracing::error!("{err}, aborting.");
+        return;
+    }
+
+    tracing::info!("Serving as {}", branding::load());
+    tracing::info!("Fingerprinted {} static assets", assets::load());
+
+    usernames::backfill_skeletons(&pool)
+        .await
+        .expect("Failed to compute user name skeletons");
+
+    tokio::spawn(threads::apply_scheduled_flags(pool.clone()));
+    tokio::spawn(stats::refresh_views(pool.clone()));
+    tokio::spawn(users::release_deleted_names(pool.clone()));
+    tokio::spawn(users::promote_trusted_users(pool.clone()));
+
+    let cluster = Cluster::new(ClusterBackend::from_env(&pool));
+    if cluster.is_distributed() {
+        tracing::info!("Sharing cluster state through Postgres");
+    }
+    tokio::spawn(cluster.clone().listen());
+    tokio::spawn(cluster.clone().sweep());
+
+    let invalidations = InvalidationBus::default();
+    let revocations = Revocations::new(cluster.clone());
+    tokio::spawn(invalidations.clone().listen(pool.clone()));
+    tokio::spawn(
+        revocations
+            .clone()
+            .receive(cluster.subscribe(Topic::Revocations)),
+    );
+
+    let notifications = Notifications::new(cluster.clone());
+    tokio::spawn(
+        notifications
+            .clone()
+            .receive(cluster.subscribe(Topic::Notifications)),
+    );
+    tokio::spawn(
+        revocations
+            .clone()
+            .forget_updated_users(invalidations.subscribe()),
+    );
+
+    let profile_stubs = ProfileStubs::default();
+    tokio::spawn(
+        profile_stubs
+            .clone()
+            .forget_updated_users(invalidations.subscribe()),
+    );
+
+    let limits = Limits::load(&pool, cluster.clone())
+        .await
+        .expect("Failed to load limits");
+    tokio::spawn(
+        limits
+            .clone()
+            .receive(pool.clone(), cluster.subscribe(Topic::Limits)),
+    );
+
+    let mut events = Events::default();
+    events.register(notifications.clone());
+    events.register(LinkPreviews);
+    events.register(Achievements::new(notifications.clone()));
+    events.register(Wallets);
+    events.register(Discoveries);
+    tokio::spawn(events.dispatch(pool.clone()));
+    tokio::spawn(link_previews::fetch_pending(pool.clone()));
+    tokio::spawn(ingestion::poll_feeds(pool.clone()));
+    tokio::spawn(digests::send_digests(pool.clone()));
+    tokio::spawn(api_tokens::flush_usage(pool.clone()));
 
     let mut app = Router::new();
 
@@ -58,8 +181,18 @@ async fn main() {
                 )
             }),
         )
+        .layer(middleware::from_fn(pages::render_error_pages))
+        .layer(middleware::from_fn(api_tokens::rate_limit))
+        .layer(middleware::from_fn(api_tokens::record_usage))
         .layer(CookieManagerLayer::new())
         .layer(TraceLayer::new_for_http())
+        .layer(Extension(Watchers::default()))
+        .layer(Extension(revocations))
+        .layer(Extension(profile_stubs))
+        .layer(Extension(cookie_keys))
+        .layer(Extension(notifications))
+        .layer(Extension(limits))
+        .layer(Extension(cluster))
         .layer(Extension(pool));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
@@ -0,0 +1,501 @@
+//! The item market. Users list a drop along with the items and currency they
+//! want for it, and anyone holding drops of those items and enough currency
+//! can buy it outright. A purchase swaps the drops and pays the seller in a
+//! single transaction with the same ownership checks as accepting a trade
+//! offer.
+use std::collections::HashMap;
+
+use axum::extract::{Extension, Form, Path, Query};
+use chrono::{NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use thiserror::Error;
+
+use crate::{
+    discoveries::Discovery,
+    get,
+    items::{Item, ItemDrop, ItemThumbnail, Rarity},
+    post,
+    users::User,
+    wallets::{Wallet, WalletReason},
+};
+
+/// Number of listings shown per page of the market.
+pub const LISTINGS_PER_PAGE: i64 = 50;
+
+/// Most items a listing may ask for.
+pub const MAX_ASKING_ITEMS: usize = 10;
+
+/// Item types that the market can be filtered by, as named in `ItemType`.
+const ITEM_TYPES: &[&str] = &[
+    "Avatar",
+    "ProfileBackground",
+    "Reaction",
+    "Badge",
+    "Useless",
+];
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct Listing {
+    pub id:           i32,
+    pub seller_id:    i32,
+    pub drop_id:      i32,
+    /// Ids of the items asked for in return. An item may appear more than
+    /// once.
+    pub asking_items: Vec<i32>,
+    pub listed:       NaiveDateTime,
+    pub sold:         Option<NaiveDateTime>,
+    pub buyer_id:     Option<i32>,
+    /// Currency asked for in return, in addition to the items
+    pub price:        i64,
+}
+
+/// An item asked for by a listing.
+#[derive(Debug, Serialize)]
+pub struct AskedItem {
+    pub id:     i32,
+    pub name:   String,
+    pub rarity: String,
+}
+
+/// A listing as shown on the market.
+#[derive(Debug, Serialize)]
+pub struct MarketListing {
+    pub id:          i32,
+    pub seller_id:   i32,
+    pub seller_name: String,
+    pub item:        ItemThumbnail,
+    pub asking:      Vec<AskedItem>,
+    pub price:       i64,
+    pub listed:      NaiveDateTime,
+}
+
+#[derive(FromRow)]
+struct OpenListing {
+    id:           i32,
+    seller_id:    i32,
+    seller_name:  String,
+    drop_id:      i32,
+    asking_items: Vec<i32>,
+    price:        i64,
+    listed:       NaiveDateTime,
+}
+
+/// Whether two lists hold the same ids, disregarding order.
+fn same_ids(a: &[i32], b: &[i32]) -> bool {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort_unstable();
+    b.sort_unstable();
+    a == b
+}
+
+#[derive(Deserialize)]
+pub struct MarketParams {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    rarity:    Option<String>,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    item_type: Option<String>,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    page:      Option<i64>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum MarketError {
+    #[error("Invalid rarity")]
+    InvalidRarity,
+    #[error("Invalid item type")]
+    InvalidItemType,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/market",
+    #[json]
+    async fn market(
+        conn: Extension<PgPool>,
+        _user: User,
+        Query(MarketParams {
+            rarity,
+            item_type,
+            page,
+        }): Query<MarketParams>,
+    ) -> Result<Vec<MarketListing>, MarketError> {
+        let rarity = rarity
+            .map(|rarity| rarity.parse::<Rarity>())
+            .transpose()
+            .map_err(|_| MarketError::InvalidRarity)?;
+        if let Some(ref item_type) = item_type {
+            if !ITEM_TYPES.contains(&item_type.as_str()) {
+                return Err(MarketError::InvalidItemType);
+            }
+        }
+        let page = page.unwrap_or(1).max(1);
+
+        // Listings whose drop has left the seller since, or whose seller has
+        // deleted their account, cannot be bought.
+        let listings: Vec<OpenListing> = sqlx::query_as(
+            r#"
+                SELECT
+                    listings.id,
+                    listings.seller_id,
+                    users.display_name AS seller_name,
+                    listings.drop_id,
+                    listings.asking_items,
+                    listings.price,
+                    listings.listed
+                FROM listings
+                JOIN users ON users.id = listings.seller_id
+                JOIN drops ON drops.id = listings.drop_id
+                JOIN items ON items.id = drops.item_id
+                WHERE
+                    listings.sold IS NULL
+                    AND users.deleted IS NULL
+                    AND drops.owner_id = listings.seller_id
+                    AND NOT drops.consumed
+                    AND ($1::rarity IS NULL OR items.rarity = $1)
+                    AND ($2::text IS NULL OR items.item_type ? $2)
+                ORDER BY listings.listed DESC
+                LIMIT $3 OFFSET $4
+            "#,
+        )
+        .bind(rarity)
+        .bind(item_type)
+        .bind(LISTINGS_PER_PAGE)
+        .bind((page - 1) * LISTINGS_PER_PAGE)
+        .fetch_all(&*conn)
+        .await?;
+
+        let drop_ids = listings
+            .iter()
+            .map(|listing| listing.drop_id)
+            .collect::<Vec<_>>();
+        let drops = ItemDrop::fetch_many_with_items(&*conn, &drop_ids).await?;
+        let asked_ids = listings
+            .iter()
+            .flat_map(|listing| listing.asking_items.iter().copied())
+            .collect::<Vec<_>>();
+        let asked: HashMap<i32, Item> = sqlx::query_as("SELECT * FROM items WHERE id = ANY($1)")
+            .bind(asked_ids)
+            .fetch_all(&*conn)
+            .await?
+            .into_iter()
+            .map(|item: Item| (item.id, item))
+            .collect();
+
+        Ok(listings
+            .into_iter()
+            .filter_map(|listing| {
+                let (item, item_drop) = drops.get(&listing.drop_id)?;
+                Some(MarketListing {
+                    id:          listing.id,
+                    seller_id:   listing.seller_id,
+                    seller_name: listing.seller_name,
+                    item:        ItemThumbnail::new(item, item_drop),
+                    asking:      listing
+                        .asking_items
+                        .iter()
+                        .filter_map(|item_id| asked.get(item_id))
+                        .map(|item| AskedItem {
+                            id:     item.id,
+                            name:   item.name.clone(),
+                            rarity: item.rarity.to_string(),
+                        })
+                        .collect(),
+                    price:       listing.price,
+                    listed:      listing.listed,
+                })
+            })
+            .collect())
+    }
+);
+
+#[derive(Deserialize)]
+pub struct ListItemForm {
+    drop_id: i32,
+    /// Comma separated ids of the items asked for
+    #[serde(default)]
+    asking:  String,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    price:   Option<i64>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum ListItemError {
+    #[error("You do not own this item")]
+    NotOwned,
+    #[error("Item is already listed")]
+    AlreadyListed,
+    #[error("A listing must ask for at least one item or a price")]
+    NothingAsked,
+    #[error("Price cannot be negative")]
+    NegativePrice,
+    #[error("A listing may ask for at most {} items", MAX_ASKING_ITEMS)]
+    TooManyItemsAsked,
+    #[error("No such item")]
+    NoSuchItem,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/market/list",
+    #[json]
+    async fn list_item(
+        conn: Extension<PgPool>,
+        seller: User,
+        Form(ListItemForm {
+            drop_id,
+            asking,
+            price,
+        }): Form<ListItemForm>,
+    ) -> Result<Listing, ListItemError> {
+        let asking = crate::parse_ids(&asking).ok_or(ListItemError::NoSuchItem)?;
+        let price = price.unwrap_or(0);
+        if price < 0 {
+            return Err(ListItemError::NegativePrice);
+        }
+        if asking.is_empty() && price == 0 {
+            return Err(ListItemError::NothingAsked);
+        }
+        if asking.len() > MAX_ASKING_ITEMS {
+            return Err(ListItemError::TooManyItemsAsked);
+        }
+        let known: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM items WHERE id = ANY($1)")
+            .bind(&asking)
+            .fetch_one(&*conn)
+            .await?;
+        let mut distinct = asking.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if known as usize != distinct.len() {
+            return Err(ListItemError::NoSuchItem);
+        }
+
+        match ItemDrop::fetch_optional(&*conn, drop_id).await? {
+            Some(item_drop) if item_drop.owner_id == seller.id && !item_drop.consumed => (),
+            _ => return Err(ListItemError::NotOwned),
+        }
+
+        // A listing left behind by a drop that changed hands is closed, so
+        // that the drop can be listed again by its new owner.
+        let mut transaction = conn.begin().await?;
+        sqlx::query(
+            r#"
+                UPDATE listings SET sold = $1
+                WHERE drop_id = $2 AND sold IS NULL AND seller_id <> $3
+            "#,
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(drop_id)
+        .bind(seller.id)
+        .execute(&mut *transaction)
+        .await?;
+        let listing = sqlx::query_as(
+            r#"
+                INSERT INTO listings (seller_id, drop_id, asking_items, price, listed)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT DO NOTHING
+                RETURNING *
+            "#,
+        )
+        .bind(seller.id)
+        .bind(drop_id)
+        .bind(asking)
+        .bind(price)
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ListItemError::AlreadyListed)?;
+        transaction.commit().await?;
+
+        Ok(listing)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct BuyForm {
+    /// Comma separated ids of the drops paid with
+    #[serde(default)]
+    payment: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum BuyError {
+    #[error("No such listing")]
+    NoSuchListing,
+    #[error("You cannot buy your own listing")]
+    OwnListing,
+    #[error("Payment does not match what the listing asks for")]
+    WrongPayment,
+    #[error("Item is no longer owned by one of the parties")]
+    ItemNoLongerOwned,
+    #[error("You cannot afford this listing")]
+    InsufficientFunds,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/market/buy/:listing_id",
+    #[json]
+    async fn buy(
+        conn: Extension<PgPool>,
+        buyer: User,
+        Path(listing_id): Path<i32>,
+        Form(BuyForm { payment }): Form<BuyForm>,
+    ) -> Result<(), BuyError> {
+        let payment = crate::parse_ids(&payment).ok_or(BuyError::WrongPayment)?;
+
+        let mut transaction = conn.begin().await?;
+        let now = Utc::now().naive_utc();
+
+        // The listing is locked so that it cannot be bought twice, and cannot
+        // be bought from a seller who has deleted their account.
+        let listing: Listing = sqlx::query_as(
+            r#"
+                SELECT listings.* FROM listings
+                JOIN users ON users.id = listings.seller_id
+                WHERE listings.id = $1 AND listings.sold IS NULL AND users.deleted IS NULL
+                FOR UPDATE OF listings
+            "#,
+        )
+        .bind(listing_id)
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(BuyError::NoSuchListing)?;
+        if listing.seller_id == buyer.id {
+            return Err(BuyError::OwnListing);
+        }
+
+        let mut paid_items = Vec::new();
+        for drop_id in &payment {
+            let item_drop = ItemDrop::fetch_optional(&mut *transaction, *drop_id)
+                .await?
+                .filter(|item_drop| item_drop.owner_id == buyer.id && !item_drop.consumed)
+                .ok_or(BuyError::WrongPayment)?;
+            paid_items.push(item_drop.item_id);
+        }
+        if !same_ids(&paid_items, &listing.asking_items) {
+            return Err(BuyError::WrongPayment);
+        }
+
+        let transfers = payment
+            .iter()
+            .map(|drop_id| (*drop_id, buyer.id, listing.seller_id))
+            .chain(std::iter::once((
+                listing.drop_id,
+                listing.seller_id,
+                buyer.id,
+            )));
+        for (drop_id, from, to) in transfers {
+            ItemDrop::fetch_optional(&mut *transaction, drop_id)
+                .await?
+                .ok_or(BuyError::ItemNoLongerOwned)?
+                .unequip(&mut transaction)
+                .await?;
+
+            let moved = sqlx::query(
+                r#"
+                    UPDATE drops SET owner_id = $1, acquired = $4, seen = TRUE
+                    WHERE id = $2 AND owner_id = $3 AND consumed = FALSE
+                "#,
+            )
+            .bind(to)
+            .bind(drop_id)
+            .bind(from)
+            .bind(now)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+            if moved == 0 {
+                transaction.rollback().await?;
+                return Err(BuyError::ItemNoLongerOwned);
+            }
+        }
+
+        if listing.price > 0 {
+            if !Wallet::debit(
+                &mut transaction,
+                buyer.id,
+                listing.price,
+                WalletReason::Purchase,
+                listing.id,
+            )
+            .await?
+            {
+                transaction.rollback().await?;
+                return Err(BuyError::InsufficientFunds);
+            }
+            Wallet::credit(
+                &mut transaction,
+                listing.seller_id,
+                listing.price,
+                WalletReason::MarketSale,
+                listing.id,
+            )
+            .await?;
+        }
+
+        Discovery::record_owned(&mut *transaction, &[buyer.id, listing.seller_id]).await?;
+
+        sqlx::query("UPDATE listings SET sold = $1, buyer_id = $2 WHERE id = $3")
+            .bind(now)
+            .bind(buyer.id)
+            .bind(listing.id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+);
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum CancelListingError {
+    #[error("No such listing")]
+    NoSuchListing,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/market/cancel/:listing_id",
+    #[json]
+    async fn cancel_listing(
+        conn: Extension<PgPool>,
+        seller: User,
+        Path(listing_id): Path<i32>,
+    ) -> Result<(), CancelListingError> {
+        let cancelled =
+            sqlx::query("DELETE FROM listings WHERE id = $1 AND seller_id = $2 AND sold IS NULL")
+                .bind(listing_id)
+                .bind(seller.id)
+                .execute(&*conn)
+                .await?
+                .rows_affected();
+        if cancelled == 0 {
+            return Err(CancelListingError::NoSuchListing);
+        }
+        Ok(())
+    }
+);
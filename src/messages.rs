@@ -0,0 +1,264 @@
+//! Private messages between two users. Every pair of users has a single
+//! conversation holding the messages they have sent each other, which is
+//! listed in the inbox of both.
+use axum::extract::{Extension, Form, Path};
+use chrono::{NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{
+    get,
+    limits::{Limit, Limits},
+    notifications::Notifications,
+    post,
+    users::User,
+};
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct Message {
+    pub id:              i32,
+    pub conversation_id: i32,
+    pub sender_id:       i32,
+    pub recipient_id:    i32,
+    pub body:            String,
+    pub sent:            NaiveDateTime,
+    /// When the recipient first saw the message
+    pub read:            Option<NaiveDateTime>,
+}
+
+impl Message {
+    /// Every message between two users, oldest first.
+    pub async fn fetch_between(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+        other_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT * FROM messages
+                WHERE
+                    (sender_id = $1 AND recipient_id = $2)
+                    OR (sender_id = $2 AND recipient_id = $1)
+                ORDER BY id ASC
+            "#,
+        )
+        .bind(user_id)
+        .bind(other_id)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Every message a user has sent or received, oldest first.
+    pub async fn fetch_involving(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT * FROM messages WHERE sender_id = $1 OR recipient_id = $1 ORDER BY id ASC",
+        )
+        .bind(user_id)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Number of messages the user has received but not read.
+    pub async fn unread_count(conn: impl PgExecutor<'_>, user_id: i32) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE recipient_id = $1 AND read IS NULL")
+            .bind(user_id)
+            .fetch_one(conn)
+            .await
+    }
+
+    /// Mark the messages a user has received from another as read. Returns
+    /// whether any were unread.
+    pub async fn mark_read(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+        sender_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query(
+            r#"
+                UPDATE messages SET read = $3
+                WHERE recipient_id = $1 AND sender_id = $2 AND read IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(sender_id)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+}
+
+/// A conversation as listed in a user's inbox.
+#[derive(Debug, FromRow)]
+pub struct ConversationSummary {
+    pub other_id:     i32,
+    pub other_name:   String,
+    pub last_body:    String,
+    pub last_message: NaiveDateTime,
+    pub unread:       i64,
+}
+
+impl ConversationSummary {
+    /// Every conversation the user is part of, most recently active first.
+    pub async fn fetch_for(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT
+                    users.id AS other_id,
+                    users.display_name AS other_name,
+                    last.body AS last_body,
+                    conversations.last_message,
+                    (
+                        SELECT COUNT(*) FROM messages
+                        WHERE
+                            messages.conversation_id = conversations.id
+                            AND messages.recipient_id = $1
+                            AND messages.read IS NULL
+                    ) AS unread
+                FROM conversations
+                JOIN users ON users.id = CASE
+                    WHEN conversations.first_user_id = $1 THEN conversations.second_user_id
+                    ELSE conversations.first_user_id
+                END
+                CROSS JOIN LATERAL (
+                    SELECT body FROM messages
+                    WHERE messages.conversation_id = conversations.id
+                    ORDER BY messages.id DESC
+                    LIMIT 1
+                ) AS last
+                WHERE conversations.first_user_id = $1 OR conversations.second_user_id = $1
+                ORDER BY conversations.last_message DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(conn)
+        .await
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SendMessageForm {
+    body: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum SendMessageError {
+    #[error("You cannot message yourself")]
+    CannotMessageSelf,
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("This user is not accepting messages from you")]
+    Blocked,
+    #[error("Message cannot be empty")]
+    EmptyMessage,
+    #[error("Message is too long (maximum {max} characters allowed)")]
+    MessageTooLong { max: usize },
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/message/:user_id",
+    #[json]
+    async fn send_message(
+        conn: Extension<PgPool>,
+        notifications: Extension<Notifications>,
+        limits: Extension<Limits>,
+        sender: User,
+        Path(recipient_id): Path<i32>,
+        Form(SendMessageForm { body }): Form<SendMessageForm>,
+    ) -> Result<Message, SendMessageError> {
+        if sender.id == recipient_id {
+            return Err(SendMessageError::CannotMessageSelf);
+        }
+        let recipient = User::fetch_optional(&*conn, recipient_id)
+            .await?
+            .filter(|recipient| recipient.deleted.is_none())
+            .ok_or(SendMessageError::NoSuchUser)?;
+        if recipient.has_blocked(&*conn, sender.id).await? {
+            return Err(SendMessageError::Blocked);
+        }
+
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(SendMessageError::EmptyMessage);
+        }
+        let max = limits.get(Limit::MaxMessageLength);
+        if body.chars().count() > max {
+            return Err(SendMessageError::MessageTooLong { max });
+        }
+
+        let now = Utc::now().naive_utc();
+        let mut transaction = conn.begin().await?;
+
+        let conversation_id: i32 = sqlx::query_scalar(
+            r#"
+                INSERT INTO conversations (first_user_id, second_user_id, last_message)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (first_user_id, second_user_id) DO UPDATE SET
+                    last_message = EXCLUDED.last_message
+                RETURNING id
+            "#,
+        )
+        .bind(sender.id.min(recipient.id))
+        .bind(sender.id.max(recipient.id))
+        .bind(now)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        let message = sqlx::query_as(
+            r#"
+                INSERT INTO messages (conversation_id, sender_id, recipient_id, body, sent)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+            "#,
+        )
+        .bind(conversation_id)
+        .bind(sender.id)
+        .bind(recipient.id)
+        .bind(body)
+        .bind(now)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        notifications.messages_changed(&*conn, recipient.id).await?;
+
+        Ok(message)
+    }
+);
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum UnreadMessagesError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/messages/unread",
+    #[json]
+    async fn unread_messages(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<i64, UnreadMessagesError> {
+        Ok(Message::unread_count(&*conn, user.id).await?)
+    }
+);
@@ -0,0 +1,54 @@
+//! Runtime metrics for administrators.
+use axum::extract::Extension;
+use chrono::NaiveDateTime;
+use marche_proc_macros::{json, ErrorCode};
+use serde::Serialize;
+use sqlx::PgPool;
+use thiserror::Error;
+
+use crate::{
+    get,
+    stats::{self, ForumStats},
+    threads::{WatcherStats, Watchers},
+    users::{Role, User},
+};
+
+#[derive(Serialize)]
+pub struct Metrics {
+    watchers:        WatcherStats,
+    stats:           ForumStats,
+    /// When `stats` was last refreshed.
+    stats_refreshed: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum MetricsError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/metrics",
+    #[json]
+    async fn metrics(
+        conn: Extension<PgPool>,
+        user: User,
+        watchers: Extension<Watchers>,
+    ) -> Result<Metrics, MetricsError> {
+        if user.role < Role::Admin {
+            return Err(MetricsError::Unauthorized);
+        }
+
+        Ok(Metrics {
+            watchers:        watchers.stats(),
+            stats:           ForumStats::fetch(&*conn).await?,
+            stats_refreshed: stats::last_refreshed(&*conn, "forum_stats").await?,
+        })
+    }
+);
@@ -0,0 +1,57 @@
+//! Database migrations. Several replicas may start at the same time, so
+//! migrations are run while holding a Postgres advisory lock, which sqlx takes
+//! for every run, and a binary refuses to serve a database that has been
+//! migrated past what it knows about.
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    PgPool,
+};
+use thiserror::Error;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    #[error("Database schema version {found} is newer than the latest known version {expected}")]
+    SchemaTooNew { found: i64, expected: i64 },
+    #[error("Database has not been migrated")]
+    NotMigrated,
+    #[error("Migration failed: {0}")]
+    MigrateError(#[from] MigrateError),
+    #[error("Internal database error: {0}")]
+    InternalDbError(#[from] sqlx::Error),
+}
+
+/// Apply any pending migrations. Only one instance migrates at a time; the
+/// others wait for the lock and then find nothing left to do.
+pub async fn run(conn: &PgPool) -> Result<(), MigrationError> {
+    MIGRATOR.run(conn).await?;
+    Ok(())
+}
+
+/// Ensure that the database schema is one that this binary understands.
+pub async fn check_schema_version(conn: &PgPool) -> Result<(), MigrationError> {
+    let migrated: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(conn)
+        .await?;
+    if !migrated {
+        return Err(MigrationError::NotMigrated);
+    }
+
+    let found: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(conn)
+            .await?;
+    let found = found.ok_or(MigrationError::NotMigrated)?;
+    let expected = MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0);
+
+    if found > expected {
+        return Err(MigrationError::SchemaTooNew { found, expected });
+    }
+
+    Ok(())
+}
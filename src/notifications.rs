@@ -0,0 +1,508 @@
+//! Notifications shown as badges, such as the number of incoming trade offers.
+//! Notifications are published to the whole cluster and can be received by
+//! long-polling, for clients that cannot hold a websocket open.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    extract::{Extension, Query},
+};
+use chrono::Utc;
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    cluster::{Cluster, Topic},
+    events::{Event, Subscriber},
+    get,
+    items::{ItemDrop, ItemThumbnail, TradeRequest},
+    login_audit::LoginAudit,
+    messages::Message,
+    push,
+    users::User,
+};
+
+/// Number of notifications that can be buffered for a slow subscriber.
+const NOTIFICATION_CHANNEL_CAPACITY: usize = 256;
+
+/// Number of recent notifications kept for polls that arrive late.
+const RECENT_NOTIFICATIONS: usize = 256;
+
+/// Longest time a poll is held open before returning empty.
+pub const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Notification {
+    /// Time the notification was sent in milliseconds since the epoch. Used
+    /// as the cursor for polling.
+    pub id:      i64,
+    /// User the notification is for
+    pub user_id: i32,
+    #[serde(flatten)]
+    pub kind:    NotificationKind,
+}
+
+impl Notification {
+    /// Whether the notification should be delivered to the user.
+    fn is_for(&self, user: &User) -> bool {
+        self.user_id == user.id && self.kind.is_wanted(&user.notification_settings)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum NotificationKind {
+    /// The user's incoming trade offers have changed.
+    Offers { incoming: i64 },
+    /// Someone offered the user a trade.
+    TradeOffer {
+        sender_name:    String,
+        sender_items:   Vec<ItemThumbnail>,
+        receiver_items: Vec<ItemThumbnail>,
+        /// Whether the offer asks for far more than it gives
+        lopsided:       bool,
+        /// Links that respond to the offer in one click
+        accept_link:    String,
+        decline_link:   String,
+    },
+    /// The user's unread private messages have changed.
+    Messages { unread: i64 },
+    /// The user received a new item, which has yet to be revealed to them.
+    Drop { item: ItemThumbnail },
+    /// Someone reacted to one of the user's replies.
+    Reaction {
+        item_name: String,
+        thumbnail: String,
+        /// Change in the user's experience, which may be negative
+        xp:        i64,
+        /// Link to the reply that was reacted to
+        link:      String,
+    },
+    /// Someone replied to one of the user's posts.
+    Reply {
+        author_name: String,
+        /// Title of the thread replied in
+        title:       String,
+        /// Link to the reply
+        link:        String,
+    },
+    /// The user earned an achievement.
+    Achievement {
+        title:       String,
+        description: String,
+        badge:       String,
+    },
+    /// Someone logged in to the user's account from a network it had never
+    /// been used from.
+    NewLogin {
+        ip_addr:    String,
+        user_agent: String,
+        country:    Option<String>,
+    },
+}
+
+impl NotificationKind {
+    /// Alert for a login from a network the user had never logged in from.
+    pub fn new_login(audit: &LoginAudit) -> Self {
+        Self::NewLogin {
+            ip_addr:    audit.ip_addr.ip().to_string(),
+            user_agent: audit.user_agent.clone(),
+            country:    audit.country.clone(),
+        }
+    }
+
+    /// Whether the user wants to be notified of this.
+    pub fn is_wanted(&self, settings: &NotificationSettings) -> bool {
+        match self {
+            Self::Offers { .. } | Self::TradeOffer { .. } => settings.trade_offers,
+            Self::Messages { .. } => settings.messages,
+            Self::Drop { .. } => settings.drops,
+            Self::Reaction { .. } => settings.reactions,
+            Self::Reply { .. } => settings.replies,
+            Self::Achievement { .. } => settings.achievements,
+            // Too important to turn off.
+            Self::NewLogin { .. } => true,
+        }
+    }
+}
+
+/// Which notifications a user wants to receive. Everything is on by default.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    pub trade_offers: bool,
+    pub messages:     bool,
+    pub drops:        bool,
+    pub reactions:    bool,
+    pub replies:      bool,
+    pub achievements: bool,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            trade_offers: true,
+            messages:     true,
+            drops:        true,
+            reactions:    true,
+            replies:      true,
+            achievements: true,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Notifications {
+    cluster: Cluster,
+    sender:  broadcast::Sender<Notification>,
+    recent:  Arc<Mutex<VecDeque<Notification>>>,
+}
+
+impl Notifications {
+    pub fn new(cluster: Cluster) -> Self {
+        let (sender, _) = broadcast::channel(NOTIFICATION_CHANNEL_CAPACITY);
+        Self {
+            cluster,
+            sender,
+            recent: Default::default(),
+        }
+    }
+
+    pub async fn notify(&self, user_id: i32, kind: NotificationKind) -> Result<(), sqlx::Error> {
+        let notification = Notification {
+            id: Utc::now().timestamp_millis(),
+            user_id,
+            kind,
+        };
+        let payload = serde_json::to_string(&notification).unwrap();
+        self.cluster.publish(Topic::Notifications, &payload).await
+    }
+
+    /// Notify a user, and push the notification to every browser they have
+    /// subscribed to push notifications from.
+    pub async fn notify_and_push(
+        &self,
+        conn: &PgPool,
+        user_id: i32,
+        kind: NotificationKind,
+    ) -> Result<(), sqlx::Error> {
+        push::push(conn, user_id, &kind);
+        self.notify(user_id, kind).await
+    }
+
+    /// Notify a user of their current number of incoming trade offers.
+    pub async fn offers_changed(
+        &self,
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        let incoming =
+            sqlx::query_scalar("SELECT COUNT(*) FROM trade_requests WHERE receiver_id = $1")
+                .bind(user_id)
+                .fetch_one(conn)
+                .await?;
+        self.notify(user_id, NotificationKind::Offers { incoming })
+            .await
+    }
+
+    /// Notify the receiver of a new trade offer, with links to the pages that
+    /// confirm accepting or declining it.
+    pub async fn offer_received(
+        &self,
+        conn: &PgPool,
+        sender: &User,
+        offer: &TradeRequest,
+    ) -> Result<(), sqlx::Error> {
+        let mut sender_items = Vec::new();
+        for drop_id in &offer.sender_items {
+            sender_items.push(
+                ItemDrop::fetch(conn, *drop_id)
+                    .await?
+                    .get_thumbnail(conn)
+                    .await?,
+            );
+        }
+        let mut receiver_items = Vec::new();
+        for drop_id in &offer.receiver_items {
+            receiver_items.push(
+                ItemDrop::fetch(conn, *drop_id)
+                    .await?
+                    .get_thumbnail(conn)
+                    .await?,
+            );
+        }
+        let link = |action: &str| format!("/offers/{}/respond?action={action}", offer.id);
+        self.notify_and_push(
+            conn,
+            offer.receiver_id,
+            NotificationKind::TradeOffer {
+                sender_name: sender.display_name.clone(),
+                sender_items,
+                receiver_items,
+                lopsided: offer.lopsided,
+                accept_link: link("accept"),
+                decline_link: link("decline"),
+            },
+        )
+        .await
+    }
+
+    /// Notify a user of their current number of unread messages.
+    pub async fn messages_changed(
+        &self,
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        let unread = Message::unread_count(conn, user_id).await?;
+        self.notify(user_id, NotificationKind::Messages { unread })
+            .await
+    }
+
+    /// Notify the author of a reply that someone reacted to it and how much
+    /// experience they gained or lost.
+    pub async fn reaction_added(
+        &self,
+        conn: &PgPool,
+        author_id: i32,
+        reply_id: i32,
+        drop_id: i32,
+        xp: i64,
+    ) -> Result<(), sqlx::Error> {
+        let item_drop = ItemDrop::fetch(conn, drop_id).await?;
+        let item = item_drop.fetch_item(conn).await?;
+        self.notify(
+            author_id,
+            NotificationKind::Reaction {
+                thumbnail: item.get_thumbnail_html(item_drop.pattern),
+                item_name: item.name,
+                xp,
+                link: format!("/reply/{reply_id}"),
+            },
+        )
+        .await
+    }
+
+    /// Notify the author of the post a reply answers, unless they replied to
+    /// themselves or have blocked the replier.
+    pub async fn reply_created(&self, conn: &PgPool, reply_id: i32) -> Result<(), sqlx::Error> {
+        let replied_to: Option<(i32, String, String)> = sqlx::query_as(
+            r#"
+                SELECT parent.author_id, users.display_name, threads.title
+                FROM replies
+                JOIN replies AS parent ON parent.id = replies.in_reply_to
+                JOIN users ON users.id = replies.author_id
+                JOIN threads ON threads.id = replies.thread_id
+                WHERE
+                    replies.id = $1
+                    AND parent.author_id <> replies.author_id
+                    AND NOT replies.hidden
+                    AND NOT EXISTS (
+                        SELECT 1 FROM user_blocks
+                        WHERE blocker_id = parent.author_id AND blocked_id = replies.author_id
+                    )
+            "#,
+        )
+        .bind(reply_id)
+        .fetch_optional(conn)
+        .await?;
+        let Some((user_id, author_name, title)) = replied_to else {
+            return Ok(());
+        };
+        self.notify_and_push(
+            conn,
+            user_id,
+            NotificationKind::Reply {
+                author_name,
+                title,
+                link: format!("/reply/{reply_id}"),
+            },
+        )
+        .await
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+
+    /// Recent notifications for a user that were sent after `since`.
+    fn since(&self, user: &User, since: i64) -> Vec<Notification> {
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|notification| notification.is_for(user) && notification.id > since)
+            .cloned()
+            .collect()
+    }
+
+    /// Background task that delivers notifications published by any instance.
+    pub async fn receive(self, mut messages: broadcast::Receiver<String>) {
+        loop {
+            let message = match messages.recv().await {
+                Ok(message) => message,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let Ok(notification) = serde_json::from_str::<Notification>(&message) else {
+                tracing::warn!("Invalid notification: `{message}`");
+                continue;
+            };
+            {
+                let mut recent = self.recent.lock().unwrap();
+                if recent.len() >= RECENT_NOTIFICATIONS {
+                    recent.pop_front();
+                }
+                recent.push_back(notification.clone());
+            }
+            // An error only means that nobody is currently listening.
+            let _ = self.sender.send(notification);
+        }
+    }
+}
+
+#[async_trait]
+impl Subscriber for Notifications {
+    fn name(&self) -> &'static str {
+        "notifications"
+    }
+
+    async fn handle(&self, conn: &PgPool, event: &Event) -> anyhow::Result<()> {
+        match *event {
+            Event::ReplyCreated { reply_id, .. } => {
+                self.reply_created(conn, reply_id).await?;
+            }
+            Event::TradeAccepted { receiver_id, .. } => {
+                self.offers_changed(conn, receiver_id).await?;
+            }
+            Event::DropCreated {
+                drop_id,
+                owner_id: user_id,
+                ..
+            }
+            | Event::DropGiven {
+                drop_id,
+                receiver_id: user_id,
+                ..
+            } => {
+                let item = ItemDrop::fetch(conn, drop_id)
+                    .await?
+                    .get_thumbnail(conn)
+                    .await?;
+                self.notify(user_id, NotificationKind::Drop { item })
+                    .await?;
+            }
+            Event::ReactionAdded {
+                reply_id,
+                author_id,
+                drop_id,
+                xp,
+                ..
+            } => {
+                self.reaction_added(conn, author_id, reply_id, drop_id, xp)
+                    .await?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PollParams {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    since: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Poll {
+    events: Vec<Notification>,
+    /// Cursor to pass as `since` to the next poll.
+    next:   i64,
+}
+
+impl Poll {
+    fn new(events: Vec<Notification>, since: i64) -> Self {
+        // Alerts delivered again are older than `since`, and must not move
+        // the cursor back.
+        let next = events
+            .iter()
+            .map(|notification| notification.id)
+            .fold(since, i64::max);
+        Self { events, next }
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum PollError {
+    #[error("Notifications are unavailable")]
+    Unavailable,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/notifications/poll",
+    #[json]
+    async fn poll_notifications(
+        conn: Extension<PgPool>,
+        user: User,
+        notifications: Extension<Notifications>,
+        Query(PollParams { since }): Query<PollParams>,
+    ) -> Result<Poll, PollError> {
+        // Subscribe before checking the backlog so that nothing is missed.
+        let mut receiver = notifications.subscribe();
+        let first_poll = since.is_none();
+        let since = since.unwrap_or_else(|| Utc::now().timestamp_millis());
+
+        let mut pending = notifications.since(&user, since);
+        // The first poll of a page also delivers the login alerts the user
+        // has yet to see, which may have been sent while they were away.
+        if first_poll {
+            pending.extend(
+                LoginAudit::fetch_unseen_alerts(&*conn, user.id)
+                    .await?
+                    .iter()
+                    .map(|audit| Notification {
+                        id:      audit.logged_in.and_utc().timestamp_millis(),
+                        user_id: user.id,
+                        kind:    NotificationKind::new_login(audit),
+                    }),
+            );
+        }
+        if !pending.is_empty() {
+            return Ok(Poll::new(pending, since));
+        }
+
+        let timeout = tokio::time::sleep(LONG_POLL_TIMEOUT);
+        tokio::pin!(timeout);
+        loop {
+            tokio::select! {
+                _ = &mut timeout => return Ok(Poll::new(Vec::new(), since)),
+                notification = receiver.recv() => match notification {
+                    Ok(notification) if notification.is_for(&user) => {
+                        return Ok(Poll::new(vec![notification], since));
+                    }
+                    Ok(_) => (),
+                    Err(RecvError::Lagged(_)) => {
+                        let pending = notifications.since(&user, since);
+                        if !pending.is_empty() {
+                            return Ok(Poll::new(pending, since));
+                        }
+                    }
+                    Err(RecvError::Closed) => return Err(PollError::Unavailable),
+                },
+            }
+        }
+    }
+);
@@ -1,35 +1,73 @@
-use std::collections::HashSet;
+use std::{collections::HashMap, sync::Arc};
 
 use askama::Template;
 use axum::{
-    extract::{Extension, Path, Query},
-    http::StatusCode,
+    extract::{Extension, Form, FromRequestParts, Path, Query},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Redirect, Response},
 };
 use chrono::prelude::*;
-use futures::{future, stream, StreamExt, TryStreamExt};
+use futures::{future, stream, stream::BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{FromRow, PgPool, Row};
 use thiserror::Error;
+use tower_cookies::Cookies;
 
 use crate::{
+    achievements::{Achievement, AwardedAchievement},
+    api_tokens::{ApiToken, TokenScope},
+    cluster::Cluster,
     get,
-    items::{IncomingOffer, Item, ItemDrop, ItemThumbnail, OutgoingOffer},
-    threads::{Post, Reply, Tag, Tags, Thread},
-    users::{LevelInfo, ProfileStub, Role, User, UserCache, UserRejection},
+    home::{self, HomeSectionView},
+    items::{
+        IncomingOffer, Item, ItemDrop, ItemThumbnail, OutgoingOffer, RarityMeta, TradeRequest,
+        TradeResponseError, ValueBand, MYTHIC_RARITY_FLAG, RARITIES,
+    },
+    languages::{self, Language},
+    limits::{Limit, Limits},
+    login_audit::LoginAudit,
+    messages::{ConversationSummary, Message},
+    notifications::{NotificationSettings, Notifications},
+    post, recovery_codes,
+    revisions::{DiffKind, RevisionView},
+    stats::{self, LeaderboardPeriod, Standing, WeeklyHighlight},
+    threads::{
+        Post, PostLoader, Reply, Tag, Tags, Thread, ThreadTemplate, ThreadTombstone, REPLY_ORDER,
+    },
+    usernames::NameChange,
+    users::{
+        CookieKeys, LevelInfo, LoginSession, ProfileBundle, ProfileStub, ProfileStubs, Revocations,
+        Role, User, UserCache, UserRejection,
+    },
 };
 
-const THREADS_PER_PAGE: i64 = 25;
+const REPLIES_PER_PAGE: i64 = 50;
 const MINUTES_TIMESTAMP_IS_EMPHASIZED: i64 = 60 * 24;
+const REACTIONS_IN_SUMMARY: i64 = 3;
+const FEED_ENTRIES: i64 = 50;
 
 #[derive(Template)]
 #[template(path = "error.html")]
 pub struct ErrorPage {
-    offers: usize,
-    code:   u16,
-    reason: &'static str,
+    offers:      i64,
+    code:        u16,
+    reason:      &'static str,
+    suggestions: Vec<ThreadSuggestion>,
 }
 
+/// A thread the viewer may have been looking for when they hit an error page.
+#[derive(Debug)]
+pub struct ThreadSuggestion {
+    id:    i32,
+    title: String,
+}
+
+/// Marks a response as a plain error page, to be filled in with the viewer's
+/// context by `render_error_pages`.
+#[derive(Copy, Clone)]
+struct PlainErrorPage;
+
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error("Not found")]
@@ -47,23 +85,159 @@ impl IntoResponse for ServerError {
             ServerError::Unauthorized => StatusCode::UNAUTHORIZED,
             ServerError::InternalDbError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
-        (
+        let mut response = (
             status_code,
             ErrorPage {
-                offers: 0,
-                code:   status_code.as_u16(),
-                reason: status_code.canonical_reason().unwrap_or("????"),
+                offers:      0,
+                code:        status_code.as_u16(),
+                reason:      status_code.canonical_reason().unwrap_or("????"),
+                suggestions: Vec::new(),
             },
         )
-            .into_response()
+            .into_response();
+        response.extensions_mut().insert(PlainErrorPage);
+        response
+    }
+}
+
+/// Middleware that re-renders error pages with the viewer's offer count and,
+/// for missing pages, threads they may have been looking for.
+pub async fn render_error_pages<B>(req: Request<B>, next: Next<B>) -> Response {
+    let uri = req.uri().clone();
+    let headers = req.headers().clone();
+    let cookies = req.extensions().get::<Cookies>().cloned();
+    let conn = req.extensions().get::<PgPool>().cloned();
+    let cookie_keys = req.extensions().get::<CookieKeys>().cloned();
+    let revocations = req.extensions().get::<Revocations>().cloned();
+
+    let response = next.run(req).await;
+    if response.extensions().get::<PlainErrorPage>().is_none() {
+        return response;
+    }
+    let Some(conn) = conn else {
+        return response;
+    };
+
+    // The request has been consumed, so rebuild enough of it to find out
+    // who is viewing the page.
+    let (mut parts, _) = Request::new(()).into_parts();
+    parts.uri = uri.clone();
+    parts.headers = headers;
+    if let Some(cookies) = cookies {
+        parts.extensions.insert(cookies);
+    }
+    if let Some(cookie_keys) = cookie_keys {
+        parts.extensions.insert(cookie_keys);
+    }
+    if let Some(revocations) = revocations {
+        parts.extensions.insert(revocations);
+    }
+    parts.extensions.insert(conn.clone());
+    let user = User::from_request_parts(&mut parts, &()).await.ok();
+
+    let status_code = response.status();
+    let offers = match user {
+        Some(ref user) => user.incoming_offers(&conn).await.unwrap_or(0),
+        None => 0,
+    };
+    let suggestions = if status_code == StatusCode::NOT_FOUND {
+        ThreadSuggestion::for_path(&conn, uri.path())
+            .await
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    (
+        status_code,
+        ErrorPage {
+            offers,
+            code: status_code.as_u16(),
+            reason: status_code.canonical_reason().unwrap_or("????"),
+            suggestions,
+        },
+    )
+        .into_response()
+}
+
+/// Maximum number of threads suggested on an error page.
+const MAX_SUGGESTIONS: i64 = 5;
+
+impl ThreadSuggestion {
+    /// Threads that a missing path most likely referred to: the thread whose
+    /// id the path starts with, e.g. `/thread/12/old-title`, followed by
+    /// threads whose titles contain the words of the last segment of the
+    /// path.
+    async fn for_path(conn: &PgPool, path: &str) -> Result<Vec<Self>, sqlx::Error> {
+        let mut suggestions = Vec::new();
+
+        if let Some(rest) = path.strip_prefix("/thread/") {
+            let digits = rest
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>();
+            if let Ok(thread_id) = digits.parse::<i32>() {
+                let mut thread: Option<(i32, String)> = sqlx::query_as(
+                    r#"
+                        SELECT thread_id, title FROM thread_summaries
+                        WHERE thread_id = $1 AND NOT hidden
+                    "#,
+                )
+                .bind(thread_id)
+                .fetch_optional(conn)
+                .await?;
+                if thread.is_none() {
+                    // Point merged threads to where their replies went.
+                    thread = sqlx::query_as(
+                        r#"
+                            SELECT thread_summaries.thread_id, thread_summaries.title
+                            FROM thread_summaries
+                            JOIN thread_tombstones ON thread_summaries.thread_id = merged_into
+                            WHERE thread_tombstones.thread_id = $1 AND NOT thread_summaries.hidden
+                        "#,
+                    )
+                    .bind(thread_id)
+                    .fetch_optional(conn)
+                    .await?;
+                }
+                suggestions.extend(thread.map(|(id, title)| Self { id, title }));
+            }
+        }
+
+        let words = path
+            .rsplit('/')
+            .find(|segment| !segment.is_empty())
+            .unwrap_or("")
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.len() > 2 && !word.chars().all(|c| c.is_ascii_digit()))
+            .collect::<Vec<_>>();
+        if !words.is_empty() {
+            for summary in
+                ThreadSummary::search(conn, &words.join(" "), false, MAX_SUGGESTIONS).await?
+            {
+                if suggestions.iter().all(|s: &Self| s.id != summary.thread_id) {
+                    suggestions.push(Self {
+                        id:    summary.thread_id,
+                        title: summary.title,
+                    });
+                }
+            }
+        }
+
+        suggestions.truncate(MAX_SUGGESTIONS as usize);
+        Ok(suggestions)
     }
 }
 
 #[derive(Debug, Template)]
 #[template(path = "items.html")]
 pub struct Items {
-    offers: usize,
-    items:  Vec<ItemStub>,
+    offers:   usize,
+    items:    Vec<ItemStub>,
+    rarities: &'static [RarityMeta],
+    /// Whether mythic items are currently rolled
+    mythic:   bool,
+    language: String,
 }
 
 #[derive(Debug)]
@@ -75,15 +249,21 @@ pub struct ItemStub {
     attrs:       String,
     thumbnail:   String,
     rarity:      String,
+    drop_rate:   String,
     available:   bool,
 }
 
 get!(
     "/items",
-    pub async fn items(conn: Extension<PgPool>, user: User) -> Result<Items, ServerError> {
+    pub async fn items(
+        conn: Extension<PgPool>,
+        cluster: Extension<Cluster>,
+        user: User,
+    ) -> Result<Items, ServerError> {
         if user.role != Role::Admin {
             return Err(ServerError::Unauthorized);
         }
+        let mythic = cluster.flag(MYTHIC_RARITY_FLAG).await?;
 
         let items = sqlx::query_as("SELECT * FROM items ORDER BY rarity DESC, id DESC, name ASC")
             .fetch(&*conn)
@@ -96,12 +276,19 @@ get!(
                 item_type:   serde_json::to_string(&item.item_type).unwrap(),
                 attrs:       serde_json::to_string(&item.attributes).unwrap(),
                 rarity:      item.rarity.to_string(),
+                drop_rate:   item.rarity.meta().drop_rate(mythic),
                 available:   item.available,
             })
             .collect()
             .await;
 
-        Ok(Items { offers: 0, items })
+        Ok(Items {
+            offers: 0,
+            items,
+            rarities: RARITIES,
+            mythic,
+            language: user.language().to_string(),
+        })
     }
 );
 
@@ -112,6 +299,7 @@ pub struct Index {
     posts:       Vec<ThreadLink>,
     offers:      i64,
     viewer_role: Role,
+    highlight:   Option<WeeklyHighlight>,
 }
 
 #[derive(Debug, Serialize)]
@@ -121,20 +309,146 @@ struct ThreadLink {
     title:          String,
     date:           String,
     emphasize_date: bool,
+    last_poster:    Option<String>,
     read:           bool,
     jump_to:        i32,
+    unread:         i64,
     replies:        String,
     tags:           Vec<String>,
     pinned:         bool,
     locked:         bool,
     hidden:         bool,
+    reactions:      ReactionSummary,
+}
+
+/// Reactions across every reply of a thread.
+#[derive(Debug, Default, Serialize)]
+struct ReactionSummary {
+    /// Images of the most used reactions, most used first.
+    top:   Vec<String>,
+    /// Total number of reactions.
+    total: i64,
+}
+
+impl ReactionSummary {
+    /// Summarizes the reactions of a number of threads at once.
+    async fn fetch_all(
+        conn: &PgPool,
+        thread_ids: &[i32],
+    ) -> Result<HashMap<i32, Self>, sqlx::Error> {
+        let mut rows = sqlx::query(
+            r#"
+                SELECT thread_id, filename, total FROM (
+                    SELECT
+                        replies.thread_id,
+                        items.item_type->'Reaction'->>'filename' AS filename,
+                        (SUM(COUNT(*)) OVER (PARTITION BY replies.thread_id))::BIGINT AS total,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY replies.thread_id
+                            ORDER BY COUNT(*) DESC, items.id ASC
+                        ) AS rank
+                    FROM replies
+                    CROSS JOIN LATERAL unnest(replies.reactions) AS reaction(drop_id)
+                    JOIN drops ON drops.id = reaction.drop_id
+                    JOIN items ON items.id = drops.item_id
+                    WHERE
+                        replies.thread_id = ANY($1)
+                        AND NOT replies.hidden
+                        AND items.item_type ? 'Reaction'
+                    GROUP BY replies.thread_id, items.id
+                ) AS summary
+                WHERE rank <= $2
+                ORDER BY thread_id, rank
+            "#,
+        )
+        .bind(thread_ids)
+        .bind(REACTIONS_IN_SUMMARY)
+        .fetch(conn);
+
+        let mut summaries = HashMap::<i32, Self>::new();
+        while let Some(row) = rows.try_next().await? {
+            let summary = summaries.entry(row.get("thread_id")).or_default();
+            summary.top.push(row.get("filename"));
+            summary.total = row.get("total");
+        }
+        Ok(summaries)
+    }
+}
+
+/// The reply of a thread with the most reactions.
+#[derive(Debug, FromRow, Serialize)]
+struct TopReactedReply {
+    id:        i32,
+    author:    String,
+    reactions: i64,
+}
+
+impl TopReactedReply {
+    async fn fetch(conn: &PgPool, thread_id: i32) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT
+                    replies.id,
+                    users.display_name AS author,
+                    cardinality(replies.reactions)::BIGINT AS reactions
+                FROM replies
+                JOIN users ON users.id = replies.author_id
+                WHERE
+                    replies.thread_id = $1
+                    AND NOT replies.hidden
+                    AND cardinality(replies.reactions) > 0
+                ORDER BY cardinality(replies.reactions) DESC, replies.id ASC
+                LIMIT 1
+            "#,
+        )
+        .bind(thread_id)
+        .fetch_optional(conn)
+        .await
+    }
+}
+
+#[derive(Template)]
+#[template(path = "home.html")]
+pub struct HomePage {
+    offers:   i64,
+    sections: Vec<HomeSectionView>,
 }
 
 get! {
     "/",
-    pub async fn redirect_to_index() -> Redirect {
-        // TODO: Redirect to default language.
-        Redirect::to("/t/en")
+    pub async fn show_home(
+        conn: Extension<PgPool>,
+        headers: HeaderMap,
+        user: Option<User>,
+    ) -> Result<Response, ServerError> {
+        let Some(mut user) = user else {
+            let language = languages::detect(&*conn, &headers).await?;
+            return Ok(Redirect::to(&format!("/t/{language}")).into_response());
+        };
+
+        // Remember the language of the user's browser on their first visit.
+        if user.language.is_none() && user.viewed_by.is_none() {
+            let language = languages::detect(&*conn, &headers).await?;
+            languages::set(&conn, user.id, language).await?;
+            user.language = Some(language.to_string());
+        }
+
+        // Without a configured layout, users land on their home tags.
+        let layout = home::fetch_layout(&*conn).await?;
+        if layout.is_empty() {
+            return Ok(Redirect::to(&user.home_path()).into_response());
+        }
+
+        let sections: Vec<_> = stream::iter(&layout)
+            .then(|section| section.render(&conn))
+            .try_collect()
+            .await?;
+
+        Ok(HomePage {
+            offers: user.incoming_offers(&*conn).await?,
+            sections,
+        }
+        .into_response())
     }
 }
 
@@ -142,117 +456,542 @@ get! {
     "/t/*tags",
     async fn index(
         conn: Extension<PgPool>,
+        limits: Extension<Limits>,
+        stubs: Extension<ProfileStubs>,
         user: User,
         Path(viewed_tags): Path<String>,
-    ) -> Result<Index, Redirect> {
+    ) -> Result<Response, Redirect> {
+        // Atom feeds live beneath the tag path, which the wildcard swallows.
+        if let Some(feed_tags) = viewed_tags.strip_suffix("/feed.xml") {
+            let feed_tags = Tags::fetch_from_str(&conn, feed_tags).await;
+            return Ok(tag_feed(&conn, &stubs, &user, feed_tags)
+                .await
+                .into_response());
+        }
+
         let viewed_tags = Tags::fetch_from_str(&conn, &*viewed_tags).await;
 
         // If no tags are selected and the user is not privileged, force
-        // the user to redirect to /t/en
+        // the user to redirect to their home tags
         if viewed_tags.is_empty() && user.role < Role::Moderator {
-            return Err(Redirect::to("/t/en"));
+            return Err(Redirect::to(&user.home_path()));
         }
         let conn = &*conn;
         let user = &user;
 
-        let posts = sqlx::query_as(
+        let threads_per_page = limits.get(Limit::ThreadsPerPage) as i64;
+        let mut posts = ThreadSummary::fetch_tagged(
+            conn,
+            &viewed_tags,
+            user.show_mature,
+            threads_per_page,
+        )
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(i, thread)| {
+                // Format the date:
+                // TODO: Consider moving duration->plaintext into common utility
+                let now = Utc::now().naive_utc();
+                let duration_since_last_post = now - thread.last_activity.unwrap_or(now);
+                let duration_min = duration_since_last_post.num_minutes();
+                let duration_hours = duration_since_last_post.num_hours();
+                let duration_days = duration_since_last_post.num_days();
+                let duration_weeks = duration_since_last_post.num_weeks();
+                let duration_string: String = if duration_weeks > 0 {
+                    format!(
+                        "{} week{} ago",
+                        duration_weeks,
+                        if duration_weeks > 1 { "s" } else { "" }
+                    )
+                } else if duration_days > 0 {
+                    format!(
+                        "{} day{} ago",
+                        duration_days,
+                        if duration_days > 1 { "s" } else { "" }
+                    )
+                } else if duration_hours > 0 {
+                    format!(
+                        "{} hour{} ago",
+                        duration_hours,
+                        if duration_hours > 1 { "s" } else { "" }
+                    )
+                } else if duration_min >= 5 {
+                    format!(
+                        "{} minute{} ago",
+                        duration_min,
+                        if duration_min > 1 { "s" } else { "" }
+                    )
+                } else {
+                    String::from("just now!")
+                };
+
+                let replies = match thread.num_replies {
+                    0 => format!("No replies"),
+                    1 => format!("1 reply"),
+                    x => format!("{} replies", x),
+                };
+
+                ThreadLink {
+                    num: i + 1,
+                    id: thread.thread_id,
+                    title: thread.title,
+                    date: duration_string,
+                    emphasize_date: duration_min < MINUTES_TIMESTAMP_IS_EMPHASIZED,
+                    last_poster: thread.last_poster_name,
+                    read: false,
+                    jump_to: thread.last_post,
+                    unread: 0,
+                    replies,
+                    tags: thread.tag_names,
+                    pinned: thread.pinned,
+                    locked: thread.locked,
+                    hidden: thread.hidden,
+                    reactions: ReactionSummary::default(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let thread_ids = posts.iter().map(|post| post.id).collect::<Vec<_>>();
+        let mut summaries = ReactionSummary::fetch_all(conn, &thread_ids)
+            .await
+            .unwrap_or_default();
+        let mut reading_status = user
+            .reading_status(conn, &thread_ids)
+            .await
+            .unwrap_or_default();
+        for post in posts.iter_mut() {
+            post.reactions = summaries.remove(&post.id).unwrap_or_default();
+            if let Some(status) = reading_status.remove(&post.id) {
+                post.read = status.read;
+                post.jump_to = status.jump_to;
+                post.unread = status.unread;
+            }
+        }
+
+        Ok(Index {
+            tags: viewed_tags.tags,
+            posts: posts,
+            viewer_role: user.role,
+            offers: user.incoming_offers(&*conn).await.unwrap_or(0),
+            highlight: WeeklyHighlight::fetch(conn).await.unwrap_or_default(),
+        }
+        .into_response())
+    }
+}
+
+/// Maximum number of threads listed on the search page.
+const SEARCH_RESULTS: i64 = 50;
+
+#[derive(Template)]
+#[template(path = "search.html")]
+pub struct SearchPage {
+    offers:  i64,
+    query:   String,
+    results: Vec<ThreadSummary>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    #[serde(default)]
+    q: String,
+}
+
+get!(
+    "/search",
+    async fn search(
+        conn: Extension<PgPool>,
+        user: User,
+        Query(SearchParams { q }): Query<SearchParams>,
+    ) -> Result<SearchPage, ServerError> {
+        Ok(SearchPage {
+            offers:  user.incoming_offers(&conn).await?,
+            results: ThreadSummary::search(&conn, &q, user.show_mature, SEARCH_RESULTS).await?,
+            query:   q,
+        })
+    }
+);
+
+/// Everything needed to list a thread in the index, maintained by triggers in
+/// the `thread_summaries` table. Hidden replies are not counted, and are never
+/// the last post.
+#[derive(Debug, FromRow)]
+struct ThreadSummary {
+    thread_id:        i32,
+    title:            String,
+    tag_names:        Vec<String>,
+    last_post:        i32,
+    last_poster_name: Option<String>,
+    last_activity:    Option<NaiveDateTime>,
+    num_replies:      i32,
+    pinned:           bool,
+    locked:           bool,
+    hidden:           bool,
+}
+
+impl ThreadSummary {
+    /// Summaries of the threads tagged with every one of the given tags, in
+    /// the same order as `tagged_threads`.
+    async fn fetch_tagged(
+        conn: &PgPool,
+        tags: &Tags,
+        show_mature: bool,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
             r#"
-                SELECT * FROM threads
+                SELECT * FROM thread_summaries
                 WHERE
                     tags @> $1
+                    AND NOT archived
+                    AND ($3 OR NOT has_mature_tag(thread_summaries.tags))
                 ORDER BY
                     pinned DESC,
                     last_post DESC
                 LIMIT $2
             "#,
         )
-        .bind(viewed_tags.clone().into_ids().collect::<Vec<_>>())
-        .bind(THREADS_PER_PAGE)
-        .fetch(conn)
-        .filter_map(|t: Result<Thread, _>| future::ready(t.ok()))
-        .enumerate()
-        .then(move |(i, thread)| async move {
-            // Format the date:
-            // TODO: Consider moving duration->plaintext into common utility
-            let duration_since_last_post = Utc::now().naive_utc()
-                - Reply::fetch(&conn, thread.last_post)
-                    .await?
-                    .post_date;
-            let duration_min = duration_since_last_post.num_minutes();
-            let duration_hours = duration_since_last_post.num_hours();
-            let duration_days = duration_since_last_post.num_days();
-            let duration_weeks = duration_since_last_post.num_weeks();
-            let duration_string: String = if duration_weeks > 0 {
-                format!(
-                    "{} week{} ago",
-                    duration_weeks,
-                    if duration_weeks > 1 { "s" } else { "" }
-                )
-            } else if duration_days > 0 {
-                format!(
-                    "{} day{} ago",
-                    duration_days,
-                    if duration_days > 1 { "s" } else { "" }
-                )
-            } else if duration_hours > 0 {
-                format!(
-                    "{} hour{} ago",
-                    duration_hours,
-                    if duration_hours > 1 { "s" } else { "" }
-                )
-            } else if duration_min >= 5 {
-                format!(
-                    "{} minute{} ago",
-                    duration_min,
-                    if duration_min > 1 { "s" } else { "" }
-                )
-            } else {
-                String::from("just now!")
-            };
-
-            let replies = match thread.num_replies {
-                0 => format!("No replies"),
-                1 => format!("1 reply"),
-                x => format!("{} replies", x),
-            };
-
-            let read = user.has_read(conn, &thread).await?;
-            let jump_to = user.next_unread(conn, &thread).await?;
-
-            sqlx::Result::Ok(ThreadLink {
-                num: i + 1,
-                id: thread.id,
-                title: thread.title,
-                date: duration_string,
-                emphasize_date: duration_min < MINUTES_TIMESTAMP_IS_EMPHASIZED,
-                read,
-                jump_to,
-                replies,
-                tags: stream::iter(thread.tags.into_iter())
-                    .filter_map(
-                        |tid| async move { Tag::fetch_from_id(conn, tid).await.ok().flatten() },
-                    )
-                    .map(|t| t.name)
-                    .collect()
-                    .await,
-                pinned: thread.pinned,
-                locked: thread.locked,
-                hidden: thread.hidden,
+        .bind(tags.clone().into_ids().collect::<Vec<_>>())
+        .bind(limit)
+        .bind(show_mature)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Summaries of visible threads whose titles contain every word of the
+    /// query, most recently active first. Archived threads are included.
+    async fn search(
+        conn: &PgPool,
+        query: &str,
+        show_mature: bool,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let patterns = query
+            .split_whitespace()
+            .map(|word| {
+                let escaped = word
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                format!("%{escaped}%")
+            })
+            .collect::<Vec<_>>();
+        if patterns.is_empty() {
+            return Ok(Vec::new());
+        }
+        sqlx::query_as(
+            r#"
+                SELECT * FROM thread_summaries
+                WHERE
+                    NOT hidden
+                    AND title ILIKE ALL($1)
+                    AND ($3 OR NOT has_mature_tag(thread_summaries.tags))
+                ORDER BY last_post DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(patterns)
+        .bind(limit)
+        .bind(show_mature)
+        .fetch_all(conn)
+        .await
+    }
+}
+
+/// Threads tagged with every one of the given tags that have not been
+/// archived, pinned threads first and then by most recent activity.
+fn tagged_threads<'a>(
+    conn: &'a PgPool,
+    tags: &Tags,
+    show_mature: bool,
+    limit: i64,
+) -> BoxStream<'a, Result<Thread, sqlx::Error>> {
+    sqlx::query_as(
+        r#"
+            SELECT * FROM threads
+            WHERE
+                tags @> $1
+                AND NOT archived
+                AND ($3 OR NOT has_mature_tag(threads.tags))
+            ORDER BY
+                pinned DESC,
+                last_visible_post DESC
+            LIMIT $2
+        "#,
+    )
+    .bind(tags.clone().into_ids().collect::<Vec<_>>())
+    .bind(limit)
+    .bind(show_mature)
+    .fetch(conn)
+}
+
+/// Longest excerpt of a reply shown in the following feed.
+const FOLLOWING_EXCERPT_LENGTH: usize = 280;
+
+#[derive(Template)]
+#[template(path = "following.html")]
+pub struct FollowingPage {
+    offers:  i64,
+    entries: Vec<FollowedPost>,
+}
+
+pub struct FollowedPost {
+    reply_id:     i32,
+    thread_id:    i32,
+    thread_title: String,
+    author_id:    i32,
+    author:       String,
+    excerpt:      String,
+    date:         String,
+    /// Whether the reply started its thread
+    new_thread:   bool,
+}
+
+#[derive(FromRow)]
+struct FollowedReply {
+    reply_id:     i32,
+    thread_id:    i32,
+    thread_title: String,
+    author_id:    i32,
+    author:       String,
+    body:         String,
+    post_date:    NaiveDateTime,
+    new_thread:   bool,
+}
+
+get!(
+    "/feed",
+    async fn show_following_feed(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<FollowingPage, ServerError> {
+        let replies: Vec<FollowedReply> = sqlx::query_as(
+            r#"
+                SELECT
+                    replies.id AS reply_id,
+                    replies.thread_id,
+                    threads.title AS thread_title,
+                    replies.author_id,
+                    users.display_name AS author,
+                    replies.body,
+                    replies.post_date,
+                    NOT EXISTS (
+                        SELECT 1 FROM replies AS earlier
+                        WHERE earlier.thread_id = replies.thread_id AND earlier.id < replies.id
+                    ) AS new_thread
+                FROM user_follows
+                JOIN replies ON replies.author_id = user_follows.followee_id
+                JOIN threads ON threads.id = replies.thread_id
+                JOIN users ON users.id = replies.author_id
+                WHERE
+                    user_follows.follower_id = $1
+                    AND NOT replies.hidden
+                    AND NOT threads.hidden
+                    AND users.deleted IS NULL
+                    AND ($3 OR NOT has_mature_tag(threads.tags))
+                ORDER BY replies.id DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(user.id)
+        .bind(FEED_ENTRIES)
+        .bind(user.show_mature)
+        .fetch_all(&*conn)
+        .await?;
+
+        let entries = replies
+            .into_iter()
+            .map(|reply| FollowedPost {
+                excerpt:      if reply.body.chars().count() > FOLLOWING_EXCERPT_LENGTH {
+                    reply
+                        .body
+                        .chars()
+                        .take(FOLLOWING_EXCERPT_LENGTH)
+                        .chain(std::iter::once('…'))
+                        .collect()
+                } else {
+                    reply.body
+                },
+                reply_id:     reply.reply_id,
+                thread_id:    reply.thread_id,
+                thread_title: reply.thread_title,
+                author_id:    reply.author_id,
+                author:       reply.author,
+                date:         reply.post_date.format(crate::DATE_FMT).to_string(),
+                new_thread:   reply.new_thread,
             })
+            .collect();
+
+        Ok(FollowingPage {
+            offers: user.incoming_offers(&*conn).await?,
+            entries,
         })
-        .filter_map(|t| future::ready(t.ok()))
-        .collect()
-        .await;
+    }
+);
 
-        Ok(Index {
-            tags: viewed_tags.tags,
-            posts: posts,
-            viewer_role: user.role,
-            offers: user.incoming_offers(&*conn).await.unwrap_or(0),
+#[derive(Template)]
+#[template(path = "feed.xml")]
+pub struct Feed {
+    id:      String,
+    title:   String,
+    link:    String,
+    updated: String,
+    entries: Vec<FeedEntry>,
+}
+
+pub struct FeedEntry {
+    id:      String,
+    title:   String,
+    link:    String,
+    updated: String,
+    author:  String,
+    content: String,
+}
+
+fn feed_date(date: NaiveDateTime) -> String {
+    Utc.from_utc_datetime(&date).to_rfc3339()
+}
+
+/// Atom feed of the latest activity in the threads shown on an index page.
+async fn tag_feed(
+    conn: &PgPool,
+    stubs: &ProfileStubs,
+    user: &User,
+    tags: Tags,
+) -> Result<Feed, ServerError> {
+    if tags.is_empty() && user.role < Role::Moderator {
+        return Err(ServerError::NotFound);
+    }
+
+    let mut threads = Vec::new();
+    let mut tagged = tagged_threads(conn, &tags, user.show_mature, FEED_ENTRIES);
+    while let Some(thread) = tagged.try_next().await? {
+        if thread.hidden && user.role < Role::Moderator {
+            continue;
+        }
+        threads.push(thread);
+    }
+    // Give back the connection the stream holds before querying again.
+    drop(tagged);
+
+    let last_post_ids = threads
+        .iter()
+        .map(|thread| thread.last_visible_post)
+        .collect::<Vec<_>>();
+    let mut last_posts: HashMap<i32, Reply> =
+        sqlx::query_as::<_, Reply>("SELECT * FROM replies WHERE id = ANY($1)")
+            .bind(&last_post_ids)
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(|reply| (reply.id, reply))
+            .collect();
+    let posts = threads
+        .into_iter()
+        .filter_map(|thread| {
+            let last_post = last_posts.remove(&thread.last_visible_post)?;
+            Some((thread, last_post))
         })
+        .collect::<Vec<_>>();
+
+    let user_cache = UserCache::new(conn, stubs);
+    let author_ids = posts
+        .iter()
+        .map(|(_, last_post)| last_post.author_id)
+        .collect::<Vec<_>>();
+    user_cache.get_many(&author_ids).await?;
+    let mut entries = Vec::new();
+    for (thread, last_post) in posts {
+        entries.push(FeedEntry {
+            id:      format!("urn:marche:reply:{}", last_post.id),
+            title:   thread.title,
+            link:    format!("/reply/{}", last_post.id),
+            updated: feed_date(last_post.post_date),
+            author:  user_cache.get(last_post.author_id).await?.name.clone(),
+            content: last_post.body,
+        });
     }
+    // Pinned threads come first in the index, but not in a feed.
+    entries.sort_by(|a, b| b.updated.cmp(&a.updated));
+
+    let names = tags.into_names().collect::<Vec<_>>();
+    Ok(Feed {
+        id: format!("urn:marche:tags:{}", names.join("/")),
+        title: names.join(", "),
+        link: format!("/t/{}", names.join("/")),
+        updated: entries
+            .first()
+            .map(|entry| entry.updated.clone())
+            .unwrap_or_else(|| feed_date(Utc::now().naive_utc())),
+        entries,
+    })
 }
 
+get!(
+    "/thread/:thread_id/feed.xml",
+    async fn thread_feed(
+        conn: Extension<PgPool>,
+        stubs: Extension<ProfileStubs>,
+        user: User,
+        Path(thread_id): Path<i32>,
+    ) -> Result<Feed, ServerError> {
+        let thread = Thread::fetch_optional(&*conn, thread_id)
+            .await?
+            .ok_or(ServerError::NotFound)?;
+
+        if thread.hidden && user.role < Role::Moderator {
+            return Err(ServerError::NotFound);
+        }
+        if !user.show_mature && thread.is_mature(&*conn).await? {
+            return Err(ServerError::NotFound);
+        }
+
+        let conn = &*conn;
+        let user_cache = UserCache::new(conn, &stubs);
+        let replies = sqlx::query_as::<_, Reply>(
+            r#"
+                SELECT * FROM replies
+                WHERE thread_id = $1 AND (NOT hidden OR $2)
+                ORDER BY post_date DESC
+                LIMIT $3
+            "#,
+        )
+        .bind(thread_id)
+        .bind(user.role >= Role::Moderator)
+        .bind(FEED_ENTRIES)
+        .fetch_all(conn)
+        .await?;
+        let author_ids = replies
+            .iter()
+            .map(|reply| reply.author_id)
+            .collect::<Vec<_>>();
+        user_cache.get_many(&author_ids).await?;
+        let mut entries = Vec::new();
+        for reply in replies {
+            entries.push(FeedEntry {
+                id:      format!("urn:marche:reply:{}", reply.id),
+                title:   format!("Reply to {}", thread.title),
+                link:    format!("/reply/{}", reply.id),
+                updated: feed_date(reply.post_date),
+                author:  user_cache.get(reply.author_id).await?.name.clone(),
+                content: reply.body,
+            });
+        }
+
+        Ok(Feed {
+            id: format!("urn:marche:thread:{thread_id}"),
+            link: format!("/thread/{thread_id}"),
+            updated: entries
+                .first()
+                .map(|entry| entry.updated.clone())
+                .unwrap_or_else(|| feed_date(Utc::now().naive_utc())),
+            title: thread.title,
+            entries,
+        })
+    }
+);
+
 #[derive(Template)]
 #[template(path = "thread.html")]
 pub struct ThreadPage {
@@ -264,77 +1003,87 @@ pub struct ThreadPage {
     pinned:      bool,
     locked:      bool,
     hidden:      bool,
+    archived:    bool,
+    slow_mode:   i32,
+    can_pin:     bool,
     viewer_role: Role,
+    paginated:   bool,
+    page:        i64,
+    last_page:   i64,
+    offset:      usize,
+    reactions:   ReactionSummary,
+    top_reply:   Option<TopReactedReply>,
+    language:    String,
+}
+
+#[derive(Deserialize)]
+pub struct ThreadParams {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    page: Option<i64>,
 }
 
 get!(
     "/thread/:thread_id",
     async fn view_thread(
         conn: Extension<PgPool>,
+        limits: Extension<Limits>,
+        stubs: Extension<ProfileStubs>,
         user: User,
         Path(thread_id): Path<i32>,
-    ) -> Result<ThreadPage, ServerError> {
-        let thread = Thread::fetch_optional(&*conn, thread_id)
-            .await?
-            .ok_or(ServerError::NotFound)?;
+        Query(ThreadParams { page }): Query<ThreadParams>,
+    ) -> Result<Response, ServerError> {
+        let Some(thread) = Thread::fetch_optional(&*conn, thread_id).await? else {
+            let tombstone = ThreadTombstone::fetch_optional(&*conn, thread_id)
+                .await?
+                .ok_or(ServerError::NotFound)?;
+            return Ok((
+                StatusCode::GONE,
+                TombstonePage {
+                    offers:      user.incoming_offers(&conn).await?,
+                    title:       (!tombstone.hidden || user.role >= Role::Moderator)
+                        .then_some(tombstone.title),
+                    merged_into: tombstone.merged_into,
+                    removed:     tombstone.removed.format(crate::DATE_FMT).to_string(),
+                },
+            )
+                .into_response());
+        };
 
         user.read_thread(&conn, &thread).await?;
 
-        if thread.hidden && user.role == Role::User {
+        if thread.hidden && user.role < Role::Moderator {
+            return Err(ServerError::NotFound);
+        }
+        if !user.show_mature && thread.is_mature(&*conn).await? {
             return Err(ServerError::NotFound);
         }
 
+        // Without a page the entire thread is displayed.
+        let paginated = page.is_some();
+        let page = page.unwrap_or(1).max(1);
+        let last_page = (thread.num_replies as i64 / REPLIES_PER_PAGE) + 1;
+        let offset = if paginated {
+            (page - 1) * REPLIES_PER_PAGE
+        } else {
+            0
+        };
+
         let conn = &*conn;
-        let user_cache = UserCache::new(conn);
-        let posts =
-            sqlx::query_as("SELECT * FROM replies WHERE thread_id = $1 ORDER BY post_date ASC")
-                .bind(thread_id)
-                .fetch(conn)
-                .filter_map(|post| async move { post.ok() })
-                .then(move |post: Reply| {
-                    let user_cache = user_cache.clone();
-                    async move {
-                        let date = post.post_date.format(crate::DATE_FMT).to_string();
-                        let reactions = stream::iter(post.reactions.into_iter())
-                            .filter_map(|drop_id| async move {
-                                ItemDrop::fetch(conn, drop_id).await.ok()
-                            })
-                            .filter_map(|item_drop| async move {
-                                item_drop.get_thumbnail(conn).await.ok()
-                            })
-                            .collect()
-                            .await;
-                        let can_edit = post.author_id == user.id; // TODO: Add time limit for replies
-                        let can_react = post.author_id != user.id;
-                        let author = user_cache.get(post.author_id).await?;
-                        let reward = if let Some(reward) = post.reward {
-                            Some(
-                                ItemDrop::fetch(conn, reward)
-                                    .await?
-                                    .get_thumbnail(conn)
-                                    .await?,
-                            )
-                        } else {
-                            None
-                        };
-                        Result::<_, sqlx::Error>::Ok(Post {
-                            id: post.id,
-                            author,
-                            date,
-                            reactions,
-                            reward,
-                            can_edit,
-                            can_react,
-                            body: post.body,
-                            hidden: post.hidden,
-                            image: post.image,
-                            thumbnail: post.thumbnail,
-                            filename: post.filename,
-                        })
-                    }
-                })
-                .try_collect()
-                .await?;
+        let can_pin =
+            user.role >= Role::Moderator || Thread::is_author(conn, thread_id, user.id).await?;
+        let query = format!(
+            "SELECT * FROM replies WHERE thread_id = $1 ORDER BY {REPLY_ORDER} LIMIT $2 OFFSET $3"
+        );
+        let replies = sqlx::query_as(&query)
+            .bind(thread_id)
+            .bind(paginated.then_some(REPLIES_PER_PAGE))
+            .bind(offset)
+            .fetch_all(conn)
+            .await?;
+        let posts = PostLoader::new(conn, &stubs, &user, &limits)
+            .await?
+            .load(replies)
+            .await?;
 
         Ok(ThreadPage {
             id: thread_id,
@@ -347,8 +1096,117 @@ get!(
             pinned: thread.pinned,
             locked: thread.locked,
             hidden: thread.hidden,
+            archived: thread.archived,
+            slow_mode: thread.slow_mode_seconds,
+            can_pin,
             offers: user.incoming_offers(conn).await?,
             viewer_role: user.role,
+            paginated,
+            page,
+            last_page,
+            offset: offset as usize,
+            reactions: ReactionSummary::fetch_all(conn, &[thread_id])
+                .await?
+                .remove(&thread_id)
+                .unwrap_or_default(),
+            top_reply: TopReactedReply::fetch(conn, thread_id).await?,
+            language: user.language().to_string(),
+        }
+        .into_response())
+    }
+);
+
+/// Shown in place of a thread that has been deleted or merged.
+#[derive(Template)]
+#[template(path = "tombstone.html")]
+pub struct TombstonePage {
+    offers:      i64,
+    /// Title of the thread, unless it was hidden from the viewer
+    title:       Option<String>,
+    merged_into: Option<i32>,
+    removed:     String,
+}
+
+get!(
+    "/reply/:post_id",
+    async fn reply_permalink(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(reply_id): Path<i32>,
+    ) -> Result<Redirect, ServerError> {
+        let reply = Reply::fetch_optional(&*conn, reply_id)
+            .await?
+            .ok_or(ServerError::NotFound)?;
+        let thread = Thread::fetch_optional(&*conn, reply.thread_id)
+            .await?
+            .ok_or(ServerError::NotFound)?;
+
+        if (thread.hidden || reply.hidden) && user.role < Role::Moderator {
+            return Err(ServerError::NotFound);
+        }
+        if !user.show_mature && thread.is_mature(&*conn).await? {
+            return Err(ServerError::NotFound);
+        }
+
+        let query = format!(
+            r#"
+            SELECT position FROM (
+                SELECT id, ROW_NUMBER() OVER (ORDER BY {REPLY_ORDER}) - 1 AS position
+                FROM replies
+                WHERE thread_id = $1
+            ) AS ordered
+            WHERE id = $2
+            "#
+        );
+        let position: i64 = sqlx::query(&query)
+            .bind(thread.id)
+            .bind(reply.id)
+            .fetch_one(&*conn)
+            .await?
+            .get(0);
+        let page = position / REPLIES_PER_PAGE + 1;
+
+        Ok(Redirect::to(&format!(
+            "/thread/{}?page={page}#reply-{reply_id}",
+            thread.id
+        )))
+    }
+);
+
+/// Revision history of a reply, with what each edit changed.
+#[derive(Template)]
+#[template(path = "revisions.html")]
+pub struct RevisionsPage {
+    offers:     i64,
+    reply_id:   i32,
+    /// Revisions, newest first
+    revisions:  Vec<RevisionView>,
+    can_revert: bool,
+}
+
+get!(
+    "/reply/:post_id/history",
+    async fn reply_history(
+        conn: Extension<PgPool>,
+        limits: Extension<Limits>,
+        user: User,
+        Path(reply_id): Path<i32>,
+    ) -> Result<RevisionsPage, ServerError> {
+        let reply = Reply::fetch_optional(&*conn, reply_id)
+            .await?
+            .ok_or(ServerError::NotFound)?;
+        if reply.hidden && user.role < Role::Moderator {
+            return Err(ServerError::NotFound);
+        }
+        if !user.show_mature && reply.is_mature(&*conn).await? {
+            return Err(ServerError::NotFound);
+        }
+
+        Ok(RevisionsPage {
+            offers: user.incoming_offers(&conn).await?,
+            reply_id,
+            revisions: RevisionView::fetch_for_reply(&conn, reply_id).await?,
+            can_revert: reply.editable_by(&conn, &user, &limits).await?,
         })
     }
 );
@@ -356,14 +1214,44 @@ get!(
 #[derive(Template, Debug)]
 #[template(path = "author.html")]
 pub struct AuthorPage {
-    offers: i64,
+    offers:      i64,
+    title:       String,
+    tags:        String,
+    body:        String,
+    templates:   Vec<ThreadTemplate>,
+    viewer_role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorParams {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    template: Option<i32>,
 }
 
 get!(
     "/author",
-    async fn author_page(conn: Extension<PgPool>, user: User) -> Result<AuthorPage, ServerError> {
+    async fn author_page(
+        conn: Extension<PgPool>,
+        user: User,
+        Query(AuthorParams { template }): Query<AuthorParams>,
+    ) -> Result<AuthorPage, ServerError> {
+        let (title, tags, body) = match template {
+            Some(template) => {
+                let template = ThreadTemplate::fetch_optional(&*conn, template)
+                    .await?
+                    .ok_or(ServerError::NotFound)?;
+                (template.title, template.tags, template.body)
+            }
+            None => (String::new(), String::from("en, "), String::new()),
+        };
+
         Ok(AuthorPage {
             offers: user.incoming_offers(&*conn).await?,
+            title,
+            tags,
+            body,
+            templates: ThreadTemplate::fetch_all(&*conn).await?,
+            viewer_role: user.role,
         })
     }
 );
@@ -381,6 +1269,7 @@ pub struct ItemPage {
     owner_id:     i32,
     owner_name:   String,
     offers:       i64,
+    language:     String,
 }
 
 pub enum AvailableEquipAction {
@@ -421,6 +1310,7 @@ get!(
             owner_id: owner.id,
             owner_name: owner.name.to_string(),
             offers: user.incoming_offers(&*conn).await?,
+            language: user.language().to_string(),
         })
     }
 );
@@ -511,6 +1401,206 @@ get! {
     }
 }
 
+#[derive(Template)]
+#[template(path = "sessions.html")]
+pub struct SessionsPage {
+    offers:   i64,
+    sessions: Vec<SessionInfo>,
+}
+
+pub struct SessionInfo {
+    id:      i32,
+    ip_addr: String,
+    started: String,
+    current: bool,
+}
+
+get!(
+    "/sessions",
+    async fn show_sessions(
+        conn: Extension<PgPool>,
+        user: User,
+        current: LoginSession,
+    ) -> Result<SessionsPage, ServerError> {
+        let sessions = LoginSession::fetch_all(&conn, user.id)
+            .await?
+            .into_iter()
+            .map(|session| SessionInfo {
+                id:      session.id,
+                ip_addr: session.ip_addr.ip().to_string(),
+                started: session.session_start.format(crate::DATE_FMT).to_string(),
+                current: session.id == current.id,
+            })
+            .collect();
+        Ok(SessionsPage {
+            offers: user.incoming_offers(&conn).await?,
+            sessions,
+        })
+    }
+);
+
+#[derive(Template)]
+#[template(path = "security.html")]
+pub struct SecurityPage {
+    offers:         i64,
+    logins:         Vec<LoginInfo>,
+    recovery_codes: i64,
+    api_tokens:     Vec<ApiToken>,
+    token_scopes:   &'static [TokenScope],
+}
+
+pub struct LoginInfo {
+    ip_addr:    String,
+    user_agent: String,
+    country:    Option<String>,
+    logged_in:  String,
+    new_range:  bool,
+}
+
+get!(
+    "/profile/security",
+    async fn show_security(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<SecurityPage, ServerError> {
+        let logins = LoginAudit::fetch_recent(&*conn, user.id)
+            .await?
+            .into_iter()
+            .map(|login| LoginInfo {
+                ip_addr:    login.ip_addr.ip().to_string(),
+                user_agent: login.user_agent,
+                country:    login.country,
+                logged_in:  login.logged_in.format(crate::DATE_FMT).to_string(),
+                new_range:  login.new_range,
+            })
+            .collect();
+        LoginAudit::mark_alerts_seen(&*conn, user.id).await?;
+        Ok(SecurityPage {
+            offers: user.incoming_offers(&conn).await?,
+            logins,
+            recovery_codes: recovery_codes::remaining(&*conn, user.id).await?,
+            api_tokens: ApiToken::fetch_for(&*conn, user.id).await?,
+            token_scopes: TokenScope::ALL,
+        })
+    }
+);
+
+#[derive(Template)]
+#[template(path = "inbox.html")]
+pub struct InboxPage {
+    offers:        i64,
+    conversations: Vec<ConversationLink>,
+}
+
+pub struct ConversationLink {
+    other_id:     i32,
+    other_name:   String,
+    last_body:    String,
+    last_message: String,
+    unread:       i64,
+}
+
+get!(
+    "/inbox",
+    async fn show_inbox(conn: Extension<PgPool>, user: User) -> Result<InboxPage, ServerError> {
+        let conversations = ConversationSummary::fetch_for(&*conn, user.id)
+            .await?
+            .into_iter()
+            .map(|conversation| ConversationLink {
+                other_id:     conversation.other_id,
+                other_name:   conversation.other_name,
+                last_body:    conversation.last_body,
+                last_message: conversation
+                    .last_message
+                    .format(crate::DATE_FMT)
+                    .to_string(),
+                unread:       conversation.unread,
+            })
+            .collect();
+        Ok(InboxPage {
+            offers: user.incoming_offers(&conn).await?,
+            conversations,
+        })
+    }
+);
+
+#[derive(Template)]
+#[template(path = "conversation.html")]
+pub struct ConversationPage {
+    offers:   i64,
+    other:    ProfileStub,
+    messages: Vec<MessageView>,
+}
+
+pub struct MessageView {
+    mine: bool,
+    body: String,
+    sent: String,
+}
+
+get!(
+    "/inbox/:user_id",
+    async fn show_conversation(
+        conn: Extension<PgPool>,
+        notifications: Extension<Notifications>,
+        user: User,
+        Path(other_id): Path<i32>,
+    ) -> Result<ConversationPage, ServerError> {
+        let other = User::fetch_optional(&*conn, other_id)
+            .await?
+            .filter(|other| other.id != user.id)
+            .ok_or(ServerError::NotFound)?;
+
+        let messages = Message::fetch_between(&*conn, user.id, other.id)
+            .await?
+            .into_iter()
+            .map(|message| MessageView {
+                mine: message.sender_id == user.id,
+                body: message.body,
+                sent: message.sent.format(crate::DATE_FMT).to_string(),
+            })
+            .collect();
+        if user.viewed_by.is_none() && Message::mark_read(&*conn, user.id, other.id).await? {
+            notifications.messages_changed(&*conn, user.id).await?;
+        }
+
+        Ok(ConversationPage {
+            offers: user.incoming_offers(&conn).await?,
+            other: other.get_profile_stub(&*conn).await?,
+            messages,
+        })
+    }
+);
+
+#[derive(Template)]
+#[template(path = "reset.html")]
+pub struct ResetPasswordPage {
+    offers:   usize,
+    username: String,
+    secret:   String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordParams {
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    secret:   String,
+}
+
+get! {
+    "/reset",
+    async fn reset_password_page(
+        Query(ResetPasswordParams { username, secret }): Query<ResetPasswordParams>,
+    ) -> ResetPasswordPage {
+        ResetPasswordPage {
+            offers: 0,
+            username,
+            secret,
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "update_bio.html")]
 pub struct UpdateBioPage {
@@ -535,19 +1625,53 @@ get! {
 #[derive(Template)]
 #[template(path = "profile.html")]
 pub struct ProfilePage {
-    bio:           String,
-    level:         LevelInfo,
-    role:          Role,
-    stub:          ProfileStub,
-    equipped:      Vec<ItemThumbnail>,
-    inventory:     Vec<ItemThumbnail>,
-    is_banned:     bool,
-    is_curr_user:  bool,
-    ban_timestamp: String,
-    viewer_role:   Role,
-    viewer_name:   String,
-    offers:        i64,
-    notes:         String,
+    bio:                String,
+    pronouns:           String,
+    location:           String,
+    website:            String,
+    level:              LevelInfo,
+    role:               Role,
+    stub:               ProfileStub,
+    equipped:           Vec<ItemThumbnail>,
+    inventory:          Vec<ItemThumbnail>,
+    inventory_value:    ValueBand,
+    is_banned:          bool,
+    is_curr_user:       bool,
+    /// Whether the viewer has blocked the user
+    is_blocked:         bool,
+    is_following:       bool,
+    ban_timestamp:      String,
+    viewer_role:        Role,
+    viewer_name:        String,
+    offers:             i64,
+    notes:              String,
+    home_tags:          String,
+    negative_reactions: bool,
+    accepts_gifts:      bool,
+    email_digest:       bool,
+    show_mature:        bool,
+    /// Currency the viewer has to spend
+    balance:            i64,
+    notifications:      NotificationSettings,
+    language:           String,
+    languages:          Vec<Language>,
+    /// Previous names of the user, only shown to moderators
+    name_history:       Vec<PastName>,
+    achievements:       Vec<AchievementView>,
+}
+
+pub struct AchievementView {
+    title:       &'static str,
+    description: &'static str,
+    badge:       &'static str,
+    /// When the user earned it, if they have
+    awarded:     Option<String>,
+}
+
+pub struct PastName {
+    old_name: String,
+    new_name: String,
+    changed:  String,
 }
 
 mod filters {
@@ -583,42 +1707,82 @@ get!(
             .await?
             .ok_or(ServerError::NotFound)?;
 
-        let equipped = user.equipped(&*conn).await?;
-
-        let mut is_equipped = HashSet::new();
-        for (_, item_drop) in &equipped {
-            is_equipped.insert(item_drop.id);
-        }
-        let inventory: Vec<_> = user
-            .inventory(&*conn)
-            .await?
-            .into_iter()
-            .filter(|(_, item_drop)| !is_equipped.contains(&item_drop.id))
-            .map(|(item, item_drop)| ItemThumbnail::new(&item, &item_drop))
-            .collect();
+        let ProfileBundle {
+            stub,
+            equipped,
+            inventory,
+        } = user.fetch_profile_bundle(&*conn).await?;
 
         let ban_timestamp = user
             .banned_until
             .map(|x| x.format(crate::DATE_FMT).to_string())
             .unwrap_or_else(String::new);
 
+        let awarded = AwardedAchievement::fetch_for(&*conn, user.id).await?;
+        let achievements = Achievement::ALL
+            .iter()
+            .map(|&achievement| AchievementView {
+                title:       achievement.title(),
+                description: achievement.description(),
+                badge:       achievement.badge(),
+                awarded:     awarded
+                    .iter()
+                    .find(|awarded| awarded.achievement == achievement)
+                    .map(|awarded| awarded.awarded.format(crate::DATE_FMT).to_string()),
+            })
+            .collect();
+
+        let name_history = if curr_user.role >= Role::Moderator {
+            NameChange::fetch_for(&*conn, user.id)
+                .await?
+                .into_iter()
+                .map(|change| PastName {
+                    old_name: change.old_display_name,
+                    new_name: change.new_display_name,
+                    changed:  change.changed.format(crate::DATE_FMT).to_string(),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         Ok(ProfilePage {
+            inventory_value: ValueBand::of_inventory(&*conn, user.id).await?,
             is_banned: user.is_banned(),
             ban_timestamp,
             offers: curr_user.incoming_offers(&*conn).await?,
-            stub: user.get_profile_stub(&*conn).await?,
+            stub,
             level: user.level_info(),
             bio: user.bio,
+            pronouns: user.pronouns,
+            location: user.location,
+            website: user.website,
             role: user.role,
             equipped: equipped
-                .into_iter()
-                .map(|(item, item_drop)| ItemThumbnail::new(&item, &item_drop))
+                .iter()
+                .map(|(item, item_drop)| ItemThumbnail::new(item, item_drop))
+                .collect(),
+            inventory: inventory
+                .iter()
+                .map(|(item, item_drop)| ItemThumbnail::new(item, item_drop))
                 .collect(),
-            inventory,
             is_curr_user: user.id == curr_user.id,
+            is_blocked: curr_user.has_blocked(&*conn, user.id).await?,
+            is_following: curr_user.is_following(&*conn, user.id).await?,
             notes: user.notes,
             viewer_role: curr_user.role,
+            language: curr_user.language().to_string(),
             viewer_name: curr_user.name,
+            home_tags: curr_user.home_tags,
+            negative_reactions: curr_user.negative_reactions,
+            accepts_gifts: curr_user.accepts_gifts,
+            email_digest: curr_user.email_digest,
+            show_mature: curr_user.show_mature,
+            balance: curr_user.balance,
+            notifications: curr_user.notification_settings.0.clone(),
+            languages: languages::available(&*conn).await?,
+            name_history,
+            achievements,
         })
     }
 );
@@ -626,42 +1790,80 @@ get!(
 #[derive(Template)]
 #[template(path = "leaderboard.html")]
 pub struct LeaderboardPage {
-    offers: i64,
-    users:  Vec<UserRank>,
+    offers:    i64,
+    users:     Vec<UserRank>,
+    refreshed: Option<String>,
+    period:    LeaderboardPeriod,
+    page:      i64,
+    last_page: i64,
 }
 
 struct UserRank {
-    rank: usize,
-    bio:  String,
-    stub: ProfileStub,
+    rank:       i64,
+    experience: i64,
+    bio:        String,
+    stub:       Arc<ProfileStub>,
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardParams {
+    #[serde(default)]
+    period: LeaderboardPeriod,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    page:   Option<i64>,
 }
 
 get!(
     "/leaderboard",
     async fn show_leaderboard(
         conn: Extension<PgPool>,
+        stubs: Extension<ProfileStubs>,
         user: User,
+        Query(LeaderboardParams { period, page }): Query<LeaderboardParams>,
     ) -> Result<LeaderboardPage, ServerError> {
         let conn = &*conn;
-        let user_profiles =
-            sqlx::query_as("SELECT * FROM users ORDER BY experience DESC LIMIT 100")
-                .fetch(conn)
-                .enumerate()
-                .filter_map(|(i, t): (_, Result<User, _>)| future::ready(t.ok().map(|t| (i, t))))
-                .then(|(i, u)| async move {
-                    sqlx::Result::Ok(UserRank {
-                        rank: i + 1,
-                        bio:  u.bio.clone(),
-                        stub: u.get_profile_stub(conn).await?,
-                    })
+        // Standings are computed when the views are refreshed, not here.
+        let (standings, last_page) = Standing::fetch_page(conn, period, page.unwrap_or(1)).await?;
+        let page = page.unwrap_or(1).clamp(1, last_page);
+
+        let user_ids = standings
+            .iter()
+            .map(|standing| standing.user_id)
+            .collect::<Vec<_>>();
+        let mut stubs: HashMap<i32, Arc<ProfileStub>> = UserCache::new(conn, &stubs)
+            .get_many(&user_ids)
+            .await?
+            .into_iter()
+            .map(|stub| (stub.id, stub))
+            .collect();
+        let mut bios: HashMap<i32, String> =
+            sqlx::query_as("SELECT id, bio FROM users WHERE id = ANY($1)")
+                .bind(&user_ids)
+                .fetch_all(conn)
+                .await?
+                .into_iter()
+                .collect();
+        let users = standings
+            .into_iter()
+            .filter_map(|standing| {
+                Some(UserRank {
+                    rank:       standing.rank,
+                    experience: standing.experience,
+                    bio:        bios.remove(&standing.user_id)?,
+                    stub:       stubs.remove(&standing.user_id)?,
                 })
-                .filter_map(|t| future::ready(t.ok()))
-                .collect()
-                .await;
+            })
+            .collect();
 
         Ok(LeaderboardPage {
-            users:  user_profiles,
+            users,
             offers: user.incoming_offers(conn).await?,
+            refreshed: stats::last_refreshed(conn, period.view())
+                .await?
+                .map(|refreshed| refreshed.format(crate::DATE_FMT).to_string()),
+            period,
+            page,
+            last_page,
         })
     }
 );
@@ -671,9 +1873,12 @@ get!(
 pub struct TradeRequestPage {
     sender:             ProfileStub,
     sender_inventory:   Vec<ItemThumbnail>,
+    sender_value:       ValueBand,
     receiver:           ProfileStub,
     receiver_inventory: Vec<ItemThumbnail>,
+    receiver_value:     ValueBand,
     offers:             i64,
+    language:           String,
 }
 
 get!(
@@ -687,20 +1892,26 @@ get!(
             .await?
             .ok_or(ServerError::NotFound)?;
 
+        let sender_inventory: Vec<_> = sender
+            .inventory(&*conn)
+            .await?
+            .map(|(i, d)| ItemThumbnail::new(&i, &d))
+            .collect();
+        let receiver_inventory: Vec<_> = receiver
+            .inventory(&*conn)
+            .await?
+            .map(|(i, d)| ItemThumbnail::new(&i, &d))
+            .collect();
+
         Ok(TradeRequestPage {
-            sender:             sender.get_profile_stub(&*conn).await?,
-            sender_inventory:   sender
-                .inventory(&*conn)
-                .await?
-                .map(|(i, d)| ItemThumbnail::new(&i, &d))
-                .collect(),
-            receiver:           receiver.get_profile_stub(&*conn).await?,
-            receiver_inventory: receiver
-                .inventory(&*conn)
-                .await?
-                .map(|(i, d)| ItemThumbnail::new(&i, &d))
-                .collect(),
-            offers:             sender.incoming_offers(&*conn).await?,
+            sender: sender.get_profile_stub(&*conn).await?,
+            sender_value: ValueBand::of_thumbnails(&sender_inventory),
+            sender_inventory,
+            receiver: receiver.get_profile_stub(&*conn).await?,
+            receiver_value: ValueBand::of_thumbnails(&receiver_inventory),
+            receiver_inventory,
+            offers: sender.incoming_offers(&*conn).await?,
+            language: sender.language().to_string(),
         })
     }
 );
@@ -712,15 +1923,17 @@ pub struct TradeRequestsPage {
     incoming_offers: Vec<IncomingOffer>,
     outgoing_offers: Vec<OutgoingOffer>,
     offers:          i64,
+    language:        String,
 }
 
 get!(
     "/offers",
     async fn show_offers(
         conn: Extension<PgPool>,
+        stubs: Extension<ProfileStubs>,
         user: User,
     ) -> Result<TradeRequestsPage, ServerError> {
-        let user_cache = UserCache::new(&*conn);
+        let user_cache = UserCache::new(&*conn, &stubs);
         let incoming_offers = IncomingOffer::retrieve(&*conn, &user_cache, &user).await;
         let outgoing_offers = OutgoingOffer::retrieve(&*conn, &user_cache, &user).await;
 
@@ -729,6 +1942,107 @@ get!(
             offers: user.incoming_offers(&*conn).await?,
             incoming_offers,
             outgoing_offers,
+            language: user.language().to_string(),
         })
     }
 );
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfferResponse {
+    Accept,
+    Decline,
+}
+
+#[derive(Deserialize)]
+pub struct RespondToOfferParams {
+    action: OfferResponse,
+}
+
+#[derive(Template)]
+#[template(path = "respond_to_offer.html")]
+pub struct RespondToOfferPage {
+    trade_id: i32,
+    sender:   ProfileStub,
+    accept:   bool,
+    token:    String,
+    offers:   i64,
+}
+
+get!(
+    "/offers/:trade_id/respond",
+    async fn confirm_offer_response(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(trade_id): Path<i32>,
+        Query(RespondToOfferParams { action }): Query<RespondToOfferParams>,
+    ) -> Result<Response, ServerError> {
+        if user.viewed_by.is_some() {
+            return Err(ServerError::Unauthorized);
+        }
+        let offer = TradeRequest::fetch(&*conn, trade_id)
+            .await?
+            .filter(|offer| offer.receiver_id == user.id)
+            .ok_or(ServerError::NotFound)?;
+
+        // Lopsided offers must be confirmed on the offers page.
+        let accept = matches!(action, OfferResponse::Accept);
+        if accept && offer.lopsided {
+            return Ok(Redirect::to(&format!("/offers?jump_to={trade_id}")).into_response());
+        }
+
+        let sender = User::fetch(&*conn, offer.sender_id).await?;
+        Ok(RespondToOfferPage {
+            trade_id,
+            sender: sender.get_profile_stub(&*conn).await?,
+            accept,
+            // The token is only shown on this page, so a response cannot be
+            // forged by another site.
+            token: offer.issue_response_token(&*conn).await?,
+            offers: user.incoming_offers(&*conn).await?,
+        }
+        .into_response())
+    }
+);
+
+#[derive(Deserialize)]
+pub struct RespondToOfferForm {
+    action: OfferResponse,
+    token:  String,
+}
+
+post!(
+    "/offers/:trade_id/respond",
+    async fn respond_to_offer(
+        conn: Extension<PgPool>,
+        notifications: Extension<Notifications>,
+        user: User,
+        Path(trade_id): Path<i32>,
+        Form(RespondToOfferForm { action, token }): Form<RespondToOfferForm>,
+    ) -> Result<Redirect, ServerError> {
+        if user.viewed_by.is_some() {
+            return Err(ServerError::Unauthorized);
+        }
+        let offer = TradeRequest::fetch(&*conn, trade_id)
+            .await?
+            .filter(|offer| offer.receiver_id == user.id && offer.check_response_token(&token))
+            .ok_or(ServerError::NotFound)?;
+        if matches!(action, OfferResponse::Accept) && offer.lopsided {
+            return Ok(Redirect::to(&format!("/offers?jump_to={trade_id}")));
+        }
+
+        let result = match action {
+            OfferResponse::Accept => offer.accept(&*conn).await,
+            OfferResponse::Decline => offer.decline(&*conn).await,
+        };
+        match result {
+            Ok(()) => (),
+            Err(TradeResponseError::InternalDbError(err)) => return Err(err.into()),
+            // The offer is left on the offers page for the user to look at.
+            Err(err) => tracing::info!("Could not respond to offer {trade_id}: {err}"),
+        }
+        notifications.offers_changed(&*conn, user.id).await?;
+
+        Ok(Redirect::to("/offers"))
+    }
+);
@@ -0,0 +1,88 @@
+//! Hashing of passwords and reset codes. Hashes record the scheme and the
+//! parameters they were made with, so when the policy changes, old hashes are
+//! still verified and are replaced with current ones when a user logs in.
+use lazy_static::lazy_static;
+use libpasta::{primitives::Argon2, Config, HashUpdate};
+
+/// Work factors used for new hashes, read from the environment.
+pub struct PasswordPolicy {
+    /// Number of passes over memory. Set with `PASSWORD_ARGON2_PASSES`.
+    pub passes:     u32,
+    /// Degree of parallelism. Set with `PASSWORD_ARGON2_LANES`.
+    pub lanes:      u32,
+    /// Memory used, in KiB. Set with `PASSWORD_ARGON2_MEMORY_KIB`.
+    pub memory_kib: u32,
+}
+
+impl PasswordPolicy {
+    fn from_env() -> Self {
+        fn var(name: &str, default: u32) -> u32 {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&value| value > 0)
+                .unwrap_or(default)
+        }
+
+        Self {
+            passes:     var("PASSWORD_ARGON2_PASSES", 2),
+            lanes:      var("PASSWORD_ARGON2_LANES", 1),
+            memory_kib: var("PASSWORD_ARGON2_MEMORY_KIB", 19 * 1024),
+        }
+    }
+
+    fn config(&self) -> Config {
+        Config::with_primitive(Argon2::new(self.passes, self.lanes, self.memory_kib))
+    }
+}
+
+lazy_static! {
+    pub static ref PASSWORD_POLICY: PasswordPolicy = PasswordPolicy::from_env();
+    static ref CONFIG: Config = PASSWORD_POLICY.config();
+}
+
+/// Outcome of checking a password against its hash.
+pub enum PasswordCheck {
+    Incorrect,
+    Correct,
+    /// The password is correct, but the hash does not follow the current
+    /// policy. Contains a new hash that should replace it.
+    Outdated(String),
+}
+
+pub fn hash(password: &str) -> String {
+    CONFIG.hash_password(password)
+}
+
+pub fn verify(hash: &str, password: &str) -> bool {
+    CONFIG.verify_password(hash, password)
+}
+
+/// Check a password, and hash it again if its hash is outdated.
+pub fn check(hash: &str, password: &str) -> PasswordCheck {
+    match CONFIG.verify_password_update_hash(hash, password) {
+        HashUpdate::Failed => PasswordCheck::Incorrect,
+        HashUpdate::Verified(None) => PasswordCheck::Correct,
+        HashUpdate::Verified(Some(new_hash)) => PasswordCheck::Outdated(new_hash),
+    }
+}
+
+/// `hash` on the blocking thread pool, as Argon2 takes long enough to hold up
+/// every other task on the worker it runs on.
+pub async fn spawn_hash(password: &str) -> String {
+    let password = password.to_string();
+    spawn(move || hash(&password)).await
+}
+
+/// `verify` on the blocking thread pool.
+pub async fn spawn_verify(hash: &str, password: &str) -> bool {
+    let (hash, password) = (hash.to_string(), password.to_string());
+    spawn(move || verify(&hash, &password)).await
+}
+
+async fn spawn<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    // A panic while hashing is passed on as if it had happened on this task.
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
@@ -0,0 +1,233 @@
+//! Terms of service and privacy policy. Admins publish new versions of each,
+//! and users are sent to `/policies` to accept them before they can use the
+//! site again. Acceptance is tracked as the id of the newest policy a user
+//! has accepted, so checking it on every request is a single indexed lookup.
+use askama::Template;
+use axum::{
+    extract::{Extension, Form, Query},
+    response::Redirect,
+};
+use chrono::{NaiveDateTime, Utc};
+use derive_more::Display;
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool, Type};
+use thiserror::Error;
+
+use crate::{
+    get,
+    invalidation::InvalidationBus,
+    pages::ServerError,
+    post,
+    users::{Role, User},
+};
+
+/// Paths that can be visited without having accepted the current policies.
+const EXEMPT_PATHS: &[&str] = &["/policies", "/logout"];
+
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "policy_kind")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyKind {
+    #[display(fmt = "Terms of service")]
+    Terms,
+    #[display(fmt = "Privacy policy")]
+    Privacy,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct Policy {
+    pub id:           i32,
+    pub kind:         PolicyKind,
+    pub version:      i32,
+    pub body:         String,
+    pub published_by: i32,
+    pub published:    NaiveDateTime,
+}
+
+impl Policy {
+    /// The newest version of each policy.
+    pub async fn fetch_current(conn: impl PgExecutor<'_>) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT DISTINCT ON (kind) * FROM policies
+                ORDER BY kind ASC, version DESC
+            "#,
+        )
+        .fetch_all(conn)
+        .await
+    }
+}
+
+/// Whether a policy has been published since the user last accepted them.
+pub async fn is_outdated(conn: impl PgExecutor<'_>, user: &User) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM policies WHERE id > $1)")
+        .bind(user.policies_accepted)
+        .fetch_one(conn)
+        .await
+}
+
+/// Whether a path can be visited without having accepted the current
+/// policies.
+pub fn is_exempt(path: &str) -> bool {
+    EXEMPT_PATHS.iter().any(|exempt| {
+        path.strip_prefix(exempt)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+#[derive(Template)]
+#[template(path = "policies.html")]
+pub struct PoliciesPage {
+    offers:   i64,
+    policies: Vec<Policy>,
+    /// Id of the newest policy shown, accepted along with the others
+    latest:   i32,
+    /// Whether the viewer is logged in and has yet to accept the policies
+    outdated: bool,
+    redirect: String,
+}
+
+#[derive(Deserialize)]
+pub struct PoliciesParams {
+    #[serde(default)]
+    redirect: String,
+}
+
+get!(
+    "/policies",
+    async fn show_policies(
+        conn: Extension<PgPool>,
+        user: Option<User>,
+        Query(PoliciesParams { redirect }): Query<PoliciesParams>,
+    ) -> Result<PoliciesPage, ServerError> {
+        let policies = Policy::fetch_current(&*conn).await?;
+        let latest = policies.iter().map(|policy| policy.id).max().unwrap_or(0);
+        let (offers, outdated) = match user {
+            Some(ref user) => (
+                user.incoming_offers(&conn).await?,
+                user.policies_accepted < latest,
+            ),
+            None => (0, false),
+        };
+        Ok(PoliciesPage {
+            offers,
+            policies,
+            latest,
+            outdated,
+            redirect,
+        })
+    }
+);
+
+#[derive(Deserialize)]
+pub struct AcceptPoliciesForm {
+    latest:   i32,
+    #[serde(default)]
+    redirect: String,
+}
+
+post!(
+    "/policies/accept",
+    async fn accept_policies(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(AcceptPoliciesForm { latest, redirect }): Form<AcceptPoliciesForm>,
+    ) -> Result<Redirect, ServerError> {
+        // Only policies that exist can be accepted, so that accepting does
+        // not also accept policies published later.
+        let accepted = sqlx::query(
+            r#"
+                UPDATE users
+                SET policies_accepted = LEAST($1, (SELECT COALESCE(MAX(id), 0) FROM policies))
+                WHERE id = $2 AND policies_accepted < $1
+            "#,
+        )
+        .bind(latest)
+        .bind(user.id)
+        .execute(&*conn)
+        .await?
+        .rows_affected();
+        if accepted > 0 {
+            InvalidationBus::user_updated(&*conn, user.id).await?;
+        }
+
+        // Only redirect within the site.
+        if redirect.starts_with('/') && !redirect.starts_with("//") {
+            Ok(Redirect::to(&redirect))
+        } else {
+            Ok(Redirect::to("/"))
+        }
+    }
+);
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum PoliciesError {
+    #[error("You are not authorized to do that")]
+    Unauthorized,
+    #[error("A policy cannot be empty")]
+    EmptyPolicy,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/admin/policies",
+    #[json]
+    async fn admin_policies(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<Vec<Policy>, PoliciesError> {
+        if user.role < Role::Admin {
+            return Err(PoliciesError::Unauthorized);
+        }
+        Ok(sqlx::query_as("SELECT * FROM policies ORDER BY id DESC")
+            .fetch_all(&*conn)
+            .await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct PublishPolicyForm {
+    kind: PolicyKind,
+    body: String,
+}
+
+post!(
+    "/admin/policies",
+    #[json]
+    async fn publish_policy(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(PublishPolicyForm { kind, body }): Form<PublishPolicyForm>,
+    ) -> Result<Policy, PoliciesError> {
+        if user.role < Role::Admin {
+            return Err(PoliciesError::Unauthorized);
+        }
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(PoliciesError::EmptyPolicy);
+        }
+        // Two versions published at once cannot share a number, so one of
+        // them fails rather than both being published.
+        Ok(sqlx::query_as(
+            r#"
+                INSERT INTO policies (kind, version, body, published_by, published)
+                SELECT $1, COALESCE(MAX(version), 0) + 1, $2, $3, $4
+                FROM policies WHERE kind = $1
+                RETURNING *
+            "#,
+        )
+        .bind(kind)
+        .bind(body)
+        .bind(user.id)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&*conn)
+        .await?)
+    }
+);
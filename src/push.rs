@@ -0,0 +1,356 @@
+//! Web Push. Browsers subscribe from a login session and are sent trade offer
+//! and reply notifications even when no page is open, through the service
+//! worker at `/sw.js`.
+//!
+//! Pushing is only enabled when `VAPID_PUBLIC_KEY`, `VAPID_PRIVATE_KEY` and
+//! `VAPID_SUBJECT` are set. Keys are URL-safe base64, as printed by
+//! `web-push generate-vapid-keys`.
+use axum::{
+    extract::{Extension, Form},
+    http::header,
+    response::IntoResponse,
+};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgPool};
+use thiserror::Error;
+use web_push::{
+    ContentEncoding, HyperWebPushClient, SubscriptionInfo, VapidSignatureBuilder, WebPushClient,
+    WebPushError, WebPushMessageBuilder, URL_SAFE_NO_PAD,
+};
+
+use crate::{
+    get,
+    notifications::{NotificationKind, NotificationSettings},
+    post,
+    users::{LoginSession, User},
+};
+
+/// Service worker that shows pushed notifications. Served from the root so
+/// that it controls every page.
+const SERVICE_WORKER: &str = include_str!("../static/sw.js");
+
+/// Most subscriptions a user may have. Subscribing again once there are this
+/// many replaces the oldest.
+const MAX_SUBSCRIPTIONS_PER_USER: i64 = 10;
+
+lazy_static! {
+    static ref VAPID: Option<VapidConfig> = VapidConfig::from_env();
+    static ref CLIENT: HyperWebPushClient = HyperWebPushClient::new();
+}
+
+struct VapidConfig {
+    public_key:  String,
+    private_key: String,
+    /// Contact for push services, a `mailto:` or `https:` URL
+    subject:     String,
+}
+
+impl VapidConfig {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            public_key:  std::env::var("VAPID_PUBLIC_KEY").ok()?,
+            private_key: std::env::var("VAPID_PRIVATE_KEY").ok()?,
+            subject:     std::env::var("VAPID_SUBJECT").ok()?,
+        })
+    }
+}
+
+/// Check the VAPID configuration for the startup self-check. Pushing is
+/// optional, but setting only some of the variables, or keys that cannot be
+/// used, is a mistake.
+pub fn check_vapid() -> Result<(), String> {
+    const VARS: &[&str] = &["VAPID_PUBLIC_KEY", "VAPID_PRIVATE_KEY", "VAPID_SUBJECT"];
+    let missing = VARS
+        .iter()
+        .filter(|var| std::env::var_os(var).is_none())
+        .copied()
+        .collect::<Vec<_>>();
+    if missing.len() == VARS.len() {
+        return Ok(());
+    }
+    if !missing.is_empty() {
+        return Err(format!("{} not set", missing.join(", ")));
+    }
+
+    let vapid = VapidConfig::from_env().ok_or("VAPID variables are not valid unicode")?;
+    let key_length =
+        |key: &str| base64::decode_config(key, base64::URL_SAFE_NO_PAD).map_or(0, |key| key.len());
+    if key_length(&vapid.public_key) != 65 {
+        return Err("VAPID_PUBLIC_KEY is not an uncompressed P-256 public key".to_string());
+    }
+    if key_length(&vapid.private_key) != 32 {
+        return Err("VAPID_PRIVATE_KEY is not a P-256 private key".to_string());
+    }
+    if !vapid.subject.starts_with("mailto:") && !vapid.subject.starts_with("https:") {
+        return Err("VAPID_SUBJECT must be a mailto: or https: URL".to_string());
+    }
+    Ok(())
+}
+
+/// What is shown by the service worker.
+#[derive(Serialize)]
+struct PushMessage {
+    title: String,
+    body:  String,
+    /// Page opened when the notification is clicked
+    url:   String,
+}
+
+impl PushMessage {
+    /// The message pushed for a notification, if it is pushed at all.
+    fn for_notification(kind: &NotificationKind) -> Option<Self> {
+        match kind {
+            NotificationKind::TradeOffer { sender_name, .. } => Some(Self {
+                title: "New trade offer".to_string(),
+                body:  format!("{sender_name} sent you a trade offer"),
+                url:   "/offers".to_string(),
+            }),
+            NotificationKind::Reply {
+                author_name,
+                title,
+                link,
+            } => Some(Self {
+                title: format!("{author_name} replied to you"),
+                body:  title.clone(),
+                url:   link.clone(),
+            }),
+            NotificationKind::NewLogin {
+                ip_addr, country, ..
+            } => Some(Self {
+                title: "New login to your account".to_string(),
+                body:  match country {
+                    Some(country) => format!("Someone logged in from {ip_addr} ({country})"),
+                    None => format!("Someone logged in from {ip_addr}"),
+                },
+                url:   "/profile/security".to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct PushSubscription {
+    id:                    i32,
+    endpoint:              String,
+    p256dh:                String,
+    auth:                  String,
+    notification_settings: Json<NotificationSettings>,
+}
+
+/// Push a notification to every logged in browser the user has subscribed
+/// from. Pushing happens in the background, so that a slow push service
+/// does not hold up the caller.
+pub fn push(conn: &PgPool, user_id: i32, kind: &NotificationKind) {
+    let Some(vapid) = VAPID.as_ref() else {
+        return;
+    };
+    let Some(message) = PushMessage::for_notification(kind) else {
+        return;
+    };
+    let conn = conn.clone();
+    let kind = kind.clone();
+    tokio::spawn(async move {
+        if let Err(err) = deliver(&conn, vapid, user_id, &kind, &message).await {
+            tracing::error!("Failed to push notification to user {user_id}: {err}");
+        }
+    });
+}
+
+async fn deliver(
+    conn: &PgPool,
+    vapid: &VapidConfig,
+    user_id: i32,
+    kind: &NotificationKind,
+    message: &PushMessage,
+) -> Result<(), sqlx::Error> {
+    let subscriptions: Vec<PushSubscription> = sqlx::query_as(
+        r#"
+            SELECT
+                push_subscriptions.id,
+                push_subscriptions.endpoint,
+                push_subscriptions.p256dh,
+                push_subscriptions.auth,
+                users.notification_settings
+            FROM push_subscriptions
+            JOIN login_sessions ON login_sessions.id = push_subscriptions.session_id
+            JOIN users ON users.id = push_subscriptions.user_id
+            WHERE push_subscriptions.user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(conn)
+    .await?;
+
+    let payload = serde_json::to_string(message).unwrap();
+    for subscription in subscriptions {
+        if !kind.is_wanted(&subscription.notification_settings) {
+            continue;
+        }
+        match send(vapid, &subscription, &payload).await {
+            Ok(()) => (),
+            // The browser has unsubscribed, so the subscription is useless.
+            Err(WebPushError::EndpointNotValid | WebPushError::EndpointNotFound) => {
+                sqlx::query("DELETE FROM push_subscriptions WHERE id = $1")
+                    .bind(subscription.id)
+                    .execute(conn)
+                    .await?;
+            }
+            Err(err) => tracing::warn!("Failed to push to subscription {}: {err}", subscription.id),
+        }
+    }
+    Ok(())
+}
+
+async fn send(
+    vapid: &VapidConfig,
+    subscription: &PushSubscription,
+    payload: &str,
+) -> Result<(), WebPushError> {
+    let info = SubscriptionInfo::new(
+        &subscription.endpoint,
+        &subscription.p256dh,
+        &subscription.auth,
+    );
+    let mut signature =
+        VapidSignatureBuilder::from_base64(&vapid.private_key, URL_SAFE_NO_PAD, &info)?;
+    signature.add_claim("sub", vapid.subject.as_str());
+    let mut builder = WebPushMessageBuilder::new(&info);
+    builder.set_payload(ContentEncoding::Aes128Gcm, payload.as_bytes());
+    builder.set_vapid_signature(signature.build()?);
+    CLIENT.send(builder.build()?).await
+}
+
+get!(
+    "/sw.js",
+    async fn service_worker() -> impl IntoResponse {
+        (
+            [
+                (header::CONTENT_TYPE, "text/javascript"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            SERVICE_WORKER,
+        )
+    }
+);
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum PushError {
+    #[error("Push notifications are not enabled")]
+    NotEnabled,
+    #[error("Invalid subscription")]
+    InvalidSubscription,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/push/key",
+    #[json]
+    async fn push_key() -> Result<String, PushError> {
+        VAPID
+            .as_ref()
+            .map(|vapid| vapid.public_key.clone())
+            .ok_or(PushError::NotEnabled)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct SubscribeForm {
+    endpoint: String,
+    p256dh:   String,
+    auth:     String,
+}
+
+post!(
+    "/push/subscribe",
+    #[json]
+    async fn subscribe(
+        conn: Extension<PgPool>,
+        user: User,
+        session: LoginSession,
+        Form(SubscribeForm {
+            endpoint,
+            p256dh,
+            auth,
+        }): Form<SubscribeForm>,
+    ) -> Result<(), PushError> {
+        if VAPID.is_none() {
+            return Err(PushError::NotEnabled);
+        }
+        if !endpoint.starts_with("https://") || p256dh.is_empty() || auth.is_empty() {
+            return Err(PushError::InvalidSubscription);
+        }
+
+        let mut transaction = conn.begin().await?;
+        sqlx::query(
+            r#"
+                INSERT INTO push_subscriptions
+                    (user_id, session_id, endpoint, p256dh, auth, created)
+                VALUES
+                    ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (endpoint) DO UPDATE SET
+                    user_id = EXCLUDED.user_id,
+                    session_id = EXCLUDED.session_id,
+                    p256dh = EXCLUDED.p256dh,
+                    auth = EXCLUDED.auth,
+                    created = EXCLUDED.created
+            "#,
+        )
+        .bind(user.id)
+        .bind(session.id)
+        .bind(endpoint)
+        .bind(p256dh)
+        .bind(auth)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query(
+            r#"
+                DELETE FROM push_subscriptions
+                WHERE user_id = $1 AND id NOT IN (
+                    SELECT id FROM push_subscriptions
+                    WHERE user_id = $1
+                    ORDER BY created DESC
+                    LIMIT $2
+                )
+            "#,
+        )
+        .bind(user.id)
+        .bind(MAX_SUBSCRIPTIONS_PER_USER)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+);
+
+#[derive(Deserialize)]
+pub struct UnsubscribeForm {
+    endpoint: String,
+}
+
+post!(
+    "/push/unsubscribe",
+    #[json]
+    async fn unsubscribe(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(UnsubscribeForm { endpoint }): Form<UnsubscribeForm>,
+    ) -> Result<(), PushError> {
+        sqlx::query("DELETE FROM push_subscriptions WHERE user_id = $1 AND endpoint = $2")
+            .bind(user.id)
+            .bind(endpoint)
+            .execute(&*conn)
+            .await?;
+        Ok(())
+    }
+);
@@ -0,0 +1,190 @@
+//! Rate limit tiers for the JSON API. Each client may make a number of
+//! requests per minute according to its tier. Requests are counted with
+//! cluster counters so that the limit holds across instances, and every
+//! response tells the client where it stands with `X-RateLimit-*` headers.
+//! Each counter expires with its window, after which the cluster sweeps it out.
+use std::time::Duration;
+
+use axum::{
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::Type;
+
+use crate::cluster::Cluster;
+
+/// Window over which requests are counted against a limit.
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many requests a client may make per minute.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "rate_limit_tier")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitTier {
+    Default,
+    Elevated,
+    Bot,
+}
+
+impl RateLimitTier {
+    pub fn requests_per_window(self) -> i64 {
+        match self {
+            Self::Default => 60,
+            Self::Elevated => 300,
+            Self::Bot => 1200,
+        }
+    }
+}
+
+/// Where a client stands against its limit in the current window.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub limit:     i64,
+    pub count:     i64,
+    pub resets_in: Duration,
+}
+
+impl RateLimit {
+    /// Count a request made by `client` against the limit of its tier.
+    pub async fn count(
+        cluster: &Cluster,
+        client: &str,
+        tier: RateLimitTier,
+    ) -> Result<Self, sqlx::Error> {
+        let key = format!("rate_limit:{client}");
+        let count = cluster.increment(&key, RATE_LIMIT_WINDOW).await?;
+        let resets_in = cluster
+            .counter(&key)
+            .await?
+            .map_or(RATE_LIMIT_WINDOW, |(_, resets_in)| resets_in);
+        Ok(Self {
+            limit: tier.requests_per_window(),
+            count,
+            resets_in,
+        })
+    }
+
+    /// Forget the requests counted for a client that will make no more, such
+    /// as a revoked token, rather than keep its counter until it expires.
+    pub async fn forget(cluster: &Cluster, client: &str) -> Result<(), sqlx::Error> {
+        cluster.reset(&format!("rate_limit:{client}")).await
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.count > self.limit
+    }
+
+    /// Whole seconds until the window resets, rounded up so that clients
+    /// never retry too early.
+    fn reset_secs(&self) -> u64 {
+        let secs = self.resets_in.as_secs();
+        if self.resets_in.subsec_nanos() > 0 {
+            secs + 1
+        } else {
+            secs.max(1)
+        }
+    }
+
+    /// Response to a request made over the limit.
+    pub fn rejection(&self) -> Response {
+        let resets_in = self.reset_secs();
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("Rate limit exceeded, try again in {resets_in} seconds"),
+        )
+            .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(resets_in));
+        response
+    }
+
+    /// Tell the client where it stands.
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(
+            HeaderName::from_static("x-ratelimit-limit"),
+            HeaderValue::from(self.limit),
+        );
+        headers.insert(
+            HeaderName::from_static("x-ratelimit-remaining"),
+            HeaderValue::from((self.limit - self.count).max(0)),
+        );
+        headers.insert(
+            HeaderName::from_static("x-ratelimit-reset"),
+            HeaderValue::from(self.reset_secs()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::ClusterBackend;
+
+    #[test]
+    fn tiers_allow_more_requests_in_order() {
+        assert!(
+            RateLimitTier::Default.requests_per_window()
+                < RateLimitTier::Elevated.requests_per_window()
+        );
+        assert!(
+            RateLimitTier::Elevated.requests_per_window()
+                < RateLimitTier::Bot.requests_per_window()
+        );
+    }
+
+    #[test]
+    fn headers_report_remaining_requests() {
+        let limit = RateLimit {
+            limit:     60,
+            count:     45,
+            resets_in: Duration::from_millis(12_300),
+        };
+        let mut headers = HeaderMap::new();
+        limit.add_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-limit"], "60");
+        assert_eq!(headers["x-ratelimit-remaining"], "15");
+        assert_eq!(headers["x-ratelimit-reset"], "13");
+        assert!(!limit.exceeded());
+    }
+
+    #[test]
+    fn rejection_asks_to_retry_after_reset() {
+        let limit = RateLimit {
+            limit:     60,
+            count:     61,
+            resets_in: Duration::ZERO,
+        };
+        assert!(limit.exceeded());
+        let mut headers = HeaderMap::new();
+        limit.add_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        let response = limit.rejection();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn counts_requests_per_client() {
+        let cluster = Cluster::new(ClusterBackend::Memory);
+        for _ in 0..60 {
+            let limit = RateLimit::count(&cluster, "a", RateLimitTier::Default)
+                .await
+                .unwrap();
+            assert!(!limit.exceeded());
+        }
+        let limit = RateLimit::count(&cluster, "a", RateLimitTier::Default)
+            .await
+            .unwrap();
+        assert!(limit.exceeded());
+        assert!(limit.resets_in <= RATE_LIMIT_WINDOW);
+        let other = RateLimit::count(&cluster, "b", RateLimitTier::Default)
+            .await
+            .unwrap();
+        assert_eq!(other.count, 1);
+        RateLimit::forget(&cluster, "a").await.unwrap();
+        assert_eq!(cluster.counter("rate_limit:a").await.unwrap(), None);
+    }
+}
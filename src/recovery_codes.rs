@@ -0,0 +1,203 @@
+//! Recovery codes, a set of single use codes given to users at registration
+//! that can stand in for the reset code if it is lost. Codes are hashed like
+//! passwords, and a new set replaces whatever is left of the old one. Each code
+//! starts with a short prefix that is stored in the clear, so that only the
+//! hash of the code it belongs to has to be checked.
+use axum::extract::{Extension, Form};
+use chrono::Utc;
+use marche_proc_macros::{json, ErrorCode};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::{passwords, post, users::User};
+
+/// Number of codes in a set.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Number of secret characters in a code, not counting the dashes.
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// Number of characters in the prefix of a code.
+const RECOVERY_CODE_PREFIX_LENGTH: usize = 4;
+
+fn random_code(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect()
+}
+
+/// Replace the user's recovery codes with a new set, returning the codes.
+/// This is the only time the codes are seen in the clear.
+pub async fn generate(conn: &mut PgConnection, user_id: i32) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let now = Utc::now().naive_utc();
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let prefix = random_code(RECOVERY_CODE_PREFIX_LENGTH);
+        let code = random_code(RECOVERY_CODE_LENGTH);
+        sqlx::query(
+            r#"
+                INSERT INTO recovery_codes (user_id, prefix, code_hash, created)
+                VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&prefix)
+        .bind(passwords::spawn_hash(&code).await)
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+        let (first, second) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+        codes.push(format!("{prefix}-{first}-{second}"));
+    }
+    Ok(codes)
+}
+
+/// Use up one of the user's recovery codes. Returns false if the code does
+/// not match any that are left.
+pub async fn consume(
+    conn: &mut PgConnection,
+    user_id: i32,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let code = normalize(code);
+    let Some((prefix, code)) = split_prefix(&code) else {
+        return Ok(false);
+    };
+
+    let unused: Vec<(i32, String)> = sqlx::query_as(
+        r#"
+            SELECT id, code_hash FROM recovery_codes
+            WHERE user_id = $1 AND prefix IS NOT DISTINCT FROM $2 AND used IS NULL
+            FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .bind(prefix)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut matching = None;
+    for (id, code_hash) in unused {
+        if passwords::spawn_verify(&code_hash, code).await {
+            matching = Some(id);
+            break;
+        }
+    }
+    let Some(id) = matching else {
+        return Ok(false);
+    };
+
+    sqlx::query("UPDATE recovery_codes SET used = $1 WHERE id = $2")
+        .bind(Utc::now().naive_utc())
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(true)
+}
+
+/// A code as it is hashed. Codes are shown with dashes and may be typed in any
+/// case.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Split a code, without its dashes, into its prefix and the secret part.
+/// Codes made before there were prefixes have none.
+fn split_prefix(code: &str) -> Option<(Option<&str>, &str)> {
+    match code.len() {
+        RECOVERY_CODE_LENGTH => Some((None, code)),
+        len if len == RECOVERY_CODE_PREFIX_LENGTH + RECOVERY_CODE_LENGTH => {
+            let (prefix, code) = code.split_at(RECOVERY_CODE_PREFIX_LENGTH);
+            Some((Some(prefix), code))
+        }
+        _ => None,
+    }
+}
+
+/// Number of recovery codes the user has left.
+pub async fn remaining(conn: impl PgExecutor<'_>, user_id: i32) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COUNT(*) FROM recovery_codes WHERE user_id = $1 AND used IS NULL")
+        .bind(user_id)
+        .fetch_one(conn)
+        .await
+}
+
+#[derive(Deserialize)]
+pub struct RegenerateRecoveryCodesForm {
+    password: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum RegenerateRecoveryCodesError {
+    #[error("Password is incorrect")]
+    PasswordIncorrect,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/security/recovery_codes/regenerate",
+    #[json]
+    async fn regenerate_recovery_codes(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(RegenerateRecoveryCodesForm { password }): Form<RegenerateRecoveryCodesForm>,
+    ) -> Result<Vec<String>, RegenerateRecoveryCodesError> {
+        if !passwords::spawn_verify(&user.password, &password).await {
+            return Err(RegenerateRecoveryCodesError::PasswordIncorrect);
+        }
+
+        let mut transaction = conn.begin().await?;
+        let codes = generate(&mut transaction, user.id).await?;
+        transaction.commit().await?;
+
+        Ok(codes)
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_codes_as_shown_or_typed() {
+        let code = normalize(" AbCd-efGHI-jklmn ");
+        assert_eq!(code, "abcdefghijklmn");
+        assert_eq!(split_prefix(&code), Some((Some("abcd"), "efghijklmn")));
+    }
+
+    #[test]
+    fn accepts_codes_made_before_prefixes() {
+        let code = normalize("efghi-jklmn");
+        assert_eq!(split_prefix(&code), Some((None, "efghijklmn")));
+    }
+
+    #[test]
+    fn rejects_codes_of_the_wrong_length() {
+        assert_eq!(split_prefix(&normalize("abcd-efghi")), None);
+        assert_eq!(split_prefix(&normalize("abcd-efghi-jklmno")), None);
+        assert_eq!(split_prefix(""), None);
+    }
+
+    #[test]
+    fn random_codes_can_be_typed() {
+        let code = random_code(RECOVERY_CODE_LENGTH);
+        assert_eq!(code.len(), RECOVERY_CODE_LENGTH);
+        assert_eq!(normalize(&code), code);
+    }
+}
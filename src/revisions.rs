@@ -0,0 +1,255 @@
+//! Revision history of replies. Every edit of a reply records the new body,
+//! so that earlier versions can be compared and restored. This is what lets
+//! wiki posts be maintained by the community: a bad edit is undone by
+//! reverting to the revision before it.
+use axum::extract::{Extension, Path};
+use chrono::{NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::Serialize;
+use sqlx::{FromRow, PgExecutor, PgPool, Postgres, Transaction};
+use thiserror::Error;
+
+use crate::{
+    get,
+    limits::Limits,
+    post,
+    threads::Reply,
+    users::{Role, User},
+};
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct ReplyRevision {
+    pub id:        i32,
+    pub reply_id:  i32,
+    pub editor_id: i32,
+    pub body:      String,
+    pub edited:    NaiveDateTime,
+}
+
+impl ReplyRevision {
+    /// Record an edit of a reply. The first edit of a reply also records its
+    /// original body, credited to its author.
+    pub async fn record(
+        transaction: &mut Transaction<'_, Postgres>,
+        reply: &Reply,
+        editor_id: i32,
+        body: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+                INSERT INTO reply_revisions (reply_id, editor_id, body, edited)
+                SELECT $1, $2, $3, $4
+                WHERE NOT EXISTS (SELECT 1 FROM reply_revisions WHERE reply_id = $1)
+            "#,
+        )
+        .bind(reply.id)
+        .bind(reply.author_id)
+        .bind(&reply.body)
+        .bind(reply.post_date)
+        .execute(&mut *transaction)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO reply_revisions (reply_id, editor_id, body, edited) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(reply.id)
+        .bind(editor_id)
+        .bind(body)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn fetch_optional(
+        conn: impl PgExecutor<'_>,
+        id: i32,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM reply_revisions WHERE id = $1")
+            .bind(id)
+            .fetch_optional(conn)
+            .await
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffKind {
+    Same,
+    Added,
+    Removed,
+}
+
+/// A line of a diff between two revisions.
+#[derive(Debug, Serialize)]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+/// Line by line difference between two bodies, from their longest common
+/// subsequence of lines.
+pub fn diff(old: &str, new: &str) -> Vec<DiffLine> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // common[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..].
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let line = |kind, text: &str| DiffLine {
+        kind,
+        text: text.to_string(),
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(line(DiffKind::Same, old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            lines.push(line(DiffKind::Removed, old[i]));
+            i += 1;
+        } else {
+            lines.push(line(DiffKind::Added, new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|text| line(DiffKind::Removed, text)));
+    lines.extend(new[j..].iter().map(|text| line(DiffKind::Added, text)));
+    lines
+}
+
+/// A revision along with what it changed from the one before.
+#[derive(Debug, Serialize)]
+pub struct RevisionView {
+    pub id:          i32,
+    pub editor_id:   i32,
+    pub editor_name: String,
+    pub edited:      NaiveDateTime,
+    pub body:        String,
+    pub diff:        Vec<DiffLine>,
+}
+
+#[derive(FromRow)]
+struct RevisionRow {
+    #[sqlx(flatten)]
+    revision:    ReplyRevision,
+    editor_name: String,
+}
+
+impl RevisionView {
+    /// Every revision of a reply, newest first.
+    pub async fn fetch_for_reply(conn: &PgPool, reply_id: i32) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<RevisionRow> = sqlx::query_as(
+            r#"
+                SELECT reply_revisions.*, users.display_name AS editor_name
+                FROM reply_revisions
+                JOIN users ON users.id = reply_revisions.editor_id
+                WHERE reply_revisions.reply_id = $1
+                ORDER BY reply_revisions.id ASC
+            "#,
+        )
+        .bind(reply_id)
+        .fetch_all(conn)
+        .await?;
+
+        let mut previous = String::new();
+        let mut views = Vec::with_capacity(rows.len());
+        for RevisionRow {
+            revision,
+            editor_name,
+        } in rows
+        {
+            views.push(RevisionView {
+                id: revision.id,
+                editor_id: revision.editor_id,
+                editor_name,
+                edited: revision.edited,
+                diff: diff(&previous, &revision.body),
+                body: revision.body.clone(),
+            });
+            previous = revision.body;
+        }
+        views.reverse();
+        Ok(views)
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum RevisionsError {
+    #[error("Post does not exist")]
+    NoSuchReply,
+    #[error("Revision does not exist")]
+    NoSuchRevision,
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/reply/:post_id/revisions",
+    #[json]
+    async fn reply_revisions(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(post_id): Path<i32>,
+    ) -> Result<Vec<RevisionView>, RevisionsError> {
+        let reply = Reply::fetch_optional(&conn, post_id)
+            .await?
+            .ok_or(RevisionsError::NoSuchReply)?;
+        if reply.hidden && user.role < Role::Moderator {
+            return Err(RevisionsError::NoSuchReply);
+        }
+        if !user.show_mature && reply.is_mature(&*conn).await? {
+            return Err(RevisionsError::NoSuchReply);
+        }
+        Ok(RevisionView::fetch_for_reply(&conn, post_id).await?)
+    }
+);
+
+post!(
+    "/reply/:post_id/revisions/:revision_id/revert",
+    #[json]
+    async fn revert_reply(
+        conn: Extension<PgPool>,
+        limits: Extension<Limits>,
+        user: User,
+        Path((post_id, revision_id)): Path<(i32, i32)>,
+    ) -> Result<(), RevisionsError> {
+        let reply = Reply::fetch_optional(&conn, post_id)
+            .await?
+            .ok_or(RevisionsError::NoSuchReply)?;
+        if reply.hidden && user.role < Role::Moderator {
+            return Err(RevisionsError::NoSuchReply);
+        }
+        if !reply.editable_by(&conn, &user, &limits).await? {
+            return Err(RevisionsError::Unauthorized);
+        }
+        let revision = ReplyRevision::fetch_optional(&*conn, revision_id)
+            .await?
+            .filter(|revision| revision.reply_id == reply.id)
+            .ok_or(RevisionsError::NoSuchRevision)?;
+
+        // A revert is an edit like any other, so it can itself be reverted.
+        reply.edit(&conn, user.id, &revision.body).await?;
+
+        Ok(())
+    }
+);
@@ -0,0 +1,88 @@
+//! Validation of the environment and external services at startup, so that a
+//! misconfigured deployment fails before it starts serving rather than on the
+//! first registration or upload.
+use std::path::Path;
+
+use crate::{
+    digests::Mailer,
+    push, signing,
+    users::{self, CookieKeys},
+};
+
+/// Assets that are served from the `static` directory and referenced by the
+/// templates. The templates themselves are compiled into the binary.
+const STATIC_ASSETS: &[&str] = &["favicon.ico", "index.js", "styles.css", "thread.js"];
+
+pub struct Check {
+    pub name:   &'static str,
+    pub result: Result<(), String>,
+}
+
+/// Run every check, returning all results so that the problems can be fixed in
+/// one go.
+pub async fn run() -> Vec<Check> {
+    let mut checks = vec![
+        Check {
+            name:   "shared secret key",
+            result: users::load_shared_secret_cipher().map(drop),
+        },
+        Check {
+            name:   "cookie keys",
+            result: CookieKeys::from_env()
+                .map(drop)
+                .map_err(|err| err.to_string()),
+        },
+        Check {
+            name:   "VAPID keys",
+            result: push::check_vapid(),
+        },
+        Check {
+            name:   "mailer",
+            result: Mailer::from_env().map(drop).map_err(|err| err.to_string()),
+        },
+        Check {
+            name:   "request signing",
+            result: signing::check_round_trip(),
+        },
+        Check {
+            name:   "static assets",
+            result: check_static_assets(),
+        },
+    ];
+    #[cfg(feature = "s3-images")]
+    checks.push(Check {
+        name:   "image store",
+        result: crate::images::check_image_store()
+            .await
+            .map_err(|err| format!("cannot reach image store: {err}")),
+    });
+    checks
+}
+
+/// Log the outcome of every check. Returns true if all of them passed.
+pub fn report(checks: &[Check]) -> bool {
+    let mut passed = true;
+    for check in checks {
+        match check.result {
+            Ok(()) => tracing::info!("Self-check {}: ok", check.name),
+            Err(ref err) => {
+                tracing::error!("Self-check {}: {err}", check.name);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+fn check_static_assets() -> Result<(), String> {
+    let missing = STATIC_ASSETS
+        .iter()
+        .filter(|asset| !Path::new("static").join(asset).is_file())
+        .copied()
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("missing from static/: {}", missing.join(", ")))
+    }
+}
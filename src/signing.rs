@@ -0,0 +1,256 @@
+//! Signed requests, which keep credentials leaked in a log from being
+//! replayed against the JSON API.
+//!
+//! A signed request carries a timestamp, a nonce and a signature made with a
+//! key shared with the client. The signature is the base64 encoded HMAC-SHA256
+//! of `METHOD\npath?query\ntimestamp\nnonce`. Requests whose timestamp is too
+//! far from the server's clock are refused, and each nonce is only accepted
+//! once per signer. The body is not signed, as it has not been read when the
+//! request is authenticated.
+use std::time::Duration;
+
+use axum::http::{HeaderMap, HeaderValue, Method, Uri};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::cluster::Cluster;
+
+/// How many seconds a signed request's timestamp may be from the server's
+/// clock.
+pub const SIGNATURE_WINDOW: i64 = 300;
+
+/// Headers of a signed request.
+pub const TIMESTAMP_HEADER: &str = "x-marche-timestamp";
+pub const NONCE_HEADER: &str = "x-marche-nonce";
+pub const SIGNATURE_HEADER: &str = "x-marche-signature";
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("This request must be signed")]
+    Unsigned,
+    #[error("The request's timestamp is too far from the current time")]
+    Expired,
+    #[error("The request's signature is invalid")]
+    BadSignature,
+    #[error("The request's nonce has already been used")]
+    Replayed,
+    #[error("Internal database error: {0}")]
+    InternalDbError(#[from] sqlx::Error),
+}
+
+/// Sign a request made at `timestamp`, in seconds since the epoch.
+pub fn sign(key: &str, method: &Method, path: &str, timestamp: i64, nonce: &str) -> String {
+    base64::encode(
+        mac(key, method, path, &timestamp.to_string(), nonce)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Verify the signature of a request and claim its nonce. `signer` identifies
+/// whoever holds the key, so that nonces of different clients cannot collide.
+pub async fn verify(
+    cluster: &Cluster,
+    signer: &str,
+    key: &str,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+) -> Result<(), SignatureError> {
+    let now = Utc::now().timestamp();
+    let (nonce, sent) = check(key, method, uri, headers, now)?;
+
+    // Nonces are remembered until their timestamp is no longer accepted, which
+    // is all it takes to refuse every replay. The cluster sweeps them out after.
+    let key = format!("request_nonce:{signer}:{nonce}");
+    let remembered_for = Duration::from_secs((sent + SIGNATURE_WINDOW - now + 1) as u64);
+    if cluster.increment(&key, remembered_for).await? > 1 {
+        return Err(SignatureError::Replayed);
+    }
+    Ok(())
+}
+
+/// Sign a request and check it again, for the startup self-check. Makes sure
+/// that requests signed by clients as documented are accepted with the
+/// server's clock.
+pub fn check_round_trip() -> Result<(), String> {
+    let key = "self-check";
+    let uri = Uri::from_static("/self-check?signed=1");
+    let now = Utc::now().timestamp();
+    let mut headers = HeaderMap::new();
+    headers.insert(TIMESTAMP_HEADER, now.into());
+    headers.insert(NONCE_HEADER, HeaderValue::from_static("self-check"));
+    let signature = sign(
+        key,
+        &Method::POST,
+        "/self-check?signed=1",
+        now,
+        "self-check",
+    );
+    headers.insert(
+        SIGNATURE_HEADER,
+        HeaderValue::from_str(&signature).map_err(|err| err.to_string())?,
+    );
+    check(key, &Method::POST, &uri, &headers, now)
+        .map(drop)
+        .map_err(|err| format!("a freshly signed request was refused: {err}"))
+}
+
+/// Check the timestamp and signature of a request received at `now`,
+/// returning its nonce and timestamp.
+fn check<'a>(
+    key: &str,
+    method: &Method,
+    uri: &Uri,
+    headers: &'a HeaderMap,
+    now: i64,
+) -> Result<(&'a str, i64), SignatureError> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let (Some(timestamp), Some(nonce), Some(signature)) = (
+        header(TIMESTAMP_HEADER),
+        header(NONCE_HEADER),
+        header(SIGNATURE_HEADER),
+    ) else {
+        return Err(SignatureError::Unsigned);
+    };
+
+    let sent: i64 = timestamp.parse().map_err(|_| SignatureError::Expired)?;
+    if now.abs_diff(sent) > SIGNATURE_WINDOW as u64 {
+        return Err(SignatureError::Expired);
+    }
+
+    let signature = base64::decode(signature).map_err(|_| SignatureError::BadSignature)?;
+    let path = uri
+        .path_and_query()
+        .map_or(uri.path(), |path| path.as_str());
+    mac(key, method, path, timestamp, nonce)
+        .verify_slice(&signature)
+        .map_err(|_| SignatureError::BadSignature)?;
+    Ok((nonce, sent))
+}
+
+fn mac(key: &str, method: &Method, path: &str, timestamp: &str, nonce: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(format!("{method}\n{path}\n{timestamp}\n{nonce}").as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::ClusterBackend;
+
+    const KEY: &str = "signing key";
+    const NOW: i64 = 1_700_000_000;
+
+    fn signed_headers(key: &str, method: &Method, path: &str, timestamp: i64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        headers.insert(NONCE_HEADER, HeaderValue::from_static("nonce"));
+        headers.insert(
+            SIGNATURE_HEADER,
+            sign(key, method, path, timestamp, "nonce").parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn accepts_signed_request() {
+        let uri: Uri = "/reply?thread=1".parse().unwrap();
+        let headers = signed_headers(KEY, &Method::POST, "/reply?thread=1", NOW);
+        assert_eq!(
+            check(KEY, &Method::POST, &uri, &headers, NOW + 10).unwrap(),
+            ("nonce", NOW)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_request() {
+        let headers = signed_headers(KEY, &Method::POST, "/reply?thread=1", NOW);
+        let other_path: Uri = "/reply?thread=2".parse().unwrap();
+        assert!(matches!(
+            check(KEY, &Method::POST, &other_path, &headers, NOW),
+            Err(SignatureError::BadSignature)
+        ));
+        let uri: Uri = "/reply?thread=1".parse().unwrap();
+        assert!(matches!(
+            check(KEY, &Method::DELETE, &uri, &headers, NOW),
+            Err(SignatureError::BadSignature)
+        ));
+        assert!(matches!(
+            check("other key", &Method::POST, &uri, &headers, NOW),
+            Err(SignatureError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_requests_outside_the_window() {
+        let uri: Uri = "/threads".parse().unwrap();
+        let headers = signed_headers(KEY, &Method::GET, "/threads", NOW);
+        assert!(check(KEY, &Method::GET, &uri, &headers, NOW - SIGNATURE_WINDOW).is_ok());
+        assert!(matches!(
+            check(
+                KEY,
+                &Method::GET,
+                &uri,
+                &headers,
+                NOW + SIGNATURE_WINDOW + 1
+            ),
+            Err(SignatureError::Expired)
+        ));
+    }
+
+    #[test]
+    fn self_check_round_trip_passes() {
+        check_round_trip().unwrap();
+    }
+
+    #[test]
+    fn rejects_unsigned_request() {
+        let uri: Uri = "/threads".parse().unwrap();
+        let mut headers = signed_headers(KEY, &Method::GET, "/threads", NOW);
+        headers.remove(NONCE_HEADER);
+        assert!(matches!(
+            check(KEY, &Method::GET, &uri, &headers, NOW),
+            Err(SignatureError::Unsigned)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_nonce() {
+        let cluster = Cluster::new(ClusterBackend::Memory);
+        let uri: Uri = "/threads".parse().unwrap();
+        let headers = signed_headers(KEY, &Method::GET, "/threads", Utc::now().timestamp());
+        verify(&cluster, "1", KEY, &Method::GET, &uri, &headers)
+            .await
+            .unwrap();
+        assert!(matches!(
+            verify(&cluster, "1", KEY, &Method::GET, &uri, &headers).await,
+            Err(SignatureError::Replayed)
+        ));
+        // Another signer may use the same nonce.
+        verify(&cluster, "2", KEY, &Method::GET, &uri, &headers)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn forgets_nonces_once_their_timestamp_expires() {
+        let cluster = Cluster::new(ClusterBackend::Memory);
+        let uri: Uri = "/threads".parse().unwrap();
+        let sent = Utc::now().timestamp() - SIGNATURE_WINDOW + 60;
+        let headers = signed_headers(KEY, &Method::GET, "/threads", sent);
+        verify(&cluster, "1", KEY, &Method::GET, &uri, &headers)
+            .await
+            .unwrap();
+        let (_, remembered_for) = cluster
+            .counter("request_nonce:1:nonce")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(remembered_for <= Duration::from_secs(61));
+    }
+}
@@ -0,0 +1,266 @@
+//! Aggregates over the whole forum, such as the leaderboard. These are kept in
+//! materialized views that are refreshed on a schedule rather than computed on
+//! every request.
+use std::time::Duration;
+
+use axum::{
+    extract::Extension,
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use chrono::{NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool};
+use thiserror::Error;
+
+use crate::get;
+
+/// How often the views are refreshed.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Materialized views that are refreshed periodically.
+const VIEWS: &[&str] = &[
+    "leaderboard",
+    "weekly_leaderboard",
+    "monthly_leaderboard",
+    "forum_stats",
+    "weekly_highlight",
+    "public_stats",
+];
+
+/// Public stats only change when the views are refreshed, so they may be
+/// cached by clients and proxies for as long.
+const PUBLIC_STATS_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Totals shown on the admin dashboard.
+#[derive(Debug, FromRow, Serialize)]
+pub struct ForumStats {
+    pub users:       i64,
+    pub threads:     i64,
+    pub replies:     i64,
+    pub drops:       i64,
+    pub open_trades: i64,
+}
+
+impl ForumStats {
+    pub async fn fetch(conn: impl PgExecutor<'_>) -> Result<Self, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM forum_stats")
+            .fetch_one(conn)
+            .await
+    }
+}
+
+/// Number of users shown on each page of a leaderboard.
+pub const LEADERBOARD_PER_PAGE: i64 = 50;
+
+/// Period a leaderboard ranks experience over.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardPeriod {
+    Week,
+    Month,
+    #[default]
+    All,
+}
+
+impl LeaderboardPeriod {
+    pub const ALL: &'static [Self] = &[Self::Week, Self::Month, Self::All];
+
+    /// The materialized view holding the standings.
+    pub fn view(self) -> &'static str {
+        match self {
+            Self::Week => "weekly_leaderboard",
+            Self::Month => "monthly_leaderboard",
+            Self::All => "leaderboard",
+        }
+    }
+
+    /// Value of the `period` query parameter.
+    pub fn param(self) -> &'static str {
+        match self {
+            Self::Week => "week",
+            Self::Month => "month",
+            Self::All => "all",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Week => "This week",
+            Self::Month => "This month",
+            Self::All => "All time",
+        }
+    }
+}
+
+/// A user's place on a leaderboard. `experience` is the total for the all
+/// time leaderboard and the amount gained during the period otherwise.
+#[derive(Debug, FromRow)]
+pub struct Standing {
+    pub user_id:    i32,
+    pub rank:       i64,
+    pub experience: i64,
+}
+
+impl Standing {
+    /// One page of a leaderboard, and the number of pages.
+    pub async fn fetch_page(
+        conn: &PgPool,
+        period: LeaderboardPeriod,
+        page: i64,
+    ) -> Result<(Vec<Self>, i64), sqlx::Error> {
+        // View names come from `LeaderboardPeriod` and are safe to interpolate.
+        let view = period.view();
+        let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {view}"))
+            .fetch_one(conn)
+            .await?;
+        let last_page = (count.max(1) + LEADERBOARD_PER_PAGE - 1) / LEADERBOARD_PER_PAGE;
+        let page = page.clamp(1, last_page);
+        let standings = sqlx::query_as(&format!(
+            "SELECT user_id, rank, experience FROM {view} ORDER BY rank LIMIT $1 OFFSET $2"
+        ))
+        .bind(LEADERBOARD_PER_PAGE)
+        .bind((page - 1) * LEADERBOARD_PER_PAGE)
+        .fetch_all(conn)
+        .await?;
+        Ok((standings, last_page))
+    }
+}
+
+/// The reply with the most reactions in the past week.
+#[derive(Debug, FromRow, Serialize)]
+pub struct WeeklyHighlight {
+    pub reply_id:  i32,
+    pub thread_id: i32,
+    pub title:     String,
+    pub reactions: i64,
+}
+
+impl WeeklyHighlight {
+    /// The highlight is shown to everyone, so there is none while it is in a
+    /// thread with a mature tag.
+    pub async fn fetch(conn: impl PgExecutor<'_>) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT * FROM weekly_highlight
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM threads
+                    WHERE threads.id = weekly_highlight.thread_id AND has_mature_tag(threads.tags)
+                )
+            "#,
+        )
+        .fetch_optional(conn)
+        .await
+    }
+}
+
+/// Aggregates that anyone may see, for community sites to embed.
+#[derive(Debug, FromRow, Serialize)]
+pub struct PublicStats {
+    pub members:         i64,
+    /// Replies posted since midnight UTC
+    pub posts_today:     i64,
+    /// Threads with a reply in the past day
+    pub active_threads:  i64,
+    pub drops_this_week: i64,
+}
+
+impl PublicStats {
+    pub async fn fetch(conn: impl PgExecutor<'_>) -> Result<Self, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM public_stats")
+            .fetch_one(conn)
+            .await
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum PublicStatsError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+#[json]
+async fn fetch_public_stats(conn: Extension<PgPool>) -> Result<PublicStats, PublicStatsError> {
+    Ok(PublicStats::fetch(&*conn).await?)
+}
+
+get!(
+    "/api/v1/stats/public",
+    async fn public_stats(conn: Extension<PgPool>) -> Response {
+        let mut response = fetch_public_stats(conn).await.into_response();
+        if response.status().is_success() {
+            response.headers_mut().insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static(PUBLIC_STATS_CACHE_CONTROL),
+            );
+        }
+        response
+    }
+);
+
+/// When a view was last refreshed, if it ever was.
+pub async fn last_refreshed(
+    conn: impl PgExecutor<'_>,
+    view: &str,
+) -> Result<Option<NaiveDateTime>, sqlx::Error> {
+    sqlx::query_scalar("SELECT refreshed FROM view_refreshes WHERE name = $1")
+        .bind(view)
+        .fetch_optional(conn)
+        .await
+}
+
+/// Background task that periodically refreshes the materialized views.
+pub async fn refresh_views(conn: PgPool) {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        for view in VIEWS {
+            if let Err(err) = refresh_view(&conn, view).await {
+                tracing::error!("Failed to refresh {view}: {err}");
+            }
+        }
+    }
+}
+
+/// Refresh a view unless another instance has done so recently.
+async fn refresh_view(conn: &PgPool, view: &str) -> Result<(), sqlx::Error> {
+    let mut transaction = conn.begin().await?;
+
+    let now = Utc::now().naive_utc();
+    let stale_before = now - chrono::Duration::from_std(REFRESH_INTERVAL).unwrap();
+
+    // Claim the refresh. The row stays locked until the transaction ends, so
+    // instances starting at the same time do not refresh twice.
+    let claimed = sqlx::query(
+        r#"
+            INSERT INTO view_refreshes (name, refreshed) VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE SET refreshed = EXCLUDED.refreshed
+            WHERE view_refreshes.refreshed < $3
+        "#,
+    )
+    .bind(view)
+    .bind(now)
+    .bind(stale_before)
+    .execute(&mut transaction)
+    .await?
+    .rows_affected()
+        > 0;
+    if !claimed {
+        return Ok(());
+    }
+
+    // Views are refreshed concurrently so that reads are never blocked. The
+    // names come from `VIEWS` and are safe to interpolate.
+    sqlx::query(&format!("REFRESH MATERIALIZED VIEW CONCURRENTLY {view}"))
+        .execute(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
@@ -1,53 +1,78 @@
 //! Display threads
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use axum::{
-    extract::{
-        ws::{Message, WebSocketUpgrade},
-        Extension, Form, Path, Query,
-    },
-    response::Response,
-};
+use axum::extract::{Extension, Form, Path, Query};
 use chrono::{prelude::*, NaiveDateTime};
-use futures::stream::StreamExt;
+use futures::stream::{StreamExt, TryStreamExt};
 use marche_proc_macros::{json, ErrorCode};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgExecutor, PgPool};
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool, Type};
 use thiserror::Error;
 
 use crate::{
+    cluster::Cluster,
+    events::Event,
     get,
-    images::{Image, UploadImageError, MAXIMUM_FILE_SIZE},
+    images::{
+        Image, ImageCredits, ImageCreditsError, ImageLicense, UploadImageError, MAXIMUM_FILE_SIZE,
+    },
     items::{ItemDrop, ItemThumbnail},
-    post,
-    users::{ProfileStub, Role, User, MIN_LEVEL_TO_UPLOAD_PHOTOS},
+    limits::{Limit, Limits},
+    link_previews::{self, LinkPreview},
+    post, put,
+    revisions::ReplyRevision,
+    users::{
+        ExperienceSource, ProfileStub, ProfileStubs, Role, User, UserCache,
+        MIN_LEVEL_TO_UPLOAD_PHOTOS,
+    },
     MultipartForm, MultipartFormError,
 };
 
 #[derive(FromRow, Default, Debug, Serialize)]
 pub struct Thread {
     /// Id of the thread
-    pub id:          i32,
+    pub id:                i32,
     /// Id of the last post
-    pub last_post:   i32,
+    pub last_post:         i32,
     /// Title of the thread
-    pub title:       String,
+    pub title:             String,
     /// Tags given to this thread
-    pub tags:        Vec<i32>,
+    pub tags:              Vec<i32>,
     /// Number of replies to this thread, not including the first.
-    pub num_replies: i32,
+    pub num_replies:       i32,
+    /// Number of replies that are not hidden, not including the first. Kept
+    /// up to date by a trigger.
+    pub visible_replies:   i32,
+    /// Id of the last post that is not hidden, or of the first post if every
+    /// post is hidden. Kept up to date by a trigger.
+    pub last_visible_post: i32,
     /// Whether or not the thread is pinned
-    pub pinned:      bool,
+    pub pinned:            bool,
     /// Whether or not the thread is locked
-    pub locked:      bool,
+    pub locked:            bool,
     /// Whether or not the thread is hidden
-    pub hidden:      bool,
+    pub hidden:            bool,
+    /// When the thread is scheduled to be locked
+    pub locked_at:         Option<NaiveDateTime>,
+    /// When the thread is scheduled to be unpinned
+    pub pinned_until:      Option<NaiveDateTime>,
+    /// Minimum number of seconds between replies by the same user, or zero
+    /// if slow mode is disabled
+    pub slow_mode_seconds: i32,
+    /// Whether or not the thread is archived. Archived threads can still be
+    /// viewed but are left out of the index and cannot be replied to.
+    pub archived:          bool,
 }
 
+/// How often scheduled thread flag changes are applied.
+pub const SCHEDULED_FLAGS_INTERVAL: Duration = Duration::from_secs(60);
+
 impl Thread {
     pub async fn fetch(conn: &PgPool, id: i32) -> Result<Self, sqlx::Error> {
         sqlx::query_as("SELECT * FROM threads WHERE id = $1")
@@ -56,12 +81,491 @@ impl Thread {
             .await
     }
 
-    pub async fn fetch_optional(conn: &PgPool, id: i32) -> Result<Option<Self>, sqlx::Error> {
+    pub async fn fetch_optional(
+        conn: impl PgExecutor<'_>,
+        id: i32,
+    ) -> Result<Option<Self>, sqlx::Error> {
         sqlx::query_as("SELECT * FROM threads WHERE id = $1")
             .bind(id)
             .fetch_optional(conn)
             .await
     }
+
+    /// Whether the thread has a tag rated mature.
+    pub async fn is_mature(&self, conn: impl PgExecutor<'_>) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT has_mature_tag($1)")
+            .bind(&self.tags)
+            .fetch_one(conn)
+            .await
+    }
+
+    /// Whether a user created the thread or is one of its co-authors.
+    pub async fn is_author(
+        conn: impl PgExecutor<'_>,
+        thread_id: i32,
+        user_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+                SELECT
+                    EXISTS (SELECT 1 FROM thread_authors WHERE thread_id = $1 AND user_id = $2)
+                    OR EXISTS (
+                        SELECT 1 FROM replies
+                        WHERE id = (SELECT MIN(id) FROM replies WHERE thread_id = $1) AND author_id = $2
+                    )
+            "#,
+        )
+        .bind(thread_id)
+        .bind(user_id)
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Locks and unpins every thread whose scheduled deadline has passed, and
+    /// publishes every scheduled reply that is due.
+    pub async fn apply_schedules(conn: &PgPool) -> Result<(), sqlx::Error> {
+        let now = Utc::now().naive_utc();
+
+        sqlx::query("UPDATE threads SET locked = TRUE, locked_at = NULL WHERE locked_at <= $1")
+            .bind(now)
+            .execute(conn)
+            .await?;
+
+        sqlx::query(
+            "UPDATE threads SET pinned = FALSE, pinned_until = NULL WHERE pinned_until <= $1",
+        )
+        .bind(now)
+        .execute(conn)
+        .await?;
+
+        ScheduledReply::publish_due(conn).await?;
+
+        Ok(())
+    }
+}
+
+/// Background task that periodically applies scheduled thread locks and
+/// unpins, and publishes scheduled replies.
+pub async fn apply_scheduled_flags(conn: PgPool) {
+    let mut interval = tokio::time::interval(SCHEDULED_FLAGS_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = Thread::apply_schedules(&conn).await {
+            tracing::error!("Failed to apply scheduled thread flags: {err}");
+        }
+    }
+}
+
+/// Maximum number of co-authors a thread may have.
+pub const MAX_THREAD_CO_AUTHORS: i64 = 10;
+
+/// A co-author of a thread.
+#[derive(FromRow, Debug, Serialize)]
+pub struct ThreadAuthor {
+    pub user_id: i32,
+    pub name:    String,
+    pub added:   NaiveDateTime,
+}
+
+impl ThreadAuthor {
+    /// Co-authors of a thread, in the order they were added. The creator of
+    /// the thread is not included.
+    pub async fn fetch_for_thread(
+        conn: impl PgExecutor<'_>,
+        thread_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT thread_authors.user_id, users.display_name AS name, thread_authors.added
+                FROM thread_authors
+                JOIN users ON users.id = thread_authors.user_id
+                WHERE thread_authors.thread_id = $1
+                ORDER BY thread_authors.added ASC
+            "#,
+        )
+        .bind(thread_id)
+        .fetch_all(conn)
+        .await
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum ThreadAuthorsError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such thread")]
+    NoSuchThread,
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("The creator of a thread is already its author")]
+    AlreadyCreator,
+    #[error(
+        "Thread has too many co-authors (maximum {} allowed)",
+        MAX_THREAD_CO_AUTHORS
+    )]
+    TooManyCoAuthors,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+#[derive(Deserialize)]
+pub struct AddThreadAuthorForm {
+    user_id: i32,
+}
+
+get!(
+    "/thread/:thread_id/authors",
+    #[json]
+    async fn thread_authors(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(thread_id): Path<i32>,
+    ) -> Result<Vec<ThreadAuthor>, ThreadAuthorsError> {
+        let thread = Thread::fetch_optional(&*conn, thread_id)
+            .await?
+            .ok_or(ThreadAuthorsError::NoSuchThread)?;
+        if thread.hidden && user.role < Role::Moderator {
+            return Err(ThreadAuthorsError::NoSuchThread);
+        }
+        Ok(ThreadAuthor::fetch_for_thread(&*conn, thread_id).await?)
+    }
+);
+
+post!(
+    "/thread/:thread_id/authors",
+    #[json]
+    async fn add_thread_author(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(thread_id): Path<i32>,
+        Form(AddThreadAuthorForm { user_id }): Form<AddThreadAuthorForm>,
+    ) -> Result<Vec<ThreadAuthor>, ThreadAuthorsError> {
+        let first = Reply::fetch_first(&*conn, thread_id)
+            .await
+            .map_err(|_| ThreadAuthorsError::NoSuchThread)?;
+        // Only the creator picks co-authors, so that a co-author cannot hand
+        // the thread to someone else.
+        if first.author_id != user.id && user.role < Role::Moderator {
+            return Err(ThreadAuthorsError::Unauthorized);
+        }
+        if first.author_id == user_id {
+            return Err(ThreadAuthorsError::AlreadyCreator);
+        }
+        User::fetch_optional(&*conn, user_id)
+            .await?
+            .filter(|co_author| co_author.deleted.is_none())
+            .ok_or(ThreadAuthorsError::NoSuchUser)?;
+
+        let mut transaction = conn.begin().await?;
+        // The thread is locked so that concurrent additions cannot exceed the
+        // maximum.
+        sqlx::query("SELECT id FROM threads WHERE id = $1 FOR UPDATE")
+            .bind(thread_id)
+            .execute(&mut *transaction)
+            .await?;
+        let co_authors: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM thread_authors WHERE thread_id = $1")
+                .bind(thread_id)
+                .fetch_one(&mut *transaction)
+                .await?;
+        if co_authors >= MAX_THREAD_CO_AUTHORS {
+            return Err(ThreadAuthorsError::TooManyCoAuthors);
+        }
+        sqlx::query(
+            r#"
+                INSERT INTO thread_authors (thread_id, user_id, added)
+                VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(thread_id)
+        .bind(user_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(ThreadAuthor::fetch_for_thread(&*conn, thread_id).await?)
+    }
+);
+
+post!(
+    "/thread/:thread_id/authors/:user_id/remove",
+    #[json]
+    async fn remove_thread_author(
+        conn: Extension<PgPool>,
+        user: User,
+        Path((thread_id, user_id)): Path<(i32, i32)>,
+    ) -> Result<Vec<ThreadAuthor>, ThreadAuthorsError> {
+        let first = Reply::fetch_first(&*conn, thread_id)
+            .await
+            .map_err(|_| ThreadAuthorsError::NoSuchThread)?;
+        // Co-authors may step down themselves.
+        if first.author_id != user.id && user.id != user_id && user.role < Role::Moderator {
+            return Err(ThreadAuthorsError::Unauthorized);
+        }
+        sqlx::query("DELETE FROM thread_authors WHERE thread_id = $1 AND user_id = $2")
+            .bind(thread_id)
+            .bind(user_id)
+            .execute(&*conn)
+            .await?;
+
+        Ok(ThreadAuthor::fetch_for_thread(&*conn, thread_id).await?)
+    }
+);
+
+/// A reply queued by a moderator to be published at a set time, such as patch
+/// notes in a locked announcement thread.
+#[derive(FromRow, Debug, Serialize)]
+pub struct ScheduledReply {
+    pub id:         i32,
+    pub thread_id:  i32,
+    pub author_id:  i32,
+    pub body:       String,
+    pub publish_at: NaiveDateTime,
+    pub created:    NaiveDateTime,
+}
+
+impl ScheduledReply {
+    /// Every reply scheduled for a thread, soonest first.
+    pub async fn fetch_for_thread(
+        conn: impl PgExecutor<'_>,
+        thread_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT * FROM scheduled_replies WHERE thread_id = $1 ORDER BY publish_at ASC",
+        )
+        .bind(thread_id)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Publish every reply whose time has come, each in its own transaction
+    /// so that one that fails does not hold back the others. Replies are
+    /// published even if their thread is locked; replies to threads that no
+    /// longer exist, or whose author is no longer a moderator in good
+    /// standing, are dropped.
+    pub async fn publish_due(conn: &PgPool) -> Result<(), sqlx::Error> {
+        let due: Vec<i32> =
+            sqlx::query_scalar("SELECT id FROM scheduled_replies WHERE publish_at <= $1")
+                .bind(Utc::now().naive_utc())
+                .fetch_all(conn)
+                .await?;
+
+        for id in due {
+            if let Err(err) = Self::publish(conn, id).await {
+                tracing::error!("Failed to publish scheduled reply {id}: {err}");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn publish(conn: &PgPool, id: i32) -> Result<(), sqlx::Error> {
+        let mut transaction = conn.begin().await?;
+        // Deleting the row claims it, so no reply is published twice.
+        let Some(scheduled): Option<Self> =
+            sqlx::query_as("DELETE FROM scheduled_replies WHERE id = $1 RETURNING *")
+                .bind(id)
+                .fetch_optional(&mut *transaction)
+                .await?
+        else {
+            return Ok(());
+        };
+
+        let thread_exists = Thread::fetch_optional(&mut *transaction, scheduled.thread_id)
+            .await?
+            .is_some();
+        let may_publish = User::fetch_optional(&mut *transaction, scheduled.author_id)
+            .await?
+            .map_or(false, |author| {
+                author.role >= Role::Moderator && author.deleted.is_none() && !author.is_banned()
+            });
+        if thread_exists && may_publish {
+            NewReply {
+                author_id:   scheduled.author_id,
+                thread_id:   scheduled.thread_id,
+                post_date:   scheduled.publish_at,
+                body:        &scheduled.body,
+                reward:      None,
+                image:       None,
+                thumbnail:   None,
+                filename:    String::new(),
+                in_reply_to: None,
+                credits:     ImageCredits::default(),
+            }
+            .post(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleReplyForm {
+    body:       String,
+    publish_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum ScheduleReplyError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such thread")]
+    NoSuchThread,
+    #[error("No such scheduled reply")]
+    NoSuchScheduledReply,
+    #[error("Reply cannot be empty")]
+    ReplyIsEmpty,
+    #[error("Reply must be scheduled for the future")]
+    PublishTimeInPast,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/thread/:thread_id/scheduled_replies",
+    #[json]
+    async fn scheduled_replies(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(thread_id): Path<i32>,
+    ) -> Result<Vec<ScheduledReply>, ScheduleReplyError> {
+        if user.role < Role::Moderator {
+            return Err(ScheduleReplyError::Unauthorized);
+        }
+        Ok(ScheduledReply::fetch_for_thread(&*conn, thread_id).await?)
+    }
+);
+
+post!(
+    "/thread/:thread_id/scheduled_replies",
+    #[json]
+    async fn schedule_reply(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(thread_id): Path<i32>,
+        Form(ScheduleReplyForm { body, publish_at }): Form<ScheduleReplyForm>,
+    ) -> Result<ScheduledReply, ScheduleReplyError> {
+        if user.role < Role::Moderator {
+            return Err(ScheduleReplyError::Unauthorized);
+        }
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(ScheduleReplyError::ReplyIsEmpty);
+        }
+        let now = Utc::now().naive_utc();
+        if publish_at <= now {
+            return Err(ScheduleReplyError::PublishTimeInPast);
+        }
+        if Thread::fetch_optional(&*conn, thread_id).await?.is_none() {
+            return Err(ScheduleReplyError::NoSuchThread);
+        }
+
+        Ok(sqlx::query_as(
+            r#"
+                INSERT INTO scheduled_replies (thread_id, author_id, body, publish_at, created)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+            "#,
+        )
+        .bind(thread_id)
+        .bind(user.id)
+        .bind(body)
+        .bind(publish_at)
+        .bind(now)
+        .fetch_one(&*conn)
+        .await?)
+    }
+);
+
+post!(
+    "/scheduled_replies/:scheduled_id/cancel",
+    #[json]
+    async fn cancel_scheduled_reply(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(scheduled_id): Path<i32>,
+    ) -> Result<(), ScheduleReplyError> {
+        if user.role < Role::Moderator {
+            return Err(ScheduleReplyError::Unauthorized);
+        }
+        let cancelled = sqlx::query("DELETE FROM scheduled_replies WHERE id = $1")
+            .bind(scheduled_id)
+            .execute(&*conn)
+            .await?
+            .rows_affected();
+        if cancelled == 0 {
+            return Err(ScheduleReplyError::NoSuchScheduledReply);
+        }
+        Ok(())
+    }
+);
+
+/// Record of a thread that was deleted or merged into another thread, shown
+/// at the thread's old URL in its place.
+#[derive(FromRow, Debug, Serialize)]
+pub struct ThreadTombstone {
+    /// Id of the removed thread
+    pub thread_id:    i32,
+    /// Title of the removed thread
+    pub title:        String,
+    /// Whether the thread was hidden when it was removed
+    pub hidden:       bool,
+    /// Thread that the replies were moved to, if the thread was merged
+    pub merged_into:  Option<i32>,
+    /// Moderator that removed the thread
+    pub moderator_id: i32,
+    /// When the thread was removed
+    pub removed:      NaiveDateTime,
+}
+
+impl ThreadTombstone {
+    pub async fn fetch_optional(
+        conn: impl PgExecutor<'_>,
+        thread_id: i32,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM thread_tombstones WHERE thread_id = $1")
+            .bind(thread_id)
+            .fetch_optional(conn)
+            .await
+    }
+
+    /// Record that a thread has been removed. Should be called with the
+    /// transaction that removes it.
+    async fn record(
+        conn: impl PgExecutor<'_>,
+        thread: &Thread,
+        moderator_id: i32,
+        merged_into: Option<i32>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+                INSERT INTO thread_tombstones
+                    (thread_id, title, hidden, merged_into, moderator_id, removed)
+                VALUES
+                    ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(thread.id)
+        .bind(&thread.title)
+        .bind(thread.hidden)
+        .bind(merged_into)
+        .bind(moderator_id)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
 }
 
 #[derive(Error, Serialize, Debug, ErrorCode)]
@@ -90,14 +594,14 @@ post!(
             return Err(DeleteThreadError::Unauthorized);
         }
 
-        // Fetch the thread title for logging purposes
-        let thread_title = Thread::fetch_optional(&*conn, dead_thread_id)
+        let thread = Thread::fetch_optional(&*conn, dead_thread_id)
             .await?
-            .ok_or(DeleteThreadError::NoSuchThread)?
-            .title;
+            .ok_or(DeleteThreadError::NoSuchThread)?;
 
         let mut transaction = conn.begin().await?;
 
+        ThreadTombstone::record(&mut *transaction, &thread, user.id, None).await?;
+
         // Delete the thread:
         sqlx::query("DELETE FROM threads WHERE id = $1")
             .bind(dead_thread_id)
@@ -113,8 +617,98 @@ post!(
         transaction.commit().await?;
 
         tracing::info!(
-            "User `{}` has deleted thread {dead_thread_id} titled: `{thread_title}`",
-            user.name
+            "User `{}` has deleted thread {dead_thread_id} titled: `{}`",
+            user.name,
+            thread.title
+        );
+
+        Ok(())
+    }
+);
+
+#[derive(Debug, Deserialize)]
+pub struct MergeThreadForm {
+    into: i32,
+}
+
+#[derive(Error, Serialize, Debug, ErrorCode)]
+pub enum MergeThreadError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such thread exists")]
+    NoSuchThread,
+    #[error("A thread cannot be merged into itself")]
+    CannotMergeIntoSelf,
+    #[error("Internal database error {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/merge_thread/:thread_id",
+    #[json]
+    pub async fn merge_thread(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(thread_id): Path<i32>,
+        Form(MergeThreadForm { into }): Form<MergeThreadForm>,
+    ) -> Result<(), MergeThreadError> {
+        if user.role < Role::Moderator {
+            return Err(MergeThreadError::Unauthorized);
+        }
+
+        if thread_id == into {
+            return Err(MergeThreadError::CannotMergeIntoSelf);
+        }
+
+        let mut transaction = conn.begin().await?;
+
+        let thread = Thread::fetch_optional(&mut *transaction, thread_id)
+            .await?
+            .ok_or(MergeThreadError::NoSuchThread)?;
+        if Thread::fetch_optional(&mut *transaction, into)
+            .await?
+            .is_none()
+        {
+            return Err(MergeThreadError::NoSuchThread);
+        }
+
+        // Move the replies over, including the first post of the merged
+        // thread, and update the destination to account for them.
+        sqlx::query("UPDATE replies SET thread_id = $1 WHERE thread_id = $2")
+            .bind(into)
+            .bind(thread_id)
+            .execute(&mut transaction)
+            .await?;
+
+        sqlx::query(
+            r#"
+                UPDATE threads SET
+                    num_replies = (SELECT COUNT(*) - 1 FROM replies WHERE thread_id = $1),
+                    last_post = (SELECT MAX(id) FROM replies WHERE thread_id = $1)
+                WHERE id = $1
+            "#,
+        )
+        .bind(into)
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query("DELETE FROM threads WHERE id = $1")
+            .bind(thread_id)
+            .execute(&mut transaction)
+            .await?;
+
+        ThreadTombstone::record(&mut *transaction, &thread, user.id, Some(into)).await?;
+
+        transaction.commit().await?;
+
+        tracing::info!(
+            "User `{}` has merged thread {thread_id} titled: `{}` into thread {into}",
+            user.name,
+            thread.title
         );
 
         Ok(())
@@ -123,21 +717,29 @@ post!(
 
 #[derive(Debug, Deserialize)]
 pub struct ThreadForm {
-    title: String,
-    tags:  String,
-    body:  String,
+    title:       String,
+    tags:        String,
+    body:        String,
+    /// License of the attached image, if any
+    #[serde(default)]
+    license:     String,
+    /// Who to credit for the attached image, if any
+    #[serde(default)]
+    attribution: String,
 }
 
 #[derive(Debug, Serialize, Error, ErrorCode)]
 pub enum SubmitThreadError {
     #[error("Title or body is empty")]
     TitleOrBodyIsEmpty,
-    #[error("There is a tag that exceeds the maximum length ({MAX_TAG_LEN} characters")]
-    TagTooLong,
-    #[error("There are too many tags (maximum {MAX_NUM_TAGS} allowed)")]
-    TooManyTags,
+    #[error("There is a tag that exceeds the maximum length ({max} characters)")]
+    TagTooLong { max: usize },
+    #[error("There are too many tags (maximum {max} allowed)")]
+    TooManyTags { max: usize },
     #[error("Error uploading image: {0}")]
     UploadImageError(#[from] UploadImageError),
+    #[error("{0}")]
+    ImageCreditsError(#[from] ImageCreditsError),
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
@@ -150,14 +752,13 @@ pub enum SubmitThreadError {
     MultipartFormError(#[from] MultipartFormError),
 }
 
-pub const MAX_TAG_LEN: usize = 16;
-pub const MAX_NUM_TAGS: usize = 6;
-
 post! {
     "/thread",
     #[json]
     async fn new_thread(
         conn: Extension<PgPool>,
+        cluster: Extension<Cluster>,
+        limits: Extension<Limits>,
         user: User,
         form: Result<MultipartForm<ThreadForm, MAXIMUM_FILE_SIZE>, MultipartFormError>,
     ) -> Result<Thread, SubmitThreadError> {
@@ -172,30 +773,33 @@ post! {
 
         let post_date = Utc::now().naive_utc();
 
-        let (image, thumbnail, filename) = if let Some(file) = file {
+        let (image, thumbnail, filename, credits) = if let Some(file) = file {
             if !user.can_post_photos() {
                 return Err(SubmitThreadError::NotAllowedToUploadPictures);
             }
+            let credits = ImageCredits::parse(&thread.license, &thread.attribution)?;
             let Image { filename: image, thumbnail } = Image::upload_image(file.bytes).await?;
-            (Some(image), thumbnail, file.name)
+            (Some(image), thumbnail, file.name, credits)
         } else {
-            (None, None, String::new())
+            (None, None, String::new(), ImageCredits::default())
         };
 
+        let max_tag_len = limits.get(Limit::MaxTagLength);
         let mut tags = Vec::new();
         for tag in parse_tag_list(&thread.tags) {
             let tag = tag.trim();
             if tag.is_empty() {
                 continue;
             }
-            if tag.len() > MAX_TAG_LEN {
-                return Err(SubmitThreadError::TagTooLong);
+            if tag.len() > max_tag_len {
+                return Err(SubmitThreadError::TagTooLong { max: max_tag_len });
             }
             tags.push(tag);
         }
 
-        if tags.len() > MAX_NUM_TAGS {
-            return Err(SubmitThreadError::TooManyTags);
+        let max_num_tags = limits.get(Limit::MaxNumTags);
+        if tags.len() > max_num_tags {
+            return Err(SubmitThreadError::TooManyTags { max: max_num_tags });
         }
 
         let mut transaction = conn.begin().await?;
@@ -222,16 +826,17 @@ post! {
         .fetch_one(&mut *transaction)
         .await?;
 
-        let item_drop = ItemDrop::drop(&mut transaction, &user)
+        let item_drop = ItemDrop::drop(&mut transaction, &cluster, &user)
             .await?
             .map(ItemDrop::to_id);
 
         let reply: Reply = sqlx::query_as(
             r#"
                  INSERT INTO replies
-                     (author_id, thread_id, post_date, body, reward, image, thumbnail, filename, reactions)
+                     (author_id, thread_id, post_date, body, reward, image, thumbnail, filename, reactions,
+                      image_license, image_attribution)
                  VALUES
-                     ($1, $2, $3, $4, $5, $6, $7, $8, '{}')
+                     ($1, $2, $3, $4, $5, $6, $7, $8, '{}', $9, $10)
                  RETURNING *
             "#
         )
@@ -243,14 +848,27 @@ post! {
         .bind(image)
         .bind(thumbnail)
         .bind(filename)
+        .bind(credits.license.map(ImageLicense::slug))
+        .bind(credits.attribution)
         .fetch_one(&mut *transaction)
         .await?;
 
-        let thread = sqlx::query_as("UPDATE threads SET last_post = $1 WHERE id = $2 RETURNING *")
-            .bind(reply.id)
-            .bind(thread.id)
-            .fetch_one(&mut *transaction)
-            .await?;
+        let thread: Thread =
+            sqlx::query_as("UPDATE threads SET last_post = $1 WHERE id = $2 RETURNING *")
+                .bind(reply.id)
+                .bind(thread.id)
+                .fetch_one(&mut *transaction)
+                .await?;
+
+        Draft::clear(&mut *transaction, user.id, NEW_THREAD_DRAFT).await?;
+
+        Event::ReplyCreated {
+            reply_id:  reply.id,
+            thread_id: thread.id,
+            author_id: user.id,
+        }
+        .publish(&mut *transaction)
+        .await?;
 
         transaction.commit().await?;
 
@@ -260,9 +878,16 @@ post! {
 
 #[derive(Deserialize)]
 struct UpdateThread {
-    locked: Option<bool>,
-    pinned: Option<bool>,
-    hidden: Option<bool>,
+    locked:            Option<bool>,
+    pinned:            Option<bool>,
+    hidden:            Option<bool>,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    locked_at:         Option<NaiveDateTime>,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    pinned_until:      Option<NaiveDateTime>,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    slow_mode_seconds: Option<i32>,
+    archived:          Option<bool>,
 }
 
 #[derive(Serialize, Error, Debug, ErrorCode)]
@@ -288,13 +913,24 @@ post!(
             locked,
             pinned,
             hidden,
+            locked_at,
+            pinned_until,
+            slow_mode_seconds,
+            archived,
         }): Query<UpdateThread>,
     ) -> Result<(), UpdateThreadError> {
         if user.role < Role::Moderator {
             return Err(UpdateThreadError::Unauthorized);
         }
 
-        if locked.is_none() && pinned.is_none() && hidden.is_none() {
+        if locked.is_none()
+            && pinned.is_none()
+            && hidden.is_none()
+            && locked_at.is_none()
+            && pinned_until.is_none()
+            && slow_mode_seconds.is_none()
+            && archived.is_none()
+        {
             return Ok(());
         }
 
@@ -324,16 +960,62 @@ post!(
                 .await?;
         }
 
+        if let Some(locked_at) = locked_at {
+            sqlx::query("UPDATE threads SET locked_at = $1 WHERE id = $2")
+                .bind(locked_at)
+                .bind(thread_id)
+                .execute(&*conn)
+                .await?;
+        }
+
+        if let Some(pinned_until) = pinned_until {
+            sqlx::query("UPDATE threads SET pinned_until = $1 WHERE id = $2")
+                .bind(pinned_until)
+                .bind(thread_id)
+                .execute(&*conn)
+                .await?;
+        }
+
+        if let Some(slow_mode_seconds) = slow_mode_seconds {
+            sqlx::query("UPDATE threads SET slow_mode_seconds = $1 WHERE id = $2")
+                .bind(slow_mode_seconds.max(0))
+                .bind(thread_id)
+                .execute(&*conn)
+                .await?;
+        }
+
+        if let Some(archived) = archived {
+            sqlx::query("UPDATE threads SET archived = $1 WHERE id = $2")
+                .bind(archived)
+                .bind(thread_id)
+                .execute(&*conn)
+                .await?;
+        }
+
         Ok(())
     }
 );
 
+/// Who a tag is suitable for. Threads with a mature tag are only listed to
+/// users who have opted in to seeing them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "content_rating")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ContentRating {
+    General,
+    Mature,
+}
+
 #[derive(Debug, FromRow, Serialize, Clone)]
 pub struct Tag {
     pub id:         i32,
     pub name:       String,
     /// Number of posts that have been tagged with this tag.
     pub num_tagged: i32,
+    /// The canonical tag that this tag has been merged into, if any.
+    pub alias_of:   Option<i32>,
+    pub rating:     ContentRating,
 }
 
 impl Tag {
@@ -343,9 +1025,11 @@ impl Tag {
 
     /// Returns the most popular tags.
     pub async fn popular(conn: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
-        sqlx::query_as("SELECT * FROM tags ORDER BY num_tagged DESC LIMIT 10")
-            .fetch_all(conn)
-            .await
+        sqlx::query_as(
+            "SELECT * FROM tags WHERE alias_of IS NULL ORDER BY num_tagged DESC LIMIT 10",
+        )
+        .fetch_all(conn)
+        .await
     }
 
     pub async fn fetch_from_id(conn: &PgPool, id: i32) -> Result<Option<Self>, sqlx::Error> {
@@ -355,6 +1039,7 @@ impl Tag {
             .await
     }
 
+    /// Fetches a tag by name, resolving aliases to their canonical tag.
     pub async fn fetch_from_str(conn: &PgPool, tag: &str) -> Result<Option<Self>, sqlx::Error> {
         let tag_name = clean_tag_name(tag);
 
@@ -362,14 +1047,21 @@ impl Tag {
             return Ok(None);
         }
 
-        sqlx::query_as("SELECT * FROM tags WHERE name = $1")
-            .bind(tag_name)
-            .fetch_optional(conn)
-            .await
+        sqlx::query_as(
+            r#"
+                SELECT canonical.* FROM tags
+                JOIN tags canonical ON canonical.id = COALESCE(tags.alias_of, tags.id)
+                WHERE tags.name = $1
+            "#,
+        )
+        .bind(tag_name)
+        .fetch_optional(conn)
+        .await
     }
 
     /// Fetches a tag, creating it if it doesn't already exist. num_tagged is
-    /// incremented or set to one.
+    /// incremented or set to one. Aliases resolve to, and increment, their
+    /// canonical tag.
     ///
     /// It's kind of a weird interface, I'm open to suggestions.
     ///
@@ -386,10 +1078,17 @@ impl Tag {
 
         sqlx::query_as(
             r#"
-                INSERT INTO tags (name)
-                VALUES ($1)
-                ON CONFLICT (name) DO UPDATE SET num_tagged = tags.num_tagged + 1
-                RETURNING *
+                WITH canonical AS (
+                    UPDATE tags SET num_tagged = num_tagged + 1
+                    WHERE id = (SELECT alias_of FROM tags WHERE name = $1)
+                    RETURNING *
+                ), inserted AS (
+                    INSERT INTO tags (name)
+                    SELECT $1 WHERE NOT EXISTS (SELECT 1 FROM canonical)
+                    ON CONFLICT (name) DO UPDATE SET num_tagged = tags.num_tagged + 1
+                    RETURNING *
+                )
+                SELECT * FROM canonical UNION ALL SELECT * FROM inserted
             "#,
         )
         .bind(tag_name)
@@ -398,6 +1097,145 @@ impl Tag {
     }
 }
 
+#[derive(Deserialize)]
+pub struct MergeTag {
+    into: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum MergeTagError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such tag exists")]
+    NoSuchTag,
+    #[error("A tag cannot be merged into itself")]
+    SameTag,
+    #[error("This tag has already been merged into another")]
+    AlreadyMerged,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/merge_tag/:tag_id",
+    #[json]
+    async fn merge_tag(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(tag_id): Path<i32>,
+        Query(MergeTag { into }): Query<MergeTag>,
+    ) -> Result<Tag, MergeTagError> {
+        if user.role < Role::Admin {
+            return Err(MergeTagError::Unauthorized);
+        }
+
+        let alias = Tag::fetch_from_id(&conn, tag_id)
+            .await?
+            .ok_or(MergeTagError::NoSuchTag)?;
+        let canonical = Tag::fetch_from_str(&conn, &into)
+            .await?
+            .ok_or(MergeTagError::NoSuchTag)?;
+        if alias.alias_of.is_some() {
+            return Err(MergeTagError::AlreadyMerged);
+        }
+        if alias.id == canonical.id {
+            return Err(MergeTagError::SameTag);
+        }
+
+        let mut transaction = conn.begin().await?;
+
+        // Point the tag, and anything already aliased to it, at the canonical
+        // tag so that aliases never chain.
+        sqlx::query("UPDATE tags SET alias_of = $1, num_tagged = 0 WHERE id = $2 OR alias_of = $2")
+            .bind(canonical.id)
+            .bind(alias.id)
+            .execute(&mut transaction)
+            .await?;
+
+        // Replace the alias in every thread, keeping the first occurrence if
+        // the thread was already tagged with the canonical tag.
+        sqlx::query(
+            r#"
+                UPDATE threads SET tags = ARRAY(
+                    SELECT tag FROM unnest(array_replace(tags, $1, $2))
+                        WITH ORDINALITY AS t(tag, i)
+                    GROUP BY tag
+                    ORDER BY MIN(i)
+                )
+                WHERE tags @> ARRAY[$1]
+            "#,
+        )
+        .bind(alias.id)
+        .bind(canonical.id)
+        .execute(&mut transaction)
+        .await?;
+
+        let canonical = sqlx::query_as(
+            "UPDATE tags SET num_tagged = num_tagged + $1 WHERE id = $2 RETURNING *",
+        )
+        .bind(alias.num_tagged)
+        .bind(canonical.id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(canonical)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct RateTagForm {
+    rating: ContentRating,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum RateTagError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such tag exists")]
+    NoSuchTag,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/rate_tag/:tag_id",
+    #[json]
+    async fn rate_tag(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(tag_id): Path<i32>,
+        Form(RateTagForm { rating }): Form<RateTagForm>,
+    ) -> Result<Tag, RateTagError> {
+        if user.role < Role::Admin {
+            return Err(RateTagError::Unauthorized);
+        }
+        // Threads are only ever tagged with canonical tags, so rating an
+        // alias would have no effect.
+        sqlx::query_as(
+            r#"
+                UPDATE tags SET rating = $1
+                WHERE id = $2 AND alias_of IS NULL
+                RETURNING *
+            "#,
+        )
+        .bind(rating)
+        .bind(tag_id)
+        .fetch_optional(&*conn)
+        .await?
+        .ok_or(RateTagError::NoSuchTag)
+    }
+);
+
 fn clean_tag_name(name: &str) -> String {
     name.trim().to_lowercase()
 }
@@ -449,33 +1287,188 @@ impl Tags {
     }
 }
 
+pub const TAGS_PER_PAGE: i64 = 100;
+pub const MAX_TAG_SUGGESTIONS: i64 = 10;
+
+#[derive(Deserialize)]
+pub struct TagsParams {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct TagPage {
+    tags:      Vec<Tag>,
+    page:      i64,
+    last_page: i64,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum TagsError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/tags",
+    #[json]
+    async fn browse_tags(
+        conn: Extension<PgPool>,
+        _user: User,
+        Query(TagsParams { page }): Query<TagsParams>,
+    ) -> Result<TagPage, TagsError> {
+        let num_tags: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tags WHERE alias_of IS NULL")
+            .fetch_one(&*conn)
+            .await?;
+        let last_page = (num_tags.max(1) + TAGS_PER_PAGE - 1) / TAGS_PER_PAGE;
+        let page = page.unwrap_or(1).clamp(1, last_page);
+
+        let tags = sqlx::query_as(
+            r#"
+                SELECT * FROM tags
+                WHERE alias_of IS NULL
+                ORDER BY num_tagged DESC, name ASC
+                LIMIT $1 OFFSET $2
+            "#,
+        )
+        .bind(TAGS_PER_PAGE)
+        .bind((page - 1) * TAGS_PER_PAGE)
+        .fetch_all(&*conn)
+        .await?;
+
+        Ok(TagPage {
+            tags,
+            page,
+            last_page,
+        })
+    }
+);
+
+#[derive(Deserialize)]
+pub struct SuggestTags {
+    #[serde(default)]
+    q: String,
+}
+
+get!(
+    "/tags/suggest",
+    #[json]
+    async fn suggest_tags(
+        conn: Extension<PgPool>,
+        _user: User,
+        Query(SuggestTags { q }): Query<SuggestTags>,
+    ) -> Result<Vec<Tag>, TagsError> {
+        let prefix = clean_tag_name(&q);
+        if prefix.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Escape the LIKE wildcards so that they match literally.
+        let pattern = prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+
+        Ok(sqlx::query_as(
+            r#"
+                SELECT * FROM tags
+                WHERE id IN (
+                    SELECT COALESCE(alias_of, id) FROM tags
+                    WHERE name LIKE $1 || '%'
+                )
+                ORDER BY num_tagged DESC, name ASC
+                LIMIT $2
+            "#,
+        )
+        .bind(pattern)
+        .bind(MAX_TAG_SUGGESTIONS)
+        .fetch_all(&*conn)
+        .await?)
+    }
+);
+
 #[derive(FromRow, Debug, Serialize, Deserialize)]
 pub struct Reply {
     /// Id of the reply
-    pub id:        i32,
+    pub id:                i32,
     /// Id of the author
-    pub author_id: i32,
+    pub author_id:         i32,
     /// Id of the thread
-    pub thread_id: i32,
+    pub thread_id:         i32,
     /// Date of posting
-    pub post_date: NaiveDateTime,
+    pub post_date:         NaiveDateTime,
     /// Body of the reply
-    pub body:      String,
+    pub body:              String,
     /// Any item that was rewarded for this post
-    pub reward:    Option<i32>,
+    pub reward:            Option<i32>,
     /// Reactions attached to this post
-    pub reactions: Vec<i32>,
+    pub reactions:         Vec<i32>,
     /// Image associated with this post
-    pub image:     Option<String>,
+    pub image:             Option<String>,
     /// Thumbnail associated with this post's image
-    pub thumbnail: Option<String>,
+    pub thumbnail:         Option<String>,
     /// Filename associated with the image
-    pub filename:  String,
+    pub filename:          String,
+    /// License the image is shared under, as an `ImageLicense` slug
+    pub image_license:     Option<String>,
+    /// Who to credit for the image
+    pub image_attribution: Option<String>,
     /// Whether or not the thread is hidden
-    pub hidden:    bool,
+    pub hidden:            bool,
+    /// Whether or not the reply is pinned to the top of the thread
+    pub pinned:            bool,
+    /// Reply, possibly in another thread, that this reply responds to
+    pub in_reply_to:       Option<i32>,
+    /// Whether the reply is a wiki post, which users of a high enough level
+    /// may edit
+    pub wiki:              bool,
 }
 
 impl Reply {
+    /// Whether a user may edit the body of the reply. Besides its author and
+    /// moderators, the co-authors of a thread may edit its first post, and
+    /// users of a high enough level may edit wiki posts.
+    pub async fn editable_by(
+        &self,
+        conn: &PgPool,
+        user: &User,
+        limits: &Limits,
+    ) -> Result<bool, sqlx::Error> {
+        if self.author_id == user.id || user.role >= Role::Moderator {
+            return Ok(true);
+        }
+        if self.wiki && user.level() as usize >= limits.get(Limit::MinLevelForWikiEdits) {
+            return Ok(true);
+        }
+        Ok(
+            Reply::fetch_first(conn, self.thread_id).await?.id == self.id
+                && Thread::is_author(conn, self.thread_id, user.id).await?,
+        )
+    }
+
+    /// Whether the reply is in a thread with a tag rated mature.
+    pub async fn is_mature(&self, conn: impl PgExecutor<'_>) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT has_mature_tag(tags) FROM threads WHERE id = $1")
+            .bind(self.thread_id)
+            .fetch_one(conn)
+            .await
+    }
+
+    /// Every reply a user has posted, oldest first.
+    pub async fn fetch_by_author(
+        conn: impl PgExecutor<'_>,
+        author_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM replies WHERE author_id = $1 ORDER BY id ASC")
+            .bind(author_id)
+            .fetch_all(conn)
+            .await
+    }
+
     pub async fn fetch(conn: &PgPool, id: i32) -> Result<Self, sqlx::Error> {
         sqlx::query_as("SELECT * FROM replies WHERE id = $1")
             .bind(id)
@@ -489,8 +1482,87 @@ impl Reply {
             .fetch_optional(conn)
             .await
     }
+
+    /// Fetches the first reply of a thread, i.e. the original post.
+    pub async fn fetch_first(conn: &PgPool, thread_id: i32) -> Result<Self, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM replies WHERE thread_id = $1 ORDER BY id ASC LIMIT 1")
+            .bind(thread_id)
+            .fetch_one(conn)
+            .await
+    }
+
+    /// First replies of the threads of the given replies that a user is a
+    /// co-author of, and may therefore edit.
+    pub async fn first_of_co_authored(
+        conn: &PgPool,
+        user_id: i32,
+        replies: &[Reply],
+    ) -> Result<HashSet<i32>, sqlx::Error> {
+        let thread_ids = replies
+            .iter()
+            .map(|reply| reply.thread_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        sqlx::query_scalar::<_, i32>(
+            r#"
+                SELECT MIN(replies.id) FROM replies
+                JOIN thread_authors ON thread_authors.thread_id = replies.thread_id
+                WHERE thread_authors.user_id = $1 AND replies.thread_id = ANY($2)
+                GROUP BY replies.thread_id
+            "#,
+        )
+        .bind(user_id)
+        .bind(thread_ids)
+        .fetch(conn)
+        .try_collect()
+        .await
+    }
+
+    /// Replace the body of the reply, recording the edit in its revision
+    /// history.
+    pub async fn edit(&self, conn: &PgPool, editor_id: i32, body: &str) -> Result<(), sqlx::Error> {
+        let mut transaction = conn.begin().await?;
+        ReplyRevision::record(&mut transaction, self, editor_id, body).await?;
+        sqlx::query("UPDATE replies SET body = $1 WHERE id = $2")
+            .bind(body)
+            .bind(self.id)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        link_previews::queue(conn, body).await?;
+
+        Ok(())
+    }
+
+    /// Of the given replies, those that have at least one visible response.
+    pub async fn with_responses(
+        conn: &PgPool,
+        reply_ids: &[i32],
+    ) -> Result<HashSet<i32>, sqlx::Error> {
+        sqlx::query_scalar::<_, i32>(
+            "SELECT DISTINCT in_reply_to FROM replies WHERE in_reply_to = ANY($1) AND NOT hidden",
+        )
+        .bind(reply_ids)
+        .fetch(conn)
+        .try_collect()
+        .await
+    }
 }
 
+/// Order in which the replies of a thread are displayed: the original post,
+/// then pinned replies, then everything else by date.
+pub const REPLY_ORDER: &str = r#"
+    id = (SELECT MIN(id) FROM replies AS first WHERE first.thread_id = replies.thread_id) DESC,
+    pinned DESC,
+    post_date ASC,
+    id ASC
+"#;
+
+/// Maximum number of replies that can be pinned in a single thread.
+pub const MAX_PINNED_REPLIES: i64 = 3;
+
 #[derive(Serialize, Error, Debug, ErrorCode)]
 pub enum DeleteReplyError {
     #[error("You are not privileged enough")]
@@ -562,10 +1634,89 @@ post!(
     }
 );
 
+/// A reply about to be posted to a thread, whether by a user or on schedule.
+pub struct NewReply<'a> {
+    pub author_id:   i32,
+    pub thread_id:   i32,
+    pub post_date:   NaiveDateTime,
+    pub body:        &'a str,
+    /// Drop given to the author for posting
+    pub reward:      Option<i32>,
+    pub image:       Option<String>,
+    pub thumbnail:   Option<String>,
+    pub filename:    String,
+    pub in_reply_to: Option<i32>,
+    pub credits:     ImageCredits,
+}
+
+impl NewReply<'_> {
+    /// Insert the reply, bring the last post and reply count of its thread up
+    /// to date and publish `Event::ReplyCreated`. Returns the reply and the
+    /// updated thread.
+    pub async fn post(self, conn: &mut PgConnection) -> Result<(Reply, Thread), sqlx::Error> {
+        let reply: Reply = sqlx::query_as(
+            r#"
+                INSERT INTO replies
+                    (author_id, thread_id, post_date, body, reward, image, thumbnail, filename, reactions, in_reply_to,
+                     image_license, image_attribution)
+                VALUES
+                    ($1, $2, $3, $4, $5, $6, $7, $8, '{}', $9, $10, $11)
+                RETURNING *
+            "#
+        )
+        .bind(self.author_id)
+        .bind(self.thread_id)
+        .bind(self.post_date)
+        .bind(self.body)
+        .bind(self.reward)
+        .bind(self.image)
+        .bind(self.thumbnail)
+        .bind(self.filename)
+        .bind(self.in_reply_to)
+        .bind(self.credits.license.map(ImageLicense::slug))
+        .bind(self.credits.attribution)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let thread: Thread = sqlx::query_as(
+            r#"
+            UPDATE threads SET
+                last_post = $1,
+                num_replies = num_replies + 1
+            WHERE
+                id = $2
+            RETURNING *
+            "#,
+        )
+        .bind(reply.id)
+        .bind(self.thread_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Event::ReplyCreated {
+            reply_id:  reply.id,
+            thread_id: self.thread_id,
+            author_id: self.author_id,
+        }
+        .publish(&mut *conn)
+        .await?;
+
+        Ok((reply, thread))
+    }
+}
+
 #[derive(Deserialize)]
 pub struct ReplyForm {
-    body:      String,
-    thread_id: String,
+    body:        String,
+    thread_id:   String,
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    in_reply_to: Option<i32>,
+    /// License of the attached image, if any
+    #[serde(default)]
+    license:     String,
+    /// Who to credit for the attached image, if any
+    #[serde(default)]
+    attribution: String,
 }
 
 #[derive(Debug, Serialize, Error, ErrorCode)]
@@ -576,12 +1727,20 @@ pub enum ReplyError {
     ReplyIsEmpty,
     #[error("Thread is locked")]
     ThreadIsLocked,
+    #[error("Thread is archived")]
+    ThreadIsArchived,
+    #[error("Thread is in slow mode, try again in {retry_after} seconds")]
+    SlowMode { retry_after: i64 },
+    #[error("The post being replied to does not exist")]
+    NoSuchReply,
     #[error("Error uploading image: {0}")]
     UploadImageError(
         #[from]
         #[serde(skip)]
         UploadImageError,
     ),
+    #[error("{0}")]
+    ImageCreditsError(#[from] ImageCreditsError),
     #[error("You must be level {MIN_LEVEL_TO_UPLOAD_PHOTOS} in order to upload photos")]
     NotAllowedToUploadPictures,
     #[error("Internal database error: {0}")]
@@ -597,10 +1756,18 @@ post!(
     #[json]
     pub async fn new_reply(
         conn: Extension<PgPool>,
+        cluster: Extension<Cluster>,
         user: User,
         MultipartForm {
             file,
-            form: ReplyForm { thread_id, body },
+            form:
+                ReplyForm {
+                    thread_id,
+                    body,
+                    in_reply_to,
+                    license,
+                    attribution,
+                },
         }: MultipartForm<ReplyForm, MAXIMUM_FILE_SIZE>,
     ) -> Result<(), ReplyError> {
         let body = body.trim();
@@ -610,81 +1777,150 @@ post!(
         }
 
         let thread_id: i32 = thread_id.parse().map_err(|_| ReplyError::NoSuchThread)?;
-        if Thread::fetch_optional(&conn, thread_id)
+        let thread = Thread::fetch_optional(&*conn, thread_id)
             .await?
-            .ok_or(ReplyError::NoSuchThread)?
-            .locked
-        {
+            .ok_or(ReplyError::NoSuchThread)?;
+        if thread.locked {
             return Err(ReplyError::ThreadIsLocked);
         }
+        if thread.archived {
+            return Err(ReplyError::ThreadIsArchived);
+        }
 
         let post_date = Utc::now().naive_utc();
 
-        let (image, thumbnail, filename) = if let Some(file) = file {
+        if thread.slow_mode_seconds > 0 && user.role < Role::Moderator {
+            let last_reply: Option<NaiveDateTime> = sqlx::query_scalar(
+                "SELECT MAX(post_date) FROM replies WHERE thread_id = $1 AND author_id = $2",
+            )
+            .bind(thread_id)
+            .bind(user.id)
+            .fetch_one(&*conn)
+            .await?;
+            if let Some(last_reply) = last_reply {
+                let retry_after = (last_reply
+                    + chrono::Duration::seconds(thread.slow_mode_seconds as i64)
+                    - post_date)
+                    .num_seconds();
+                if retry_after > 0 {
+                    return Err(ReplyError::SlowMode { retry_after });
+                }
+            }
+        }
+
+        if let Some(in_reply_to) = in_reply_to {
+            match Reply::fetch_optional(&conn, in_reply_to).await? {
+                Some(reply) if !reply.hidden || user.role >= Role::Moderator => (),
+                _ => return Err(ReplyError::NoSuchReply),
+            }
+        }
+
+        let (image, thumbnail, filename, credits) = if let Some(file) = file {
             if !user.can_post_photos() {
                 return Err(ReplyError::NotAllowedToUploadPictures);
             }
+            let credits = ImageCredits::parse(&license, &attribution)?;
             let Image {
                 filename: image,
                 thumbnail,
             } = Image::upload_image(file.bytes).await?;
-            (Some(image), thumbnail, file.name)
+            (Some(image), thumbnail, file.name, credits)
         } else {
-            (None, None, String::new())
+            (None, None, String::new(), ImageCredits::default())
         };
 
         let mut transaction = conn.begin().await?;
 
-        let reply: Reply = sqlx::query_as(
-            r#"
-                INSERT INTO replies
-                    (author_id, thread_id, post_date, body, reward, image, thumbnail, filename, reactions)
-                VALUES
-                    ($1, $2, $3, $4, $5, $6, $7, $8, '{}')
-                RETURNING *
-            "#
-        )
-        .bind(user.id)
-        .bind(thread_id)
-        .bind(post_date)
-        .bind(body)
-        .bind(
-            ItemDrop::drop(&mut transaction, &user)
-                .await?
-                .map(ItemDrop::to_id)
-        )
-        .bind(image)
-        .bind(thumbnail)
-        .bind(filename)
-        .fetch_one(&mut *transaction)
+        let reward = ItemDrop::drop(&mut transaction, &cluster, &user)
+            .await?
+            .map(ItemDrop::to_id);
+        let (_, thread) = NewReply {
+            author_id: user.id,
+            thread_id,
+            post_date,
+            body,
+            reward,
+            image,
+            thumbnail,
+            filename,
+            in_reply_to,
+            credits,
+        }
+        .post(&mut transaction)
         .await?;
 
-        let thread: Thread = sqlx::query_as(
+        Draft::clear(&mut *transaction, user.id, thread_id).await?;
+
+        transaction.commit().await?;
+
+        user.read_thread(&*conn, &thread).await?;
+
+        Ok(())
+    }
+);
+
+/// A reply that responds to another post.
+#[derive(FromRow, Serialize)]
+pub struct ReplyResponse {
+    pub id:        i32,
+    pub thread_id: i32,
+    pub author_id: i32,
+    pub author:    String,
+    pub post_date: NaiveDateTime,
+}
+
+#[derive(Serialize, Error, Debug, ErrorCode)]
+pub enum ReplyResponsesError {
+    #[error("Post does not exist")]
+    NoSuchReply,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/reply/:post_id/responses",
+    #[json]
+    async fn reply_responses(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(post_id): Path<i32>,
+    ) -> Result<Vec<ReplyResponse>, ReplyResponsesError> {
+        let reply = Reply::fetch_optional(&conn, post_id)
+            .await?
+            .ok_or(ReplyResponsesError::NoSuchReply)?;
+        if reply.hidden && user.role < Role::Moderator {
+            return Err(ReplyResponsesError::NoSuchReply);
+        }
+        if !user.show_mature && reply.is_mature(&*conn).await? {
+            return Err(ReplyResponsesError::NoSuchReply);
+        }
+
+        Ok(sqlx::query_as(
             r#"
-            UPDATE threads SET
-                last_post = $1,
-                num_replies = num_replies + 1
-            WHERE
-                id = $2
-            RETURNING *
+                SELECT replies.id, replies.thread_id, replies.author_id, users.display_name AS author, replies.post_date
+                FROM replies
+                JOIN threads ON threads.id = replies.thread_id
+                JOIN users ON users.id = replies.author_id
+                WHERE replies.in_reply_to = $1 AND (NOT (replies.hidden OR threads.hidden) OR $2)
+                ORDER BY replies.post_date ASC
             "#,
         )
-        .bind(reply.id)
-        .bind(thread_id)
-        .fetch_one(&mut *transaction)
-        .await?;
-
-        transaction.commit().await?;
-
-        user.read_thread(&*conn, &thread).await?;
-
-        Ok(())
+        .bind(post_id)
+        .bind(user.role >= Role::Moderator)
+        .fetch_all(&*conn)
+        .await?)
     }
 );
 
 #[derive(Deserialize)]
 pub struct UpdateReplyParams {
     hidden: Option<bool>,
+    pinned: Option<bool>,
+    wiki:   Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -700,6 +1936,10 @@ pub enum UpdateReplyError {
     NoSuchReply,
     #[error("You cannot make a post empty")]
     CannotMakeEmpty,
+    #[error("The first post of a thread cannot be pinned")]
+    CannotPinFirstReply,
+    #[error("Too many replies are pinned (maximum {MAX_PINNED_REPLIES} allowed)")]
+    TooManyPinnedReplies,
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
@@ -713,10 +1953,13 @@ post! {
     #[json]
     pub async fn update_reply(
         conn: Extension<PgPool>,
+        limits: Extension<Limits>,
         user: User,
         Path(post_id): Path<i32>,
         Query(UpdateReplyParams {
             hidden,
+            pinned,
+            wiki,
         }): Query<UpdateReplyParams>,
         Form(UpdateReplyForm { body }): Form<UpdateReplyForm>,
     ) -> Result<(), UpdateReplyError> {
@@ -735,11 +1978,51 @@ post! {
                 .await?;
         }
 
+        if let Some(pinned) = pinned {
+            // Moderators and the authors of the thread may pin replies
+            let first = Reply::fetch_first(&*conn, post.thread_id).await?;
+            if user.role < Role::Moderator
+                && !Thread::is_author(&*conn, post.thread_id, user.id).await?
+            {
+                return Err(UpdateReplyError::Unauthorized);
+            }
+            if first.id == post.id {
+                return Err(UpdateReplyError::CannotPinFirstReply);
+            }
+            if pinned && !post.pinned {
+                let num_pinned: i64 = sqlx::query_scalar(
+                    "SELECT COUNT(*) FROM replies WHERE thread_id = $1 AND pinned",
+                )
+                .bind(post.thread_id)
+                .fetch_one(&*conn)
+                .await?;
+                if num_pinned >= MAX_PINNED_REPLIES {
+                    return Err(UpdateReplyError::TooManyPinnedReplies);
+                }
+            }
+            sqlx::query("UPDATE replies SET pinned = $1 WHERE id = $2")
+                .bind(pinned)
+                .bind(post_id)
+                .execute(&*conn)
+                .await?;
+        }
+
+        if let Some(wiki) = wiki {
+            if user.role < Role::Moderator {
+                return Err(UpdateReplyError::Unauthorized);
+            }
+            sqlx::query("UPDATE replies SET wiki = $1 WHERE id = $2")
+                .bind(wiki)
+                .bind(post_id)
+                .execute(&*conn)
+                .await?;
+        }
+
         let Some(body) = body else {
             return Ok(());
         };
 
-        if post.author_id != user.id && user.role < Role::Moderator {
+        if !post.editable_by(&conn, &user, &limits).await? {
             return Err(UpdateReplyError::Unauthorized);
         }
 
@@ -749,16 +2032,242 @@ post! {
             return Err(UpdateReplyError::CannotMakeEmpty);
         }
 
-        sqlx::query("UPDATE replies SET body = $1 WHERE id = $2")
-            .bind(body)
-            .bind(post_id)
-            .execute(&*conn)
+        post.edit(&conn, user.id, body).await?;
+
+        Ok(())
+    }
+}
+
+/// A saved, unsubmitted thread or reply body.
+#[derive(FromRow, Debug, Serialize)]
+pub struct Draft {
+    /// Id of the draft
+    pub id:        i32,
+    /// Id of the author
+    pub author_id: i32,
+    /// Id of the thread being replied to, or NEW_THREAD_DRAFT
+    pub thread_id: i32,
+    /// Body of the draft
+    pub body:      String,
+    /// When the draft was last saved
+    pub updated:   NaiveDateTime,
+}
+
+/// Thread id used for drafts of threads that have not been posted yet.
+pub const NEW_THREAD_DRAFT: i32 = 0;
+
+impl Draft {
+    pub async fn fetch_optional(
+        conn: impl PgExecutor<'_>,
+        author_id: i32,
+        thread_id: i32,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM drafts WHERE author_id = $1 AND thread_id = $2")
+            .bind(author_id)
+            .bind(thread_id)
+            .fetch_optional(conn)
+            .await
+    }
+
+    /// Removes a draft, usually because it has been posted.
+    pub async fn clear(
+        conn: impl PgExecutor<'_>,
+        author_id: i32,
+        thread_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM drafts WHERE author_id = $1 AND thread_id = $2")
+            .bind(author_id)
+            .bind(thread_id)
+            .execute(conn)
             .await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DraftParams {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    thread_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct DraftForm {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    thread_id: Option<i32>,
+    body:      String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum DraftError {
+    #[error("Draft is too long (maximum {MAX_DRAFT_LEN} characters allowed)")]
+    TooLong,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+pub const MAX_DRAFT_LEN: usize = 32768;
+
+put!(
+    "/draft",
+    #[json]
+    async fn save_draft(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(DraftForm { thread_id, body }): Form<DraftForm>,
+    ) -> Result<(), DraftError> {
+        let thread_id = thread_id.unwrap_or(NEW_THREAD_DRAFT);
+
+        if body.len() > MAX_DRAFT_LEN {
+            return Err(DraftError::TooLong);
+        }
+
+        if body.trim().is_empty() {
+            Draft::clear(&*conn, user.id, thread_id).await?;
+            return Ok(());
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO drafts
+                (author_id, thread_id, body, updated)
+            VALUES
+                ($1, $2, $3, $4)
+            ON CONFLICT
+                (author_id, thread_id)
+            DO UPDATE SET
+                body = EXCLUDED.body,
+                updated = EXCLUDED.updated
+            "#,
+        )
+        .bind(user.id)
+        .bind(thread_id)
+        .bind(body)
+        .bind(Utc::now().naive_utc())
+        .execute(&*conn)
+        .await?;
 
         Ok(())
     }
+);
+
+get!(
+    "/draft",
+    #[json]
+    async fn get_draft(
+        conn: Extension<PgPool>,
+        user: User,
+        Query(DraftParams { thread_id }): Query<DraftParams>,
+    ) -> Result<Option<Draft>, DraftError> {
+        Ok(Draft::fetch_optional(&*conn, user.id, thread_id.unwrap_or(NEW_THREAD_DRAFT)).await?)
+    }
+);
+
+/// Pre-filled title, tags and body for threads that are posted regularly.
+#[derive(FromRow, Debug, Serialize)]
+pub struct ThreadTemplate {
+    /// Id of the template
+    pub id:        i32,
+    /// Name the template is listed under
+    pub name:      String,
+    /// Id of the moderator who last saved the template
+    pub author_id: i32,
+    /// Title of the thread
+    pub title:     String,
+    /// Comma separated list of tags
+    pub tags:      String,
+    /// Body of the first post
+    pub body:      String,
+}
+
+impl ThreadTemplate {
+    pub async fn fetch_optional(conn: &PgPool, id: i32) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM thread_templates WHERE id = $1")
+            .bind(id)
+            .fetch_optional(conn)
+            .await
+    }
+
+    pub async fn fetch_all(conn: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM thread_templates ORDER BY name ASC")
+            .fetch_all(conn)
+            .await
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ThreadTemplateForm {
+    name:  String,
+    title: String,
+    tags:  String,
+    body:  String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum ThreadTemplateError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Template name is empty")]
+    NameIsEmpty,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
 }
 
+post!(
+    "/thread_template",
+    #[json]
+    async fn save_thread_template(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(ThreadTemplateForm {
+            name,
+            title,
+            tags,
+            body,
+        }): Form<ThreadTemplateForm>,
+    ) -> Result<ThreadTemplate, ThreadTemplateError> {
+        if user.role < Role::Moderator {
+            return Err(ThreadTemplateError::Unauthorized);
+        }
+
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(ThreadTemplateError::NameIsEmpty);
+        }
+
+        Ok(sqlx::query_as(
+            r#"
+            INSERT INTO thread_templates
+                (name, author_id, title, tags, body)
+            VALUES
+                ($1, $2, $3, $4, $5)
+            ON CONFLICT
+                (name)
+            DO UPDATE SET
+                author_id = EXCLUDED.author_id,
+                title = EXCLUDED.title,
+                tags = EXCLUDED.tags,
+                body = EXCLUDED.body
+            RETURNING *
+            "#,
+        )
+        .bind(name)
+        .bind(user.id)
+        .bind(title.trim())
+        .bind(tags.trim())
+        .bind(body.trim())
+        .fetch_one(&*conn)
+        .await?)
+    }
+);
+
 #[derive(Serialize, Error, Debug, ErrorCode)]
 pub enum ReactError {
     #[error("No such reply exists")]
@@ -769,6 +2278,12 @@ pub enum ReactError {
     AlreadyConsumed,
     #[error("You cannot react to your own post")]
     ThisIsYourPost,
+    #[error("This user does not accept reactions that take experience away")]
+    NegativeReactionsDisabled,
+    #[error("You must be at least level {min_level} to use reactions that take experience away")]
+    LevelTooLow { min_level: usize },
+    #[error("This user cannot lose any more experience to reactions today")]
+    NegativeExperienceCapReached,
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
@@ -782,6 +2297,7 @@ post!(
     #[json]
     pub async fn react(
         conn: Extension<PgPool>,
+        limits: Extension<Limits>,
         user: User,
         Path(post_id): Path<i32>,
         Form(used_reactions): Form<HashMap<i32, String>>,
@@ -796,7 +2312,20 @@ post!(
 
         let mut transaction = conn.begin().await?;
         let mut new_reactions = Vec::new();
-        let author = User::fetch(&mut transaction, reply.author_id).await?;
+        // The author is locked so that concurrent reactions cannot take them
+        // past the daily cap on lost experience.
+        let author: User = sqlx::query_as("SELECT * FROM users WHERE id = $1 FOR UPDATE")
+            .bind(reply.author_id)
+            .fetch_one(&mut transaction)
+            .await?;
+        let min_level = limits.get(Limit::MinLevelForNegativeReactions);
+        let mut negative_xp_left = (limits.get(Limit::MaxDailyNegativeXp) as i64)
+            - author
+                .experience_lost_since(
+                    &mut transaction,
+                    Utc::now().naive_utc() - chrono::Duration::days(1),
+                )
+                .await?;
 
         // Verify that all of the reactions are owned by the user:
         for (reaction, selected) in used_reactions.into_iter() {
@@ -806,6 +2335,20 @@ post!(
                 return Err(ReactError::Unauthorized);
             }
 
+            let item_xp = item.get_experience().unwrap() as i64;
+            if item_xp < 0 {
+                if !author.negative_reactions {
+                    return Err(ReactError::NegativeReactionsDisabled);
+                }
+                if (user.level() as usize) < min_level {
+                    return Err(ReactError::LevelTooLow { min_level });
+                }
+                if -item_xp > negative_xp_left {
+                    return Err(ReactError::NegativeExperienceCapReached);
+                }
+                negative_xp_left += item_xp;
+            }
+
             // Set the drops to consumed:
             if sqlx::query("UPDATE drops SET consumed = TRUE WHERE id = $1 AND consumed = FALSE")
                 .bind(reaction)
@@ -818,9 +2361,27 @@ post!(
             }
 
             new_reactions.push(reaction);
-            author
-                .add_experience(&mut transaction, item.get_experience().unwrap() as i64)
+            let xp = author
+                .add_experience(
+                    &mut transaction,
+                    item_xp,
+                    ExperienceSource::Reaction {
+                        reply_id:   post_id,
+                        drop_id:    reaction,
+                        reactor_id: user.id,
+                    },
+                )
                 .await?;
+
+            Event::ReactionAdded {
+                reply_id: post_id,
+                author_id: author.id,
+                reactor_id: user.id,
+                drop_id: reaction,
+                xp,
+            }
+            .publish(&mut *transaction)
+            .await?;
         }
 
         sqlx::query("UPDATE replies SET reactions = reactions || $1 WHERE id = $2")
@@ -838,84 +2399,337 @@ post!(
 /// A post is a generalized reply and thread.
 #[derive(Serialize)]
 pub struct Post {
-    pub id:        i32,
-    pub author:    Arc<ProfileStub>,
-    pub body:      String,
-    pub date:      String,
-    pub reactions: Vec<ItemThumbnail>,
-    pub reward:    Option<ItemThumbnail>,
-    pub can_react: bool,
-    pub can_edit:  bool,
-    pub hidden:    bool,
-    pub image:     Option<String>,
-    pub thumbnail: Option<String>,
-    pub filename:  String,
+    pub id:            i32,
+    pub author:        Arc<ProfileStub>,
+    pub body:          String,
+    pub date:          String,
+    pub reactions:     Vec<ItemThumbnail>,
+    pub reward:        Option<ItemThumbnail>,
+    pub can_react:     bool,
+    pub can_edit:      bool,
+    pub hidden:        bool,
+    /// Whether the post is a wiki post
+    pub wiki:          bool,
+    pub pinned:        bool,
+    pub image:         Option<String>,
+    pub thumbnail:     Option<String>,
+    pub filename:      String,
+    /// Name of the license of the image
+    pub license_name:  Option<&'static str>,
+    /// Where the terms of the image's license can be read
+    pub license_url:   Option<&'static str>,
+    /// Who to credit for the image
+    pub attribution:   Option<String>,
+    /// Reply that this post responds to
+    pub in_reply_to:   Option<i32>,
+    /// Whether any visible replies respond to this post
+    pub has_responses: bool,
+    /// Previews of the links in the body
+    pub previews:      Vec<LinkPreview>,
+    /// Whether the viewer has blocked the author, in which case the post is
+    /// collapsed
+    pub collapsed:     bool,
 }
 
-get!(
-    "/watch/:thread_id",
-    pub async fn watch(
-        _user: User,
-        conn: Extension<PgPool>,
-        ws: WebSocketUpgrade,
-        Path(thread_id): Path<i32>,
-    ) -> Response {
-        let latest_thread: Reply =
-            sqlx::query_as("SELECT * FROM replies WHERE thread_id = $1 ORDER BY post_date DESC")
-                .bind(thread_id)
-                .fetch_one(&*conn)
-                .await
-                .unwrap();
-        let mut last_post = latest_thread.id;
-        ws.on_upgrade(move |mut socket| async move {
-            // Don't use listeners. It will quickly exhaust the number of connections
-            loop {
-                let mut new_posts = sqlx::query_as(
-                "SELECT * FROM replies WHERE thread_id = $1 AND id > $2 ORDER BY post_date ASC  ",
-            )
-            .bind(thread_id)
-            .bind(last_post)
-            .fetch(&*conn);
-                while let Some(reply) = new_posts.next().await {
-                    let reply: Reply = reply.unwrap();
-                    last_post = reply.id;
-                    let user = User::fetch(&*conn, reply.author_id).await.unwrap();
-                    let body = askama::filters::linebreaks(
-                        askama::filters::escape(askama::Html, reply.body).unwrap(),
-                    )
-                    .unwrap();
-                    let post = Post {
-                        id: reply.id,
-                        author: Arc::new(user.get_profile_stub(&*conn).await.unwrap()),
-                        body,
-                        date: reply.post_date.format(crate::DATE_FMT).to_string(),
-                        reactions: vec![],
-                        reward: match reply.reward {
-                            Some(drop_id) => ItemDrop::fetch(&*conn, drop_id)
-                                .await
-                                .unwrap()
-                                .get_thumbnail(&*conn)
-                                .await
-                                .ok(),
-                            _ => None,
-                        },
-                        can_react: false,
-                        can_edit: true,
-                        hidden: false,
-                        image: reply.image,
-                        thumbnail: reply.thumbnail,
-                        filename: reply.filename,
-                    };
-                    if socket
-                        .send(Message::from(serde_json::to_string(&post).unwrap()))
-                        .await
-                        .is_err()
-                    {
-                        return;
-                    }
-                }
-                tokio::time::sleep(Duration::from_millis(50)).await;
-            }
+/// Builds fully populated `Post`s for a viewer. Authors, reactions, rewards,
+/// responses and link previews are fetched for every reply at once, so the
+/// number of queries does not grow with the number of replies. Authors are
+/// shared with other requests through the profile stub cache.
+pub struct PostLoader<'a> {
+    conn:        &'a PgPool,
+    users:       UserCache<'a>,
+    viewer:      &'a User,
+    blocked:     HashSet<i32>,
+    /// Whether the viewer may edit wiki posts
+    wiki_editor: bool,
+}
+
+impl<'a> PostLoader<'a> {
+    pub async fn new(
+        conn: &'a PgPool,
+        stubs: &ProfileStubs,
+        viewer: &'a User,
+        limits: &Limits,
+    ) -> Result<PostLoader<'a>, sqlx::Error> {
+        Ok(Self {
+            conn,
+            users: UserCache::new(conn, stubs),
+            viewer,
+            blocked: viewer.blocked_users(conn).await?,
+            wiki_editor: viewer.level() as usize >= limits.get(Limit::MinLevelForWikiEdits),
         })
     }
-);
+
+    /// Turns replies into posts, in the same order.
+    pub async fn load(&self, replies: Vec<Reply>) -> Result<Vec<Post>, sqlx::Error> {
+        let author_ids = replies
+            .iter()
+            .map(|reply| reply.author_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let authors = self
+            .users
+            .get_many(&author_ids)
+            .await?
+            .into_iter()
+            .map(|stub| (stub.id, stub))
+            .collect::<HashMap<_, _>>();
+
+        let drop_ids = replies
+            .iter()
+            .flat_map(|reply| reply.reactions.iter().copied().chain(reply.reward))
+            .collect::<Vec<_>>();
+        let drops = ItemDrop::fetch_many_with_items(self.conn, &drop_ids).await?;
+        let thumbnail = |drop_id: &i32| {
+            drops
+                .get(drop_id)
+                .map(|(item, item_drop)| ItemThumbnail::new(item, item_drop))
+        };
+
+        // Responses themselves are only fetched once asked for.
+        let reply_ids = replies.iter().map(|reply| reply.id).collect::<Vec<_>>();
+        let with_responses = Reply::with_responses(self.conn, &reply_ids).await?;
+        let previews =
+            LinkPreview::fetch_for(self.conn, replies.iter().map(|reply| reply.body.as_str()))
+                .await?;
+        let co_authored = Reply::first_of_co_authored(self.conn, self.viewer.id, &replies).await?;
+
+        replies
+            .into_iter()
+            .map(|reply| {
+                let author = authors
+                    .get(&reply.author_id)
+                    .cloned()
+                    .ok_or(sqlx::Error::RowNotFound)?;
+                let license = reply
+                    .image_license
+                    .as_deref()
+                    .and_then(|license| license.parse::<ImageLicense>().ok());
+                Ok(Post {
+                    id: reply.id,
+                    author,
+                    date: reply.post_date.format(crate::DATE_FMT).to_string(),
+                    reactions: reply.reactions.iter().filter_map(thumbnail).collect(),
+                    reward: reply.reward.as_ref().and_then(thumbnail),
+                    // TODO: Add time limit for replies
+                    can_edit: reply.author_id == self.viewer.id
+                        || co_authored.contains(&reply.id)
+                        || (reply.wiki && self.wiki_editor),
+                    can_react: reply.author_id != self.viewer.id,
+                    hidden: reply.hidden,
+                    pinned: reply.pinned,
+                    wiki: reply.wiki,
+                    image: reply.image,
+                    thumbnail: reply.thumbnail,
+                    filename: reply.filename,
+                    license_name: license.map(ImageLicense::name),
+                    license_url: license.and_then(ImageLicense::url),
+                    attribution: reply.image_attribution,
+                    in_reply_to: reply.in_reply_to,
+                    has_responses: with_responses.contains(&reply.id),
+                    previews: LinkPreview::for_body(&reply.body, &previews),
+                    collapsed: self.blocked.contains(&reply.author_id),
+                    body: reply.body,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Maximum number of watch sockets that may be open across the entire server.
+pub const MAX_WATCHERS: usize = 1024;
+/// Maximum number of watch sockets a single user may have open.
+pub const MAX_WATCHERS_PER_USER: usize = 8;
+/// Maximum number of watch sockets a trusted user, or anyone above, may have
+/// open.
+pub const MAX_WATCHERS_PER_TRUSTED_USER: usize = 16;
+/// Maximum number of watch sockets a single IP address may have open.
+pub const MAX_WATCHERS_PER_IP: usize = 16;
+
+/// Connection accounting for the `/watch` websockets. Every open socket holds
+/// a polling loop against the database, so we keep track of how many are open
+/// and who they belong to.
+#[derive(Clone, Default)]
+pub struct Watchers {
+    counts: Arc<Mutex<WatcherCounts>>,
+}
+
+#[derive(Default)]
+struct WatcherCounts {
+    total:    usize,
+    rejected: u64,
+    per_user: HashMap<i32, usize>,
+    per_ip:   HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug, Error, Serialize)]
+pub enum WatchError {
+    #[error("The server is watching too many threads, try again later")]
+    TooManyWatchers,
+    #[error("You are watching too many threads at once")]
+    TooManyUserWatchers,
+    #[error("Your address is watching too many threads at once")]
+    TooManyIpWatchers,
+}
+
+/// Snapshot of the current watcher accounting.
+#[derive(Serialize)]
+pub struct WatcherStats {
+    pub open:     usize,
+    pub users:    usize,
+    pub ips:      usize,
+    pub rejected: u64,
+}
+
+impl Watchers {
+    /// Reserve a slot for a new watch socket. The slot is released when the
+    /// returned guard is dropped.
+    pub fn acquire(
+        &self,
+        user_id: i32,
+        role: Role,
+        ip: IpAddr,
+    ) -> Result<WatcherGuard, WatchError> {
+        let max_per_user = if role >= Role::Trusted {
+            MAX_WATCHERS_PER_TRUSTED_USER
+        } else {
+            MAX_WATCHERS_PER_USER
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let rejection = if counts.total >= MAX_WATCHERS {
+            Some(WatchError::TooManyWatchers)
+        } else if counts.per_user.get(&user_id).copied().unwrap_or(0) >= max_per_user {
+            Some(WatchError::TooManyUserWatchers)
+        } else if counts.per_ip.get(&ip).copied().unwrap_or(0) >= MAX_WATCHERS_PER_IP {
+            Some(WatchError::TooManyIpWatchers)
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            counts.rejected += 1;
+            return Err(rejection);
+        }
+        counts.total += 1;
+        *counts.per_user.entry(user_id).or_default() += 1;
+        *counts.per_ip.entry(ip).or_default() += 1;
+        Ok(WatcherGuard {
+            watchers: self.clone(),
+            user_id,
+            ip,
+        })
+    }
+
+    pub fn stats(&self) -> WatcherStats {
+        let counts = self.counts.lock().unwrap();
+        WatcherStats {
+            open:     counts.total,
+            users:    counts.per_user.len(),
+            ips:      counts.per_ip.len(),
+            rejected: counts.rejected,
+        }
+    }
+
+    fn release(&self, user_id: i32, ip: IpAddr) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        decrement(&mut counts.per_user, user_id);
+        decrement(&mut counts.per_ip, ip);
+    }
+}
+
+fn decrement<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Entry::Occupied(mut entry) = counts.entry(key) {
+        *entry.get_mut() -= 1;
+        if *entry.get() == 0 {
+            entry.remove();
+        }
+    }
+}
+
+/// A reserved watch socket slot.
+pub struct WatcherGuard {
+    watchers: Watchers,
+    user_id:  i32,
+    ip:       IpAddr,
+}
+
+impl Drop for WatcherGuard {
+    fn drop(&mut self) {
+        self.watchers.release(self.user_id, self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn caps_watchers_per_user() {
+        let watchers = Watchers::default();
+        let guards = (0..MAX_WATCHERS_PER_USER)
+            .map(|n| watchers.acquire(1, Role::User, ip(n as u32)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            watchers.acquire(1, Role::User, ip(1000)),
+            Err(WatchError::TooManyUserWatchers)
+        ));
+        // Trusted users are allowed more.
+        let trusted = (0..MAX_WATCHERS_PER_TRUSTED_USER)
+            .map(|n| watchers.acquire(2, Role::Trusted, ip(n as u32)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(watchers.acquire(2, Role::Trusted, ip(1000)).is_err());
+        assert_eq!(watchers.stats().rejected, 2);
+        drop((guards, trusted));
+    }
+
+    #[test]
+    fn caps_watchers_per_ip() {
+        let watchers = Watchers::default();
+        let guards = (0..MAX_WATCHERS_PER_IP)
+            .map(|n| watchers.acquire(n as i32, Role::User, ip(1)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            watchers.acquire(1000, Role::User, ip(1)),
+            Err(WatchError::TooManyIpWatchers)
+        ));
+        assert!(watchers.acquire(1000, Role::User, ip(2)).is_ok());
+        drop(guards);
+    }
+
+    #[test]
+    fn caps_watchers_across_the_server() {
+        let watchers = Watchers::default();
+        let guards = (0..MAX_WATCHERS)
+            .map(|n| {
+                watchers
+                    .acquire(n as i32, Role::User, ip(n as u32))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            watchers.acquire(-1, Role::Admin, ip(u32::MAX)),
+            Err(WatchError::TooManyWatchers)
+        ));
+        drop(guards);
+    }
+
+    #[test]
+    fn releases_slots_when_guards_are_dropped() {
+        let watchers = Watchers::default();
+        let guards = (0..MAX_WATCHERS_PER_USER)
+            .map(|_| watchers.acquire(1, Role::User, ip(1)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(watchers.stats().open, MAX_WATCHERS_PER_USER);
+        drop(guards);
+        let stats = watchers.stats();
+        assert_eq!((stats.open, stats.users, stats.ips), (0, 0, 0));
+        assert!(watchers.acquire(1, Role::User, ip(1)).is_ok());
+    }
+}
@@ -0,0 +1,647 @@
+//! Validation of user names. Names may use any script, but are normalized so
+//! that names which look the same to a reader are treated as the same name,
+//! which keeps users from impersonating one another with lookalike letters.
+use axum::extract::{Extension, Form, Path};
+use chrono::{Duration, NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use marche_proc_macros::{json, ErrorCode};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool, Row, Type};
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{confusable_detection::skeleton, GeneralSecurityProfile, MixedScript};
+
+use crate::{
+    get,
+    invalidation::InvalidationBus,
+    post,
+    users::{Role, User},
+};
+
+/// Names that cannot be registered, nor can any name that looks like them.
+/// Administrators can reserve more with `UsernameRule`s.
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "moderator",
+    "mod",
+    "root",
+    "staff",
+    "support",
+    "system",
+    "marche",
+];
+
+/// Prefix of the names that deleted accounts are renamed to once their name is
+/// released, see `users::release_deleted_names`. No name may start with it,
+/// nor with anything that looks like it.
+const DELETED_NAME_PREFIX: &str = "deleted-";
+
+/// Characters allowed in a name besides letters, marks and digits.
+const SEPARATORS: &[char] = &['_', '-'];
+
+/// Number of days a user must wait between changes to their name. Changing
+/// only the case of the name can be done at any time.
+const RENAME_COOLDOWN_DAYS: i64 = 30;
+
+/// How strictly names are validated, read from the environment.
+pub struct UsernamePolicy {
+    /// Longest name allowed, in characters. Set with `USERNAME_MAX_LENGTH`.
+    pub max_length:          usize,
+    /// Whether a name may mix scripts, such as Latin and Cyrillic. Set with
+    /// `USERNAME_ALLOW_MIXED_SCRIPTS`.
+    pub allow_mixed_scripts: bool,
+}
+
+impl UsernamePolicy {
+    fn from_env() -> Self {
+        Self {
+            max_length:          std::env::var("USERNAME_MAX_LENGTH")
+                .ok()
+                .and_then(|max_length| max_length.parse().ok())
+                .unwrap_or(24),
+            allow_mixed_scripts: std::env::var("USERNAME_ALLOW_MIXED_SCRIPTS")
+                .map_or(false, |allow| allow == "1" || allow == "true"),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref USERNAME_POLICY: UsernamePolicy = UsernamePolicy::from_env();
+}
+
+#[derive(Debug, Error, Serialize)]
+pub enum UsernameError {
+    #[error("User name cannot be empty")]
+    Empty,
+    #[error("User name is too long (maximum {max} characters)")]
+    TooLong { max: usize },
+    #[error("User names can only contain letters, digits, `_` and `-`")]
+    InvalidCharacter,
+    #[error("User names cannot mix letters from different scripts")]
+    MixedScripts,
+    #[error("User name is reserved")]
+    Reserved,
+    #[error("User name is not allowed")]
+    Banned,
+}
+
+/// A name that has passed validation.
+#[derive(Debug)]
+pub struct Username {
+    /// Name as it is displayed
+    pub display_name: String,
+    /// Name used to log in, unique among users
+    pub name:         String,
+    /// Form of the name shared by all names that look alike
+    pub skeleton:     String,
+}
+
+/// Normalize a name as typed into the form used to log in.
+pub fn canonical(username: &str) -> String {
+    username.trim().nfkc().collect::<String>().to_lowercase()
+}
+
+/// Form of a name that is shared by all names that look alike, such as
+/// `paypal` and `pаypal` with a Cyrillic `а`.
+pub fn name_skeleton(name: &str) -> String {
+    skeleton(&canonical(name))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Check that a name is allowed, without checking whether it is in use.
+pub fn validate(username: &str) -> Result<Username, UsernameError> {
+    let display_name = username.trim().nfkc().collect::<String>();
+    let length = display_name.chars().count();
+    if length == 0 {
+        return Err(UsernameError::Empty);
+    }
+    if length > USERNAME_POLICY.max_length {
+        return Err(UsernameError::TooLong {
+            max: USERNAME_POLICY.max_length,
+        });
+    }
+    if !display_name
+        .chars()
+        .all(|c| SEPARATORS.contains(&c) || c.identifier_allowed())
+        || display_name.starts_with(SEPARATORS)
+    {
+        return Err(UsernameError::InvalidCharacter);
+    }
+    if !USERNAME_POLICY.allow_mixed_scripts && !display_name.is_single_script() {
+        return Err(UsernameError::MixedScripts);
+    }
+
+    let skeleton = name_skeleton(&display_name);
+    if RESERVED_USERNAMES
+        .iter()
+        .any(|reserved| name_skeleton(reserved) == skeleton)
+        || skeleton.starts_with(&name_skeleton(DELETED_NAME_PREFIX))
+    {
+        return Err(UsernameError::Reserved);
+    }
+
+    Ok(Username {
+        name: display_name.to_lowercase(),
+        display_name,
+        skeleton,
+    })
+}
+
+/// Whether any existing user has a name that looks like this one.
+pub async fn is_confusable(
+    conn: impl PgExecutor<'_>,
+    username: &Username,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE name_skeleton = $1)")
+        .bind(&username.skeleton)
+        .fetch_one(conn)
+        .await
+}
+
+/// Compute the skeletons of users that do not have one yet, such as those
+/// that registered before skeletons were stored.
+pub async fn backfill_skeletons(conn: &PgPool) -> Result<(), sqlx::Error> {
+    let users = sqlx::query("SELECT id, name FROM users WHERE name_skeleton IS NULL")
+        .fetch_all(conn)
+        .await?;
+    for user in users {
+        let id: i32 = user.get("id");
+        let name: String = user.get("name");
+        sqlx::query("UPDATE users SET name_skeleton = $1 WHERE id = $2")
+            .bind(name_skeleton(&name))
+            .bind(id)
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "username_rule_kind")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum UsernameRuleKind {
+    /// Names held back, e.g. for staff. Administrators may still give them out.
+    Reserved,
+    /// Names that may never be used, e.g. slurs.
+    Banned,
+}
+
+/// A pattern of names that users may not take, managed by administrators.
+#[derive(Debug, FromRow, Serialize)]
+pub struct UsernameRule {
+    pub id:         i32,
+    /// Either a wildcard pattern, where `*` matches any number of characters
+    /// and `?` matches one, or a regular expression.
+    pub pattern:    String,
+    pub is_regex:   bool,
+    pub kind:       UsernameRuleKind,
+    pub created_by: i32,
+    pub created:    NaiveDateTime,
+}
+
+impl UsernameRule {
+    pub async fn fetch_all(conn: impl PgExecutor<'_>) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM username_rules ORDER BY id ASC")
+            .fetch_all(conn)
+            .await
+    }
+
+    /// Compile the pattern into a regex matching whole names, ignoring case.
+    fn compile(pattern: &str, is_regex: bool) -> Result<Regex, regex::Error> {
+        let pattern = if is_regex {
+            pattern.to_string()
+        } else {
+            regex::escape(pattern)
+                .replace(r"\*", ".*")
+                .replace(r"\?", ".")
+        };
+        RegexBuilder::new(&format!("^(?:{pattern})$"))
+            .case_insensitive(true)
+            .build()
+    }
+
+    /// Whether the rule matches a name or anything that looks like it.
+    fn matches(&self, username: &Username) -> bool {
+        match Self::compile(&self.pattern, self.is_regex) {
+            Ok(regex) => regex.is_match(&username.name) || regex.is_match(&username.skeleton),
+            Err(err) => {
+                tracing::warn!("Invalid user name rule {}: {err}", self.id);
+                false
+            }
+        }
+    }
+}
+
+/// Check a name against the rules managed by administrators. Reserved names
+/// are only allowed when `allow_reserved` is set, which only administrators
+/// may do; banned names are never allowed.
+pub async fn check_rules(
+    conn: impl PgExecutor<'_>,
+    username: &Username,
+    allow_reserved: bool,
+) -> Result<Result<(), UsernameError>, sqlx::Error> {
+    let mut violation = Ok(());
+    for rule in UsernameRule::fetch_all(conn).await? {
+        if !rule.matches(username) {
+            continue;
+        }
+        match rule.kind {
+            UsernameRuleKind::Banned => return Ok(Err(UsernameError::Banned)),
+            UsernameRuleKind::Reserved if !allow_reserved => {
+                violation = Err(UsernameError::Reserved)
+            }
+            UsernameRuleKind::Reserved => (),
+        }
+    }
+    Ok(violation)
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum UsernameRuleError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(String),
+    #[error("No such rule")]
+    NoSuchRule,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/username_rules",
+    #[json]
+    async fn show_username_rules(
+        conn: Extension<PgPool>,
+        user: User,
+    ) -> Result<Vec<UsernameRule>, UsernameRuleError> {
+        if user.role < Role::Admin {
+            return Err(UsernameRuleError::Unauthorized);
+        }
+        Ok(UsernameRule::fetch_all(&*conn).await?)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct UsernameRuleForm {
+    pattern:  String,
+    #[serde(default)]
+    is_regex: bool,
+    kind:     UsernameRuleKind,
+}
+
+post!(
+    "/username_rules",
+    #[json]
+    async fn add_username_rule(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(UsernameRuleForm {
+            pattern,
+            is_regex,
+            kind,
+        }): Form<UsernameRuleForm>,
+    ) -> Result<UsernameRule, UsernameRuleError> {
+        if user.role < Role::Admin {
+            return Err(UsernameRuleError::Unauthorized);
+        }
+
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(UsernameRuleError::InvalidPattern(String::from(
+                "pattern is empty",
+            )));
+        }
+        UsernameRule::compile(pattern, is_regex)
+            .map_err(|err| UsernameRuleError::InvalidPattern(err.to_string()))?;
+
+        let rule: UsernameRule = sqlx::query_as(
+            r#"
+                INSERT INTO username_rules (pattern, is_regex, kind, created_by, created)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+            "#,
+        )
+        .bind(pattern)
+        .bind(is_regex)
+        .bind(kind)
+        .bind(user.id)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&*conn)
+        .await?;
+
+        tracing::info!("User `{}` has added user name rule `{pattern}`", user.name);
+
+        Ok(rule)
+    }
+);
+
+post!(
+    "/username_rules/:rule_id/delete",
+    #[json]
+    async fn delete_username_rule(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(rule_id): Path<i32>,
+    ) -> Result<(), UsernameRuleError> {
+        if user.role < Role::Admin {
+            return Err(UsernameRuleError::Unauthorized);
+        }
+
+        let deleted = sqlx::query("DELETE FROM username_rules WHERE id = $1")
+            .bind(rule_id)
+            .execute(&*conn)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            return Err(UsernameRuleError::NoSuchRule);
+        }
+
+        Ok(())
+    }
+);
+
+/// A change to a user's name, visible to moderators.
+#[derive(Debug, FromRow, Serialize)]
+pub struct NameChange {
+    pub id:               i32,
+    pub user_id:          i32,
+    pub old_display_name: String,
+    pub old_name:         String,
+    pub new_display_name: String,
+    pub new_name:         String,
+    /// The user themselves, or the administrator that changed their name
+    pub changed_by:       i32,
+    pub changed:          NaiveDateTime,
+}
+
+impl NameChange {
+    /// Every change to a user's name, most recent first.
+    pub async fn fetch_for(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM name_history WHERE user_id = $1 ORDER BY changed DESC")
+            .bind(user_id)
+            .fetch_all(conn)
+            .await
+    }
+
+    /// When the user last changed their own name, rather than just its case.
+    async fn last_rename(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Option<NaiveDateTime>, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+                SELECT MAX(changed) FROM name_history
+                WHERE user_id = $1 AND changed_by = $1 AND old_name <> new_name
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(conn)
+        .await
+    }
+
+    async fn record(
+        conn: impl PgExecutor<'_>,
+        user: &User,
+        new_display_name: &str,
+        new_name: &str,
+        changed_by: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+                INSERT INTO name_history (
+                    user_id, old_display_name, old_name, new_display_name, new_name,
+                    changed_by, changed
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(user.id)
+        .bind(&user.display_name)
+        .bind(&user.name)
+        .bind(new_display_name)
+        .bind(new_name)
+        .bind(changed_by)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DisplayNameForm {
+    display_name:      String,
+    /// Allow a reserved name, e.g. to give a staff member their name.
+    #[serde(default)]
+    override_reserved: bool,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum UpdateDisplayNameError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("{0}")]
+    InvalidUserName(#[from] UsernameError),
+    #[error("User name is too similar to one that has already been registered")]
+    UserNameConfusable,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/user/:user_id/display_name",
+    #[json]
+    async fn update_display_name(
+        conn: Extension<PgPool>,
+        admin: User,
+        Path(user_id): Path<i32>,
+        Form(DisplayNameForm {
+            display_name,
+            override_reserved,
+        }): Form<DisplayNameForm>,
+    ) -> Result<String, UpdateDisplayNameError> {
+        if admin.role < Role::Admin {
+            return Err(UpdateDisplayNameError::Unauthorized);
+        }
+
+        // The built-in reserved names can be overridden as well.
+        let username = match validate(&display_name) {
+            Err(UsernameError::Reserved) if override_reserved => {
+                let display_name = display_name.trim().nfkc().collect::<String>();
+                Username {
+                    name: display_name.to_lowercase(),
+                    skeleton: name_skeleton(&display_name),
+                    display_name,
+                }
+            }
+            username => username?,
+        };
+        check_rules(&*conn, &username, override_reserved).await??;
+
+        let confusable: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM users WHERE name_skeleton = $1 AND id <> $2)",
+        )
+        .bind(&username.skeleton)
+        .bind(user_id)
+        .fetch_one(&*conn)
+        .await?;
+        if confusable {
+            return Err(UpdateDisplayNameError::UserNameConfusable);
+        }
+
+        let mut transaction = conn.begin().await?;
+
+        let user: User = sqlx::query_as("SELECT * FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_optional(&mut transaction)
+            .await?
+            .ok_or(UpdateDisplayNameError::NoSuchUser)?;
+
+        sqlx::query("UPDATE users SET display_name = $1, name_skeleton = $2 WHERE id = $3")
+            .bind(&username.display_name)
+            .bind(&username.skeleton)
+            .bind(user_id)
+            .execute(&mut transaction)
+            .await?;
+
+        NameChange::record(
+            &mut transaction,
+            &user,
+            &username.display_name,
+            &user.name,
+            admin.id,
+        )
+        .await?;
+
+        InvalidationBus::user_updated(&mut *transaction, user_id).await?;
+
+        transaction.commit().await?;
+
+        tracing::info!(
+            "User `{}` has changed the display name of user {user_id} to `{}`",
+            admin.name,
+            username.display_name
+        );
+
+        Ok(username.display_name)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct ChangeNameForm {
+    display_name: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum ChangeNameError {
+    #[error("{0}")]
+    InvalidUserName(#[from] UsernameError),
+    #[error("User name is too similar to one that has already been registered")]
+    UserNameConfusable,
+    #[error("You can change your name again on {available}")]
+    RenameCooldown { available: String },
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+// Change the current user's name. Names that only differ in case can be
+// changed to freely; anything else counts as a rename and is limited by
+// `RENAME_COOLDOWN_DAYS`.
+post!(
+    "/display_name",
+    #[json]
+    async fn change_display_name(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(ChangeNameForm { display_name }): Form<ChangeNameForm>,
+    ) -> Result<String, ChangeNameError> {
+        let username = validate(&display_name)?;
+        if username.display_name == user.display_name {
+            return Ok(username.display_name);
+        }
+        check_rules(&*conn, &username, false).await??;
+
+        let mut transaction = conn.begin().await?;
+
+        // Lock the user so that two renames cannot both pass the cooldown.
+        let user: User = sqlx::query_as("SELECT * FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user.id)
+            .fetch_one(&mut transaction)
+            .await?;
+
+        if username.name != user.name {
+            if let Some(last_rename) = NameChange::last_rename(&mut transaction, user.id).await? {
+                let available = last_rename + Duration::days(RENAME_COOLDOWN_DAYS);
+                if available > Utc::now().naive_utc() {
+                    return Err(ChangeNameError::RenameCooldown {
+                        available: available.format(crate::DATE_FMT).to_string(),
+                    });
+                }
+            }
+
+            let confusable: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM users WHERE name_skeleton = $1 AND id <> $2)",
+            )
+            .bind(&username.skeleton)
+            .bind(user.id)
+            .fetch_one(&mut transaction)
+            .await?;
+            if confusable {
+                return Err(ChangeNameError::UserNameConfusable);
+            }
+        }
+
+        sqlx::query(
+            "UPDATE users SET display_name = $1, name = $2, name_skeleton = $3 WHERE id = $4",
+        )
+        .bind(&username.display_name)
+        .bind(&username.name)
+        .bind(&username.skeleton)
+        .bind(user.id)
+        .execute(&mut transaction)
+        .await?;
+
+        NameChange::record(
+            &mut transaction,
+            &user,
+            &username.display_name,
+            &username.name,
+            user.id,
+        )
+        .await?;
+
+        InvalidationBus::user_updated(&mut *transaction, user.id).await?;
+
+        transaction.commit().await?;
+
+        tracing::info!(
+            "User `{}` has changed their name to `{}`",
+            user.name,
+            username.display_name
+        );
+
+        Ok(username.display_name)
+    }
+);
@@ -1,36 +1,59 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    net::IpAddr,
     ops::Range,
     string::FromUtf8Error,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration as StdDuration, Instant},
 };
+
 use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
 use askama::Template;
 use axum::{
     async_trait,
     extract::{Extension, Form, FromRequestParts, Path, Query},
-    http::request::Parts,
+    http::{request::Parts, HeaderMap, Method, StatusCode},
     response::{IntoResponse, Redirect, Response},
 };
 use axum_client_ip::ClientIp;
 use chrono::{prelude::*, Duration};
 use cookie::time as cookie_time;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
 use google_authenticator::{create_secret, qr_code_url};
 use ipnetwork::IpNetwork;
 use lazy_static::lazy_static;
-use libpasta::{hash_password, verify_password};
 use marche_proc_macros::{json, ErrorCode};
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgExecutor, PgPool, Postgres, Row, Transaction, Type};
+use sqlx::{types::Json, FromRow, PgExecutor, PgPool, Postgres, Row, Transaction, Type};
 use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tower_cookies::{Cookie, Cookies, Key};
 
 use crate::{
-    items::{Item, ItemDrop},
-    post,
-    threads::{Reply, Thread},
+    api_tokens::{self, ApiToken, TokenScope},
+    bans::{BanAppeal, BanDetails, IpBan},
+    branding::branding,
+    cluster::{Cluster, Topic},
+    events::Event,
+    get,
+    images::{Image, UploadImageError, MAXIMUM_FILE_SIZE},
+    invalidation::InvalidationBus,
+    items::{Badge, Item, ItemDrop, TradeRequest},
+    languages,
+    limits::{Limit, Limits},
+    login_audit::LoginAudit,
+    messages::Message,
+    notifications::{NotificationKind, NotificationSettings, Notifications},
+    passwords::{self, PasswordCheck},
+    policies, post, recovery_codes,
+    signing::SignatureError,
+    threads::{Reply, Tags, Thread},
+    usernames::{self, UsernameError},
+    MultipartForm, MultipartFormError,
 };
 
 #[derive(FromRow, Debug)]
@@ -55,6 +78,8 @@ pub struct User {
     pub role:                  Role,
     /// Exprerience
     pub experience:            i64,
+    /// Currency the user has to spend
+    pub balance:               i64,
     /// Last reward
     pub last_reward:           NaiveDateTime,
     /// ProfilePic equipment slot
@@ -65,8 +90,66 @@ pub struct User {
     pub equip_slot_badges:     Vec<i32>,
     /// If the user is banned, and for how long
     pub banned_until:          Option<NaiveDateTime>,
+    /// Why the user was banned
+    pub ban_reason:            Option<String>,
+    /// Id of the moderator who banned the user
+    pub banned_by:             Option<i32>,
     /// Notes on the user by moderators or admins
     pub notes:                 String,
+    /// Tags, separated by slashes, that the user lands on when visiting the
+    /// root of the site
+    pub home_tags:             String,
+    /// When the user deleted their account, if they have
+    pub deleted:               Option<NaiveDateTime>,
+    /// Whether others may react to the user's replies with reactions that
+    /// take experience away
+    pub negative_reactions:    bool,
+    /// Whether other users may give the user items
+    pub accepts_gifts:         bool,
+    /// Whether the user wants a weekly email digest
+    pub email_digest:          bool,
+    /// Token that unsubscribes the user from digests, set once they opt in
+    pub digest_token:          Option<String>,
+    /// When the user was last sent a digest
+    pub last_digest:           Option<NaiveDateTime>,
+    /// Whether the user has confirmed they are old enough to see threads
+    /// with mature tags, and want to
+    pub show_mature:           bool,
+    /// Id of the newest policy the user has accepted, along with every
+    /// policy before it
+    pub policies_accepted:     i32,
+    pub pronouns:              String,
+    pub location:              String,
+    /// Link to the user's website, if they have given one
+    pub website:               String,
+    /// Shown under every post by the user
+    pub signature:             String,
+    /// Which notifications the user wants
+    pub notification_settings: Json<NotificationSettings>,
+    /// Language tag the user reads, once it has been detected or chosen
+    pub language:              Option<String>,
+    /// When the account was created
+    pub joined:                NaiveDateTime,
+    /// Uploaded profile picture, shown when no avatar item is equipped
+    pub avatar:                Option<String>,
+    /// Id of the admin viewing the site as this user, if this user was
+    /// extracted in view as user mode. Never stored. Anything done on behalf
+    /// of the user while viewing a page must be skipped when this is set.
+    #[sqlx(default)]
+    pub viewed_by:             Option<i32>,
+    /// Id of the API token the user was extracted with, if the request was
+    /// authenticated with one rather than a session. Never stored.
+    #[sqlx(default)]
+    pub api_token:             Option<i32>,
+}
+
+/// Everything needed to render a user's profile page.
+pub struct ProfileBundle {
+    pub stub:      ProfileStub,
+    /// Equipped items, in slot order
+    pub equipped:  Vec<(Item, ItemDrop)>,
+    /// Items that are owned but not equipped, rarest first
+    pub inventory: Vec<(Item, ItemDrop)>,
 }
 
 /// Displayable user profile
@@ -76,8 +159,40 @@ pub struct ProfileStub {
     pub name:       String,
     pub picture:    Option<String>,
     pub background: Option<String>,
-    pub badges:     Vec<String>,
+    pub badges:     Vec<Badge>,
     pub level:      LevelInfo,
+    pub signature:  String,
+}
+
+impl ProfileStub {
+    /// Fetches the profile stubs of a number of users in three queries, keyed
+    /// by user id.
+    pub async fn fetch_many(
+        conn: &PgPool,
+        user_ids: &[i32],
+    ) -> Result<HashMap<i32, Self>, sqlx::Error> {
+        let users: Vec<User> = sqlx::query_as("SELECT * FROM users WHERE id = ANY($1)")
+            .bind(user_ids)
+            .fetch_all(conn)
+            .await?;
+        let equip_slots = users
+            .iter()
+            .flat_map(|user| {
+                user.equip_slot_prof_pic
+                    .into_iter()
+                    .chain(user.equip_slot_background)
+                    .chain(user.equip_slot_badges.iter().copied())
+            })
+            .collect::<Vec<_>>();
+        let equipped = ItemDrop::fetch_many_with_items(conn, &equip_slots).await?;
+        Ok(users
+            .iter()
+            .map(|user| {
+                let stub = user.profile_stub_with(|drop_id| equipped.get(drop_id).cloned());
+                (user.id, stub)
+            })
+            .collect())
+    }
 }
 
 #[derive(
@@ -87,10 +202,26 @@ pub struct ProfileStub {
 #[sqlx(rename_all = "snake_case")]
 pub enum Role {
     User,
+    /// A user who has been around long enough to be trusted with more than a
+    /// new account. Users are promoted automatically by
+    /// `promote_trusted_users`.
+    Trusted,
     Moderator,
     Admin,
 }
 
+/// What caused a change in a user's experience, recorded in the experience
+/// ledger.
+#[derive(Copy, Clone, Debug)]
+pub enum ExperienceSource {
+    /// Another user reacted to one of the user's replies.
+    Reaction {
+        reply_id:   i32,
+        drop_id:    i32,
+        reactor_id: i32,
+    },
+}
+
 #[derive(Copy, Clone, Serialize)]
 pub struct LevelInfo {
     pub level:         u32,
@@ -137,7 +268,7 @@ impl User {
     }
 
     pub fn can_post_photos(&self) -> bool {
-        self.level() >= MIN_LEVEL_TO_UPLOAD_PHOTOS
+        self.role >= Role::Trusted || self.level() >= MIN_LEVEL_TO_UPLOAD_PHOTOS
     }
 
     pub fn is_banned(&self) -> bool {
@@ -167,17 +298,71 @@ impl User {
         }
     }
 
+    /// Add experience to the user and record it in the ledger. Returns the
+    /// change that was actually applied, which is smaller than `xp` when the
+    /// user would have been left with negative experience.
     pub async fn add_experience(
         &self,
         conn: &mut Transaction<'_, Postgres>,
         xp: i64,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query("UPDATE users SET experience = GREATEST(experience + $1, 0) WHERE id = $2")
-            .bind(xp)
-            .bind(self.id)
-            .execute(conn)
-            .await?;
-        Ok(())
+        source: ExperienceSource,
+    ) -> Result<i64, sqlx::Error> {
+        let delta: i64 = sqlx::query_scalar(
+            r#"
+                UPDATE users SET experience = GREATEST(users.experience + $1, 0)
+                FROM users AS previous
+                WHERE users.id = $2 AND previous.id = users.id
+                RETURNING users.experience - previous.experience
+            "#,
+        )
+        .bind(xp)
+        .bind(self.id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let ExperienceSource::Reaction {
+            reply_id,
+            drop_id,
+            reactor_id,
+        } = source;
+        sqlx::query(
+            r#"
+                INSERT INTO experience_ledger
+                    (user_id, delta, source, reply_id, drop_id, source_user_id, created)
+                VALUES
+                    ($1, $2, 'reaction', $3, $4, $5, $6)
+            "#,
+        )
+        .bind(self.id)
+        .bind(delta)
+        .bind(reply_id)
+        .bind(drop_id)
+        .bind(reactor_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *conn)
+        .await?;
+
+        InvalidationBus::user_updated(&mut *conn, self.id).await?;
+        Ok(delta)
+    }
+
+    /// Experience the user has lost since the given time, as a positive
+    /// number.
+    pub async fn experience_lost_since(
+        &self,
+        conn: impl PgExecutor<'_>,
+        since: NaiveDateTime,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+                SELECT COALESCE(-SUM(delta), 0)::BIGINT FROM experience_ledger
+                WHERE user_id = $1 AND delta < 0 AND created >= $2
+            "#,
+        )
+        .bind(self.id)
+        .bind(since)
+        .fetch_one(conn)
+        .await
     }
 
     /// Returns a vec of equipped items.
@@ -229,13 +414,14 @@ impl User {
 
     pub async fn get_avatar(&self, conn: &PgPool) -> Result<Option<String>, sqlx::Error> {
         let Some(drop_id) = self.equip_slot_prof_pic else {
-            return Ok(None);
+            return Ok(self.avatar.clone());
         };
         Ok(ItemDrop::fetch(conn, drop_id)
             .await?
             .fetch_item(conn)
             .await?
-            .as_avatar())
+            .as_avatar()
+            .or_else(|| self.avatar.clone()))
     }
 
     pub async fn get_profile_background(
@@ -252,13 +438,14 @@ impl User {
             .as_profile_background(item_drop.pattern))
     }
 
-    pub async fn get_badges(&self, conn: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    pub async fn get_badges(&self, conn: &PgPool) -> Result<Vec<Badge>, sqlx::Error> {
         let mut badges = Vec::new();
         for badge in self.equip_slot_badges.iter() {
-            let Some(item) = ItemDrop::fetch(conn, *badge).await?.fetch_item(&*conn).await?.as_badge() else {
+            let item_drop = ItemDrop::fetch(conn, *badge).await?;
+            let Some(badge) = item_drop.fetch_item(&*conn).await?.as_badge(&item_drop) else {
                 continue;
             };
-            badges.push(item);
+            badges.push(badge);
         }
         Ok(badges)
     }
@@ -278,6 +465,24 @@ impl User {
         Ok(rows_affected > 0)
     }
 
+    /// Tag of the user's language, or the default language if it is not
+    /// known yet.
+    pub fn language(&self) -> &str {
+        self.language
+            .as_deref()
+            .unwrap_or(languages::DEFAULT_LANGUAGE)
+    }
+
+    /// Index the user lands on: their home tags if they have chosen any, and
+    /// otherwise the tag of their language.
+    pub fn home_path(&self) -> String {
+        if self.home_tags.is_empty() {
+            format!("/t/{}", self.language())
+        } else {
+            format!("/t/{}", self.home_tags)
+        }
+    }
+
     pub async fn get_profile_stub(&self, conn: &PgPool) -> Result<ProfileStub, sqlx::Error> {
         Ok(ProfileStub {
             id:         self.id,
@@ -286,56 +491,152 @@ impl User {
             background: self.get_profile_background(conn).await?,
             badges:     self.get_badges(conn).await?,
             level:      self.level_info(),
+            signature:  self.signature.clone(),
         })
     }
 
-    pub async fn next_unread(&self, conn: &PgPool, thread: &Thread) -> Result<i32, sqlx::Error> {
-        let reading_history: Option<ReadingHistory> =
-            sqlx::query_as("SELECT * FROM reading_history WHERE reader_id = $1 AND thread_id = $2")
-                .bind(self.id)
-                .bind(thread.id)
-                .fetch_optional(conn)
-                .await?;
+    /// Builds the profile stub from the user's equipped drops, which
+    /// `with_item` looks up.
+    fn profile_stub_with(
+        &self,
+        with_item: impl Fn(&i32) -> Option<(Item, ItemDrop)>,
+    ) -> ProfileStub {
+        ProfileStub {
+            id:         self.id,
+            name:       self.display_name.clone(),
+            picture:    self
+                .equip_slot_prof_pic
+                .as_ref()
+                .and_then(&with_item)
+                .and_then(|(item, _)| item.as_avatar())
+                .or_else(|| self.avatar.clone()),
+            background: self
+                .equip_slot_background
+                .as_ref()
+                .and_then(&with_item)
+                .and_then(|(item, item_drop)| item.as_profile_background(item_drop.pattern)),
+            badges:     self
+                .equip_slot_badges
+                .iter()
+                .filter_map(&with_item)
+                .filter_map(|(item, item_drop)| item.as_badge(&item_drop))
+                .collect(),
+            level:      self.level_info(),
+            signature:  self.signature.clone(),
+        }
+    }
 
-        let last_read = match reading_history {
-            None => {
-                // Find the first reply
-                let reply: Reply = sqlx::query_as(
-                    "SELECT * FROM replies WHERE thread_id = $1 ORDER BY post_date ASC",
-                )
-                .bind(thread.id)
-                .fetch_one(conn)
-                .await?;
+    /// Fetches everything shown on the user's profile page: equipped items,
+    /// inventory and the profile stub. Unlike calling `equipped`, `inventory`
+    /// and `get_profile_stub` separately, this takes two queries regardless of
+    /// how many items the user owns.
+    pub async fn fetch_profile_bundle(&self, conn: &PgPool) -> Result<ProfileBundle, sqlx::Error> {
+        let equip_slots = self
+            .equip_slot_prof_pic
+            .into_iter()
+            .chain(self.equip_slot_background)
+            .chain(self.equip_slot_badges.iter().copied())
+            .collect::<Vec<_>>();
+
+        let drops: HashMap<i32, ItemDrop> = sqlx::query_as(
+            "SELECT * FROM drops WHERE (owner_id = $1 AND consumed = FALSE) OR id = ANY($2)",
+        )
+        .bind(self.id)
+        .bind(&equip_slots)
+        .fetch(conn)
+        .map_ok(|item_drop: ItemDrop| (item_drop.id, item_drop))
+        .try_collect()
+        .await?;
 
-                reply.id
-            }
-            Some(ReadingHistory { last_read, .. }) => sqlx::query_as(
-                "SELECT * FROM replies WHERE thread_id = $1 AND id > $2 ORDER BY post_date ASC",
+        let items: HashMap<i32, Item> = sqlx::query_as("SELECT * FROM items WHERE id = ANY($1)")
+            .bind(
+                drops
+                    .values()
+                    .map(|item_drop| item_drop.item_id)
+                    .collect::<Vec<_>>(),
             )
-            .bind(thread.id)
-            .bind(last_read)
-            .fetch_optional(conn)
-            .await?
-            .map_or_else(|| last_read, |reply: Reply| reply.id),
+            .fetch(conn)
+            .map_ok(|item: Item| (item.id, item))
+            .try_collect()
+            .await?;
+
+        let with_item = |drop_id: &i32| {
+            let item_drop = drops.get(drop_id)?;
+            Some((items.get(&item_drop.item_id)?.clone(), item_drop.clone()))
         };
 
-        Ok(last_read)
+        let equipped = equip_slots.iter().filter_map(with_item).collect::<Vec<_>>();
+
+        let mut inventory = drops
+            .values()
+            .filter(|item_drop| {
+                item_drop.owner_id == self.id
+                    && !item_drop.consumed
+                    && !equip_slots.contains(&item_drop.id)
+            })
+            .filter_map(|item_drop| with_item(&item_drop.id))
+            .collect::<Vec<_>>();
+        inventory.sort_by(|a, b| a.0.rarity.cmp(&b.0.rarity).reverse());
+
+        let stub = self.profile_stub_with(with_item);
+
+        Ok(ProfileBundle {
+            stub,
+            equipped,
+            inventory,
+        })
     }
 
-    pub async fn has_read(&self, conn: &PgPool, thread: &Thread) -> Result<bool, sqlx::Error> {
-        Ok(
-            sqlx::query_as("SELECT * FROM reading_history WHERE reader_id = $1 AND thread_id = $2")
-                .bind(self.id)
-                .bind(thread.id)
-                .fetch_optional(conn)
-                .await?
-                .map_or(false, |history: ReadingHistory| {
-                    history.last_read >= thread.last_post
-                }),
+    /// Fetches how far the user has read into each of a number of threads.
+    pub async fn reading_status(
+        &self,
+        conn: &PgPool,
+        thread_ids: &[i32],
+    ) -> Result<HashMap<i32, ReadingStatus>, sqlx::Error> {
+        sqlx::query(
+            r#"
+                SELECT
+                    threads.id,
+                    COALESCE(reading_history.last_read >= threads.last_visible_post, FALSE) AS read,
+                    COALESCE(
+                        MIN(replies.id) FILTER (WHERE replies.id > reading_history.last_read),
+                        reading_history.last_read,
+                        MIN(replies.id)
+                    ) AS jump_to,
+                    COUNT(replies.id) FILTER (
+                        WHERE NOT replies.hidden
+                            AND replies.id > COALESCE(reading_history.last_read, 0)
+                    ) AS unread
+                FROM threads
+                LEFT JOIN reading_history
+                    ON reading_history.thread_id = threads.id AND reading_history.reader_id = $1
+                LEFT JOIN replies ON replies.thread_id = threads.id
+                WHERE threads.id = ANY($2)
+                GROUP BY threads.id, reading_history.last_read
+            "#,
         )
+        .bind(self.id)
+        .bind(thread_ids)
+        .fetch(conn)
+        .map_ok(|row| {
+            (
+                row.get("id"),
+                ReadingStatus {
+                    read:    row.get("read"),
+                    jump_to: row.get::<Option<i32>, _>("jump_to").unwrap_or_default(),
+                    unread:  row.get("unread"),
+                },
+            )
+        })
+        .try_collect()
+        .await
     }
 
     pub async fn read_thread(&self, conn: &PgPool, thread: &Thread) -> Result<(), sqlx::Error> {
+        if self.viewed_by.is_some() {
+            return Ok(());
+        }
+
         sqlx::query(
             r#"
             INSERT INTO reading_history
@@ -366,6 +667,53 @@ impl User {
                 .get(0),
         )
     }
+
+    /// Whether the user has blocked another user.
+    pub async fn has_blocked(
+        &self,
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM user_blocks WHERE blocker_id = $1 AND blocked_id = $2)",
+        )
+        .bind(self.id)
+        .bind(user_id)
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Ids of every user that the user has blocked.
+    pub async fn blocked_users(
+        &self,
+        conn: impl PgExecutor<'_>,
+    ) -> Result<HashSet<i32>, sqlx::Error> {
+        let blocked: Vec<i32> =
+            sqlx::query_scalar("SELECT blocked_id FROM user_blocks WHERE blocker_id = $1")
+                .bind(self.id)
+                .fetch_all(conn)
+                .await?;
+        Ok(blocked.into_iter().collect())
+    }
+
+    /// Whether the user follows another user.
+    pub async fn is_following(
+        &self,
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+                SELECT EXISTS (
+                    SELECT 1 FROM user_follows WHERE follower_id = $1 AND followee_id = $2
+                )
+            "#,
+        )
+        .bind(self.id)
+        .bind(user_id)
+        .fetch_one(conn)
+        .await
+    }
 }
 
 #[derive(Deserialize)]
@@ -377,20 +725,25 @@ pub struct UserRegistrationForm {
 
 #[derive(Serialize)]
 pub struct UserRegistration {
-    qr_code_url: String,
-    reset_code:  String,
+    qr_code_url:    String,
+    reset_code:     String,
+    recovery_codes: Vec<String>,
 }
 
 #[derive(Error, Debug, Serialize, ErrorCode)]
 pub enum UserRegistrationError {
-    #[error("User names can only contain alphabetical characters")]
-    InvalidUserName,
+    #[error("{0}")]
+    InvalidUserName(#[from] UsernameError),
     #[error("Password is too short (minimum {MINIMUM_PASSWORD_LENGTH} characters)")]
     PasswordTooShort,
     #[error("User name has already been registered")]
     UserNameInUse,
+    #[error("User name is too similar to one that has already been registered")]
+    UserNameConfusable,
     #[error("Invalid email")]
     InvalidEmail,
+    #[error("Registration is not allowed from your network")]
+    NetworkBanned,
     #[error("Internal db error: {0}")]
     InternalDbError(
         #[from]
@@ -414,19 +767,18 @@ post!(
     #[json]
     async fn register_user(
         conn: Extension<PgPool>,
+        ClientIp(ip): ClientIp,
         Form(UserRegistrationForm {
             username,
             password,
             email,
         }): Form<UserRegistrationForm>,
     ) -> Result<UserRegistration, UserRegistrationError> {
-        let username = username.trim();
-        if !is_valid_username(&username) {
-            return Err(UserRegistrationError::InvalidUserName);
+        if IpBan::covering(&*conn, ip).await?.is_some() {
+            return Err(UserRegistrationError::NetworkBanned);
         }
 
-        let name = username.to_lowercase();
-        let display_name = username;
+        let username = usernames::validate(&username)?;
         let email = email.trim();
 
         if password.len() < MINIMUM_PASSWORD_LENGTH {
@@ -438,7 +790,7 @@ post!(
         }
 
         let existing_user: Option<User> = sqlx::query_as("SELECT * FROM users WHERE name = $1")
-            .bind(&name)
+            .bind(&username.name)
             .fetch_optional(&*conn)
             .await?;
 
@@ -446,46 +798,176 @@ post!(
             return Err(UserRegistrationError::UserNameInUse);
         }
 
+        if usernames::is_confusable(&*conn, &username).await? {
+            return Err(UserRegistrationError::UserNameConfusable);
+        }
+
+        usernames::check_rules(&*conn, &username, false).await??;
+
         let shared_secret = create_secret!();
         let nonce = Nonce::from_slice(SHARED_SECRET_NONCE);
         let encrypted_secret = SHARED_SECRET_CIPHER.encrypt(nonce, shared_secret.as_ref())?;
-        let qr_code_url = qr_code_url!(&shared_secret, "C'est Le Marché", "C'est Le Marché");
+        let qr_code_url =
+            qr_code_url!(&shared_secret, &branding().site_name, &branding().site_name);
 
         let reset_code =
             base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
-        let hashed_reset_code = hash_password(&reset_code);
-        let password = hash_password(&password);
+        let hashed_reset_code = passwords::hash(&reset_code);
+        let password = passwords::hash(&password);
 
-        sqlx::query(
+        let mut transaction = conn.begin().await?;
+
+        let user_id: i32 = sqlx::query_scalar(
             r#"
             INSERT INTO users (
-                name, display_name, password, secret, reset_code, email,
+                name, display_name, name_skeleton, password, secret, reset_code, email,
                 role, last_reward, experience, bio, equip_slot_badges, notes
-            ) VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, 0, '', '{}', '' )
+            ) VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9, 0, '', '{}', '' )
+            RETURNING id
             "#,
         )
-        .bind(name)
-        .bind(display_name)
+        .bind(username.name)
+        .bind(username.display_name)
+        .bind(username.skeleton)
         .bind(password)
         .bind(encrypted_secret)
         .bind(&hashed_reset_code)
         .bind(email.trim())
         .bind(Role::User)
         .bind(Utc::now().naive_utc())
-        .execute(&*conn)
+        .fetch_one(&mut transaction)
         .await?;
 
+        let recovery_codes = recovery_codes::generate(&mut transaction, user_id).await?;
+
+        transaction.commit().await?;
+
         Ok(UserRegistration {
             qr_code_url,
             reset_code,
+            recovery_codes,
         })
     }
 );
 
-fn is_valid_username(username: &str) -> bool {
-    username.chars().all(char::is_alphanumeric)
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    username:   String,
+    reset_code: String,
+    password:   String,
+}
+
+#[derive(Serialize)]
+pub struct PasswordReset {
+    qr_code_url: String,
+    reset_code:  String,
+}
+
+#[derive(Error, Debug, Serialize, ErrorCode)]
+pub enum ResetPasswordError {
+    #[error("User name or reset code is incorrect")]
+    UserOrResetCodeIncorrect,
+    #[error("Password is too short (minimum {MINIMUM_PASSWORD_LENGTH} characters)")]
+    PasswordTooShort,
+    #[error("Too many failed attempts, try again in {retry_after} seconds")]
+    TooManyAttempts { retry_after: u64 },
+    #[error("Internal db error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+    #[error("Internal encryption error")]
+    InternalEncryptionError,
+}
+
+impl From<aes_gcm::Error> for ResetPasswordError {
+    fn from(_: aes_gcm::Error) -> Self {
+        ResetPasswordError::InternalEncryptionError
+    }
 }
 
+post!(
+    "/reset_password",
+    #[json]
+    async fn reset_password(
+        conn: Extension<PgPool>,
+        cluster: Extension<Cluster>,
+        revocations: Extension<Revocations>,
+        ClientIp(ip): ClientIp,
+        Form(ResetPasswordForm {
+            username,
+            reset_code,
+            password,
+        }): Form<ResetPasswordForm>,
+    ) -> Result<PasswordReset, ResetPasswordError> {
+        if password.len() < MINIMUM_PASSWORD_LENGTH {
+            return Err(ResetPasswordError::PasswordTooShort);
+        }
+
+        // Reset codes are guessed at like passwords, so they share the limits.
+        let name = usernames::canonical(&username);
+        if let Some(retry_after) = count_attempt(&cluster, &name, ip).await? {
+            return Err(ResetPasswordError::TooManyAttempts { retry_after });
+        }
+
+        let mut transaction = conn.begin().await?;
+
+        let user: User =
+            sqlx::query_as("SELECT * FROM users WHERE name = $1 AND deleted IS NULL FOR UPDATE")
+                .bind(&name)
+                .fetch_optional(&mut transaction)
+                .await?
+                .ok_or(ResetPasswordError::UserOrResetCodeIncorrect)?;
+
+        // A recovery code is only used up if the reset code doesn't match.
+        let reset_code = reset_code.trim();
+        if !passwords::spawn_verify(&user.reset_code, reset_code).await
+            && !recovery_codes::consume(&mut transaction, user.id, reset_code).await?
+        {
+            return Err(ResetPasswordError::UserOrResetCodeIncorrect);
+        }
+
+        // The reset code is single use, so the account is given a new one
+        // along with a new second factor.
+        let shared_secret = create_secret!();
+        let nonce = Nonce::from_slice(SHARED_SECRET_NONCE);
+        let encrypted_secret = SHARED_SECRET_CIPHER.encrypt(nonce, shared_secret.as_ref())?;
+        let qr_code_url =
+            qr_code_url!(&shared_secret, &branding().site_name, &branding().site_name);
+
+        let reset_code =
+            base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+
+        sqlx::query("UPDATE users SET password = $1, secret = $2, reset_code = $3 WHERE id = $4")
+            .bind(passwords::spawn_hash(&password).await)
+            .bind(encrypted_secret)
+            .bind(passwords::spawn_hash(&reset_code).await)
+            .bind(user.id)
+            .execute(&mut transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM login_sessions WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&mut transaction)
+            .await?;
+
+        InvalidationBus::user_updated(&mut *transaction, user.id).await?;
+
+        transaction.commit().await?;
+
+        revocations.revoke(Revocation::User(user.id)).await?;
+        forget_attempts(&cluster, &name).await?;
+
+        tracing::info!("User `{}` has reset their password", user.name);
+
+        Ok(PasswordReset {
+            qr_code_url,
+            reset_code,
+        })
+    }
+);
+
 #[derive(Deserialize)]
 pub struct UpdateUser {
     role: Role,
@@ -528,6 +1010,8 @@ post!(
             .execute(&*conn)
             .await?;
 
+        InvalidationBus::user_updated(&*conn, user_id).await?;
+
         Ok(())
     }
 );
@@ -536,6 +1020,9 @@ post!(
 pub struct BanUser {
     #[serde(default, deserialize_with = "crate::empty_string_as_none")]
     ban_len: Option<u32>,
+    /// Shown to the user while they are banned
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    reason:  Option<String>,
 }
 
 post!(
@@ -543,9 +1030,10 @@ post!(
     #[json]
     async fn ban_user(
         conn: Extension<PgPool>,
+        revocations: Extension<Revocations>,
         moderator: User,
         Path(user_id): Path<i32>,
-        Query(BanUser { ban_len }): Query<BanUser>,
+        Query(BanUser { ban_len, reason }): Query<BanUser>,
     ) -> Result<(), UpdateUserError> {
         if moderator.role < Role::Moderator || moderator.id == user_id {
             return Err(UpdateUserError::Unauthorized);
@@ -554,25 +1042,52 @@ post!(
             .await?
             .ok_or(UpdateUserError::NoSuchUser)?;
 
-        sqlx::query("UPDATE users SET banned_until = $1 WHERE id = $2")
-            .bind(ban_len.map(|days| (Utc::now() + Duration::days(days as i64)).naive_utc()))
-            .bind(user_id)
-            .execute(&*conn)
+        let mut transaction = conn.begin().await?;
+
+        let until = ban_len.map(|days| (Utc::now() + Duration::days(days as i64)).naive_utc());
+        // Lifting a ban forgets why it was issued.
+        let (reason, banned_by) = match until {
+            Some(_) => (reason, Some(moderator.id)),
+            None => (None, None),
+        };
+        sqlx::query(
+            "UPDATE users SET banned_until = $1, ban_reason = $2, banned_by = $3 WHERE id = $4",
+        )
+        .bind(until)
+        .bind(reason)
+        .bind(banned_by)
+        .bind(user_id)
+        .execute(&mut transaction)
+        .await?;
+
+        InvalidationBus::user_updated(&mut *transaction, user_id).await?;
+
+        if let Some(until) = until {
+            Event::UserBanned {
+                user_id,
+                moderator_id: moderator.id,
+                until,
+            }
+            .publish(&mut *transaction)
             .await?;
+        }
+
+        transaction.commit().await?;
+
+        if until.is_some() {
+            revocations.revoke(Revocation::User(user_id)).await?;
+        }
 
         Ok(())
     }
 );
 
-#[derive(Deserialize)]
-pub struct UpdateBioForm {
-    bio: String,
-}
-
 #[derive(Debug, Serialize, Error, ErrorCode)]
-pub enum UpdateBioError {
-    #[error("Bio is too long (maximum {MAX_BIO_LEN} characters allowed)")]
-    TooLong,
+pub enum BlockUserError {
+    #[error("You cannot block yourself")]
+    CannotBlockSelf,
+    #[error("No such user")]
+    NoSuchUser,
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
@@ -581,39 +1096,78 @@ pub enum UpdateBioError {
     ),
 }
 
-pub const MAX_BIO_LEN: usize = 300;
-
 post!(
-    "/bio",
+    "/block/:user_id",
     #[json]
-    async fn update_bio(
+    async fn block_user(
         conn: Extension<PgPool>,
+        notifications: Extension<Notifications>,
         user: User,
-        Form(UpdateBioForm { bio }): Form<UpdateBioForm>,
-    ) -> Result<(), UpdateBioError> {
-        if bio.len() > MAX_BIO_LEN {
-            return Err(UpdateBioError::TooLong);
+        Path(user_id): Path<i32>,
+    ) -> Result<(), BlockUserError> {
+        if user.id == user_id {
+            return Err(BlockUserError::CannotBlockSelf);
         }
+        User::fetch_optional(&*conn, user_id)
+            .await?
+            .ok_or(BlockUserError::NoSuchUser)?;
 
-        sqlx::query("UPDATE users SET bio = $1 WHERE id = $2")
-            .bind(bio)
+        let mut transaction = conn.begin().await?;
+
+        sqlx::query(
+            r#"
+                INSERT INTO user_blocks (blocker_id, blocked_id, created) VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user.id)
+        .bind(user_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut transaction)
+        .await?;
+
+        // Offers that the blocked user has already sent are declined.
+        let declined =
+            sqlx::query("DELETE FROM trade_requests WHERE sender_id = $1 AND receiver_id = $2")
+                .bind(user_id)
+                .bind(user.id)
+                .execute(&mut transaction)
+                .await?
+                .rows_affected();
+
+        transaction.commit().await?;
+
+        if declined > 0 {
+            notifications.offers_changed(&*conn, user.id).await?;
+        }
+
+        Ok(())
+    }
+);
+
+post!(
+    "/unblock/:user_id",
+    #[json]
+    async fn unblock_user(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(user_id): Path<i32>,
+    ) -> Result<(), BlockUserError> {
+        sqlx::query("DELETE FROM user_blocks WHERE blocker_id = $1 AND blocked_id = $2")
             .bind(user.id)
+            .bind(user_id)
             .execute(&*conn)
             .await?;
-
         Ok(())
     }
 );
 
-#[derive(Deserialize)]
-pub struct AddNoteForm {
-    body: String,
-}
-
-#[derive(Debug, Error, Serialize, ErrorCode)]
-pub enum AddNoteError {
-    #[error("You are not privileged enough")]
-    Unauthorized,
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum FollowUserError {
+    #[error("You cannot follow yourself")]
+    CannotFollowSelf,
+    #[error("No such user")]
+    NoSuchUser,
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
@@ -623,303 +1177,1497 @@ pub enum AddNoteError {
 }
 
 post!(
-    "/add_note/:user_id",
+    "/follow/:user_id",
     #[json]
-    pub async fn submit(
+    async fn follow_user(
         conn: Extension<PgPool>,
-        viewer: User,
+        user: User,
         Path(user_id): Path<i32>,
-        Form(AddNoteForm { body }): Form<AddNoteForm>,
-    ) -> Result<(), AddNoteError> {
-        if viewer.role < Role::Moderator {
-            return Err(AddNoteError::Unauthorized);
+    ) -> Result<(), FollowUserError> {
+        if user.id == user_id {
+            return Err(FollowUserError::CannotFollowSelf);
         }
+        User::fetch_optional(&*conn, user_id)
+            .await?
+            .filter(|followee| followee.deleted.is_none())
+            .ok_or(FollowUserError::NoSuchUser)?;
 
-        let viewer_name = viewer.name;
-        let body = html_escape::encode_text(&body);
-        let new_note = format!("<p>“{body}” — {viewer_name}</p>");
+        sqlx::query(
+            r#"
+                INSERT INTO user_follows (follower_id, followee_id, created) VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user.id)
+        .bind(user_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&*conn)
+        .await?;
 
-        sqlx::query("UPDATE users SET notes = notes || $1 WHERE id = $2")
-            .bind(new_note)
+        Ok(())
+    }
+);
+
+post!(
+    "/unfollow/:user_id",
+    #[json]
+    async fn unfollow_user(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(user_id): Path<i32>,
+    ) -> Result<(), FollowUserError> {
+        sqlx::query("DELETE FROM user_follows WHERE follower_id = $1 AND followee_id = $2")
+            .bind(user.id)
             .bind(user_id)
             .execute(&*conn)
             .await?;
-
         Ok(())
     }
 );
 
-/// Name of the cookie we use to store the session Id.
-const USER_SESSION_ID_COOKIE: &str = "session_id";
-// TODO: Move to environmental variable
-const PRIVATE_COOKIE_KEY: &str = "ea63npVp7Vg+ileGuoO0OJbBLOdSkHKkNwu87B8/joU=";
-
-#[async_trait]
-impl<S> FromRequestParts<S> for User
-where
-    S: Send + Sync,
-{
-    type Rejection = UserRejection;
+/// Number of revocations that can be buffered for a slow subscriber.
+const REVOCATION_CHANNEL_CAPACITY: usize = 64;
+/// How long a validated login session is trusted before it is re-fetched.
+const SESSION_CACHE_TTL: StdDuration = StdDuration::from_secs(60);
+/// Maximum number of login sessions kept in the cache.
+const SESSION_CACHE_CAPACITY: usize = 4096;
+
+/// Access that has been taken away from a user.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Revocation {
+    /// Every session belonging to a user, e.g. because they were banned or
+    /// logged out everywhere.
+    User(i32),
+    /// A single login session.
+    Session(i32),
+}
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let redirect = parts
-            .uri
-            .path_and_query()
-            .map(|x| x.as_str().to_string())
-            .unwrap_or_else(String::new);
-        let cookies = Cookies::from_request_parts(parts, state)
-            .await
-            .map_err(|_| UserRejection::Unauthorized {
-                redirect: redirect.clone(),
-            })?;
-        let private_key = Key::derive_from(PRIVATE_COOKIE_KEY.as_bytes());
-        let signed = cookies.private(&private_key);
-        let session_id = signed
-            .get(USER_SESSION_ID_COOKIE)
-            .ok_or(UserRejection::Unauthorized {
-                redirect: redirect.clone(),
-            })?;
-        let conn = Extension::<PgPool>::from_request_parts(parts, state)
-            .await
-            .map_err(|_| UserRejection::UnknownError)?;
-        let Some(session) = LoginSession::fetch(&conn, session_id.value()).await? else {
-            return Err(UserRejection::Unauthorized {
-                redirect: redirect.clone(),
-            });
-        };
-        let user = match User::fetch_optional(&*conn, session.user_id).await {
-            Ok(Some(user)) => user,
-            Ok(None) => return Err(UserRejection::UnknownUser),
-            Err(_) => return Err(UserRejection::Unauthorized { redirect }),
-        };
-        if user.is_banned() {
-            Err(UserRejection::Banned {
-                until: user.banned_until.unwrap(),
-            })
-        } else {
-            Ok(user)
+impl Revocation {
+    pub fn applies_to(&self, session: &LoginSession) -> bool {
+        match *self {
+            Self::User(user_id) => session.user_id == user_id,
+            Self::Session(id) => session.id == id,
         }
     }
 }
 
-#[derive(Debug, Error)]
-pub enum UserRejection {
-    #[error("An unknown error occurred")]
-    UnknownError,
-    #[error("Internal database error: {0}")]
-    InternalDbError(#[from] sqlx::Error),
-    #[error("Unknown user")]
-    UnknownUser,
-    #[error("Unauthorized user")]
-    Unauthorized { redirect: String },
-    #[error("Banned until {until}")]
-    Banned { until: NaiveDateTime },
+/// Broadcast of revoked access, so that long-lived connections such as watch
+/// sockets can be closed. Revocations are published to the whole cluster and
+/// reach local subscribers through `receive`. Also holds the cache of recently
+/// validated login sessions used by the `User` extractor, which is purged on
+/// revocation.
+#[derive(Clone)]
+pub struct Revocations {
+    cluster:  Cluster,
+    sender:   broadcast::Sender<Revocation>,
+    sessions: Arc<Mutex<HashMap<String, (LoginSession, Instant)>>>,
 }
 
-#[derive(Template)]
-#[template(path = "banned.html")]
-pub struct Banned {
-    judge_type: bool,
-    until:      String,
-}
+impl Revocations {
+    pub fn new(cluster: Cluster) -> Self {
+        let (sender, _) = broadcast::channel(REVOCATION_CHANNEL_CAPACITY);
+        Self {
+            cluster,
+            sender,
+            sessions: Default::default(),
+        }
+    }
 
-impl IntoResponse for UserRejection {
-    fn into_response(self) -> Response {
-        match self {
-            Self::Banned { until } => Banned {
-                judge_type: rand::random(),
-                until:      until.format(crate::DATE_FMT).to_string(),
-            }
-            .into_response(),
-            Self::Unauthorized { redirect } => {
-                Redirect::to(&format!("/login?redirect={redirect}")).into_response()
+    pub async fn revoke(&self, revocation: Revocation) -> Result<(), sqlx::Error> {
+        let payload = serde_json::to_string(&revocation).unwrap();
+        self.cluster.publish(Topic::Revocations, &payload).await
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Revocation> {
+        self.sender.subscribe()
+    }
+
+    /// Background task that applies revocations published by any instance.
+    pub async fn receive(self, mut messages: broadcast::Receiver<String>) {
+        loop {
+            let revocation = match messages.recv().await {
+                Ok(message) => match serde_json::from_str::<Revocation>(&message) {
+                    Ok(revocation) => revocation,
+                    Err(_) => {
+                        tracing::warn!("Invalid revocation: `{message}`");
+                        continue;
+                    }
+                },
+                // We can't tell which sessions were revoked, so trust none.
+                Err(RecvError::Lagged(_)) => {
+                    self.sessions.lock().unwrap().clear();
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            self.sessions
+                .lock()
+                .unwrap()
+                .retain(|_, (session, _)| !revocation.applies_to(session));
+            // An error only means that nobody is currently listening.
+            let _ = self.sender.send(revocation);
+        }
+    }
+
+    fn cached_session(&self, session_id: &str) -> Option<LoginSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(session_id) {
+            Some((session, cached_at))
+                if cached_at.elapsed() < SESSION_CACHE_TTL && !session.is_expired() =>
+            {
+                Some(session.clone())
             }
-            err => {
-                tracing::error!("Unknown error occurred: {:?}", err);
-                Redirect::to("/login").into_response()
+            Some(_) => {
+                sessions.remove(session_id);
+                None
             }
+            None => None,
         }
     }
-}
-
-#[derive(Clone)]
-pub struct UserCache<'a> {
-    conn:   &'a PgPool,
-    cached: Arc<Mutex<HashMap<i32, Arc<ProfileStub>>>>,
-}
 
-impl<'a> UserCache<'a> {
-    pub fn new(conn: &'a PgPool) -> Self {
-        UserCache {
-            conn,
-            cached: Arc::new(Mutex::new(Default::default())),
+    /// Drops every cached session belonging to users that have been updated,
+    /// so that they are re-validated on their next request.
+    pub async fn forget_updated_users(self, mut updates: broadcast::Receiver<i32>) {
+        loop {
+            match updates.recv().await {
+                Ok(user_id) => self
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .retain(|_, (session, _)| session.user_id != user_id),
+                Err(RecvError::Lagged(_)) => self.sessions.lock().unwrap().clear(),
+                Err(RecvError::Closed) => return,
+            }
         }
     }
 
-    pub async fn get(&self, id: i32) -> Result<Arc<ProfileStub>, sqlx::Error> {
-        if let Some(result) = self.cached.lock().unwrap().get(&id) {
-            return Ok(result.clone());
+    fn cache_session(&self, session: LoginSession) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= SESSION_CACHE_CAPACITY {
+            sessions.clear();
         }
-        let profile_stub = Arc::new(
-            User::fetch(self.conn, id)
-                .await?
-                .get_profile_stub(self.conn)
-                .await?,
-        );
-        self.cached.lock().unwrap().insert(id, profile_stub.clone());
-        Ok(profile_stub)
+        sessions.insert(session.session_id.clone(), (session, Instant::now()));
     }
 }
 
-/// User login sessions
-#[derive(FromRow)]
-pub struct LoginSession {
-    /// Id of the login session
-    pub id:            i32,
-    /// Auth token
-    pub session_id:    String,
-    /// UserId of the session
-    pub user_id:       i32,
-    /// When the session began
-    pub session_start: NaiveDateTime,
-    /// The IP address of the connecting client
-    pub ip_addr:       IpNetwork,
+#[derive(Deserialize)]
+pub struct UpdateBioForm {
+    bio: String,
 }
 
 #[derive(Debug, Serialize, Error, ErrorCode)]
-pub enum LoginFailure {
-    #[error("Username or password is incorrect")]
-    UserOrPasswordIncorrect,
+pub enum UpdateBioError {
+    #[error("Bio is too long (maximum {max} characters allowed)")]
+    TooLong { max: usize },
     #[error("Internal database error: {0}")]
     InternalDbError(
         #[from]
         #[serde(skip)]
         sqlx::Error,
     ),
-    #[error("Internal encryption error")]
-    InternalEncryptionError,
-}
-
-impl From<aes_gcm::Error> for LoginFailure {
-    fn from(_: aes_gcm::Error) -> Self {
-        LoginFailure::InternalEncryptionError
-    }
 }
 
-impl From<FromUtf8Error> for LoginFailure {
-    fn from(_: FromUtf8Error) -> Self {
-        LoginFailure::InternalEncryptionError
-    }
-}
+post!(
+    "/bio",
+    #[json]
+    async fn update_bio(
+        conn: Extension<PgPool>,
+        limits: Extension<Limits>,
+        user: User,
+        Form(UpdateBioForm { bio }): Form<UpdateBioForm>,
+    ) -> Result<(), UpdateBioError> {
+        let max = limits.get(Limit::MaxBioLength);
+        if bio.len() > max {
+            return Err(UpdateBioError::TooLong { max });
+        }
 
-lazy_static! {
-    pub static ref SHARED_SECRET_CIPHER: Aes256Gcm = {
-        let key = std::env::var("SHARED_SECRET_KEY").unwrap();
-        let key_bytes = base64::decode(&key).expect("SHARED_SECRET_KEY is not valid base64");
-        Aes256Gcm::new_from_slice(&key_bytes).expect("could not construct shared secret cipher")
-    };
-}
+        sqlx::query("UPDATE users SET bio = $1 WHERE id = $2")
+            .bind(bio)
+            .bind(user.id)
+            .execute(&*conn)
+            .await?;
 
-pub const SHARED_SECRET_NONCE: &[u8; 12] = b"96bitsIs12u8";
+        InvalidationBus::user_updated(&*conn, user.id).await?;
 
-impl LoginSession {
-    /// Fetch the login session.
-    pub async fn fetch(conn: &PgPool, session_id: &str) -> Result<Option<Self>, sqlx::Error> {
-        Ok(
-            sqlx::query_as("SELECT * FROM login_sessions WHERE session_id = $1")
-                .bind(session_id)
-                .fetch_optional(conn)
-                .await?
-                .filter(|session: &Self| {
-                    // The session is automatically invalid if the session is longer than a month
-                    // old.
-                    session.session_start >= (Utc::now() - Duration::weeks(52)).naive_utc()
-                }),
-        )
+        Ok(())
     }
+);
 
-    /// Attempt to login a user
-    pub async fn login(
-        conn: &PgPool,
-        username: &str,
-        password: &str,
-        ip_addr: IpNetwork,
-    ) -> Result<Self, LoginFailure> {
-        let username = username.trim();
-        let user: User = {
-            sqlx::query_as("SELECT * FROM users WHERE name = $1")
-                .bind(username.to_lowercase())
-                .fetch_optional(conn)
-                .await?
-                .ok_or(LoginFailure::UserOrPasswordIncorrect)?
-        };
-
-        if !verify_password(&user.password, password) {
-            return Err(LoginFailure::UserOrPasswordIncorrect);
-        }
+pub const MAX_PRONOUNS_LENGTH: usize = 32;
+pub const MAX_LOCATION_LENGTH: usize = 64;
+pub const MAX_WEBSITE_LENGTH: usize = 200;
+pub const MAX_SIGNATURE_LENGTH: usize = 200;
 
-        // TODO: Add extra protections here?
+#[derive(Deserialize)]
+pub struct ProfileSettingsForm {
+    #[serde(default)]
+    pronouns:  String,
+    #[serde(default)]
+    location:  String,
+    #[serde(default)]
+    website:   String,
+    #[serde(default)]
+    signature: String,
+}
 
-        let mut key = [0u8; 16];
-        OsRng.fill_bytes(&mut key);
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum UpdateProfileError {
+    #[error("The {field} is too long (maximum {max} characters allowed)")]
+    TooLong { field: &'static str, max: usize },
+    #[error("Website must be a link starting with http:// or https://")]
+    InvalidWebsite,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
 
-        // Found a user, create a new login sessions
-        let session_start = Utc::now().naive_utc();
+post!(
+    "/profile/settings",
+    #[json]
+    async fn update_profile_settings(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(form): Form<ProfileSettingsForm>,
+    ) -> Result<(), UpdateProfileError> {
+        let pronouns = form.pronouns.trim();
+        let location = form.location.trim();
+        let website = form.website.trim();
+        let signature = form.signature.trim();
+        for (field, value, max) in [
+            ("pronouns", pronouns, MAX_PRONOUNS_LENGTH),
+            ("location", location, MAX_LOCATION_LENGTH),
+            ("website", website, MAX_WEBSITE_LENGTH),
+            ("signature", signature, MAX_SIGNATURE_LENGTH),
+        ] {
+            if value.chars().count() > max {
+                return Err(UpdateProfileError::TooLong { field, max });
+            }
+        }
+        if !website.is_empty()
+            && !(website.starts_with("https://") || website.starts_with("http://"))
+        {
+            return Err(UpdateProfileError::InvalidWebsite);
+        }
 
-        Ok(sqlx::query_as(
+        sqlx::query(
             r#"
-                INSERT INTO login_sessions
-                    (user_id, session_id, session_start, ip_addr)
-                VALUES
-                    ($1, $2, $3, $4)
-                RETURNING
-                    *
+                UPDATE users SET pronouns = $1, location = $2, website = $3, signature = $4
+                WHERE id = $5
             "#,
         )
+        .bind(pronouns)
+        .bind(location)
+        .bind(website)
+        .bind(signature)
         .bind(user.id)
-        .bind(i128::from_be_bytes(key).to_string())
-        .bind(session_start)
-        .bind(ip_addr)
-        .fetch_one(conn)
-        .await?)
-    }
-}
+        .execute(&*conn)
+        .await?;
 
-#[derive(Deserialize)]
-pub struct LoginForm {
-    username: String,
-    password: String,
-}
+        InvalidationBus::user_updated(&*conn, user.id).await?;
 
-post!(
-    "/login",
-    #[json]
-    async fn login(
-        pool: Extension<PgPool>,
-        jar: Cookies,
-        ClientIp(ip): ClientIp,
-        login: Form<LoginForm>,
-    ) -> Result<(), LoginFailure> {
-        let key = Key::derive_from(PRIVATE_COOKIE_KEY.as_bytes());
-        let private = jar.private(&key);
-        private.remove(Cookie::named(USER_SESSION_ID_COOKIE));
-        let LoginSession { session_id, .. } = LoginSession::login(
-            &pool,
-            login.username.trim(),
-            login.password.trim(),
-            IpNetwork::from(ip),
-        )
-        .await?;
-        let mut cookie = Cookie::new(USER_SESSION_ID_COOKIE, session_id.to_string());
-        cookie
-            .set_expires(cookie_time::OffsetDateTime::now_utc() + cookie_time::Duration::weeks(52));
-        private.add(cookie);
         Ok(())
     }
 );
 
+/// Uploading a profile picture takes no fields besides the file.
+#[derive(Deserialize)]
+pub struct AvatarForm {}
+
 #[derive(Debug, Serialize, Error, ErrorCode)]
-pub enum LogoutFailure {
+pub enum UploadAvatarError {
+    #[error("You must be level {MIN_LEVEL_TO_UPLOAD_PHOTOS} in order to upload photos")]
+    NotAllowedToUploadPictures,
+    #[error("Error uploading image: {0}")]
+    UploadImageError(#[from] UploadImageError),
+    #[error("Multipart form error: {0}")]
+    MultipartFormError(#[from] MultipartFormError),
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/profile/avatar",
+    #[json]
+    async fn upload_avatar(
+        conn: Extension<PgPool>,
+        user: User,
+        form: Result<MultipartForm<AvatarForm, MAXIMUM_FILE_SIZE>, MultipartFormError>,
+    ) -> Result<Option<String>, UploadAvatarError> {
+        // Sending the form without a file removes the picture.
+        let avatar = match form?.file {
+            Some(file) => {
+                if !user.can_post_photos() {
+                    return Err(UploadAvatarError::NotAllowedToUploadPictures);
+                }
+                let image = Image::upload_image(file.bytes).await?;
+                Some(image.thumbnail.unwrap_or(image.filename))
+            }
+            None => None,
+        };
+
+        sqlx::query("UPDATE users SET avatar = $1 WHERE id = $2")
+            .bind(&avatar)
+            .bind(user.id)
+            .execute(&*conn)
+            .await?;
+
+        InvalidationBus::user_updated(&*conn, user.id).await?;
+
+        Ok(avatar)
+    }
+);
+
+/// Number of experience ledger entries per page of a user's history.
+pub const EXPERIENCE_HISTORY_PER_PAGE: i64 = 50;
+
+/// A change in a user's experience, as recorded in the experience ledger.
+#[derive(Debug, FromRow, Serialize)]
+pub struct ExperienceLedgerEntry {
+    pub id:             i32,
+    pub delta:          i64,
+    /// What caused the change, e.g. `reaction`
+    pub source:         String,
+    pub reply_id:       Option<i32>,
+    pub drop_id:        Option<i32>,
+    pub source_user_id: Option<i32>,
+    pub created:        NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+pub struct ExperienceHistoryParams {
+    #[serde(default, deserialize_with = "crate::empty_string_as_none")]
+    page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ExperienceHistory {
+    entries:   Vec<ExperienceLedgerEntry>,
+    page:      i64,
+    last_page: i64,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum ExperienceHistoryError {
+    #[error("You are not authorized to view this user's experience history")]
+    Unauthorized,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/profile/:user_id/xp_history",
+    #[json]
+    async fn experience_history(
+        conn: Extension<PgPool>,
+        user: User,
+        Path(user_id): Path<i32>,
+        Query(ExperienceHistoryParams { page }): Query<ExperienceHistoryParams>,
+    ) -> Result<ExperienceHistory, ExperienceHistoryError> {
+        // Users may see their own history, moderators may see anyone's.
+        if user.id != user_id && user.role < Role::Moderator {
+            return Err(ExperienceHistoryError::Unauthorized);
+        }
+
+        let num_entries: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM experience_ledger WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_one(&*conn)
+                .await?;
+        let last_page =
+            (num_entries.max(1) + EXPERIENCE_HISTORY_PER_PAGE - 1) / EXPERIENCE_HISTORY_PER_PAGE;
+        let page = page.unwrap_or(1).clamp(1, last_page);
+
+        let entries = sqlx::query_as(
+            r#"
+                SELECT
+                    id, delta, source::TEXT AS source, reply_id, drop_id, source_user_id, created
+                FROM experience_ledger
+                WHERE user_id = $1
+                ORDER BY id DESC
+                LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(EXPERIENCE_HISTORY_PER_PAGE)
+        .bind((page - 1) * EXPERIENCE_HISTORY_PER_PAGE)
+        .fetch_all(&*conn)
+        .await?;
+
+        Ok(ExperienceHistory {
+            entries,
+            page,
+            last_page,
+        })
+    }
+);
+
+#[derive(Deserialize)]
+pub struct UpdateHomeTagsForm {
+    tags: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum UpdateHomeTagsError {
+    #[error("None of those tags exist")]
+    NoSuchTags,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/settings/home_tags",
+    #[json]
+    async fn update_home_tags(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(UpdateHomeTagsForm { tags }): Form<UpdateHomeTagsForm>,
+    ) -> Result<String, UpdateHomeTagsError> {
+        let tags = Tags::fetch_from_str(&conn, &tags.replace(',', "/")).await;
+        if tags.is_empty() {
+            return Err(UpdateHomeTagsError::NoSuchTags);
+        }
+        let home_tags = tags.into_names().collect::<Vec<_>>().join("/");
+
+        sqlx::query("UPDATE users SET home_tags = $1 WHERE id = $2")
+            .bind(&home_tags)
+            .bind(user.id)
+            .execute(&*conn)
+            .await?;
+
+        InvalidationBus::user_updated(&*conn, user.id).await?;
+
+        Ok(home_tags)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct UpdateNegativeReactionsForm {
+    allow: bool,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum UpdateSettingsError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/settings/notifications",
+    #[json]
+    async fn update_notification_settings(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(settings): Form<NotificationSettings>,
+    ) -> Result<NotificationSettings, UpdateSettingsError> {
+        sqlx::query("UPDATE users SET notification_settings = $1 WHERE id = $2")
+            .bind(Json(&settings))
+            .bind(user.id)
+            .execute(&*conn)
+            .await?;
+
+        InvalidationBus::user_updated(&*conn, user.id).await?;
+
+        Ok(settings)
+    }
+);
+
+post!(
+    "/settings/negative_reactions",
+    #[json]
+    async fn update_negative_reactions(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(UpdateNegativeReactionsForm { allow }): Form<UpdateNegativeReactionsForm>,
+    ) -> Result<bool, UpdateSettingsError> {
+        sqlx::query("UPDATE users SET negative_reactions = $1 WHERE id = $2")
+            .bind(allow)
+            .bind(user.id)
+            .execute(&*conn)
+            .await?;
+
+        InvalidationBus::user_updated(&*conn, user.id).await?;
+
+        Ok(allow)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct UpdateAcceptsGiftsForm {
+    allow: bool,
+}
+
+post!(
+    "/settings/gifts",
+    #[json]
+    async fn update_accepts_gifts(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(UpdateAcceptsGiftsForm { allow }): Form<UpdateAcceptsGiftsForm>,
+    ) -> Result<bool, UpdateSettingsError> {
+        sqlx::query("UPDATE users SET accepts_gifts = $1 WHERE id = $2")
+            .bind(allow)
+            .bind(user.id)
+            .execute(&*conn)
+            .await?;
+
+        InvalidationBus::user_updated(&*conn, user.id).await?;
+
+        Ok(allow)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct UpdateEmailDigestForm {
+    allow: bool,
+}
+
+post!(
+    "/settings/digest",
+    #[json]
+    async fn update_email_digest(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(UpdateEmailDigestForm { allow }): Form<UpdateEmailDigestForm>,
+    ) -> Result<bool, UpdateSettingsError> {
+        // The token is put in every digest so that the user can unsubscribe
+        // without logging in. It only allows unsubscribing, so it is kept as
+        // is rather than hashed.
+        let token = base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+        sqlx::query(
+            r#"
+                UPDATE users SET
+                    email_digest = $1,
+                    digest_token = COALESCE(digest_token, $2)
+                WHERE id = $3
+            "#,
+        )
+        .bind(allow)
+        .bind(token)
+        .bind(user.id)
+        .execute(&*conn)
+        .await?;
+
+        InvalidationBus::user_updated(&*conn, user.id).await?;
+
+        Ok(allow)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct UpdateShowMatureForm {
+    allow: bool,
+}
+
+post!(
+    "/settings/mature",
+    #[json]
+    async fn update_show_mature(
+        conn: Extension<PgPool>,
+        user: User,
+        Form(UpdateShowMatureForm { allow }): Form<UpdateShowMatureForm>,
+    ) -> Result<bool, UpdateSettingsError> {
+        sqlx::query("UPDATE users SET show_mature = $1 WHERE id = $2")
+            .bind(allow)
+            .bind(user.id)
+            .execute(&*conn)
+            .await?;
+
+        InvalidationBus::user_updated(&*conn, user.id).await?;
+
+        Ok(allow)
+    }
+);
+
+#[derive(Deserialize)]
+pub struct AddNoteForm {
+    body: String,
+}
+
+#[derive(Debug, Error, Serialize, ErrorCode)]
+pub enum AddNoteError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/add_note/:user_id",
+    #[json]
+    pub async fn submit(
+        conn: Extension<PgPool>,
+        viewer: User,
+        Path(user_id): Path<i32>,
+        Form(AddNoteForm { body }): Form<AddNoteForm>,
+    ) -> Result<(), AddNoteError> {
+        if viewer.role < Role::Moderator {
+            return Err(AddNoteError::Unauthorized);
+        }
+
+        let viewer_name = viewer.name;
+        let body = html_escape::encode_text(&body);
+        let new_note = format!("<p>“{body}” — {viewer_name}</p>");
+
+        sqlx::query("UPDATE users SET notes = notes || $1 WHERE id = $2")
+            .bind(new_note)
+            .bind(user_id)
+            .execute(&*conn)
+            .await?;
+
+        Ok(())
+    }
+);
+
+#[derive(Debug, Error, Serialize, ErrorCode)]
+pub enum ViewAsError {
+    #[error("You are not privileged enough")]
+    Unauthorized,
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post!(
+    "/admin/view_as/:user_id",
+    #[json]
+    async fn view_as(
+        conn: Extension<PgPool>,
+        keys: Extension<CookieKeys>,
+        cookies: Cookies,
+        admin: User,
+        Path(user_id): Path<i32>,
+    ) -> Result<(), ViewAsError> {
+        if admin.role < Role::Admin {
+            return Err(ViewAsError::Unauthorized);
+        }
+        let user = User::fetch_optional(&*conn, user_id)
+            .await?
+            .ok_or(ViewAsError::NoSuchUser)?;
+        tracing::info!("Admin `{}` started viewing as `{}`", admin.name, user.name);
+        keys.set_view_as(&cookies, user.id);
+        Ok(())
+    }
+);
+
+get!(
+    "/view_as/stop",
+    async fn stop_view_as(keys: Extension<CookieKeys>, cookies: Cookies) -> Redirect {
+        // Removing the cookie needs no privileges, and the user extractor
+        // would return the viewed user here anyway.
+        cookies
+            .private(&keys.current)
+            .remove(Cookie::build(VIEW_AS_COOKIE, "").path("/").finish());
+        Redirect::to("/")
+    }
+);
+
+/// Name of the cookie we use to store the session Id.
+const USER_SESSION_ID_COOKIE: &str = "session_id";
+
+/// Name of the cookie holding the Id of the user an admin is viewing the site
+/// as.
+const VIEW_AS_COOKIE: &str = "view_as";
+
+/// Paths of the pages that admins may view as another user. Everything else,
+/// such as the inbox, sessions, drafts or profiles, which show private fields
+/// to their owner, is always served as the admin.
+const VIEW_AS_PATHS: &[&str] = &[
+    "/",
+    "/author",
+    "/item",
+    "/leaderboard",
+    "/reply",
+    "/search",
+    "/t",
+    "/tags",
+    "/thread",
+];
+
+/// Whether an admin may view a path as another user.
+fn can_view_as(path: &str) -> bool {
+    let matches = |prefix: &&str| {
+        path.strip_prefix(prefix)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    VIEW_AS_PATHS.iter().any(matches)
+}
+
+/// Environment variable holding the secret used to encrypt session cookies.
+const COOKIE_KEY_VAR: &str = "COOKIE_KEY";
+
+/// Environment variable holding the secret that was used before the current
+/// one, so that sessions survive a key rotation.
+const PREVIOUS_COOKIE_KEY_VAR: &str = "PREVIOUS_COOKIE_KEY";
+
+/// Shortest secret that a cookie key can be derived from.
+const MIN_COOKIE_KEY_LENGTH: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum CookieKeyError {
+    #[error("{0} is not set")]
+    Missing(&'static str),
+    #[error("{var} must be at least {MIN_COOKIE_KEY_LENGTH} bytes long")]
+    TooShort { var: &'static str },
+}
+
+/// Keys used to encrypt session cookies. Cookies are always written with the
+/// current key. Cookies written with the previous key are still accepted and
+/// are re-written with the current one the next time they are read.
+#[derive(Clone)]
+pub struct CookieKeys {
+    current:  Key,
+    previous: Option<Key>,
+}
+
+impl CookieKeys {
+    pub fn from_env() -> Result<Self, CookieKeyError> {
+        let current =
+            std::env::var(COOKIE_KEY_VAR).map_err(|_| CookieKeyError::Missing(COOKIE_KEY_VAR))?;
+        let previous = std::env::var(PREVIOUS_COOKIE_KEY_VAR)
+            .ok()
+            .filter(|key| !key.is_empty());
+        Ok(Self {
+            current:  derive_key(COOKIE_KEY_VAR, &current)?,
+            previous: previous
+                .map(|key| derive_key(PREVIOUS_COOKIE_KEY_VAR, &key))
+                .transpose()?,
+        })
+    }
+
+    /// The session Id stored in the cookies, if any.
+    fn session_id(&self, cookies: &Cookies) -> Option<String> {
+        if let Some(cookie) = cookies.private(&self.current).get(USER_SESSION_ID_COOKIE) {
+            return Some(cookie.value().to_string());
+        }
+        let cookie = cookies
+            .private(self.previous.as_ref()?)
+            .get(USER_SESSION_ID_COOKIE)?;
+        self.set_session_id(cookies, cookie.value());
+        Some(cookie.value().to_string())
+    }
+
+    fn set_session_id(&self, cookies: &Cookies, session_id: &str) {
+        let mut cookie = Cookie::new(USER_SESSION_ID_COOKIE, session_id.to_string());
+        cookie
+            .set_expires(cookie_time::OffsetDateTime::now_utc() + cookie_time::Duration::weeks(52));
+        cookies.private(&self.current).add(cookie);
+    }
+
+    fn remove_session_id(&self, cookies: &Cookies) {
+        cookies
+            .private(&self.current)
+            .remove(Cookie::named(USER_SESSION_ID_COOKIE));
+    }
+
+    /// The user an admin is viewing the site as, if any. Only ever written
+    /// with the current key, so a key rotation ends view as user mode.
+    fn view_as(&self, cookies: &Cookies) -> Option<i32> {
+        cookies
+            .private(&self.current)
+            .get(VIEW_AS_COOKIE)?
+            .value()
+            .parse()
+            .ok()
+    }
+
+    fn set_view_as(&self, cookies: &Cookies, user_id: i32) {
+        let mut cookie = Cookie::new(VIEW_AS_COOKIE, user_id.to_string());
+        cookie.set_path("/");
+        cookies.private(&self.current).add(cookie);
+    }
+}
+
+fn derive_key(var: &'static str, secret: &str) -> Result<Key, CookieKeyError> {
+    if secret.len() < MIN_COOKIE_KEY_LENGTH {
+        return Err(CookieKeyError::TooShort { var });
+    }
+    Ok(Key::derive_from(secret.as_bytes()))
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for LoginSession
+where
+    S: Send + Sync,
+{
+    type Rejection = UserRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let redirect = request_redirect(parts);
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::Unauthorized {
+                redirect: redirect.clone(),
+            })?;
+        let keys = Extension::<CookieKeys>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        let session_id = keys
+            .session_id(&cookies)
+            .ok_or(UserRejection::Unauthorized {
+                redirect: redirect.clone(),
+            })?;
+        let revocations = Extension::<Revocations>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        if let Some(session) = revocations.cached_session(&session_id) {
+            return Ok(session);
+        }
+        let conn = Extension::<PgPool>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        let Some(session) = LoginSession::fetch(&conn, &session_id).await? else {
+            return Err(UserRejection::Unauthorized { redirect });
+        };
+        revocations.cache_session(session.clone());
+        Ok(session)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for User
+where
+    S: Send + Sync,
+{
+    type Rejection = UserRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(secret) = api_tokens::bearer_token(&parts.headers) {
+            let secret = secret.to_string();
+            return User::from_api_token(parts, state, &secret).await;
+        }
+        let session = LoginSession::from_request_parts(parts, state).await?;
+        let conn = Extension::<PgPool>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        let user = match User::fetch_optional(&*conn, session.user_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(UserRejection::UnknownUser),
+            Err(_) => {
+                return Err(UserRejection::Unauthorized {
+                    redirect: request_redirect(parts),
+                })
+            }
+        };
+        if user.is_banned() {
+            return Err(UserRejection::banned(&conn, &user).await);
+        }
+        if !policies::is_exempt(parts.uri.path()) && policies::is_outdated(&*conn, &user).await? {
+            return Err(UserRejection::PoliciesNotAccepted {
+                redirect: request_redirect(parts),
+            });
+        }
+
+        // Admins may view public pages as another user. Only GET requests are
+        // viewed as the user, so nothing can be done on their behalf, and
+        // nothing private to them can be read.
+        if parts.method != Method::GET || user.role < Role::Admin || !can_view_as(parts.uri.path())
+        {
+            return Ok(user);
+        }
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        let keys = Extension::<CookieKeys>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        let Some(viewed_id) = keys.view_as(&cookies) else {
+            return Ok(user);
+        };
+        let Some(mut viewed) = User::fetch_optional(&*conn, viewed_id).await? else {
+            return Ok(user);
+        };
+        tracing::info!(
+            "Admin `{}` is viewing {} as `{}`",
+            user.name,
+            parts.uri.path(),
+            viewed.name
+        );
+        viewed.viewed_by = Some(user.id);
+        if viewed.is_banned() {
+            return Err(UserRejection::banned(&conn, &viewed).await);
+        }
+        Ok(viewed)
+    }
+}
+
+impl User {
+    /// Extract the user an API token belongs to, if the token allows the
+    /// request being made.
+    async fn from_api_token<S>(
+        parts: &mut Parts,
+        state: &S,
+        secret: &str,
+    ) -> Result<Self, UserRejection>
+    where
+        S: Send + Sync,
+    {
+        let conn = Extension::<PgPool>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        // Usually already resolved by the `api_tokens::rate_limit` middleware.
+        let token = match parts.extensions.get::<ApiToken>().cloned() {
+            Some(token) => token,
+            None => ApiToken::fetch_by_secret(&*conn, secret)
+                .await?
+                .ok_or(UserRejection::InvalidToken)?,
+        };
+        match TokenScope::required_for(&parts.method, parts.uri.path()) {
+            Some(scope) if token.allows(scope) => (),
+            _ => return Err(UserRejection::TokenNotAllowed),
+        }
+        let cluster = Extension::<Cluster>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UserRejection::UnknownError)?;
+        token
+            .verify_signature(&cluster, &parts.method, &parts.uri, &parts.headers)
+            .await?;
+        let mut user = User::fetch_optional(&*conn, token.user_id)
+            .await?
+            .filter(|user| user.deleted.is_none())
+            .ok_or(UserRejection::InvalidToken)?;
+        if user.is_banned() {
+            return Err(UserRejection::banned(&conn, &user).await);
+        }
+        // Bots can't accept the policies themselves, so the owner has to log
+        // in and accept them before the token works again.
+        if policies::is_outdated(&*conn, &user).await? {
+            return Err(UserRejection::TokenPoliciesNotAccepted);
+        }
+        // Tokens are handed to bots, so they never carry moderator or admin
+        // powers, whatever the role of the account they belong to.
+        user.role = user.role.min(Role::User);
+        user.api_token = Some(token.id);
+        Ok(user)
+    }
+}
+
+/// The path to return to after logging in.
+fn request_redirect(parts: &Parts) -> String {
+    parts
+        .uri
+        .path_and_query()
+        .map(|x| x.as_str().to_string())
+        .unwrap_or_else(String::new)
+}
+
+#[derive(Debug, Error)]
+pub enum UserRejection {
+    #[error("An unknown error occurred")]
+    UnknownError,
+    #[error("Internal database error: {0}")]
+    InternalDbError(#[from] sqlx::Error),
+    #[error("Unknown user")]
+    UnknownUser,
+    #[error("Unauthorized user")]
+    Unauthorized { redirect: String },
+    #[error("Banned until {}", .0.until)]
+    Banned(BanDetails),
+    #[error("The current policies have not been accepted")]
+    PoliciesNotAccepted { redirect: String },
+    #[error("Invalid API token")]
+    InvalidToken,
+    #[error("This API token is not allowed to make this request")]
+    TokenNotAllowed,
+    #[error("The current policies must be accepted before this API token can be used")]
+    TokenPoliciesNotAccepted,
+    #[error("{0}")]
+    InvalidSignature(SignatureError),
+}
+
+impl From<SignatureError> for UserRejection {
+    fn from(err: SignatureError) -> Self {
+        match err {
+            SignatureError::InternalDbError(err) => Self::InternalDbError(err),
+            err => Self::InvalidSignature(err),
+        }
+    }
+}
+
+impl UserRejection {
+    /// Rejection of a banned user, with the details of their ban.
+    async fn banned(conn: &PgPool, user: &User) -> Self {
+        match BanDetails::fetch(conn, user).await {
+            Ok(Some(details)) => Self::Banned(details),
+            Ok(None) => Self::UnknownError,
+            Err(err) => err.into(),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "banned.html")]
+pub struct Banned {
+    judge_type: bool,
+    until:      String,
+    reason:     Option<String>,
+    banned_by:  Option<String>,
+    appeal:     Option<BanAppeal>,
+}
+
+impl IntoResponse for UserRejection {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Banned(details) => Banned {
+                judge_type: rand::random(),
+                until:      details.until.format(crate::DATE_FMT).to_string(),
+                reason:     details.reason,
+                banned_by:  details.banned_by,
+                appeal:     details.appeal,
+            }
+            .into_response(),
+            Self::Unauthorized { redirect } => {
+                Redirect::to(&format!("/login?redirect={redirect}")).into_response()
+            }
+            Self::PoliciesNotAccepted { redirect } => Redirect::to(&format!(
+                "/policies?redirect={}",
+                urlencoding::encode(&redirect)
+            ))
+            .into_response(),
+            // Bots can't follow a redirect to the login page.
+            err @ (Self::InvalidToken | Self::InvalidSignature(_)) => {
+                (StatusCode::UNAUTHORIZED, err.to_string()).into_response()
+            }
+            err @ (Self::TokenNotAllowed | Self::TokenPoliciesNotAccepted) => {
+                (StatusCode::FORBIDDEN, err.to_string()).into_response()
+            }
+            err => {
+                tracing::error!("Unknown error occurred: {:?}", err);
+                Redirect::to("/login").into_response()
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UserCache<'a> {
+    conn:  &'a PgPool,
+    stubs: ProfileStubs,
+}
+
+impl<'a> UserCache<'a> {
+    pub fn new(conn: &'a PgPool, stubs: &ProfileStubs) -> Self {
+        UserCache {
+            conn,
+            stubs: stubs.clone(),
+        }
+    }
+
+    pub async fn get(&self, id: i32) -> Result<Arc<ProfileStub>, sqlx::Error> {
+        if let Some(result) = self.stubs.get(id) {
+            return Ok(result);
+        }
+        let version = self.stubs.version();
+        let profile_stub = Arc::new(
+            User::fetch(self.conn, id)
+                .await?
+                .get_profile_stub(self.conn)
+                .await?,
+        );
+        self.stubs.insert(id, profile_stub.clone(), version);
+        Ok(profile_stub)
+    }
+
+    /// Fetches the profile stubs of every user not already cached in a single
+    /// batch, so that later calls to `get` do not query the database.
+    /// Returns the stubs in the order of the ids given, skipping users that
+    /// do not exist.
+    pub async fn get_many(&self, ids: &[i32]) -> Result<Vec<Arc<ProfileStub>>, sqlx::Error> {
+        let mut found = HashMap::new();
+        let mut missing = Vec::new();
+        for &id in ids {
+            match self.stubs.get(id) {
+                Some(profile_stub) => {
+                    found.insert(id, profile_stub);
+                }
+                None => missing.push(id),
+            }
+        }
+        missing.sort_unstable();
+        missing.dedup();
+        if !missing.is_empty() {
+            let version = self.stubs.version();
+            for (id, profile_stub) in ProfileStub::fetch_many(self.conn, &missing).await? {
+                let profile_stub = Arc::new(profile_stub);
+                self.stubs.insert(id, profile_stub.clone(), version);
+                found.insert(id, profile_stub);
+            }
+        }
+        Ok(ids.iter().filter_map(|id| found.get(id).cloned()).collect())
+    }
+}
+
+/// How long a profile stub is shared before it is built again, in case an
+/// update to its user was missed.
+const PROFILE_STUB_TTL: StdDuration = StdDuration::from_secs(5 * 60);
+
+/// Number of profile stubs kept before expired ones are purged.
+const MAX_CACHED_PROFILE_STUBS: usize = 10_000;
+
+/// Profile stubs shared by every request, so that pages showing the same
+/// users do not build their stubs again. A stub is dropped as soon as its
+/// user is updated: equipping or unequipping an item, changing a bio or a
+/// display name and every other change to a user is announced through the
+/// `InvalidationBus`.
+#[derive(Clone, Default)]
+pub struct ProfileStubs {
+    cached:  Arc<Mutex<HashMap<i32, (Arc<ProfileStub>, Instant)>>>,
+    /// Number of updates heard so far. A stub built while an update was
+    /// heard may predate it, and is not cached.
+    version: Arc<AtomicU64>,
+}
+
+impl ProfileStubs {
+    /// The version to pass to `insert` for a stub about to be built.
+    fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    fn get(&self, id: i32) -> Option<Arc<ProfileStub>> {
+        let mut cached = self.cached.lock().unwrap();
+        match cached.get(&id) {
+            Some((profile_stub, cached_at)) if cached_at.elapsed() < PROFILE_STUB_TTL => {
+                Some(profile_stub.clone())
+            }
+            Some(_) => {
+                cached.remove(&id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, id: i32, profile_stub: Arc<ProfileStub>, version: u64) {
+        let mut cached = self.cached.lock().unwrap();
+        // Checked while holding the lock, as updates are only counted while
+        // holding it too.
+        if self.version() != version {
+            return;
+        }
+        if cached.len() >= MAX_CACHED_PROFILE_STUBS {
+            cached.retain(|_, (_, cached_at)| cached_at.elapsed() < PROFILE_STUB_TTL);
+            if cached.len() >= MAX_CACHED_PROFILE_STUBS {
+                cached.clear();
+            }
+        }
+        cached.insert(id, (profile_stub, Instant::now()));
+    }
+
+    /// Drops the stubs of users that have been updated.
+    pub async fn forget_updated_users(self, mut updates: broadcast::Receiver<i32>) {
+        loop {
+            match updates.recv().await {
+                Ok(user_id) => {
+                    let mut cached = self.cached.lock().unwrap();
+                    self.version.fetch_add(1, Ordering::SeqCst);
+                    cached.remove(&user_id);
+                }
+                // We can't tell which users were updated, so forget them all.
+                Err(RecvError::Lagged(_)) => {
+                    let mut cached = self.cached.lock().unwrap();
+                    self.version.fetch_add(1, Ordering::SeqCst);
+                    cached.clear();
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// User login sessions
+#[derive(FromRow, Clone, Debug)]
+pub struct LoginSession {
+    /// Id of the login session
+    pub id:            i32,
+    /// Auth token
+    pub session_id:    String,
+    /// UserId of the session
+    pub user_id:       i32,
+    /// When the session began
+    pub session_start: NaiveDateTime,
+    /// The IP address of the connecting client
+    pub ip_addr:       IpNetwork,
+}
+
+/// Login attempts allowed for a user name within `FAILED_LOGIN_WINDOW`. A
+/// successful login starts the count over.
+const MAX_FAILED_LOGINS_PER_USER: i64 = 5;
+
+/// Login attempts allowed from an address within `FAILED_LOGIN_WINDOW`, for
+/// any number of user names.
+const MAX_FAILED_LOGINS_PER_IP: i64 = 20;
+
+/// Time after the first login attempt before the count starts over.
+const FAILED_LOGIN_WINDOW: StdDuration = StdDuration::from_secs(15 * 60);
+
+/// Count an attempt to prove who a user is, by logging in or by resetting
+/// their password, against the limits for the user name and for the address it
+/// comes from. Returns the seconds until another attempt may be made if there
+/// have been too many. Every attempt is counted before it is checked, so that
+/// concurrent guesses cannot all slip in under the limit.
+async fn count_attempt(
+    cluster: &Cluster,
+    name: &str,
+    ip: IpAddr,
+) -> Result<Option<u64>, sqlx::Error> {
+    let limits = [
+        (
+            format!("login_failures:user:{name}"),
+            MAX_FAILED_LOGINS_PER_USER,
+        ),
+        (format!("login_failures:ip:{ip}"), MAX_FAILED_LOGINS_PER_IP),
+    ];
+    for (key, max) in &limits {
+        if cluster.increment(key, FAILED_LOGIN_WINDOW).await? > *max {
+            let resets_in = cluster
+                .counter(key)
+                .await?
+                .map_or(FAILED_LOGIN_WINDOW, |(_, resets_in)| resets_in);
+            return Ok(Some(resets_in.as_secs().max(1)));
+        }
+    }
+    Ok(None)
+}
+
+/// Start the count of attempts for a user name over after one succeeded.
+/// Attempts from the address are kept, so that guessing the password of an
+/// account that the attacker owns does not reset their limit.
+async fn forget_attempts(cluster: &Cluster, name: &str) -> Result<(), sqlx::Error> {
+    cluster.reset(&format!("login_failures:user:{name}")).await
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum LoginFailure {
+    #[error("Username or password is incorrect")]
+    UserOrPasswordIncorrect,
+    #[error("Too many failed attempts, try again in {retry_after} seconds")]
+    TooManyAttempts { retry_after: u64 },
+    #[error("Logging in is not allowed from your network")]
+    NetworkBanned,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+    #[error("Internal encryption error")]
+    InternalEncryptionError,
+}
+
+impl From<aes_gcm::Error> for LoginFailure {
+    fn from(_: aes_gcm::Error) -> Self {
+        LoginFailure::InternalEncryptionError
+    }
+}
+
+impl From<FromUtf8Error> for LoginFailure {
+    fn from(_: FromUtf8Error) -> Self {
+        LoginFailure::InternalEncryptionError
+    }
+}
+
+lazy_static! {
+    pub static ref SHARED_SECRET_CIPHER: Aes256Gcm =
+        load_shared_secret_cipher().unwrap_or_else(|err| panic!("{err}"));
+}
+
+/// Construct the cipher used to encrypt shared secrets from the
+/// `SHARED_SECRET_KEY` environment variable.
+pub fn load_shared_secret_cipher() -> Result<Aes256Gcm, String> {
+    let key = std::env::var("SHARED_SECRET_KEY").map_err(|_| "SHARED_SECRET_KEY is not set")?;
+    let key_bytes = base64::decode(&key).map_err(|_| "SHARED_SECRET_KEY is not valid base64")?;
+    Aes256Gcm::new_from_slice(&key_bytes)
+        .map_err(|_| "SHARED_SECRET_KEY is not a valid 256-bit key".to_string())
+}
+
+pub const SHARED_SECRET_NONCE: &[u8; 12] = b"96bitsIs12u8";
+
+impl LoginSession {
+    /// Fetch the login session.
+    pub async fn fetch(conn: &PgPool, session_id: &str) -> Result<Option<Self>, sqlx::Error> {
+        Ok(
+            sqlx::query_as("SELECT * FROM login_sessions WHERE session_id = $1")
+                .bind(session_id)
+                .fetch_optional(conn)
+                .await?
+                .filter(|session: &Self| !session.is_expired()),
+        )
+    }
+
+    /// Every unexpired session of a user, most recent first.
+    pub async fn fetch_all(conn: &PgPool, user_id: i32) -> Result<Vec<Self>, sqlx::Error> {
+        let sessions: Vec<Self> = sqlx::query_as(
+            "SELECT * FROM login_sessions WHERE user_id = $1 ORDER BY session_start DESC",
+        )
+        .bind(user_id)
+        .fetch_all(conn)
+        .await?;
+        Ok(sessions
+            .into_iter()
+            .filter(|session| !session.is_expired())
+            .collect())
+    }
+
+    /// The session is automatically invalid if the session is longer than a
+    /// year old.
+    pub fn is_expired(&self) -> bool {
+        self.session_start < (Utc::now() - Duration::weeks(52)).naive_utc()
+    }
+
+    /// Attempt to login a user
+    pub async fn login(
+        conn: &PgPool,
+        cluster: &Cluster,
+        username: &str,
+        password: &str,
+        ip_addr: IpNetwork,
+    ) -> Result<Self, LoginFailure> {
+        // Moderators and admins may log in from banned networks, so that a
+        // ban that is too wide cannot lock out the people who can lift it.
+        let name = usernames::canonical(username);
+        let role: Option<Role> =
+            sqlx::query_scalar("SELECT role FROM users WHERE name = $1 AND deleted IS NULL")
+                .bind(&name)
+                .fetch_optional(conn)
+                .await?;
+        if role.map_or(true, |role| role < Role::Moderator)
+            && IpBan::covering(conn, ip_addr.ip()).await?.is_some()
+        {
+            return Err(LoginFailure::NetworkBanned);
+        }
+
+        if let Some(retry_after) = count_attempt(cluster, &name, ip_addr.ip()).await? {
+            return Err(LoginFailure::TooManyAttempts { retry_after });
+        }
+
+        let user = Self::authenticate(conn, &name, password).await?;
+        forget_attempts(cluster, &name).await?;
+
+        let mut key = [0u8; 16];
+        OsRng.fill_bytes(&mut key);
+
+        // Found a user, create a new login sessions
+        let session_start = Utc::now().naive_utc();
+
+        Ok(sqlx::query_as(
+            r#"
+                INSERT INTO login_sessions
+                    (user_id, session_id, session_start, ip_addr)
+                VALUES
+                    ($1, $2, $3, $4)
+                RETURNING
+                    *
+            "#,
+        )
+        .bind(user.id)
+        .bind(i128::from_be_bytes(key).to_string())
+        .bind(session_start)
+        .bind(ip_addr)
+        .fetch_one(conn)
+        .await?)
+    }
+
+    /// Check a user's password, upgrading its hash if needed.
+    async fn authenticate(conn: &PgPool, name: &str, password: &str) -> Result<User, LoginFailure> {
+        let user: User = sqlx::query_as("SELECT * FROM users WHERE name = $1")
+            .bind(name)
+            .fetch_optional(conn)
+            .await?
+            .filter(|user: &User| user.deleted.is_none())
+            .ok_or(LoginFailure::UserOrPasswordIncorrect)?;
+
+        match passwords::check(&user.password, password) {
+            PasswordCheck::Incorrect => return Err(LoginFailure::UserOrPasswordIncorrect),
+            PasswordCheck::Correct => (),
+            // Only now is the password known, so this is the only chance to
+            // bring its hash up to date with the policy.
+            PasswordCheck::Outdated(new_hash) => {
+                sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+                    .bind(new_hash)
+                    .bind(user.id)
+                    .execute(conn)
+                    .await?;
+            }
+        }
+
+        Ok(user)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+post!(
+    "/login",
+    #[json]
+    async fn login(
+        pool: Extension<PgPool>,
+        cluster: Extension<Cluster>,
+        keys: Extension<CookieKeys>,
+        notifications: Extension<Notifications>,
+        jar: Cookies,
+        ClientIp(ip): ClientIp,
+        headers: HeaderMap,
+        login: Form<LoginForm>,
+    ) -> Result<(), LoginFailure> {
+        keys.remove_session_id(&jar);
+        let LoginSession {
+            session_id,
+            user_id,
+            ..
+        } = LoginSession::login(
+            &pool,
+            &cluster,
+            login.username.trim(),
+            login.password.trim(),
+            IpNetwork::from(ip),
+        )
+        .await?;
+        keys.set_session_id(&jar, &session_id);
+
+        let audit = LoginAudit::record(&pool, user_id, ip, &headers).await?;
+        if audit.new_range {
+            notifications
+                .notify_and_push(&pool, user_id, NotificationKind::new_login(&audit))
+                .await?;
+        }
+
+        Ok(())
+    }
+);
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum LogoutFailure {
     #[error("An unknown error occurred")]
     UnknownError,
     #[error("Internal database error: {0}")]
@@ -935,27 +2683,724 @@ post! {
     #[json]
     async fn logout(
         pool: Extension<PgPool>,
+        revocations: Extension<Revocations>,
+        keys: Extension<CookieKeys>,
         cookies: Cookies,
     ) -> Result<(), LogoutFailure> {
-        let private_key = Key::derive_from(PRIVATE_COOKIE_KEY.as_bytes());
-        let signed = cookies.private(&private_key);
-        let session_id = signed
-            .get(USER_SESSION_ID_COOKIE)
+        let session_id = keys
+            .session_id(&cookies)
             .ok_or(LogoutFailure::UnknownError)?;
 
-        sqlx::query("DELETE FROM login_sessions WHERE id = $1")
-            .bind(session_id.value())
+        let session: Option<LoginSession> =
+            sqlx::query_as("DELETE FROM login_sessions WHERE session_id = $1 RETURNING *")
+                .bind(session_id)
+                .fetch_optional(&*pool)
+                .await?;
+
+        if let Some(session) = session {
+            revocations.revoke(Revocation::Session(session.id)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+post! {
+    "/logout_all",
+    #[json]
+    async fn logout_all(
+        pool: Extension<PgPool>,
+        revocations: Extension<Revocations>,
+        user: User,
+    ) -> Result<(), LogoutFailure> {
+        sqlx::query("DELETE FROM login_sessions WHERE user_id = $1")
+            .bind(user.id)
             .execute(&*pool)
             .await?;
 
+        revocations.revoke(Revocation::User(user.id)).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum RevokeSessionError {
+    #[error("No such session")]
+    NoSuchSession,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post! {
+    "/sessions/:session_id/revoke",
+    #[json]
+    async fn revoke_session(
+        pool: Extension<PgPool>,
+        revocations: Extension<Revocations>,
+        user: User,
+        Path(session_id): Path<i32>,
+    ) -> Result<(), RevokeSessionError> {
+        let session: LoginSession = sqlx::query_as(
+            "DELETE FROM login_sessions WHERE id = $1 AND user_id = $2 RETURNING *",
+        )
+        .bind(session_id)
+        .bind(user.id)
+        .fetch_optional(&*pool)
+        .await?
+        .ok_or(RevokeSessionError::NoSuchSession)?;
+
+        revocations.revoke(Revocation::Session(session.id)).await?;
+
+        Ok(())
+    }
+}
+
+post! {
+    "/sessions/revoke_all",
+    #[json]
+    async fn revoke_other_sessions(
+        pool: Extension<PgPool>,
+        revocations: Extension<Revocations>,
+        user: User,
+        current: LoginSession,
+    ) -> Result<(), RevokeSessionError> {
+        // Unlike `/logout_all`, the session making the request is kept.
+        let revoked: Vec<i32> = sqlx::query_scalar(
+            "DELETE FROM login_sessions WHERE user_id = $1 AND id <> $2 RETURNING id",
+        )
+        .bind(user.id)
+        .bind(current.id)
+        .fetch_all(&*pool)
+        .await?;
+
+        for session_id in revoked {
+            revocations.revoke(Revocation::Session(session_id)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of days a deleted account's user name is held before anyone else
+/// can register it.
+pub const DELETED_NAME_GRACE_PERIOD_DAYS: i64 = 30;
+
+/// How often user names of deleted accounts are checked for release.
+const RELEASE_NAMES_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// Name shown in place of the display name of a deleted account.
+const DELETED_DISPLAY_NAME: &str = "Deleted user";
+
+/// Clear everything personal from an account and mark it as deleted. Caches
+/// of the user must still be invalidated.
+async fn tombstone(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+) -> Result<(), sqlx::Error> {
+    // Replies are kept so that threads still make sense, but nothing on
+    // them points back to the person that wrote them. The user name is
+    // kept for a grace period so that nobody can take it over straight
+    // away, see `release_deleted_names`.
+    sqlx::query(
+        r#"
+            UPDATE users SET
+                display_name = $1,
+                bio = '',
+                pronouns = '',
+                location = '',
+                website = '',
+                signature = '',
+                email = '',
+                notes = '',
+                equip_slot_prof_pic = NULL,
+                equip_slot_background = NULL,
+                equip_slot_badges = '{}',
+                avatar = NULL,
+                deleted = $2
+            WHERE id = $3
+        "#,
+    )
+    .bind(DELETED_DISPLAY_NAME)
+    .bind(Utc::now().naive_utc())
+    .bind(user_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    TradeRequest::cancel_involving(&mut *transaction, user_id).await?;
+
+    sqlx::query("DELETE FROM listings WHERE seller_id = $1 AND sold IS NULL")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM login_sessions WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM push_subscriptions WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM drafts WHERE author_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM scheduled_replies WHERE author_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM name_history WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM drop_rolls WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM login_audit WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query(
+        "DELETE FROM api_token_usage WHERE token_id IN (SELECT id FROM api_tokens WHERE user_id = $1)",
+    )
+    .bind(user_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query("DELETE FROM api_tokens WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM user_follows WHERE follower_id = $1 OR followee_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("DELETE FROM thread_authors WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountForm {
+    password: String,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum DeleteAccountError {
+    #[error("Password is incorrect")]
+    PasswordIncorrect,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post! {
+    "/account/delete",
+    #[json]
+    async fn delete_account(
+        pool: Extension<PgPool>,
+        revocations: Extension<Revocations>,
+        user: User,
+        Form(DeleteAccountForm { password }): Form<DeleteAccountForm>,
+    ) -> Result<(), DeleteAccountError> {
+        if !passwords::verify(&user.password, &password) {
+            return Err(DeleteAccountError::PasswordIncorrect);
+        }
+
+        let mut transaction = pool.begin().await?;
+
+        tombstone(&mut transaction, user.id).await?;
+
+        InvalidationBus::user_updated(&mut *transaction, user.id).await?;
+
+        transaction.commit().await?;
+
+        revocations.revoke(Revocation::User(user.id)).await?;
+
+        tracing::info!("User `{}` has deleted their account", user.name);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum MergeUsersError {
+    #[error("You are not authorized to merge users")]
+    Unauthorized,
+    #[error("Cannot merge a user into themselves")]
+    SameUser,
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+post! {
+    "/admin/merge_users/:from/:to",
+    #[json]
+    async fn merge_users(
+        pool: Extension<PgPool>,
+        revocations: Extension<Revocations>,
+        admin: User,
+        Path((from_id, to_id)): Path<(i32, i32)>,
+    ) -> Result<(), MergeUsersError> {
+        if admin.role < Role::Admin {
+            return Err(MergeUsersError::Unauthorized);
+        }
+        if from_id == to_id {
+            return Err(MergeUsersError::SameUser);
+        }
+
+        let mut transaction = pool.begin().await?;
+
+        let users: Vec<User> = sqlx::query_as(
+            "SELECT * FROM users WHERE id = ANY($1) AND deleted IS NULL ORDER BY id FOR UPDATE",
+        )
+        .bind(vec![from_id, to_id])
+        .fetch_all(&mut transaction)
+        .await?;
+        let from = users.iter().find(|user| user.id == from_id);
+        let to = users.iter().find(|user| user.id == to_id);
+        let (Some(from), Some(to)) = (from, to) else {
+            return Err(MergeUsersError::NoSuchUser);
+        };
+
+        // Offers between the two accounts would become offers to oneself.
+        sqlx::query(
+            r#"
+                DELETE FROM trade_requests
+                WHERE
+                    (sender_id = $1 AND receiver_id = $2)
+                    OR (sender_id = $2 AND receiver_id = $1)
+            "#,
+        )
+        .bind(from_id)
+        .bind(to_id)
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query("UPDATE trade_requests SET sender_id = $2 WHERE sender_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query("UPDATE trade_requests SET receiver_id = $2 WHERE receiver_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+
+        sqlx::query("UPDATE replies SET author_id = $2 WHERE author_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+
+        sqlx::query("UPDATE drops SET owner_id = $2 WHERE owner_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+
+        sqlx::query("UPDATE experience_ledger SET user_id = $2 WHERE user_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+
+        // Threads read on both accounts keep whichever position is further.
+        sqlx::query(
+            r#"
+                INSERT INTO reading_history (reader_id, thread_id, last_read)
+                SELECT $2, thread_id, last_read FROM reading_history WHERE reader_id = $1
+                ON CONFLICT (reader_id, thread_id) DO UPDATE SET
+                    last_read = GREATEST(reading_history.last_read, EXCLUDED.last_read)
+            "#,
+        )
+        .bind(from_id)
+        .bind(to_id)
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query("DELETE FROM reading_history WHERE reader_id = $1")
+            .bind(from_id)
+            .execute(&mut transaction)
+            .await?;
+
+        // Coins earned on both accounts for the same thing are added up, so
+        // that the ledger still accounts for the whole balance.
+        sqlx::query(
+            r#"
+                WITH moved AS (DELETE FROM wallet_ledger WHERE user_id = $1 RETURNING *)
+                INSERT INTO wallet_ledger (user_id, delta, reason, reference_id, created)
+                SELECT $2, delta, reason, reference_id, created FROM moved
+                ON CONFLICT (user_id, reason, reference_id) DO UPDATE SET
+                    delta = wallet_ledger.delta + EXCLUDED.delta
+            "#,
+        )
+        .bind(from_id)
+        .bind(to_id)
+        .execute(&mut transaction)
+        .await?;
+
+        // Open listings follow their drops, which now belong to the target.
+        sqlx::query("UPDATE listings SET seller_id = $2 WHERE seller_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query("UPDATE listings SET buyer_id = $2 WHERE buyer_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+
+        // Items discovered on both accounts keep the earliest discovery.
+        sqlx::query(
+            r#"
+                WITH moved AS (DELETE FROM discoveries WHERE user_id = $1 RETURNING *)
+                INSERT INTO discoveries (user_id, item_id, discovered)
+                SELECT $2, item_id, discovered FROM moved
+                ON CONFLICT (user_id, item_id) DO UPDATE SET
+                    discovered = LEAST(discoveries.discovered, EXCLUDED.discovered)
+            "#,
+        )
+        .bind(from_id)
+        .bind(to_id)
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query("UPDATE scheduled_replies SET author_id = $2 WHERE author_id = $1")
+            .bind(from_id)
+            .bind(to_id)
+            .execute(&mut transaction)
+            .await?;
+
+        // Items equipped on the target stay equipped. Slots it has free are
+        // filled with what was equipped on the source, the rest of which ends
+        // up in the inventory.
+        let mut badges = to.equip_slot_badges.clone();
+        for badge in &from.equip_slot_badges {
+            if badges.len() < MAX_NUM_BADGES && !badges.contains(badge) {
+                badges.push(*badge);
+            }
+        }
+        sqlx::query(
+            r#"
+                UPDATE users SET
+                    experience = $1,
+                    balance = $2,
+                    equip_slot_prof_pic = $3,
+                    equip_slot_background = $4,
+                    equip_slot_badges = $5,
+                    avatar = $6
+                WHERE id = $7
+            "#,
+        )
+        .bind(to.experience + from.experience)
+        .bind(to.balance + from.balance)
+        .bind(to.equip_slot_prof_pic.or(from.equip_slot_prof_pic))
+        .bind(to.equip_slot_background.or(from.equip_slot_background))
+        .bind(badges)
+        .bind(to.avatar.as_ref().or(from.avatar.as_ref()))
+        .bind(to_id)
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query("UPDATE users SET balance = 0 WHERE id = $1")
+            .bind(from_id)
+            .execute(&mut transaction)
+            .await?;
+
+        // The source's sessions and the push subscriptions made from them are
+        // ended by `tombstone` rather than moved, so that whoever is logged in
+        // as the source is not logged in as the target.
+        tombstone(&mut transaction, from_id).await?;
+
+        sqlx::query(
+            "INSERT INTO user_merges (from_id, to_id, merged_by, merged) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(from_id)
+        .bind(to_id)
+        .bind(admin.id)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut transaction)
+        .await?;
+
+        InvalidationBus::user_updated(&mut *transaction, from_id).await?;
+        InvalidationBus::user_updated(&mut *transaction, to_id).await?;
+
+        transaction.commit().await?;
+
+        // Sessions of the source now belong to the target, so any copies of
+        // them cached for the source must go.
+        revocations.revoke(Revocation::User(from_id)).await?;
+
+        tracing::info!(
+            "User `{}` was merged into `{}` by `{}`",
+            from.name,
+            to.name,
+            admin.name
+        );
+
         Ok(())
     }
 }
 
-#[derive(FromRow)]
+/// Background task that frees up the user names of accounts that were deleted
+/// longer than `DELETED_NAME_GRACE_PERIOD_DAYS` ago.
+pub async fn release_deleted_names(conn: PgPool) {
+    let mut interval = tokio::time::interval(RELEASE_NAMES_INTERVAL);
+    loop {
+        interval.tick().await;
+        let released = sqlx::query(
+            r#"
+                UPDATE users SET name = 'deleted-' || id, name_skeleton = NULL
+                WHERE deleted < $1 AND name <> 'deleted-' || id
+            "#,
+        )
+        .bind((Utc::now() - Duration::days(DELETED_NAME_GRACE_PERIOD_DAYS)).naive_utc())
+        .execute(&conn)
+        .await;
+        if let Err(err) = released {
+            tracing::error!("Failed to release user names of deleted accounts: {err}");
+        }
+    }
+}
+
+/// Requirements for a user to be promoted to `Role::Trusted`, read from the
+/// environment.
+pub struct TrustPolicy {
+    /// Age of the account, in days. Set with `TRUSTED_MIN_ACCOUNT_AGE_DAYS`.
+    pub min_account_age_days: i64,
+    /// Set with `TRUSTED_MIN_EXPERIENCE`.
+    pub min_experience:       i64,
+    /// Number of replies posted. Set with `TRUSTED_MIN_POSTS`.
+    pub min_posts:            i64,
+}
+
+impl TrustPolicy {
+    fn from_env() -> Self {
+        fn var(name: &str, default: i64) -> i64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&value| value >= 0)
+                .unwrap_or(default)
+        }
+
+        Self {
+            min_account_age_days: var("TRUSTED_MIN_ACCOUNT_AGE_DAYS", 30),
+            min_experience:       var("TRUSTED_MIN_EXPERIENCE", 256),
+            min_posts:            var("TRUSTED_MIN_POSTS", 50),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref TRUST_POLICY: TrustPolicy = TrustPolicy::from_env();
+}
+
+/// How often users are checked for promotion to `Role::Trusted`.
+const PROMOTE_TRUSTED_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// Background task that promotes users who meet the `TRUST_POLICY` to
+/// `Role::Trusted`. Banned and deleted users are never promoted, and nobody is
+/// ever demoted by this task.
+pub async fn promote_trusted_users(conn: PgPool) {
+    let mut interval = tokio::time::interval(PROMOTE_TRUSTED_INTERVAL);
+    loop {
+        interval.tick().await;
+        match promote_trusted(&conn).await {
+            Ok(0) => (),
+            Ok(promoted) => tracing::info!("Promoted {promoted} users to trusted"),
+            Err(err) => tracing::error!("Failed to promote trusted users: {err}"),
+        }
+    }
+}
+
+async fn promote_trusted(conn: &PgPool) -> Result<usize, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let mut transaction = conn.begin().await?;
+
+    let promoted: Vec<i32> = sqlx::query_scalar(
+        r#"
+            UPDATE users SET role = $1
+            WHERE
+                role = $2
+                AND deleted IS NULL
+                AND (banned_until IS NULL OR banned_until < $3)
+                AND joined <= $4
+                AND experience >= $5
+                AND (SELECT COUNT(*) FROM replies WHERE replies.author_id = users.id) >= $6
+            RETURNING id
+        "#,
+    )
+    .bind(Role::Trusted)
+    .bind(Role::User)
+    .bind(now)
+    .bind(now - Duration::days(TRUST_POLICY.min_account_age_days))
+    .bind(TRUST_POLICY.min_experience)
+    .bind(TRUST_POLICY.min_posts)
+    .fetch_all(&mut transaction)
+    .await?;
+
+    for &user_id in &promoted {
+        InvalidationBus::user_updated(&mut *transaction, user_id).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(promoted.len())
+}
+
+/// Everything stored about a user, for them to take with them.
+#[derive(Serialize)]
+pub struct AccountExport {
+    pub id:              i32,
+    pub name:            String,
+    pub display_name:    String,
+    pub email:           String,
+    pub bio:             String,
+    pub pronouns:        String,
+    pub location:        String,
+    pub website:         String,
+    pub signature:       String,
+    pub role:            Role,
+    pub experience:      i64,
+    pub home_tags:       String,
+    pub replies:         Vec<Reply>,
+    pub items:           Vec<ItemDrop>,
+    pub trades:          Vec<TradeRequest>,
+    pub reading_history: Vec<ReadingHistory>,
+    pub messages:        Vec<Message>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum ExportAccountError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get! {
+    "/account/export",
+    #[json]
+    async fn export_account(
+        pool: Extension<PgPool>,
+        user: User,
+    ) -> Result<AccountExport, ExportAccountError> {
+        Ok(AccountExport {
+            replies: Reply::fetch_by_author(&*pool, user.id).await?,
+            items: ItemDrop::fetch_owned_by(&*pool, user.id).await?,
+            trades: TradeRequest::fetch_involving(&*pool, user.id).await?,
+            reading_history: ReadingHistory::fetch_all(&*pool, user.id).await?,
+            messages: Message::fetch_involving(&*pool, user.id).await?,
+            id: user.id,
+            name: user.name,
+            display_name: user.display_name,
+            email: user.email,
+            bio: user.bio,
+            pronouns: user.pronouns,
+            location: user.location,
+            website: user.website,
+            signature: user.signature,
+            role: user.role,
+            experience: user.experience,
+            home_tags: user.home_tags,
+        })
+    }
+}
+
+/// How far a user has read into a thread.
+#[derive(Debug)]
+pub struct ReadingStatus {
+    /// Whether the user has read up to the last post
+    pub read:    bool,
+    /// The first unread reply, or the last read one if everything is read
+    pub jump_to: i32,
+    /// Number of visible replies the user has not read
+    pub unread:  i64,
+}
+
+#[derive(FromRow, Serialize)]
 pub struct ReadingHistory {
     pub id:        i32,
     pub reader_id: i32,
     pub thread_id: i32,
     pub last_read: i32,
 }
+
+impl ReadingHistory {
+    pub async fn fetch_all(
+        conn: impl PgExecutor<'_>,
+        reader_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM reading_history WHERE reader_id = $1 ORDER BY thread_id ASC")
+            .bind(reader_id)
+            .fetch_all(conn)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn views_public_pages_as_another_user() {
+        assert!(can_view_as("/"));
+        assert!(can_view_as("/thread/1"));
+        assert!(can_view_as("/item/12"));
+        assert!(can_view_as("/t/news"));
+    }
+
+    #[test]
+    fn never_views_private_pages_as_another_user() {
+        for path in [
+            "/profile",
+            "/profile/2",
+            "/profile/security",
+            "/offers",
+            "/sessions",
+            "/drafts",
+            "/admin",
+        ] {
+            assert!(!can_view_as(path), "{path}");
+        }
+        // Paths that merely start like an allowed one don't match it.
+        assert!(!can_view_as("/threads_by_me"));
+        assert!(!can_view_as("/tagsx"));
+    }
+}
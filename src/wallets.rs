@@ -0,0 +1,244 @@
+//! Soft currency. Users earn it for every reply they post and every level
+//! they reach, and by selling drops they no longer want, and spend it on the
+//! market. Every change to a balance is recorded in the wallet ledger, keyed
+//! by what it is for, so a reward for an event that is delivered twice is
+//! only granted once.
+use axum::{async_trait, extract::Extension};
+use chrono::{NaiveDateTime, Utc};
+use marche_proc_macros::{json, ErrorCode};
+use serde::Serialize;
+use sqlx::{FromRow, PgExecutor, PgPool, Postgres, Transaction, Type};
+use thiserror::Error;
+
+use crate::{
+    events::{Event, Subscriber},
+    get,
+    invalidation::InvalidationBus,
+    items::Rarity,
+    users::User,
+};
+
+/// Currency granted for every reply posted, including the first post of a
+/// thread.
+pub const POST_REWARD: i64 = 2;
+
+/// Currency granted for reaching a level, multiplied by the level.
+pub const LEVEL_UP_REWARD: i64 = 10;
+
+/// Currency paid for selling a drop, per common item it is worth.
+pub const SALE_PRICE_PER_COMMON: i64 = 1;
+
+/// Number of ledger entries returned with a wallet.
+const LEDGER_ENTRIES: i64 = 50;
+
+/// Price paid for selling a drop of the given rarity.
+pub fn sale_price(rarity: Rarity) -> i64 {
+    rarity.value() as i64 * SALE_PRICE_PER_COMMON
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Type)]
+#[sqlx(type_name = "wallet_reason")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WalletReason {
+    /// Posted a reply; referenced by the reply id
+    Post,
+    /// Reached a level; referenced by the level
+    LevelUp,
+    /// Sold a drop; referenced by the drop id
+    Sale,
+    /// Bought a listing on the market; referenced by the listing id
+    Purchase,
+    /// A listing was bought from the user; referenced by the listing id
+    MarketSale,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct LedgerEntry {
+    pub delta:        i64,
+    pub reason:       WalletReason,
+    pub reference_id: i32,
+    pub created:      NaiveDateTime,
+}
+
+pub struct Wallet;
+
+impl Wallet {
+    /// Add currency to the user's balance. Returns false if the change was
+    /// already recorded for the same reference, in which case nothing is
+    /// added.
+    pub async fn credit(
+        conn: &mut Transaction<'_, Postgres>,
+        user_id: i32,
+        amount: i64,
+        reason: WalletReason,
+        reference_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let recorded = sqlx::query(
+            r#"
+                INSERT INTO wallet_ledger (user_id, delta, reason, reference_id, created)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id)
+        .bind(amount)
+        .bind(reason)
+        .bind(reference_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        if recorded == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("UPDATE users SET balance = balance + $1 WHERE id = $2")
+            .bind(amount)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+        InvalidationBus::user_updated(&mut *conn, user_id).await?;
+        Ok(true)
+    }
+
+    /// Take currency from the user's balance. Returns false if the user does
+    /// not have enough, in which case nothing is taken.
+    pub async fn debit(
+        conn: &mut Transaction<'_, Postgres>,
+        user_id: i32,
+        amount: i64,
+        reason: WalletReason,
+        reference_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let debited =
+            sqlx::query("UPDATE users SET balance = balance - $1 WHERE id = $2 AND balance >= $1")
+                .bind(amount)
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await?
+                .rows_affected();
+        if debited == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            r#"
+                INSERT INTO wallet_ledger (user_id, delta, reason, reference_id, created)
+                VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(user_id)
+        .bind(-amount)
+        .bind(reason)
+        .bind(reference_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *conn)
+        .await?;
+        InvalidationBus::user_updated(&mut *conn, user_id).await?;
+        Ok(true)
+    }
+
+    /// Most recent changes to the user's balance, newest first.
+    pub async fn ledger(
+        conn: impl PgExecutor<'_>,
+        user_id: i32,
+    ) -> Result<Vec<LedgerEntry>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+                SELECT delta, reason, reference_id, created FROM wallet_ledger
+                WHERE user_id = $1
+                ORDER BY created DESC, id DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(LEDGER_ENTRIES)
+        .fetch_all(conn)
+        .await
+    }
+}
+
+/// Grants the posting and leveling rewards as events are delivered.
+pub struct Wallets;
+
+impl Wallets {
+    /// Grant the reward for every level the user has reached that they have
+    /// not been rewarded for yet.
+    async fn reward_levels(&self, conn: &PgPool, user_id: i32) -> Result<(), sqlx::Error> {
+        let mut transaction = conn.begin().await?;
+        let level = User::fetch(&mut transaction, user_id).await?.level();
+        for level in 2..=level {
+            Wallet::credit(
+                &mut transaction,
+                user_id,
+                LEVEL_UP_REWARD * level as i64,
+                WalletReason::LevelUp,
+                level as i32,
+            )
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Subscriber for Wallets {
+    fn name(&self) -> &'static str {
+        "wallets"
+    }
+
+    async fn handle(&self, conn: &PgPool, event: &Event) -> anyhow::Result<()> {
+        match *event {
+            Event::ReplyCreated {
+                reply_id,
+                author_id,
+                ..
+            } => {
+                let mut transaction = conn.begin().await?;
+                Wallet::credit(
+                    &mut transaction,
+                    author_id,
+                    POST_REWARD,
+                    WalletReason::Post,
+                    reply_id,
+                )
+                .await?;
+                transaction.commit().await?;
+            }
+            Event::ReactionAdded { author_id, xp, .. } if xp > 0 => {
+                self.reward_levels(conn, author_id).await?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalletView {
+    pub balance: i64,
+    pub ledger:  Vec<LedgerEntry>,
+}
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum WalletError {
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+}
+
+get!(
+    "/wallet",
+    #[json]
+    async fn wallet(conn: Extension<PgPool>, user: User) -> Result<WalletView, WalletError> {
+        Ok(WalletView {
+            balance: user.balance,
+            ledger:  Wallet::ledger(&*conn, user.id).await?,
+        })
+    }
+);
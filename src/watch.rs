@@ -0,0 +1,324 @@
+//! Watching a thread over a websocket, which sends new replies to the client
+//! as they are posted. Requires the `websockets` feature.
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocketUpgrade},
+        Extension, Path,
+    },
+    response::{IntoResponse, Response},
+};
+use axum_client_ip::ClientIp;
+use marche_proc_macros::ErrorCode;
+use serde::Serialize;
+use sqlx::PgPool;
+use thiserror::Error;
+use tokio::{sync::broadcast::error::RecvError, time::MissedTickBehavior};
+
+use crate::{
+    get,
+    limits::Limits,
+    threads::{Post, PostLoader, Thread, Watchers},
+    users::{LoginSession, ProfileStubs, Revocations, Role, User},
+};
+
+#[derive(Debug, Serialize, Error, ErrorCode)]
+pub enum WatchThreadError {
+    #[error("No such thread")]
+    NoSuchThread,
+    #[error("Internal database error: {0}")]
+    InternalDbError(
+        #[from]
+        #[serde(skip)]
+        sqlx::Error,
+    ),
+    #[error("Internal error rendering post: {0}")]
+    InternalRenderError(
+        #[from]
+        #[serde(skip)]
+        askama::Error,
+    ),
+    #[error("Internal error serializing post: {0}")]
+    InternalSerializeError(
+        #[from]
+        #[serde(skip)]
+        serde_json::Error,
+    ),
+}
+
+impl WatchThreadError {
+    /// The error in the same shape as the errors of JSON endpoints, which is
+    /// sent to watchers as a text message.
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "error": format!("{}", self), "error_type": self })
+    }
+}
+
+impl IntoResponse for WatchThreadError {
+    fn into_response(self) -> Response {
+        (
+            crate::ErrorCode::error_code(&self),
+            axum::Json(self.to_json()),
+        )
+            .into_response()
+    }
+}
+
+/// Whether the viewer may see a thread at all, by the same rules as the thread
+/// page.
+async fn can_watch(conn: &PgPool, viewer: &User, thread_id: i32) -> Result<bool, sqlx::Error> {
+    let Some(thread) = Thread::fetch_optional(conn, thread_id).await? else {
+        return Ok(false);
+    };
+    if thread.hidden && viewer.role < Role::Moderator {
+        return Ok(false);
+    }
+    Ok(viewer.show_mature || !thread.is_mature(conn).await?)
+}
+
+/// Whether a session is still valid and its user not banned, for when
+/// revocations may have been missed.
+async fn still_authorized(conn: &PgPool, session: &LoginSession) -> Result<bool, sqlx::Error> {
+    let Some(session) = LoginSession::fetch(conn, &session.session_id).await? else {
+        return Ok(false);
+    };
+    Ok(User::fetch_optional(conn, session.user_id)
+        .await?
+        .map_or(false, |user| !user.is_banned()))
+}
+
+/// Posts in a thread newer than `last_post`, ready to be sent to a watcher.
+/// Hidden posts are only sent to moderators, and nothing is sent once the
+/// thread is hidden from the viewer or gains a mature tag they don't want to
+/// see. Unlike on the thread page, bodies are escaped here, since the client
+/// inserts them as they are.
+async fn new_posts(
+    loader: &PostLoader<'_>,
+    conn: &PgPool,
+    viewer: &User,
+    thread_id: i32,
+    last_post: i32,
+) -> Result<Vec<Post>, WatchThreadError> {
+    let replies = sqlx::query_as(
+        r#"
+            SELECT replies.* FROM replies
+            JOIN threads ON threads.id = replies.thread_id
+            WHERE replies.thread_id = $1 AND replies.id > $2
+                AND (NOT (replies.hidden OR threads.hidden) OR $3)
+                AND ($4 OR NOT has_mature_tag(threads.tags))
+            ORDER BY replies.post_date ASC
+        "#,
+    )
+    .bind(thread_id)
+    .bind(last_post)
+    .bind(viewer.role >= Role::Moderator)
+    .bind(viewer.show_mature)
+    .fetch_all(conn)
+    .await?;
+    let mut posts = loader.load(replies).await?;
+    for post in &mut posts {
+        post.body =
+            askama::filters::linebreaks(askama::filters::escape(askama::Html, &post.body)?)?;
+    }
+    Ok(posts)
+}
+
+/// How often the database is polled for new replies to a watched thread.
+pub const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often watch sockets are pinged to check that they are still alive.
+pub const WATCH_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a watch socket may go without hearing from the client before it is
+/// closed.
+pub const WATCH_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Number of polls in a row that may fail before a watch socket is closed.
+/// Failures are usually the database being briefly unavailable, so a few are
+/// reported to the client and retried.
+pub const MAX_WATCH_POLL_FAILURES: usize = 5;
+
+get!(
+    "/watch/:thread_id",
+    pub async fn watch(
+        user: User,
+        session: LoginSession,
+        conn: Extension<PgPool>,
+        watchers: Extension<Watchers>,
+        revocations: Extension<Revocations>,
+        limits: Extension<Limits>,
+        stubs: Extension<ProfileStubs>,
+        ClientIp(ip): ClientIp,
+        ws: WebSocketUpgrade,
+        Path(thread_id): Path<i32>,
+    ) -> Response {
+        match can_watch(&conn, &user, thread_id).await {
+            Ok(true) => (),
+            Ok(false) => return WatchThreadError::NoSuchThread.into_response(),
+            Err(err) => {
+                tracing::error!("Failed to watch thread {thread_id}: {err}");
+                return WatchThreadError::from(err).into_response();
+            }
+        }
+
+        let last_post: Option<i32> =
+            match sqlx::query_scalar("SELECT MAX(id) FROM replies WHERE thread_id = $1")
+                .bind(thread_id)
+                .fetch_one(&*conn)
+                .await
+            {
+                Ok(last_post) => last_post,
+                Err(err) => {
+                    tracing::error!("Failed to watch thread {thread_id}: {err}");
+                    return WatchThreadError::from(err).into_response();
+                }
+            };
+        let Some(mut last_post) = last_post else {
+            return WatchThreadError::NoSuchThread.into_response();
+        };
+        let user_id = user.id;
+        ws.on_upgrade(move |mut socket| async move {
+            let _guard = match watchers.acquire(user_id, user.role, ip) {
+                Ok(guard) => guard,
+                Err(err) => {
+                    tracing::warn!("Rejecting watcher for user {user_id} from {ip}: {err}");
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code:   close_code::AGAIN,
+                            reason: err.to_string().into(),
+                        })))
+                        .await;
+                    return;
+                }
+            };
+            let loader = match PostLoader::new(&conn, &stubs, &user, &limits).await {
+                Ok(loader) => loader,
+                Err(err) => {
+                    tracing::error!(
+                        "Failed to load viewer {user_id} watching thread {thread_id}: {err}"
+                    );
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code:   close_code::ERROR,
+                            reason: WatchThreadError::from(err).to_string().into(),
+                        })))
+                        .await;
+                    return;
+                }
+            };
+            let mut revocations = revocations.subscribe();
+            let mut poll = tokio::time::interval(WATCH_POLL_INTERVAL);
+            poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut heartbeat = tokio::time::interval(WATCH_HEARTBEAT_INTERVAL);
+            let mut last_seen = Instant::now();
+            let mut failures = 0;
+            // Don't use listeners. It will quickly exhaust the number of connections
+            loop {
+                tokio::select! {
+                    _ = poll.tick() => {
+                        let posts = match new_posts(&loader, &conn, &user, thread_id, last_post).await {
+                            Ok(posts) => {
+                                failures = 0;
+                                posts
+                            }
+                            Err(err) => {
+                                failures += 1;
+                                tracing::error!(
+                                    "Failed to fetch posts after {last_post} in thread {thread_id} \
+                                     for user {user_id} ({failures} in a row): {err}"
+                                );
+                                if failures >= MAX_WATCH_POLL_FAILURES {
+                                    let _ = socket
+                                        .send(Message::Close(Some(CloseFrame {
+                                            code:   close_code::ERROR,
+                                            reason: err.to_string().into(),
+                                        })))
+                                        .await;
+                                    return;
+                                }
+                                if socket.send(Message::from(err.to_json().to_string())).await.is_err() {
+                                    return;
+                                }
+                                continue;
+                            }
+                        };
+                        for post in posts {
+                            // A post that cannot be sent is skipped rather than
+                            // sent again on every poll.
+                            last_post = last_post.max(post.id);
+                            let message = match serde_json::to_string(&post) {
+                                Ok(post) => post,
+                                Err(err) => {
+                                    tracing::error!(
+                                        "Failed to serialize post {} for user {user_id}: {err}",
+                                        post.id
+                                    );
+                                    WatchThreadError::from(err).to_json().to_string()
+                                }
+                            };
+                            if socket.send(Message::from(message)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    _ = heartbeat.tick() => {
+                        if last_seen.elapsed() > WATCH_IDLE_TIMEOUT {
+                            let _ = socket
+                                .send(Message::Close(Some(CloseFrame {
+                                    code:   close_code::AWAY,
+                                    reason: "Idle timeout".into(),
+                                })))
+                                .await;
+                            return;
+                        }
+                        if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                            return;
+                        }
+                    }
+                    msg = socket.recv() => match msg {
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                        Some(Ok(_)) => last_seen = Instant::now(),
+                    },
+                    revoked = revocations.recv() => match revoked {
+                        Ok(revoked) if revoked.applies_to(&session) => {
+                            let _ = socket
+                                .send(Message::Close(Some(CloseFrame {
+                                    code:   close_code::POLICY,
+                                    reason: "Your access has been revoked".into(),
+                                })))
+                                .await;
+                            return;
+                        }
+                        Ok(_) => (),
+                        // Revocations were missed, so check with the database
+                        // that the session still stands.
+                        Err(RecvError::Lagged(_)) => match still_authorized(&conn, &session).await {
+                            Ok(true) => (),
+                            Ok(false) => {
+                                let _ = socket
+                                    .send(Message::Close(Some(CloseFrame {
+                                        code:   close_code::POLICY,
+                                        reason: "Your access has been revoked".into(),
+                                    })))
+                                    .await;
+                                return;
+                            }
+                            Err(err) => {
+                                tracing::error!(
+                                    "Failed to check session of user {user_id} watching thread \
+                                     {thread_id}: {err}"
+                                );
+                                let _ = socket
+                                    .send(Message::Close(Some(CloseFrame {
+                                        code:   close_code::ERROR,
+                                        reason: WatchThreadError::from(err).to_string().into(),
+                                    })))
+                                    .await;
+                                return;
+                            }
+                        },
+                        Err(RecvError::Closed) => return,
+                    },
+                }
+            }
+        })
+    }
+);
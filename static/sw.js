@@ -0,0 +1,18 @@
+// Shows notifications pushed by the server and opens the page they link to
+// when clicked.
+self.addEventListener('push', function(event) {
+    if (!event.data) {
+        return;
+    }
+    const message = event.data.json();
+    event.waitUntil(self.registration.showNotification(message.title, {
+        body: message.body,
+        icon: '/static/icon.svg',
+        data: { url: message.url },
+    }));
+});
+
+self.addEventListener('notificationclick', function(event) {
+    event.notification.close();
+    event.waitUntil(clients.openWindow(event.notification.data.url));
+});
@@ -139,11 +139,13 @@ $(document).ready(function() {
             button.css("background-color", "#b1c66d");
             buttonTextHolder[0].textContent="✔️ file";
             filenameTextHolder[0].textContent=`└ ${file.name}`;
+            $("#image-credits").show();
         } else {
             button.attr("title", "");
             button.css("background-color", "");
             buttonTextHolder[0].textContent="file";
             filenameTextHolder[0].textContent="";
+            $("#image-credits").hide();
         }
       });
 });